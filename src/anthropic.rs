@@ -3,29 +3,344 @@ use futures_util::StreamExt;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
-use crate::ui::ToolMode;
+use crate::{
+    bedrock::{Bedrock, EventStreamParser},
+    ollama::{ChatStreamParser, LocalModel, Ollama},
+    sse::{SseEvent, SseParser},
+    ui::ToolMode,
+};
 
 #[derive(Debug, Clone)]
 pub struct AnthropicClient {
     api_key: String,
     client: Client,
+    provider: Provider,
     tool_mode: ToolMode,
+    client_tools: Vec<ClientTool>, // Tools from the config, run locally when Claude calls them
+    forced_tool: Option<String>,   // Tool every answer must call (--schema)
+    web_search_max_uses: Option<u32>, // Searches allowed per request when web search is on
+    model: String,                 // Used for requests that don't name a model themselves
+    sampling: RequestParams,       // Session-wide sampling settings under per-request overrides
+    stall_timeout: Duration,       // Silence on an open stream before it is reported as stalled
+    request_timeout: Option<Duration>, // Deadline for one streamed answer
 }
 
-#[derive(Debug, Serialize)]
+// Where requests go: the Anthropic API, Claude on AWS Bedrock, or a local Ollama server
+#[derive(Debug, Clone)]
+pub enum Provider {
+    Anthropic,
+    Bedrock(Bedrock),
+    Ollama(Ollama),
+}
+
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Anthropic => "the Anthropic API",
+            Provider::Bedrock(_) => "Bedrock",
+            Provider::Ollama(_) => "Ollama",
+        }
+    }
+
+    // Code execution and web search run on Anthropic's side; local models have neither
+    pub fn supports_server_tools(&self) -> bool {
+        !matches!(self, Provider::Ollama(_))
+    }
+}
+
+// Splits a response body into Messages API events: SSE from Anthropic, the binary AWS
+// event stream from Bedrock, translated JSON lines from Ollama
+enum StreamDecoder {
+    Sse(SseParser),
+    EventStream(EventStreamParser),
+    Ollama(ChatStreamParser),
+}
+
+impl StreamDecoder {
+    fn for_provider(provider: &Provider) -> Self {
+        match provider {
+            Provider::Anthropic => StreamDecoder::Sse(SseParser::new()),
+            Provider::Bedrock(_) => StreamDecoder::EventStream(EventStreamParser::new()),
+            Provider::Ollama(_) => StreamDecoder::Ollama(ChatStreamParser::new()),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        match self {
+            StreamDecoder::Sse(parser) => parser.push(bytes),
+            StreamDecoder::EventStream(parser) => parser.push(bytes),
+            StreamDecoder::Ollama(parser) => parser.push(bytes),
+        }
+    }
+}
+
+// Network timeouts for every API call
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,         // Establishing the TCP/TLS connection
+    pub read: Duration,            // Silence on an open connection before it is dropped
+    pub request: Option<Duration>, // A whole streamed answer; None for no limit
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            read: Duration::from_secs(120),
+            request: Some(Duration::from_secs(900)),
+        }
+    }
+}
+
+// TCP keep-alive probes keep idle connections through NATs and notice dead peers
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+fn http_client(timeouts: &Timeouts) -> Client {
+    Client::builder()
+        .connect_timeout(timeouts.connect)
+        .read_timeout(timeouts.read)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .build()
+        .unwrap_or_else(|e| {
+            log_debug!("Failed to build HTTP client, using defaults: {}", e);
+            Client::new()
+        })
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    pub content: Vec<InputContent>,
 }
 
-#[derive(Debug, Serialize)]
-struct Tool {
+impl Message {
+    pub fn text(role: &str, text: String) -> Self {
+        Self {
+            role: role.to_string(),
+            content: vec![InputContent::Text { text }],
+        }
+    }
+}
+
+// Content blocks of an outgoing message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum InputContent {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "image")]
+    Image { source: Base64Source },
+    #[serde(rename = "document")]
+    Document { source: Base64Source }, // A PDF
+    #[serde(rename = "container_upload")]
+    ContainerUpload { file_id: String }, // A Files API file copied into the code execution container
+    #[serde(rename = "tool_use")]
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(rename = "tool_result")]
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Base64Source {
     #[serde(rename = "type")]
-    tool_type: String,
-    name: String,
+    pub source_type: String,
+    pub media_type: String,
+    pub data: String,
+}
+
+impl InputContent {
+    pub fn image(media_type: &str, bytes: &[u8]) -> Self {
+        use base64::Engine;
+        Self::base64_image(
+            media_type,
+            base64::engine::general_purpose::STANDARD.encode(bytes),
+        )
+    }
+
+    pub fn base64_image(media_type: &str, data: String) -> Self {
+        InputContent::Image {
+            source: Base64Source {
+                source_type: "base64".to_string(),
+                media_type: media_type.to_string(),
+                data,
+            },
+        }
+    }
+
+    pub fn document(media_type: &str, bytes: &[u8]) -> Self {
+        use base64::Engine;
+        Self::base64_document(
+            media_type,
+            base64::engine::general_purpose::STANDARD.encode(bytes),
+        )
+    }
+
+    pub fn base64_document(media_type: &str, data: String) -> Self {
+        InputContent::Document {
+            source: Base64Source {
+                source_type: "base64".to_string(),
+                media_type: media_type.to_string(),
+                data,
+            },
+        }
+    }
+}
+
+// A conversation given as JSON (`--input-format messages`): either a bare array of
+// messages or an object with "messages" and an optional "system" prompt. Content is a
+// string or a list of text/image blocks, as in the Messages API.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TranscriptInput {
+    Messages(Vec<TranscriptMessage>),
+    Request {
+        messages: Vec<TranscriptMessage>,
+        #[serde(default)]
+        system: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct TranscriptMessage {
+    role: String,
+    content: TranscriptContent,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TranscriptContent {
+    Text(String),
+    Blocks(Vec<InputContent>),
+}
+
+// Parse and check a JSON transcript, returning its messages and system prompt
+pub fn parse_transcript(json: &str) -> Result<(Vec<Message>, Option<String>)> {
+    let input: TranscriptInput = serde_json::from_str(json)
+        .map_err(|e| anyhow::anyhow!("stdin is not a valid messages transcript: {}", e))?;
+    let (transcript, system) = match input {
+        TranscriptInput::Messages(messages) => (messages, None),
+        TranscriptInput::Request { messages, system } => (messages, system),
+    };
+
+    let mut messages = Vec::new();
+    for (index, message) in transcript.into_iter().enumerate() {
+        if message.role != "user" && message.role != "assistant" {
+            anyhow::bail!(
+                "message {} has role \"{}\"; only user and assistant are allowed",
+                index + 1,
+                message.role
+            );
+        }
+        let content = match message.content {
+            TranscriptContent::Text(text) => vec![InputContent::Text { text }],
+            TranscriptContent::Blocks(blocks) => blocks,
+        };
+        if content.is_empty() {
+            anyhow::bail!("message {} has no content", index + 1);
+        }
+        messages.push(Message {
+            role: message.role,
+            content,
+        });
+    }
+    match messages.first() {
+        None => anyhow::bail!("the transcript has no messages"),
+        Some(first) if first.role != "user" => {
+            anyhow::bail!("the transcript must start with a user message")
+        }
+        Some(_) => Ok((messages, system)),
+    }
+}
+
+// Identify the image formats supported by the API from their magic bytes
+pub fn detect_image_media_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+// The API rejects images larger than this
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+pub const PDF_MEDIA_TYPE: &str = "application/pdf";
+
+// Requests can't be larger than this, so bigger PDFs can't be sent inline
+pub const MAX_DOCUMENT_BYTES: usize = 32 * 1024 * 1024;
+
+pub fn is_pdf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"%PDF-")
+}
+
+// Read an image file for a vision request, returning its media type and bytes
+pub fn read_image(path: &std::path::Path) -> Result<(&'static str, Vec<u8>)> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))?;
+    let media_type = detect_image_media_type(&bytes).ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} is not a supported image (PNG, JPEG, GIF, WebP)",
+            path.display()
+        )
+    })?;
+    if bytes.len() > MAX_IMAGE_BYTES {
+        anyhow::bail!(
+            "{} is {} KB; images must be under {} KB",
+            path.display(),
+            bytes.len() / 1024,
+            MAX_IMAGE_BYTES / 1024
+        );
+    }
+    Ok((media_type, bytes))
+}
+
+// A tool Anthropic runs (code execution, web search), or one agnt runs itself
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Tool {
+    Server {
+        #[serde(rename = "type")]
+        tool_type: String,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_uses: Option<u32>, // Cap on searches per request (web search only)
+    },
+    Client(ClientTool),
+}
+
+// A tool agnt runs itself: one described by its schema, or one of Anthropic's
+// (`tool_type` set) that the model already knows and that takes no schema
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientTool {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub tool_type: Option<String>,
+    pub name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub input_schema: Value,
 }
 
 #[derive(Debug, Serialize)]
@@ -38,6 +353,133 @@ struct MessagesRequest {
     system: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+}
+
+// Makes the model call one particular tool
+#[derive(Debug, Serialize)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    choice_type: &'static str,
+    name: String,
+}
+
+impl ToolChoice {
+    fn tool(name: &str) -> Self {
+        Self {
+            choice_type: "tool",
+            name: name.to_string(),
+        }
+    }
+}
+
+// The parts of a messages request that count_tokens accepts
+#[derive(Debug, Serialize)]
+struct CountTokensRequest {
+    model: String,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Tool>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountTokensResponse {
+    input_tokens: u64,
+}
+
+// Per-request overrides; anything left unset uses the client's defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RequestParams {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub max_tokens: Option<u32>,
+}
+
+// Output limit for requests that don't set one
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+impl RequestParams {
+    // Fill whatever this request leaves unset from `defaults`
+    pub fn or(self, defaults: &RequestParams) -> Self {
+        Self {
+            model: self.model.or_else(|| defaults.model.clone()),
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            stop_sequences: self
+                .stop_sequences
+                .or_else(|| defaults.stop_sequences.clone()),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+        }
+    }
+
+    // The ranges the API accepts, checked up front for a clearer message
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature
+            && !(0.0..=1.0).contains(&temperature)
+        {
+            anyhow::bail!("temperature must be between 0 and 1");
+        }
+        if let Some(top_p) = self.top_p
+            && !(0.0..=1.0).contains(&top_p)
+        {
+            anyhow::bail!("top_p must be between 0 and 1");
+        }
+        if self.top_k == Some(0) {
+            anyhow::bail!("top_k must be at least 1");
+        }
+        if self.max_tokens == Some(0) {
+            anyhow::bail!("max_tokens must be at least 1");
+        }
+        if let Some(stops) = &self.stop_sequences
+            && stops.iter().any(|stop| stop.trim().is_empty())
+        {
+            anyhow::bail!("stop sequences can't be empty or whitespace");
+        }
+        Ok(())
+    }
+
+    // Short human-readable summary, e.g. "temperature 1, model claude-opus-4-1"
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(temperature) = self.temperature {
+            parts.push(format!("temperature {}", temperature));
+        }
+        if let Some(top_p) = self.top_p {
+            parts.push(format!("top_p {}", top_p));
+        }
+        if let Some(top_k) = self.top_k {
+            parts.push(format!("top_k {}", top_k));
+        }
+        if let Some(stops) = &self.stop_sequences {
+            parts.push(format!("stop {:?}", stops));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            parts.push(format!("max_tokens {}", max_tokens));
+        }
+        if let Some(model) = &self.model {
+            parts.push(format!("model {}", model));
+        }
+        if parts.is_empty() {
+            "same settings".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,15 +494,166 @@ pub enum StreamEventData {
     #[serde(rename = "content_block_stop")]
     ContentBlockStop,
     #[serde(rename = "message_delta")]
-    MessageDelta,
+    MessageDelta {
+        #[serde(default)]
+        delta: Option<MessageDeltaInfo>,
+        #[serde(default)]
+        usage: Option<Usage>,
+    },
     #[serde(rename = "message_stop")]
     MessageStop,
+    #[serde(rename = "error")]
+    Error { error: ApiErrorBody },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiErrorBody {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+// Error responses are {"type": "error", "error": {"type": ..., "message": ...}}
+#[derive(Debug, Deserialize)]
+struct ApiErrorResponse {
+    error: ApiErrorBody,
+}
+
+// A request that failed, either before the stream started or through an SSE error event
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: Option<u16>, // HTTP status; None for connection failures and mid-stream errors
+    pub kind: String,        // The API's error type, e.g. overloaded_error, or connection_error
+    pub message: String,
+}
+
+impl ApiError {
+    fn connection(error: &reqwest::Error) -> Self {
+        let host = error
+            .url()
+            .and_then(|url| url.host_str())
+            .unwrap_or("the API")
+            .to_string();
+        if error.is_timeout() {
+            return ApiError::timeout(format!("Connecting to {} timed out: {}", host, error));
+        }
+        ApiError {
+            status: None,
+            kind: "connection_error".to_string(),
+            message: format!("Failed to connect to {}: {}", host, error),
+        }
+    }
+
+    fn timeout(message: String) -> Self {
+        ApiError {
+            status: None,
+            kind: "timeout_error".to_string(),
+            message,
+        }
+    }
+
+    fn from_stream(body: ApiErrorBody) -> Self {
+        ApiError {
+            status: None,
+            kind: body.error_type,
+            message: body.message,
+        }
+    }
+
+    fn from_response(status: reqwest::StatusCode, body: &str) -> Self {
+        let (kind, detail) = match serde_json::from_str::<ApiErrorResponse>(body) {
+            Ok(response) => (response.error.error_type, response.error.message),
+            // Bedrock and Ollama send {"message": ...} and {"error": "..."}
+            Err(_) => {
+                let detail = serde_json::from_str::<Value>(body)
+                    .ok()
+                    .and_then(|value| {
+                        let message = value.get("message").or_else(|| value.get("error"))?;
+                        message.as_str().map(str::to_string)
+                    })
+                    .unwrap_or_else(|| body.to_string());
+                ("api_error".to_string(), detail)
+            }
+        };
+        let message = match status.as_u16() {
+            401 => format!("Invalid or missing API key: {}", detail),
+            400 if detail.contains("model") => format!("Invalid model name: {}", detail),
+            400 => format!("Bad request: {}", detail),
+            429 => format!("Rate limit exceeded: {}", detail),
+            529 => format!("Anthropic API is overloaded: {}", detail),
+            _ if status.is_server_error() => format!("Anthropic server error: {}", detail),
+            _ => format!("API error ({}): {}", status, detail),
+        };
+        ApiError {
+            status: Some(status.as_u16()),
+            kind,
+            message,
+        }
+    }
+
+    // Name the call that failed, e.g. "Failed to upload file: Rate limit exceeded: ..."
+    fn during(mut self, action: &str) -> Self {
+        self.message = format!("Failed to {}: {}", action, self.message);
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageDeltaInfo {
+    #[serde(default)]
+    pub stop_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MessageStartData {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub container: Option<Container>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+}
+
+// Timing and size measurements for a single request/response turn
+#[derive(Debug, Clone, Default)]
+pub struct TurnMetrics {
+    pub ttft: Option<Duration>, // Time from sending the request to the first content delta
+    pub first_text: Option<Duration>, // Time from sending the request to the first text delta
+    pub duration: Duration,     // Total time from sending the request to the end of the stream
+    pub bytes: usize,           // Raw SSE bytes received
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub model: String,
+    pub stop_reason: Option<String>, // end_turn, max_tokens, tool_use, refusal, ...
+}
+
+impl TurnMetrics {
+    // Output speed once text started arriving; None for turns too short to measure
+    pub fn tokens_per_sec(&self) -> Option<f64> {
+        let start = self.first_text.or(self.ttft)?;
+        let secs = self.duration.saturating_sub(start).as_secs_f64();
+        (secs >= 0.05 && self.output_tokens > 0).then(|| self.output_tokens as f64 / secs)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -77,6 +670,8 @@ pub enum ContentBlock {
         #[allow(dead_code)]
         text: String,
     },
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String },
     #[serde(rename = "server_tool_use")]
     ServerToolUse {
         #[allow(dead_code)]
@@ -132,6 +727,25 @@ pub struct ListFilesResponse {
     pub next_page: Option<String>,
 }
 
+// A model from GET /v1/models (or an installed Ollama model)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListModelsResponse {
+    data: Vec<ModelInfo>,
+    #[serde(default)]
+    has_more: bool,
+    #[serde(default)]
+    last_id: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum CodeExecutionResult {
@@ -158,22 +772,284 @@ pub enum StreamEvent {
         files: Vec<(String, String)>, // (file_id, filename)
     },
     CodeError(String),
+    ToolUse {
+        id: String,
+        name: String,
+        input: String, // JSON object
+    },
     ContainerInfo {
         id: String,
         expires_at: String,
     },
     ConnectionStatus(String),
+    Disconnected(String), // The stream broke off before message_stop
+    Stalled(Duration),    // Nothing (not even a ping) arrived for this long
+    StallCleared,         // Data is flowing again after a stall
+    Metrics(TurnMetrics),
+    Error(ApiError), // The request failed; shown as an error, never as answer text
+}
+
+// ANTHROPIC_MODEL may be an alias such as "sonnet"
+pub fn default_model() -> String {
+    std::env::var("ANTHROPIC_MODEL")
+        .map(|model| crate::models::resolve(&model))
+        .unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string())
+}
+
+const MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+const COUNT_TOKENS_URL: &str = "https://api.anthropic.com/v1/messages/count_tokens";
+const MODELS_URL: &str = "https://api.anthropic.com/v1/models";
+
+// Rate limits (429) and overload (503/529) are retried this many times before the
+// error is shown
+const MAX_RETRIES: u32 = 4;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 503 | 529)
+}
+
+// The server's retry-after (in seconds) when it sends one, otherwise 1s, 2s, 4s, ...
+fn retry_delay(headers: &reqwest::header::HeaderMap, attempt: u32) -> Duration {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_else(|| Duration::from_secs(1 << attempt.min(5)))
+        .min(MAX_RETRY_DELAY)
+}
+
+// request_timeout_secs ran out before the answer was complete
+fn deadline_error(limit: Duration) -> ApiError {
+    log_debug!("Request deadline of {}s reached", limit.as_secs());
+    ApiError::timeout(format!(
+        "Request timed out: no complete answer within {}s (request_timeout_secs)",
+        limit.as_secs()
+    ))
+}
+
+// Count down to the next attempt in the status line. Returns false if the request
+// was cancelled while waiting.
+async fn wait_for_retry(
+    tx: &mpsc::Sender<StreamEvent>,
+    token: &CancellationToken,
+    reason: &str,
+    delay: Duration,
+    attempt: u32,
+) -> bool {
+    log_debug!(
+        "{}, retry {}/{} in {:?}",
+        reason,
+        attempt,
+        MAX_RETRIES,
+        delay
+    );
+    let deadline = Instant::now() + delay;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        let _ = tx
+            .send(StreamEvent::ConnectionStatus(format!(
+                "{}. Retrying in {}s... ({}/{})",
+                reason,
+                remaining.as_secs_f64().ceil() as u64,
+                attempt,
+                MAX_RETRIES
+            )))
+            .await;
+        let tick = remaining.min(Duration::from_secs(1));
+        tokio::select! {
+            _ = token.cancelled() => return false,
+            _ = tokio::time::sleep(tick) => {}
+        }
+    }
+}
+
+fn build_request(
+    tool_mode: ToolMode,
+    web_search_max_uses: Option<u32>,
+    client_tools: &[ClientTool],
+    default_model: &str,
+    messages: Vec<Message>,
+    system_prompt: Option<String>,
+    params: RequestParams,
+) -> MessagesRequest {
+    let code_execution = Tool::Server {
+        tool_type: "code_execution_20250522".to_string(),
+        name: "code_execution".to_string(),
+        max_uses: None,
+    };
+    let web_search = Tool::Server {
+        tool_type: "web_search_20250305".to_string(),
+        name: "web_search".to_string(),
+        max_uses: web_search_max_uses,
+    };
+    let mut tools = match tool_mode {
+        ToolMode::None => vec![],
+        ToolMode::CodeExecution => vec![code_execution],
+        ToolMode::WebSearch => vec![web_search],
+        ToolMode::Both => vec![code_execution, web_search],
+    };
+    tools.extend(client_tools.iter().cloned().map(Tool::Client));
+    let tools = (!tools.is_empty()).then_some(tools);
+
+    let model = params.model.unwrap_or_else(|| default_model.to_string());
+
+    MessagesRequest {
+        model,
+        messages,
+        max_tokens: params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        stream: true,
+        system: system_prompt,
+        tools,
+        tool_choice: None,
+        temperature: params.temperature,
+        top_p: params.top_p,
+        top_k: params.top_k,
+        stop_sequences: params.stop_sequences,
+    }
+}
+
+fn beta_features(tool_mode: ToolMode) -> Vec<&'static str> {
+    if matches!(tool_mode, ToolMode::CodeExecution | ToolMode::Both) {
+        vec!["code-execution-2025-05-22", "files-api-2025-04-14"]
+    } else {
+        Vec::new()
+    }
+}
+
+fn request_headers(api_key: &str, tool_mode: ToolMode) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("x-api-key", api_key.to_string()),
+        ("anthropic-version", "2023-06-01".to_string()),
+        ("content-type", "application/json".to_string()),
+    ];
+    let betas = beta_features(tool_mode);
+    if !betas.is_empty() {
+        headers.push(("anthropic-beta", betas.join(",")));
+    }
+    headers
 }
 
 impl AnthropicClient {
     pub fn new(api_key: String) -> Self {
+        let timeouts = Timeouts::default();
         Self {
             api_key,
-            client: Client::new(),
+            client: http_client(&timeouts),
+            provider: Provider::Anthropic,
             tool_mode: ToolMode::None,
+            client_tools: Vec::new(),
+            forced_tool: None,
+            web_search_max_uses: None,
+            sampling: RequestParams::default(),
+            model: default_model(),
+            stall_timeout: Duration::from_secs(30),
+            request_timeout: timeouts.request,
         }
     }
 
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.client = http_client(&timeouts);
+        self.request_timeout = timeouts.request;
+        self
+    }
+
+    pub fn with_provider(mut self, provider: Provider) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    pub fn provider(&self) -> &Provider {
+        &self.provider
+    }
+
+    // Token counting and the Files API only exist on the Anthropic API
+    fn require_anthropic(&self, feature: &str) -> Result<()> {
+        match self.provider {
+            Provider::Anthropic => Ok(()),
+            _ => anyhow::bail!("{} is not available on {}", feature, self.provider.name()),
+        }
+    }
+
+    // Models installed on the Ollama server
+    pub async fn local_models(&self) -> Result<Vec<LocalModel>> {
+        match &self.provider {
+            Provider::Ollama(ollama) => ollama.list_models(&self.client).await,
+            _ => anyhow::bail!("Local models are only listed with --provider ollama"),
+        }
+    }
+
+    // Every model the API key can use, newest first
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        if let Provider::Ollama(_) = self.provider {
+            let models = self.local_models().await?;
+            return Ok(models
+                .into_iter()
+                .map(|model| ModelInfo {
+                    display_name: model.name.clone(),
+                    id: model.name,
+                    created_at: Some(model.modified_at),
+                })
+                .collect());
+        }
+        self.require_anthropic("Model listing")?;
+
+        let mut models = Vec::new();
+        let mut after_id: Option<String> = None;
+        loop {
+            let mut query = vec![("limit", "1000".to_string())];
+            if let Some(after_id) = after_id.take() {
+                query.push(("after_id", after_id));
+            }
+            let response = self
+                .client
+                .get(MODELS_URL)
+                .query(&query)
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .send()
+                .await
+                .map_err(|e| ApiError::connection(&e))?;
+            let status = response.status();
+            if !status.is_success() {
+                let error_text = response.text().await.unwrap_or_default();
+                log_debug!("list models error (status {}): {}", status, error_text);
+                return Err(ApiError::from_response(status, &error_text).into());
+            }
+            let page: ListModelsResponse = response.json().await?;
+            models.extend(page.data);
+            match page.last_id {
+                Some(last_id) if page.has_more => after_id = Some(last_id),
+                _ => break,
+            }
+        }
+        crate::models::save_cache(&models);
+        Ok(models)
+    }
+
+    pub fn with_web_search_max_uses(mut self, max_uses: Option<u32>) -> Self {
+        self.web_search_max_uses = max_uses;
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: RequestParams) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn sampling(&self) -> &RequestParams {
+        &self.sampling
+    }
+
+    pub fn with_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
     pub fn with_tool_mode(mut self, mode: ToolMode) -> Self {
         self.tool_mode = mode;
         self
@@ -183,11 +1059,122 @@ impl AnthropicClient {
         self.tool_mode
     }
 
+    pub fn with_client_tools(mut self, tools: Vec<ClientTool>) -> Self {
+        self.client_tools = tools;
+        self
+    }
+
+    pub fn with_forced_tool(mut self, name: &str) -> Self {
+        self.forced_tool = Some(name.to_string());
+        self
+    }
+
+    pub fn with_model(mut self, model: String) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    // The exact request send_message_stream would make, with the API key redacted
+    pub fn dry_run(
+        &self,
+        messages: Vec<Message>,
+        system_prompt: Option<String>,
+        params: RequestParams,
+    ) -> Value {
+        let params = params.or(&self.sampling);
+        let mut request = build_request(
+            self.tool_mode,
+            self.web_search_max_uses,
+            &self.client_tools,
+            &self.model,
+            messages,
+            system_prompt,
+            params,
+        );
+        request.tool_choice = self.forced_tool.as_deref().map(ToolChoice::tool);
+        match &self.provider {
+            Provider::Anthropic => {}
+            Provider::Bedrock(bedrock) => {
+                let request = serde_json::to_value(&request).unwrap_or_default();
+                return serde_json::json!({
+                    "url": bedrock.invoke_url(&self.model),
+                    "body": Bedrock::request_body(&request, &beta_features(self.tool_mode)),
+                });
+            }
+            Provider::Ollama(ollama) => {
+                let request = serde_json::to_value(&request).unwrap_or_default();
+                return serde_json::json!({
+                    "url": ollama.chat_url(),
+                    "body": Ollama::chat_body(&request),
+                });
+            }
+        }
+        let headers: serde_json::Map<String, Value> = request_headers("<redacted>", self.tool_mode)
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Value::String(value)))
+            .collect();
+        serde_json::json!({
+            "url": MESSAGES_URL,
+            "headers": headers,
+            "body": request,
+        })
+    }
+
+    // Exact input token count of the request send_message_stream would make
+    pub async fn count_tokens(
+        &self,
+        messages: Vec<Message>,
+        system_prompt: Option<String>,
+        params: RequestParams,
+    ) -> Result<u64> {
+        self.require_anthropic("Token counting")?;
+        let params = params.or(&self.sampling);
+        let request = build_request(
+            self.tool_mode,
+            self.web_search_max_uses,
+            &self.client_tools,
+            &self.model,
+            messages,
+            system_prompt,
+            params,
+        );
+        let body = CountTokensRequest {
+            model: request.model,
+            messages: request.messages,
+            system: request.system,
+            tools: request.tools,
+        };
+
+        let mut request_builder = self.client.post(COUNT_TOKENS_URL);
+        for (name, value) in request_headers(&self.api_key, self.tool_mode) {
+            request_builder = request_builder.header(name, value);
+        }
+        let response = request_builder
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| ApiError::connection(&e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            log_debug!("count_tokens error (status {}): {}", status, error_text);
+            return Err(ApiError::from_response(status, &error_text).into());
+        }
+        let count: CountTokensResponse = response.json().await?;
+        Ok(count.input_tokens)
+    }
+
     pub async fn send_message_stream(
         &self,
         messages: Vec<Message>,
         system_prompt: Option<String>,
+        params: RequestParams,
     ) -> Result<(mpsc::Receiver<StreamEvent>, CancellationToken)> {
+        let params = params.or(&self.sampling);
         let (tx, rx) = mpsc::channel(100);
         let cancellation_token = CancellationToken::new();
         let token_clone = cancellation_token.clone();
@@ -195,10 +1182,18 @@ impl AnthropicClient {
         // Clone necessary data for the spawned task
         let api_key = self.api_key.clone();
         let client = self.client.clone();
+        let provider = self.provider.clone();
         let tool_mode = self.tool_mode;
+        let web_search_max_uses = self.web_search_max_uses;
+        let client_tools = self.client_tools.clone();
+        let forced_tool = self.forced_tool.clone();
+        let model = self.model.clone();
+        let stall_timeout = self.stall_timeout;
+        let request_timeout = self.request_timeout;
 
         // Spawn the entire request handling as a separate task
         tokio::spawn(async move {
+            let deadline = Instant::now() + request_timeout.unwrap_or_default();
             // Send initial connection status
             let _ = tx
                 .send(StreamEvent::ConnectionStatus(
@@ -206,120 +1201,150 @@ impl AnthropicClient {
                 ))
                 .await;
 
-            // Build the request
-            let tools = match tool_mode {
-                ToolMode::None => None,
-                ToolMode::CodeExecution => Some(vec![Tool {
-                    tool_type: "code_execution_20250522".to_string(),
-                    name: "code_execution".to_string(),
-                }]),
-                ToolMode::WebSearch => Some(vec![Tool {
-                    tool_type: "web_search_20250305".to_string(),
-                    name: "web_search".to_string(),
-                }]),
-                ToolMode::Both => Some(vec![
-                    Tool {
-                        tool_type: "code_execution_20250522".to_string(),
-                        name: "code_execution".to_string(),
-                    },
-                    Tool {
-                        tool_type: "web_search_20250305".to_string(),
-                        name: "web_search".to_string(),
-                    },
-                ]),
-            };
-
-            let model = std::env::var("ANTHROPIC_MODEL")
-                .unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
-
-            let request = MessagesRequest {
-                model,
+            let mut request = build_request(
+                tool_mode,
+                web_search_max_uses,
+                &client_tools,
+                &model,
                 messages,
-                max_tokens: 4096,
-                stream: true,
-                system: system_prompt,
-                tools,
+                system_prompt,
+                params,
+            );
+            request.tool_choice = forced_tool.as_deref().map(ToolChoice::tool);
+
+            let mut request_start = Instant::now();
+            let mut metrics = TurnMetrics {
+                model: request.model.clone(),
+                ..Default::default()
             };
+            let mut attempt = 0;
+            // Waiting for the response headers and the retries before them count toward
+            // the deadline and can be cancelled, like the stream that follows
+            let connect = async {
+                loop {
+                    let request_builder = match &provider {
+                        Provider::Anthropic => {
+                            let mut request_builder = client.post(MESSAGES_URL).json(&request);
+                            for (name, value) in request_headers(&api_key, tool_mode) {
+                                request_builder = request_builder.header(name, value);
+                            }
+                            request_builder
+                        }
+                        Provider::Bedrock(bedrock) => {
+                            let signed = serde_json::to_value(&request)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|request| {
+                                    bedrock.invoke_stream(
+                                        &client,
+                                        &request,
+                                        &beta_features(tool_mode),
+                                    )
+                                });
+                            match signed {
+                                Ok(request_builder) => request_builder,
+                                Err(e) => {
+                                    let _ = tx
+                                        .send(StreamEvent::Error(ApiError {
+                                            status: None,
+                                            kind: "invalid_request_error".to_string(),
+                                            message: format!("Cannot build Bedrock request: {}", e),
+                                        }))
+                                        .await;
+                                    return None;
+                                }
+                            }
+                        }
+                        Provider::Ollama(ollama) => {
+                            let request = serde_json::to_value(&request).unwrap_or_default();
+                            ollama.chat_stream(&client, &request)
+                        }
+                    };
 
-            let mut request_builder = client
-                .post("https://api.anthropic.com/v1/messages")
-                .header("x-api-key", &api_key)
-                .header("anthropic-version", "2023-06-01")
-                .header("content-type", "application/json");
+                    // Send the request (this is now in the spawned task)
+                    request_start = Instant::now();
+                    let _ = tx
+                        .send(StreamEvent::ConnectionStatus(
+                            "Sending request...".to_string(),
+                        ))
+                        .await;
+                    let response = match request_builder.send().await {
+                        Ok(resp) => resp,
+                        Err(e) => {
+                            log_debug!("Failed to send request to Messages API: {}", e);
+                            if e.to_string().contains("dns") || e.to_string().contains("connect") {
+                                log_debug!("Network/connection error detected");
+                            } else if e.to_string().contains("timed out") {
+                                log_debug!("Request timeout error");
+                            }
+                            let _ = tx.send(StreamEvent::Error(ApiError::connection(&e))).await;
+                            return None;
+                        }
+                    };
 
-            if matches!(tool_mode, ToolMode::CodeExecution | ToolMode::Both) {
-                request_builder = request_builder.header(
-                    "anthropic-beta",
-                    "code-execution-2025-05-22,files-api-2025-04-14",
-                );
-            }
+                    let status = response.status();
+                    if status.is_success() {
+                        break Some(response);
+                    }
+                    let delay = retry_delay(response.headers(), attempt);
+                    let error_text = response.text().await.unwrap_or_else(|e| {
+                        log_debug!("Failed to read error response body: {}", e);
+                        "Failed to read error response".to_string()
+                    });
 
-            // Send the request (this is now in the spawned task)
-            let _ = tx
-                .send(StreamEvent::ConnectionStatus(
-                    "Sending request...".to_string(),
-                ))
-                .await;
-            let response = match request_builder.json(&request).send().await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    log_debug!("Failed to send request to Messages API: {}", e);
-                    if e.to_string().contains("dns") || e.to_string().contains("connect") {
-                        log_debug!("Network/connection error detected");
-                    } else if e.to_string().contains("timed out") {
-                        log_debug!("Request timeout error");
+                    log_debug!("API error response (status {}): {}", status, error_text);
+
+                    // Overload and rate limits usually clear up on their own
+                    if is_retryable(status) && attempt < MAX_RETRIES {
+                        attempt += 1;
+                        let reason = if status == 429 {
+                            "Rate limited"
+                        } else {
+                            "API overloaded"
+                        };
+                        if !wait_for_retry(&tx, &token_clone, reason, delay, attempt).await {
+                            return None;
+                        }
+                        continue;
                     }
-                    // Send error through the channel
-                    let error_msg = format!("Failed to connect to Anthropic API: {}", e);
-                    let _ = tx
-                        .send(StreamEvent::Text(format!("\n\nError: {}\n", error_msg)))
-                        .await;
+
+                    let error = ApiError::from_response(status, &error_text);
+                    log_debug!(
+                        "Request failed: status={:?} kind={}",
+                        error.status,
+                        error.kind
+                    );
+                    let _ = tx.send(StreamEvent::Error(error)).await;
+                    return None;
+                }
+            };
+            let response = tokio::select! {
+                response = connect => match response {
+                    Some(response) => response,
+                    None => return,
+                },
+                _ = token_clone.cancelled() => return,
+                _ = tokio::time::sleep_until(deadline.into()), if request_timeout.is_some() => {
+                    let error = deadline_error(request_timeout.unwrap_or_default());
+                    let _ = tx.send(StreamEvent::Error(error)).await;
                     return;
                 }
             };
 
-            let status = response.status();
-            if !status.is_success() {
-                let error_text = response.text().await.unwrap_or_else(|e| {
-                    log_debug!("Failed to read error response body: {}", e);
-                    "Failed to read error response".to_string()
-                });
-
-                log_debug!("API error response (status {}): {}", status, error_text);
-
-                // Parse specific error types and send through channel
-                let error_msg = if status == 401 {
-                    log_debug!("Authentication error - invalid or missing API key");
-                    format!("Invalid or missing API key: {}", error_text)
-                } else if status == 400 {
-                    if error_text.contains("model") {
-                        log_debug!("Invalid model name error");
-                        format!("Invalid model name: {}", error_text)
-                    } else {
-                        log_debug!("Bad request error");
-                        format!("Bad request: {}", error_text)
-                    }
-                } else if status == 429 {
-                    log_debug!("Rate limit error");
-                    format!("Rate limit exceeded: {}", error_text)
-                } else if status.is_server_error() {
-                    log_debug!("Server error ({})", status);
-                    format!("Anthropic server error: {}", error_text)
-                } else {
-                    format!("API error ({}): {}", status, error_text)
-                };
-
-                let _ = tx
-                    .send(StreamEvent::Text(format!("\n\nError: {}\n", error_msg)))
-                    .await;
-                return;
-            }
-
             // Process the streaming response
             let mut stream = response.bytes_stream();
-            let mut buffer = String::new();
+            let mut parser = StreamDecoder::for_provider(&provider);
             let mut current_code_input = String::new();
             let mut collecting_code = false;
+            // Input of a web_search call, shown as a status while the search runs
+            let mut current_search_input = String::new();
+            let mut collecting_search = false;
+            // A call to one of the config's tools: (id, name) and its input so far
+            let mut tool_call: Option<(String, String)> = None;
+            let mut current_tool_input = String::new();
+            let mut completed = false;
+            let mut dropped = None;
+            let mut last_activity = Instant::now();
+            let mut stalled = false;
 
             loop {
                 tokio::select! {
@@ -327,116 +1352,222 @@ impl AnthropicClient {
                         // Streaming was cancelled
                         break;
                     }
+                    _ = tokio::time::sleep_until(deadline.into()), if request_timeout.is_some() => {
+                        let error = deadline_error(request_timeout.unwrap_or_default());
+                        let _ = tx.send(StreamEvent::Error(error)).await;
+                        break;
+                    }
+                    _ = tokio::time::sleep_until((last_activity + stall_timeout).into()), if !stalled => {
+                        log_debug!("Stream stalled: no data for {:?}", stall_timeout);
+                        stalled = true;
+                        let _ = tx.send(StreamEvent::Stalled(stall_timeout)).await;
+                    }
                     chunk = stream.next() => {
+                        last_activity = Instant::now();
+                        if stalled {
+                            stalled = false;
+                            let _ = tx.send(StreamEvent::StallCleared).await;
+                        }
                         match chunk {
                             Some(Ok(bytes)) => {
-                                if let Ok(text) = std::str::from_utf8(&bytes) {
-                                    buffer.push_str(text);
-
-                                    // Process complete SSE events
-                                    while let Some(event_end) = buffer.find("\n\n") {
-                                            let event_data = buffer[..event_end].to_string();
-                                            buffer = buffer[event_end + 2..].to_string();
-
-                                            // Parse SSE event
-                                            if let Some(data_line) =
-                                                event_data.lines().find(|line| line.starts_with("data: "))
-                                            {
-                                                let json_str = &data_line[6..];
+                                metrics.bytes += bytes.len();
+                                for sse_event in parser.push(&bytes) {
+                                    if sse_event.event.as_deref() == Some("ping") {
+                                        continue;
+                                    }
+                                    let event = match serde_json::from_str::<StreamEventData>(&sse_event.data) {
+                                        Ok(event) => event,
+                                        Err(e) => {
+                                            log_debug!("Skipping stream event {:?}: {}", sse_event.event.as_deref().unwrap_or(""), e);
+                                            continue;
+                                        }
+                                    };
+                                    match event {
+                                        StreamEventData::MessageStart { message } => {
+                                            if let Some(usage) = message.usage {
+                                                metrics.input_tokens = usage.input_tokens;
+                                                metrics.output_tokens = usage.output_tokens;
+                                                metrics.cache_creation_tokens = usage.cache_creation_input_tokens;
+                                                metrics.cache_read_tokens = usage.cache_read_input_tokens;
+                                            }
+                                            if let Some(container) = message.container {
+                                                let _ = tx.send(StreamEvent::ContainerInfo {
+                                                    id: container.id,
+                                                    expires_at: container.expires_at,
+                                                }).await;
+                                            }
+                                        }
+                                        StreamEventData::ContentBlockStart { content_block } => {
+                                            match content_block {
+                                                ContentBlock::ToolUse { id, name } => {
+                                                    tool_call = Some((id, name));
+                                                    current_tool_input.clear();
+                                                }
+                                                ContentBlock::ServerToolUse { name, .. } if name == "code_execution" => {
+                                                    collecting_code = true;
+                                                    current_code_input.clear();
+                                                }
+                                                ContentBlock::ServerToolUse { name, .. } if name == "web_search" => {
+                                                    collecting_search = true;
+                                                    current_search_input.clear();
+                                                }
+                                                ContentBlock::CodeExecutionToolResult { content, .. } => {
+                                                    match content {
+                                                        CodeExecutionResult::Success { stdout, stderr, return_code, content } => {
+                                                            // Extract files from the content array
+                                                            let files: Vec<(String, String)> = content.iter()
+                                                                .map(|f| match f {
+                                                                    FileOutput::CodeExecutionOutput { file_id } => {
+                                                                        // Use file ID as both ID and temporary filename
+                                                                        // The UI will show just the file ID to avoid duplicate "file_file" prefix
+                                                                        (file_id.clone(), file_id.clone())
+                                                                    }
+                                                                })
+                                                                .collect();
 
 
-                                                if let Ok(event) = serde_json::from_str::<StreamEventData>(json_str) {
-                                                    match event {
-                                                    StreamEventData::MessageStart { message } => {
-                                                        if let Some(container) = message.container {
-                                                            let _ = tx.send(StreamEvent::ContainerInfo {
-                                                                id: container.id,
-                                                                expires_at: container.expires_at,
+                                                            let _ = tx.send(StreamEvent::CodeOutput {
+                                                                stdout,
+                                                                stderr,
+                                                                return_code,
+                                                                files,
                                                             }).await;
                                                         }
-                                                    }
-                                                    StreamEventData::ContentBlockStart { content_block } => {
-                                                        match content_block {
-                                                            ContentBlock::ServerToolUse { name, .. } => {
-                                                                if name == "code_execution" {
-                                                                    collecting_code = true;
-                                                                    current_code_input.clear();
-                                                                }
-                                                            }
-                                                            ContentBlock::CodeExecutionToolResult { content, .. } => {
-                                                                match content {
-                                                                    CodeExecutionResult::Success { stdout, stderr, return_code, content } => {
-                                                                        // Extract files from the content array
-                                                                        let files: Vec<(String, String)> = content.iter()
-                                                                            .map(|f| match f {
-                                                                                FileOutput::CodeExecutionOutput { file_id } => {
-                                                                                    // Use file ID as both ID and temporary filename
-                                                                                    // The UI will show just the file ID to avoid duplicate "file_file" prefix
-                                                                                    (file_id.clone(), file_id.clone())
-                                                                                }
-                                                                            })
-                                                                            .collect();
-
-
-                                                                        let _ = tx.send(StreamEvent::CodeOutput {
-                                                                            stdout,
-                                                                            stderr,
-                                                                            return_code,
-                                                                            files,
-                                                                        }).await;
-                                                                    }
-                                                                    CodeExecutionResult::Error { error_code } => {
-                                                                        let _ = tx.send(StreamEvent::CodeError(error_code)).await;
-                                                                    }
-                                                                }
-                                                            }
-                                                            _ => {}
+                                                        CodeExecutionResult::Error { error_code } => {
+                                                            let _ = tx.send(StreamEvent::CodeError(error_code)).await;
                                                         }
                                                     }
-                                                    StreamEventData::ContentBlockDelta { delta } => {
-                                                        match delta {
-                                                            Delta::TextDelta { text } => {
-                                                                if tx.send(StreamEvent::Text(text)).await.is_err() {
-                                                                    break; // Exit if receiver dropped
-                                                                }
-                                                            }
-                                                            Delta::InputJsonDelta { partial_json } => {
-                                                                if collecting_code {
-                                                                    current_code_input.push_str(&partial_json);
-                                                                }
-                                                            }
-                                                        }
+                                                }
+                                                _ => {}
+                                            }
+                                        }
+                                        StreamEventData::ContentBlockDelta { delta } => {
+                                            if metrics.ttft.is_none() {
+                                                metrics.ttft = Some(request_start.elapsed());
+                                            }
+                                            match delta {
+                                                Delta::TextDelta { text } => {
+                                                    if metrics.first_text.is_none() {
+                                                        metrics.first_text = Some(request_start.elapsed());
                                                     }
-                                                    StreamEventData::ContentBlockStop => {
-                                                        if collecting_code && !current_code_input.is_empty() {
-                                                            // Extract code from JSON
-                                                            if let Ok(json) = serde_json::from_str::<Value>(&current_code_input) {
-                                                                if let Some(code) = json.get("code").and_then(|v| v.as_str()) {
-                                                                    let _ = tx.send(StreamEvent::CodeInput(code.to_string())).await;
-                                                                }
-                                                            }
-                                                            collecting_code = false;
-                                                            current_code_input.clear();
-                                                        }
+                                                    if tx.send(StreamEvent::Text(text)).await.is_err() {
+                                                        break; // Exit if receiver dropped
+                                                    }
+                                                }
+                                                Delta::InputJsonDelta { partial_json } => {
+                                                    if tool_call.is_some() {
+                                                        current_tool_input.push_str(&partial_json);
+                                                    } else if collecting_code {
+                                                        current_code_input.push_str(&partial_json);
+                                                    } else if collecting_search {
+                                                        current_search_input.push_str(&partial_json);
                                                     }
-                                                    _ => {}
                                                 }
                                             }
+                                        }
+                                        StreamEventData::ContentBlockStop if tool_call.is_some() => {
+                                            if let Some((id, name)) = tool_call.take() {
+                                                // A call without arguments streams no input at all
+                                                let input = if current_tool_input.trim().is_empty() {
+                                                    "{}".to_string()
+                                                } else {
+                                                    std::mem::take(&mut current_tool_input)
+                                                };
+                                                let _ = tx.send(StreamEvent::ToolUse { id, name, input }).await;
                                             }
                                         }
+                                        StreamEventData::ContentBlockStop if collecting_code && !current_code_input.is_empty() => {
+                                            // Extract code from JSON
+                                            if let Ok(json) = serde_json::from_str::<Value>(&current_code_input)
+                                                && let Some(code) = json.get("code").and_then(|v| v.as_str())
+                                            {
+                                                let _ = tx.send(StreamEvent::CodeInput(code.to_string())).await;
+                                            }
+                                            collecting_code = false;
+                                            current_code_input.clear();
+                                        }
+                                        StreamEventData::ContentBlockStop if collecting_search => {
+                                            if let Ok(json) = serde_json::from_str::<Value>(&current_search_input)
+                                                && let Some(query) = json.get("query").and_then(|v| v.as_str())
+                                            {
+                                                let _ = tx.send(StreamEvent::ConnectionStatus(format!("Searching the web for \"{}\"...", query))).await;
+                                            }
+                                            collecting_search = false;
+                                            current_search_input.clear();
+                                        }
+                                        StreamEventData::MessageDelta { delta, usage } => {
+                                            // message_delta carries cumulative token counts; input tokens only
+                                            // appear here for providers that count them at the end
+                                            if let Some(usage) = usage {
+                                                metrics.output_tokens = usage.output_tokens;
+                                                if usage.input_tokens > 0 {
+                                                    metrics.input_tokens = usage.input_tokens;
+                                                }
+                                            }
+                                            if let Some(reason) = delta.and_then(|d| d.stop_reason) {
+                                                metrics.stop_reason = Some(reason);
+                                            }
+                                        }
+                                        StreamEventData::MessageStop => {
+                                            completed = true;
+                                        }
+                                        StreamEventData::Error { error } => {
+                                            log_debug!("Stream error event: {} {}", error.error_type, error.message);
+                                            // Overload mid-answer is picked up again by the reconnect logic
+                                            if error.error_type == "overloaded_error" {
+                                                dropped = Some(format!("API overloaded: {}", error.message));
+                                            } else {
+                                                let _ = tx.send(StreamEvent::Error(ApiError::from_stream(error))).await;
+                                            }
+                                            completed = true;
+                                        }
+                                        _ => {}
+                                    }
                                 }
                             }
-                            Some(Err(_)) | None => break,
+                            Some(Err(e)) => {
+                                log_debug!("Stream read error: {}", e);
+                                dropped = Some(if e.is_timeout() {
+                                    "read timed out".to_string()
+                                } else {
+                                    e.to_string()
+                                });
+                                break;
+                            }
+                            None => {
+                                if !completed {
+                                    log_debug!("Stream closed before message_stop");
+                                    dropped = Some("connection closed by server".to_string());
+                                }
+                                break;
+                            }
                         }
                     }
                 }
             }
+
+            if let Some(reason) = dropped {
+                let _ = tx.send(StreamEvent::Disconnected(reason)).await;
+            }
+
+            metrics.duration = request_start.elapsed();
+            log_debug!(
+                "Turn metrics: ttft={:?} first_text={:?} duration={:?} bytes={} input_tokens={} output_tokens={}",
+                metrics.ttft,
+                metrics.first_text,
+                metrics.duration,
+                metrics.bytes,
+                metrics.input_tokens,
+                metrics.output_tokens
+            );
+            let _ = tx.send(StreamEvent::Metrics(metrics)).await;
         });
 
         Ok((rx, cancellation_token))
     }
 
     pub async fn get_file_metadata(&self, file_id: &str) -> Result<FileMetadata> {
+        self.require_anthropic("The Files API")?;
         log_debug!("Fetching metadata for file: {}", file_id);
 
         let response = match self
@@ -451,7 +1582,7 @@ impl AnthropicClient {
             Ok(resp) => resp,
             Err(e) => {
                 log_debug!("Failed to fetch file metadata: {}", e);
-                return Err(anyhow::anyhow!("Failed to fetch file metadata: {}", e));
+                return Err(ApiError::connection(&e).into());
             }
         };
 
@@ -466,10 +1597,9 @@ impl AnthropicClient {
                 status,
                 error_text
             );
-            return Err(anyhow::anyhow!(
-                "Failed to get file metadata: {}",
-                error_text
-            ));
+            return Err(ApiError::from_response(status, &error_text)
+                .during("get file metadata")
+                .into());
         }
 
         let response_text = response.text().await.map_err(|e| {
@@ -494,9 +1624,19 @@ impl AnthropicClient {
     }
 
     pub async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        self.download_file_with_progress(file_id, |_, _| {}).await
+    }
+
+    // `progress` is called with the bytes received so far and the total size, when known
+    pub async fn download_file_with_progress(
+        &self,
+        file_id: &str,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<Vec<u8>> {
+        self.require_anthropic("The Files API")?;
         log_debug!("Downloading file: {}", file_id);
 
-        let response = match self
+        let mut response = match self
             .client
             .get(format!(
                 "https://api.anthropic.com/v1/files/{}/content",
@@ -511,7 +1651,7 @@ impl AnthropicClient {
             Ok(resp) => resp,
             Err(e) => {
                 log_debug!("Failed to download file: {}", e);
-                return Err(anyhow::anyhow!("Failed to download file: {}", e));
+                return Err(ApiError::connection(&e).into());
             }
         };
 
@@ -526,20 +1666,92 @@ impl AnthropicClient {
                 status,
                 error_text
             );
-            return Err(anyhow::anyhow!("Failed to download file: {}", error_text));
+            return Err(ApiError::from_response(status, &error_text)
+                .during("download file")
+                .into());
         }
 
-        let content = response.bytes().await.map_err(|e| {
+        let total = response.content_length();
+        let mut content = Vec::with_capacity(total.unwrap_or(0) as usize);
+        progress(0, total);
+        while let Some(chunk) = response.chunk().await.map_err(|e| {
             log_debug!("Failed to read file content: {}", e);
             anyhow::anyhow!("Failed to read file content: {}", e)
-        })?;
+        })? {
+            content.extend_from_slice(&chunk);
+            progress(content.len() as u64, total);
+        }
 
         log_debug!("Successfully downloaded {} bytes", content.len());
-        Ok(content.to_vec())
+        Ok(content)
+    }
+
+    pub async fn delete_file(&self, file_id: &str) -> Result<()> {
+        self.require_anthropic("The Files API")?;
+        log_debug!("Deleting file: {}", file_id);
+        let response = self
+            .client
+            .delete(format!("https://api.anthropic.com/v1/files/{}", file_id))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("anthropic-beta", "files-api-2025-04-14")
+            .send()
+            .await
+            .map_err(|e| ApiError::connection(&e))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            return Err(ApiError::from_response(status, &error_text)
+                .during("delete file")
+                .into());
+        }
+        Ok(())
+    }
+
+    pub async fn upload_file(&self, path: &std::path::Path) -> Result<FileMetadata> {
+        self.require_anthropic("The Files API")?;
+        log_debug!("Uploading file: {}", path.display());
+        let form = reqwest::multipart::Form::new()
+            .file("file", path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))?;
+        self.upload(form).await
+    }
+
+    // Upload data that isn't in a file, such as piped stdin, under `name`
+    pub async fn upload_bytes(&self, name: &str, bytes: Vec<u8>) -> Result<FileMetadata> {
+        self.require_anthropic("The Files API")?;
+        log_debug!("Uploading {} ({} bytes)", name, bytes.len());
+        let part = reqwest::multipart::Part::bytes(bytes).file_name(name.to_string());
+        self.upload(reqwest::multipart::Form::new().part("file", part))
+            .await
+    }
+
+    async fn upload(&self, form: reqwest::multipart::Form) -> Result<FileMetadata> {
+        let response = self
+            .client
+            .post("https://api.anthropic.com/v1/files")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("anthropic-beta", "files-api-2025-04-14")
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| ApiError::connection(&e))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            return Err(ApiError::from_response(status, &error_text)
+                .during("upload file")
+                .into());
+        }
+        Ok(response.json().await?)
     }
 
-    #[allow(dead_code)]
     pub async fn list_files(&self) -> Result<ListFilesResponse> {
+        self.require_anthropic("The Files API")?;
         let response = self
             .client
             .get("https://api.anthropic.com/v1/files")
@@ -547,11 +1759,15 @@ impl AnthropicClient {
             .header("anthropic-version", "2023-06-01")
             .header("anthropic-beta", "files-api-2025-04-14")
             .send()
-            .await?;
+            .await
+            .map_err(|e| ApiError::connection(&e))?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             let error_text = response.text().await?;
-            return Err(anyhow::anyhow!("Failed to list files: {}", error_text));
+            return Err(ApiError::from_response(status, &error_text)
+                .during("list files")
+                .into());
         }
 
         let files_response: ListFilesResponse = response.json().await?;
@@ -0,0 +1,47 @@
+use anyhow::{Result, anyhow};
+use keyring::Entry;
+
+// Where `agnt auth login` keeps the API key in the OS keychain (macOS Keychain,
+// Windows Credential Manager, or the Secret Service on Linux)
+const SERVICE: &str = "agnt";
+const USER: &str = "anthropic_api_key";
+
+// The Secret Service backend drives its own async runtime, which can't start on a
+// thread already running tokio, so keychain calls get a thread of their own
+fn with_entry<T: Send + 'static>(
+    f: impl FnOnce(&Entry) -> keyring::Result<T> + Send + 'static,
+) -> keyring::Result<T> {
+    std::thread::spawn(move || f(&Entry::new(SERVICE, USER)?))
+        .join()
+        .unwrap_or_else(|_| {
+            Err(keyring::Error::PlatformFailure(
+                "keychain access panicked".into(),
+            ))
+        })
+}
+
+// The stored key, used when ANTHROPIC_API_KEY is unset
+pub fn stored_key() -> Option<String> {
+    match with_entry(|entry| entry.get_password()) {
+        Ok(key) => Some(key),
+        Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            log_debug!("Cannot read API key from keychain: {}", e);
+            None
+        }
+    }
+}
+
+pub fn store_key(key: String) -> Result<()> {
+    with_entry(move |entry| entry.set_password(&key))
+        .map_err(|e| anyhow!("Cannot save the key in the OS keychain: {}", e))
+}
+
+// False when there was no key to remove
+pub fn delete_key() -> Result<bool> {
+    match with_entry(|entry| entry.delete_credential()) {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(anyhow!("Cannot remove the key from the OS keychain: {}", e)),
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::Result;
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+use crate::{
+    anthropic::{self, AnthropicClient, StreamEvent, TurnMetrics},
+    pricing, usage,
+};
+
+// One request of a --batch run
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct BatchInput {
+    #[serde(default)]
+    pub id: Option<serde_json::Value>, // Echoed back so results can be matched up
+    pub prompt: String,
+}
+
+// One line of --batch output
+#[derive(Debug, Default, Serialize)]
+struct BatchResult {
+    index: usize, // Position among the inputs, from 0 (blank lines are skipped)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_reason: Option<String>,
+    input_tokens: u64,
+    output_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost_usd: Option<f64>,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Every non-blank line is a prompt, or with `json` an object with a "prompt" and an
+// optional "id"; `prefix` (from --message) goes in front of each prompt
+pub fn parse_inputs(
+    text: &str,
+    json: bool,
+    prefix: Option<&str>,
+) -> Result<Vec<BatchInput>, String> {
+    let mut inputs = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut input = if json {
+            serde_json::from_str::<BatchInput>(line)
+                .map_err(|e| format!("Line {} is not a batch object: {}", n + 1, e))?
+        } else {
+            BatchInput {
+                id: None,
+                prompt: line.to_string(),
+            }
+        };
+        if let Some(prefix) = prefix {
+            input.prompt = format!("{} {}", prefix, input.prompt);
+        }
+        inputs.push(input);
+    }
+    Ok(inputs)
+}
+
+// Send every input as its own request, at most `concurrency` at a time, and print one
+// JSON result per input in input order. Returns how many requests failed.
+pub async fn run(
+    client: &AnthropicClient,
+    system_prompt: String,
+    inputs: Vec<BatchInput>,
+    concurrency: usize,
+    session_id: &str,
+) -> Result<usize> {
+    let mut results = stream::iter(inputs.into_iter().enumerate())
+        .map(|(index, input)| ask(client, system_prompt.clone(), index, input, session_id))
+        .buffered(concurrency.max(1));
+    let mut failed = 0;
+    while let Some(result) = results.next().await {
+        if result.error.is_some() {
+            failed += 1;
+        }
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer(&mut stdout, &result)?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+    }
+    Ok(failed)
+}
+
+async fn ask(
+    client: &AnthropicClient,
+    system_prompt: String,
+    index: usize,
+    input: BatchInput,
+    session_id: &str,
+) -> BatchResult {
+    let mut result = BatchResult {
+        index,
+        id: input.id,
+        ..Default::default()
+    };
+    let messages = vec![anthropic::Message::text("user", input.prompt)];
+    let mut receiver = match client
+        .send_message_stream(messages, Some(system_prompt), Default::default())
+        .await
+    {
+        Ok((receiver, _cancellation)) => receiver,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+    while let Some(event) = receiver.recv().await {
+        match event {
+            StreamEvent::Text(text) => result.text.push_str(&text),
+            StreamEvent::Error(error) => result.error = Some(error.message),
+            StreamEvent::Disconnected(reason) => {
+                result.error = Some(format!("Connection lost: {}", reason))
+            }
+            StreamEvent::Metrics(metrics) => record(&mut result, &metrics, session_id),
+            _ => {}
+        }
+    }
+    result
+}
+
+fn record(result: &mut BatchResult, metrics: &TurnMetrics, session_id: &str) {
+    if let Err(e) = usage::record(&usage::UsageRecord::new(session_id, metrics)) {
+        log_debug!("Failed to record usage: {}", e);
+    }
+    result.stop_reason = metrics.stop_reason.clone();
+    result.input_tokens = metrics.input_tokens;
+    result.output_tokens = metrics.output_tokens;
+    result.cost_usd = pricing::cost(
+        &metrics.model,
+        metrics.input_tokens,
+        metrics.output_tokens,
+        metrics.cache_creation_tokens,
+        metrics.cache_read_tokens,
+    );
+    result.duration_ms = metrics.duration.as_millis();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_skip_blank_lines_and_take_ids() {
+        let inputs = parse_inputs(
+            "great product\n\n  \nbroke in a day\n",
+            false,
+            Some("Sentiment:"),
+        )
+        .unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[1].prompt, "Sentiment: broke in a day");
+
+        let inputs = parse_inputs("{\"id\": 7, \"prompt\": \"hi\"}\n", true, None).unwrap();
+        assert_eq!(inputs[0].id, Some(serde_json::json!(7)));
+        assert!(parse_inputs("{\"text\": \"hi\"}", true, None).is_err());
+    }
+}
@@ -0,0 +1,545 @@
+use anyhow::{Result, anyhow, bail};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, RequestBuilder};
+use ring::{digest, hmac};
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use crate::sse::SseEvent;
+
+// Version string Bedrock expects in place of the anthropic-version header
+const BEDROCK_VERSION: &str = "bedrock-2023-05-31";
+const SERVICE: &str = "bedrock";
+
+// Claude on AWS Bedrock: the region to call and how to authenticate there
+#[derive(Debug, Clone)]
+pub struct Bedrock {
+    pub region: String,
+    auth: Auth,
+}
+
+#[derive(Clone)]
+enum Auth {
+    Bearer(String), // A Bedrock API key from AWS_BEARER_TOKEN_BEDROCK
+    SigV4(Credentials),
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+// Keeps secrets out of debug logs
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Auth::Bearer(_) => write!(f, "Bearer(<redacted>)"),
+            Auth::SigV4(credentials) => write!(f, "SigV4({})", credentials.access_key_id),
+        }
+    }
+}
+
+impl Bedrock {
+    // Credentials come from AWS_BEARER_TOKEN_BEDROCK, the usual AWS_ACCESS_KEY_ID /
+    // AWS_SECRET_ACCESS_KEY / AWS_SESSION_TOKEN variables, or the AWS_PROFILE (default:
+    // "default") profile in ~/.aws/credentials. The region is `region`, AWS_REGION,
+    // AWS_DEFAULT_REGION or the profile's region in ~/.aws/config.
+    pub fn from_env(region: Option<String>) -> Result<Self> {
+        let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        let region = region
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .or_else(|| {
+                let section = if profile == "default" {
+                    profile.clone()
+                } else {
+                    format!("profile {}", profile)
+                };
+                ini_section(&aws_dir().join("config"), &section)?.remove("region")
+            })
+            .filter(|region| !region.is_empty())
+            .ok_or_else(|| {
+                anyhow!("No AWS region set (use AWS_REGION or bedrock_region in the config)")
+            })?;
+
+        if let Ok(token) = std::env::var("AWS_BEARER_TOKEN_BEDROCK") {
+            return Ok(Self {
+                region,
+                auth: Auth::Bearer(token),
+            });
+        }
+
+        let credentials = match (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key_id), Ok(secret_access_key)) => Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            },
+            _ => {
+                let path = aws_dir().join("credentials");
+                let mut section = ini_section(&path, &profile).ok_or_else(|| {
+                    anyhow!(
+                        "No AWS credentials: set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or add a [{}] profile to {}",
+                        profile,
+                        path.display()
+                    )
+                })?;
+                let (Some(access_key_id), Some(secret_access_key)) = (
+                    section.remove("aws_access_key_id"),
+                    section.remove("aws_secret_access_key"),
+                ) else {
+                    bail!(
+                        "Profile [{}] in {} has no access keys",
+                        profile,
+                        path.display()
+                    );
+                };
+                Credentials {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: section.remove("aws_session_token"),
+                }
+            }
+        };
+        Ok(Self {
+            region,
+            auth: Auth::SigV4(credentials),
+        })
+    }
+
+    fn host(&self) -> String {
+        format!("bedrock-runtime.{}.amazonaws.com", self.region)
+    }
+
+    // Path of the streaming invoke call; the model id's ':' is percent-encoded
+    fn invoke_path(model: &str) -> String {
+        format!(
+            "/model/{}/invoke-with-response-stream",
+            uri_encode(&model_id(model))
+        )
+    }
+
+    pub fn invoke_url(&self, model: &str) -> String {
+        format!("https://{}{}", self.host(), Self::invoke_path(model))
+    }
+
+    // A Messages API request body in Bedrock's form: the model moves into the URL, beta
+    // features into the body, and streaming is implied by the endpoint
+    pub fn request_body(request: &Value, betas: &[&str]) -> Value {
+        let mut body = request.clone();
+        if let Some(object) = body.as_object_mut() {
+            object.remove("model");
+            object.remove("stream");
+            object.insert("anthropic_version".to_string(), BEDROCK_VERSION.into());
+            if !betas.is_empty() {
+                object.insert("anthropic_beta".to_string(), betas.into());
+            }
+        }
+        body
+    }
+
+    pub fn invoke_stream(
+        &self,
+        client: &Client,
+        request: &Value,
+        betas: &[&str],
+    ) -> Result<RequestBuilder> {
+        let model = request
+            .get("model")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("request has no model"))?;
+        let body = serde_json::to_vec(&Self::request_body(request, betas))?;
+        let path = Self::invoke_path(model);
+        let mut builder = client
+            .post(format!("https://{}{}", self.host(), path))
+            .header("content-type", "application/json")
+            .header("accept", "application/vnd.amazon.eventstream");
+
+        match &self.auth {
+            Auth::Bearer(token) => {
+                builder = builder.bearer_auth(token);
+            }
+            Auth::SigV4(credentials) => {
+                let signing = SigningRequest {
+                    method: "POST",
+                    host: &self.host(),
+                    // Non-S3 services sign the path with every segment encoded once more
+                    canonical_path: &path
+                        .split('/')
+                        .map(uri_encode)
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    query: "",
+                    headers: vec![("content-type", "application/json".to_string())],
+                    body: &body,
+                };
+                for (name, value) in signing.sign(credentials, &self.region, SERVICE, Utc::now()) {
+                    builder = builder.header(name, value);
+                }
+            }
+        }
+        Ok(builder.body(body))
+    }
+}
+
+// Bedrock model ids for Anthropic model names: "claude-sonnet-4-20250514" becomes
+// "anthropic.claude-sonnet-4-20250514-v1:0". Ids that already name a Bedrock model or
+// inference profile ("us.anthropic...", ARNs) are used as they are.
+pub fn model_id(model: &str) -> String {
+    if model.contains("anthropic.") || model.starts_with("arn:") {
+        model.to_string()
+    } else {
+        format!("anthropic.{}-v1:0", model)
+    }
+}
+
+fn aws_dir() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".aws"))
+        .unwrap_or_else(|| PathBuf::from(".aws"))
+}
+
+// Keys of one [section] of an AWS ini file
+fn ini_section(path: &std::path::Path, name: &str) -> Option<HashMap<String, String>> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut in_section = false;
+    let mut found = None;
+    for line in contents.lines().map(str::trim) {
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = header.trim() == name;
+            if in_section {
+                found.get_or_insert_with(HashMap::new);
+            }
+        } else if in_section && let Some((key, value)) = line.split_once('=') {
+            found
+                .get_or_insert_with(HashMap::new)
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    found
+}
+
+// Percent-encode everything but unreserved characters, as SigV4 requires
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+// What AWS Signature Version 4 covers of a request
+struct SigningRequest<'a> {
+    method: &'a str,
+    host: &'a str,
+    canonical_path: &'a str,
+    query: &'a str,                  // Already sorted and encoded
+    headers: Vec<(&'a str, String)>, // Lowercase names, besides host and x-amz-*
+    body: &'a [u8],
+}
+
+impl SigningRequest<'_> {
+    // Headers to add to the request: x-amz-date, the session token if any, and authorization
+    fn sign(
+        &self,
+        credentials: &Credentials,
+        region: &str,
+        service: &str,
+        now: DateTime<Utc>,
+    ) -> Vec<(&'static str, String)> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let mut headers = self.headers.clone();
+        headers.push(("host", self.host.to_string()));
+        headers.push(("x-amz-date", amz_date.clone()));
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.sort_by(|a, b| a.0.cmp(b.0));
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            self.method,
+            self.canonical_path,
+            self.query,
+            canonical_headers,
+            signed_headers,
+            sha256_hex(self.body)
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+
+        let mut key = hmac_sha256(
+            format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+            &date,
+        );
+        for part in [region, service, "aws4_request"] {
+            key = hmac_sha256(&key, part);
+        }
+        let signature = hex(&hmac_sha256(&key, &string_to_sign));
+
+        let mut added = vec![("x-amz-date", amz_date)];
+        if let Some(token) = &credentials.session_token {
+            added.push(("x-amz-security-token", token.clone()));
+        }
+        added.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        added
+    }
+}
+
+// Decoder for the binary AWS event stream Bedrock answers with. Each frame carries one
+// Messages API event (base64 JSON in a "chunk"), or an exception, which is turned into
+// an API error event so both providers are handled the same way downstream.
+#[derive(Debug, Default)]
+pub struct EventStreamParser {
+    buffer: Vec<u8>,
+}
+
+// total length + headers length + prelude CRC, and the trailing message CRC
+const PRELUDE_LEN: usize = 12;
+const CRC_LEN: usize = 4;
+
+impl EventStreamParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while self.buffer.len() >= PRELUDE_LEN {
+            let total = read_u32(&self.buffer[0..4]) as usize;
+            let headers_len = read_u32(&self.buffer[4..8]) as usize;
+            if total < PRELUDE_LEN + headers_len + CRC_LEN {
+                log_debug!("Malformed event stream frame of {} bytes", total);
+                self.buffer.clear();
+                break;
+            }
+            if self.buffer.len() < total {
+                break;
+            }
+            let frame: Vec<u8> = self.buffer.drain(..total).collect();
+            let headers = parse_headers(&frame[PRELUDE_LEN..PRELUDE_LEN + headers_len]);
+            let payload = &frame[PRELUDE_LEN + headers_len..total - CRC_LEN];
+            if let Some(event) = frame_event(&headers, payload) {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// String-valued headers; values of other types are skipped over
+fn parse_headers(mut bytes: &[u8]) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    while let Some((&name_len, rest)) = bytes.split_first() {
+        let name_len = name_len as usize;
+        if rest.len() < name_len + 1 {
+            break;
+        }
+        let name = String::from_utf8_lossy(&rest[..name_len]).into_owned();
+        let value_type = rest[name_len];
+        let rest = &rest[name_len + 1..];
+        let (value_len, skip) = match value_type {
+            0 | 1 => (0, 0), // true, false
+            2 => (1, 0),     // byte
+            3 => (2, 0),     // short
+            4 => (4, 0),     // int
+            5 | 8 => (8, 0), // long, timestamp
+            9 => (16, 0),    // uuid
+            6 | 7 if rest.len() >= 2 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, 2),
+            _ => break,
+        };
+        if rest.len() < skip + value_len {
+            break;
+        }
+        if value_type == 7 {
+            let value = String::from_utf8_lossy(&rest[skip..skip + value_len]).into_owned();
+            headers.insert(name, value);
+        }
+        bytes = &rest[skip + value_len..];
+    }
+    headers
+}
+
+fn frame_event(headers: &HashMap<String, String>, payload: &[u8]) -> Option<SseEvent> {
+    let body: Value = serde_json::from_slice(payload).unwrap_or_default();
+    match headers.get(":message-type").map(String::as_str) {
+        Some("event") => {
+            let encoded = body.get("bytes").and_then(Value::as_str)?;
+            match base64::engine::general_purpose::STANDARD.decode(encoded) {
+                Ok(bytes) => Some(SseEvent {
+                    event: headers.get(":event-type").cloned(),
+                    data: String::from_utf8_lossy(&bytes).into_owned(),
+                }),
+                Err(e) => {
+                    log_debug!("Undecodable Bedrock chunk: {}", e);
+                    None
+                }
+            }
+        }
+        Some("exception") | Some("error") => {
+            let kind = headers
+                .get(":exception-type")
+                .or_else(|| headers.get(":error-code"))
+                .map(String::as_str)
+                .unwrap_or("bedrock_error");
+            // Throttling and capacity problems are retried like Anthropic overload errors
+            let kind = match kind {
+                "throttlingException" | "serviceUnavailableException" => "overloaded_error",
+                other => other,
+            };
+            let message = body
+                .get("message")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .or_else(|| headers.get(":error-message").cloned())
+                .unwrap_or_default();
+            Some(SseEvent {
+                event: Some("error".to_string()),
+                data: serde_json::json!({
+                    "type": "error",
+                    "error": { "type": kind, "message": message },
+                })
+                .to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The IAM ListUsers example from the AWS Signature Version 4 documentation
+    #[test]
+    fn signs_aws_documentation_example() {
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let request = SigningRequest {
+            method: "GET",
+            host: "iam.amazonaws.com",
+            canonical_path: "/",
+            query: "Action=ListUsers&Version=2010-05-08",
+            headers: vec![(
+                "content-type",
+                "application/x-www-form-urlencoded; charset=utf-8".to_string(),
+            )],
+            body: b"",
+        };
+        let now = DateTime::parse_from_rfc3339("2015-08-30T12:36:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = request.sign(&credentials, "us-east-1", "iam", now);
+        assert_eq!(headers[0], ("x-amz-date", "20150830T123600Z".to_string()));
+        assert_eq!(
+            headers[1].1,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+
+    fn frame(headers: &[(&str, &str)], payload: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for (name, value) in headers {
+            encoded.push(name.len() as u8);
+            encoded.extend_from_slice(name.as_bytes());
+            encoded.push(7);
+            encoded.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            encoded.extend_from_slice(value.as_bytes());
+        }
+        let total = PRELUDE_LEN + encoded.len() + payload.len() + CRC_LEN;
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&(total as u32).to_be_bytes());
+        frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(&encoded);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&[0; 4]);
+        frame
+    }
+
+    #[test]
+    fn decodes_chunks_and_exceptions_across_reads() {
+        let event = r#"{"type":"message_stop"}"#;
+        let chunk = format!(
+            r#"{{"bytes":"{}"}}"#,
+            base64::engine::general_purpose::STANDARD.encode(event)
+        );
+        let mut bytes = frame(
+            &[(":message-type", "event"), (":event-type", "chunk")],
+            chunk.as_bytes(),
+        );
+        bytes.extend(frame(
+            &[
+                (":message-type", "exception"),
+                (":exception-type", "throttlingException"),
+            ],
+            br#"{"message":"Too many requests"}"#,
+        ));
+
+        let mut parser = EventStreamParser::new();
+        let mut events = Vec::new();
+        for piece in bytes.chunks(5) {
+            events.extend(parser.push(piece));
+        }
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, event);
+        let error: Value = serde_json::from_str(&events[1].data).unwrap();
+        assert_eq!(error["error"]["type"], "overloaded_error");
+        assert_eq!(error["error"]["message"], "Too many requests");
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use base64::Engine;
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use crate::tmux;
+
+// Copy text to the system clipboard and return a description of where it went.
+// Over SSH there usually is no clipboard utility (or it would copy on the remote box),
+// so the OSC 52 escape sequence is used to let the local terminal do the copy instead.
+// Inside tmux the text is additionally stored in a tmux paste buffer.
+pub fn copy(text: &str) -> Result<String> {
+    let method = copy_to_system(text)?;
+
+    if tmux::is_active() {
+        match tmux::load_buffer(text) {
+            Ok(()) => return Ok(format!("{} + tmux buffer", method)),
+            Err(e) => log_debug!("Failed to copy into tmux buffer: {}", e),
+        }
+    }
+
+    Ok(method.to_string())
+}
+
+fn copy_to_system(text: &str) -> Result<&'static str> {
+    if is_remote_session() {
+        copy_osc52(text)?;
+        return Ok("OSC 52");
+    }
+
+    for (program, args) in local_clipboard_commands() {
+        match pipe_to_command(program, args, text) {
+            Ok(()) => return Ok(program),
+            Err(e) => log_debug!("Clipboard command {} failed: {}", program, e),
+        }
+    }
+
+    // No local utility worked; most modern terminals still understand OSC 52
+    copy_osc52(text)?;
+    Ok("OSC 52")
+}
+
+fn is_remote_session() -> bool {
+    std::env::var_os("SSH_CONNECTION").is_some() || std::env::var_os("SSH_TTY").is_some()
+}
+
+fn local_clipboard_commands() -> Vec<(&'static str, &'static [&'static str])> {
+    if cfg!(target_os = "macos") {
+        return vec![("pbcopy", &[])];
+    }
+
+    let mut commands: Vec<(&'static str, &'static [&'static str])> = Vec::new();
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        commands.push(("wl-copy", &[]));
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        commands.push(("xclip", &["-selection", "clipboard"]));
+        commands.push(("xsel", &["--clipboard", "--input"]));
+    }
+    commands
+}
+
+fn pipe_to_command(program: &str, args: &[&str], text: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("{} exited with {}", program, status));
+    }
+    Ok(())
+}
+
+// OSC 52: ESC ] 52 ; c ; <base64 text> BEL, written straight to the terminal
+fn copy_osc52(text: &str) -> Result<()> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", encoded)?;
+    stdout.flush()?;
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Debug, Clone)]
+pub struct CodeBlock {
+    pub language: Option<String>,
+    pub filename: Option<String>, // Name given by the fence label or the text around the block
+    pub code: String,
+}
+
+// Fenced blocks in a markdown answer. A filename is taken from the fence label
+// (```rust src/main.rs or ```rust:src/main.rs), a leading `// file: ...` style
+// comment, or a name mentioned on the line introducing the block.
+pub fn extract(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines();
+    let mut previous_line = "";
+
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        let Some(info) = trimmed.strip_prefix("```") else {
+            if !line.trim().is_empty() {
+                previous_line = line;
+            }
+            continue;
+        };
+
+        let mut code_lines = Vec::new();
+        for code_line in lines.by_ref() {
+            if code_line.trim_start().starts_with("```") {
+                break;
+            }
+            code_lines.push(code_line);
+        }
+
+        let (language, label) = parse_info(info.trim());
+        let filename = label
+            .or_else(|| code_lines.first().and_then(|first| filename_comment(first)))
+            .or_else(|| mentioned_filename(previous_line));
+        blocks.push(CodeBlock {
+            language,
+            filename,
+            code: code_lines.join("\n") + "\n",
+        });
+        previous_line = "";
+    }
+
+    blocks
+}
+
+// Pick a distinct, relative path for every block
+pub fn plan_files(blocks: &[CodeBlock]) -> Vec<(PathBuf, String)> {
+    let mut used = HashSet::new();
+    let mut planned = Vec::new();
+
+    for (i, block) in blocks.iter().enumerate() {
+        let base = block
+            .filename
+            .as_deref()
+            .and_then(safe_relative_path)
+            .unwrap_or_else(|| {
+                PathBuf::from(format!(
+                    "snippet_{}.{}",
+                    i + 1,
+                    extension_for(block.language.as_deref())
+                ))
+            });
+
+        let mut path = base.clone();
+        let mut n = 2;
+        while !used.insert(path.clone()) {
+            let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match base.extension() {
+                Some(ext) => format!("{}_{}.{}", stem, n, ext.to_string_lossy()),
+                None => format!("{}_{}", stem, n),
+            };
+            path = base.with_file_name(name);
+            n += 1;
+        }
+        planned.push((path, block.code.clone()));
+    }
+
+    planned
+}
+
+fn parse_info(info: &str) -> (Option<String>, Option<String>) {
+    if info.is_empty() {
+        return (None, None);
+    }
+    let (first, rest) = match info.split_once([' ', ':']) {
+        Some((first, rest)) => (first, Some(rest.trim())),
+        None => (info, None),
+    };
+
+    // A bare label like ```main.rs names the file, with the language implied
+    if looks_like_filename(first) && rest.is_none() {
+        return (None, Some(first.to_string()));
+    }
+
+    let label = rest
+        .and_then(|rest| rest.split_whitespace().find(|w| looks_like_filename(w)))
+        .map(|w| w.trim_start_matches("title=").trim_matches('"').to_string());
+    (Some(first.to_lowercase()), label)
+}
+
+// `// file: src/main.rs`, `# filename: app.py`, or a comment holding only a path
+fn filename_comment(line: &str) -> Option<String> {
+    let comment = ["//", "#", "--", "/*", "<!--"]
+        .iter()
+        .find_map(|prefix| line.trim().strip_prefix(prefix))?;
+    let comment = comment
+        .trim()
+        .trim_end_matches("*/")
+        .trim_end_matches("-->");
+    let name = comment
+        .split_once(':')
+        .filter(|(key, _)| matches!(key.trim().to_lowercase().as_str(), "file" | "filename"))
+        .map(|(_, name)| name.trim())
+        .unwrap_or(comment.trim());
+    looks_like_filename(name).then(|| name.to_string())
+}
+
+// The last filename-looking word of a line such as "Save this as `server.py`:"
+fn mentioned_filename(line: &str) -> Option<String> {
+    line.split_whitespace()
+        .rev()
+        .map(|word| {
+            word.trim_matches(|c: char| matches!(c, '`' | '*' | '"' | '\'' | ':' | ',' | '(' | ')'))
+                .trim_end_matches('.')
+        })
+        .find(|word| looks_like_filename(word))
+        .map(str::to_string)
+}
+
+fn looks_like_filename(word: &str) -> bool {
+    let Some((stem, ext)) = word.rsplit_once('.') else {
+        return false;
+    };
+    !stem.is_empty()
+        && !stem.ends_with('/')
+        && (1..=5).contains(&ext.len())
+        && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        && !ext.chars().all(|c| c.is_ascii_digit())
+        && word
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+}
+
+// Keep suggested paths inside the current directory
+fn safe_relative_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    let safe = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if safe {
+        Some(path.to_path_buf())
+    } else {
+        path.file_name().map(PathBuf::from)
+    }
+}
+
+fn extension_for(language: Option<&str>) -> &'static str {
+    match language.unwrap_or("") {
+        "python" | "py" => "py",
+        "rust" | "rs" => "rs",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "tsx" => "tsx",
+        "jsx" => "jsx",
+        "go" | "golang" => "go",
+        "java" => "java",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "csharp" | "cs" => "cs",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "swift" => "swift",
+        "kotlin" | "kt" => "kt",
+        "bash" | "sh" | "shell" | "zsh" => "sh",
+        "html" => "html",
+        "css" => "css",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "sql" => "sql",
+        "markdown" | "md" => "md",
+        "dockerfile" => "dockerfile",
+        _ => "txt",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_blocks_and_plans_distinct_safe_paths() {
+        let answer = "\
+Here it is:
+```rust:src/main.rs
+fn main() {}
+```
+```python
+# file: app.py
+print(1)
+```
+Save this as `server.py`:
+```py
+pass
+```
+Version 1.0 follows.
+```
+plain
+```
+```sh ../../etc/app.sh
+echo
+```
+```rust src/main.rs
+// again
+```";
+        let blocks = extract(answer);
+        let names: Vec<_> = blocks.iter().map(|b| b.filename.as_deref()).collect();
+        assert_eq!(
+            names,
+            [
+                Some("src/main.rs"),
+                Some("app.py"),
+                Some("server.py"),
+                None,
+                Some("../../etc/app.sh"),
+                Some("src/main.rs")
+            ]
+        );
+        assert_eq!(blocks[0].language.as_deref(), Some("rust"));
+        assert_eq!(blocks[0].code, "fn main() {}\n");
+
+        let paths: Vec<_> = plan_files(&blocks)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "src/main.rs",
+                "app.py",
+                "server.py",
+                "snippet_4.txt",
+                "app.sh",
+                "src/main_2.rs"
+            ]
+            .map(PathBuf::from)
+        );
+    }
+}
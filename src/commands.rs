@@ -0,0 +1,424 @@
+use anyhow::Result;
+use clap::Subcommand;
+use serde_json::Value;
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    net::IpAddr,
+    path::PathBuf,
+};
+
+use crate::{
+    anthropic::{AnthropicClient, FileMetadata, Provider},
+    auth, config,
+    downloads::human_size,
+    exit_code::Failure,
+    models, session, store, usage,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Open the interactive chat (the default when no subcommand is given)
+    Chat,
+    /// Ask one question and print the answer; piped stdin is appended to it
+    Ask {
+        #[arg(required = true, value_name = "QUESTION")]
+        prompt: Vec<String>,
+    },
+    /// Review staged changes, or `git diff RANGE` (e.g. main..HEAD -- src), with comments anchored to file:line
+    Review {
+        #[arg(value_name = "RANGE", allow_hyphen_values = true)]
+        range: Vec<String>,
+    },
+    /// Ask again whenever files change, with their diffs attached (e.g. --glob 'src/**/*.rs' -m "Review this")
+    Watch {
+        /// Files to watch, relative to the current directory; repeat for several (default: every file git doesn't ignore)
+        #[arg(long = "glob", value_name = "GLOB")]
+        globs: Vec<String>,
+        /// What to ask about each round of changes
+        #[arg(short = 'm', long, value_name = "MESSAGE")]
+        message: String,
+        /// Seconds between checks for changes
+        #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+        interval: f64,
+    },
+    /// Serve a JSON-RPC API on a Unix socket for editors and scripts, until interrupted
+    Daemon {
+        /// Socket to listen on (default: ~/.agnt/daemon.sock)
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+    /// Speak the daemon's JSON-RPC API on stdin and stdout with LSP framing, for editor plugins
+    Rpc,
+    /// Serve an OpenAI-compatible chat completions API over HTTP, until interrupted
+    Serve {
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// Address to listen on; anything but loopback lets others spend your API key
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1")]
+        host: IpAddr,
+        /// API key clients must send as a bearer token (default: serve_token from the config)
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// List the models agnt can use (installed models with --provider ollama)
+    Models,
+    /// Manage files in the Files API workspace (created by code execution)
+    Files {
+        #[command(subcommand)]
+        command: FilesCommand,
+    },
+    /// List and maintain saved sessions (lists them without a subcommand)
+    Sessions {
+        #[command(subcommand)]
+        command: Option<SessionsCommand>,
+    },
+    /// Report tokens and cost per model, day and session (--json for a JSON report)
+    Usage {
+        /// Only requests since then: an age like 7d, 24h, 2w or 30m, or a date like 2026-01-31
+        #[arg(long, value_name = "WHEN")]
+        since: Option<String>,
+    },
+    /// Show or change settings in ~/.agnt/config.json
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Store the API key in the OS keychain instead of ANTHROPIC_API_KEY
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FilesCommand {
+    /// List workspace files
+    Ls,
+    /// Download a file
+    Get {
+        file_id: String,
+        /// Where to save it (default: its own name in the current directory)
+        #[arg(long = "out", value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+    /// Delete files
+    Rm {
+        #[arg(required = true)]
+        file_ids: Vec<String>,
+    },
+    /// Upload files so they can be used by code execution
+    Upload {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionsCommand {
+    /// List saved sessions, most recent first, or the best matches for QUERY
+    Ls { query: Vec<String> },
+    /// Move sessions not updated for DAYS days into the compressed archive pack
+    Archive { days: u32 },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuthCommand {
+    /// Save an API key (prompted for, or read from stdin when it isn't a terminal)
+    Login,
+    /// Remove the stored API key
+    Logout,
+    /// Show where the API key comes from
+    Status,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the effective configuration
+    Show,
+    /// Print one setting
+    Get { key: String },
+    /// Change one setting; the value is read as JSON, falling back to a plain string
+    Set { key: String, value: String },
+    /// Print the path of the config file
+    Path,
+}
+
+pub async fn files(client: &AnthropicClient, command: FilesCommand) -> Result<()> {
+    match command {
+        FilesCommand::Ls => {
+            let files = client.list_files().await?.data;
+            if files.is_empty() {
+                println!("No files");
+                return Ok(());
+            }
+            print_files_header();
+            for file in &files {
+                print_file_row(file);
+            }
+        }
+        FilesCommand::Get { file_id, path } => {
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    let metadata = client.get_file_metadata(&file_id).await?;
+                    // Only the last component, so a name like "../x" stays in this directory
+                    PathBuf::from(metadata.filename)
+                        .file_name()
+                        .map(PathBuf::from)
+                        .unwrap_or_else(|| PathBuf::from(&file_id))
+                }
+            };
+            let content = client.download_file(&file_id).await?;
+            fs::write(&path, &content)?;
+            println!(
+                "Saved {} ({}) to {}",
+                file_id,
+                human_size(content.len() as u64),
+                path.display()
+            );
+        }
+        FilesCommand::Rm { file_ids } => {
+            for file_id in file_ids {
+                client.delete_file(&file_id).await?;
+                println!("Deleted {}", file_id);
+            }
+        }
+        FilesCommand::Upload { paths } => {
+            for (i, path) in paths.iter().enumerate() {
+                let file = client.upload_file(path).await?;
+                if i == 0 {
+                    print_files_header();
+                }
+                print_file_row(&file);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Models from the Models API, installed models with Ollama, or the built-in aliases
+// on Bedrock, which has no listing
+pub async fn models(client: &AnthropicClient) -> Result<()> {
+    match client.provider() {
+        Provider::Anthropic => {
+            let available = client.list_models().await?;
+            println!("{:<36}  {:<24}  CREATED", "ID", "NAME");
+            for model in &available {
+                println!(
+                    "{:<36}  {:<24}  {}",
+                    model.id,
+                    model.display_name,
+                    model
+                        .created_at
+                        .as_deref()
+                        .map(short_time)
+                        .unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+        Provider::Bedrock(_) => {
+            println!("{:<8}  MODEL", "ALIAS");
+            for choice in models::picker_entries(client.model(), &[]) {
+                println!("{:<8}  {}", choice.label, choice.id);
+            }
+            return Ok(());
+        }
+        Provider::Ollama(_) => {}
+    }
+    let local = client.local_models().await?;
+    if local.is_empty() {
+        println!("No models installed; pull one with `ollama pull <model>`");
+        return Ok(());
+    }
+    println!("{:<40}  {:>9}  MODIFIED", "NAME", "SIZE");
+    for model in &local {
+        println!(
+            "{:<40}  {:>9}  {}",
+            model.name,
+            human_size(model.size),
+            short_time(&model.modified_at)
+        );
+    }
+    Ok(())
+}
+
+fn print_files_header() {
+    println!("{:<32}  {:>9}  {:<16}  FILENAME", "ID", "SIZE", "CREATED");
+}
+
+fn print_file_row(file: &FileMetadata) {
+    println!(
+        "{:<32}  {:>9}  {:<16}  {}",
+        file.id,
+        human_size(file.size),
+        file.created_at
+            .as_deref()
+            .map(short_time)
+            .unwrap_or_else(|| "-".to_string()),
+        file.filename
+    );
+}
+
+pub fn sessions(command: Option<SessionsCommand>) -> Result<()> {
+    match command.unwrap_or(SessionsCommand::Ls { query: Vec::new() }) {
+        SessionsCommand::Ls { query } => {
+            let sessions = session::list();
+            if sessions.is_empty() {
+                println!("No saved sessions in {}", store::db_path().display());
+                return Ok(());
+            }
+            let matches = session::search(&sessions, &query.join(" "));
+            if matches.is_empty() {
+                println!("No sessions match '{}'", query.join(" "));
+                return Ok(());
+            }
+            println!(
+                "{:<22}  {:<20}  {:>8}  OPENING",
+                "ID", "UPDATED", "MESSAGES"
+            );
+            for session in matches.iter().map(|&i| &sessions[i]) {
+                println!(
+                    "{:<22}  {:<20}  {:>8}  {}",
+                    session.id,
+                    short_time(&session.updated_at),
+                    session.messages.len(),
+                    session.opening()
+                );
+            }
+        }
+        SessionsCommand::Archive { days } => {
+            let archived = session::archive_old_sessions(days)?;
+            println!(
+                "Archived {} session(s) into {}",
+                archived,
+                session::archive_path().display()
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn usage(since: Option<String>, json: bool) -> Result<()> {
+    let since = since
+        .map(|since| usage::parse_since(&since, chrono::Local::now()))
+        .transpose()
+        .map_err(Failure::Usage)?;
+    let report = usage::report(&usage::load(), since);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    if report.total.requests == 0 {
+        println!("No usage recorded in {}", store::db_path().display());
+        return Ok(());
+    }
+    for (title, rows) in [
+        ("MODEL", &report.models),
+        ("DAY", &report.days),
+        ("SESSION", &report.sessions),
+    ] {
+        print_usage_header(title);
+        for (name, totals) in rows {
+            print_usage_row(name, totals);
+        }
+        println!();
+    }
+    print_usage_header("");
+    print_usage_row("Total", &report.total);
+    if report.total.unpriced > 0 {
+        println!(
+            "\n{} request(s) to models without known pricing aren't in the cost",
+            report.total.unpriced
+        );
+    }
+    Ok(())
+}
+
+fn print_usage_header(title: &str) {
+    println!(
+        "{:<32}  {:>8}  {:>12}  {:>12}  {:>12}  {:>10}",
+        title, "REQUESTS", "INPUT", "OUTPUT", "CACHED", "COST"
+    );
+}
+
+// Cached is cache writes and reads together
+fn print_usage_row(name: &str, totals: &usage::Totals) {
+    println!(
+        "{:<32}  {:>8}  {:>12}  {:>12}  {:>12}  {:>10}",
+        name,
+        totals.requests,
+        totals.input_tokens,
+        totals.output_tokens,
+        totals.cache_creation_tokens + totals.cache_read_tokens,
+        format!("${:.4}", totals.cost_usd)
+    );
+}
+
+pub fn auth(command: AuthCommand) -> Result<()> {
+    match command {
+        AuthCommand::Login => {
+            let key = if io::stdin().is_terminal() {
+                rpassword::prompt_password("Anthropic API key: ")?
+            } else {
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)?;
+                line
+            };
+            let key = key.trim().to_string();
+            if key.is_empty() {
+                anyhow::bail!("No API key entered");
+            }
+            if !key.starts_with("sk-ant-") {
+                eprintln!("Warning: Anthropic API keys usually start with sk-ant-");
+            }
+            auth::store_key(key)?;
+            println!("Saved the API key in the OS keychain");
+        }
+        AuthCommand::Logout => {
+            if auth::delete_key()? {
+                println!("Removed the API key from the OS keychain");
+            } else {
+                println!("No API key is stored");
+            }
+        }
+        AuthCommand::Status => {
+            if std::env::var("ANTHROPIC_API_KEY").is_ok() {
+                println!("Using ANTHROPIC_API_KEY from the environment");
+            } else if auth::stored_key().is_some() {
+                println!("Using the API key stored in the OS keychain");
+            } else {
+                println!("No API key: set ANTHROPIC_API_KEY or run `agnt auth login`");
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn config(command: ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::Show => {
+            println!("{}", serde_json::to_string_pretty(&config::load())?);
+        }
+        ConfigCommand::Get { key } => {
+            let value = config::get(&key)?;
+            match value {
+                Value::String(text) => println!("{}", text),
+                other => println!("{}", other),
+            }
+        }
+        ConfigCommand::Set { key, value } => {
+            config::set(&key, &value)?;
+            println!("Set {} in {}", key, config::config_path().display());
+        }
+        ConfigCommand::Path => println!("{}", config::config_path().display()),
+    }
+    Ok(())
+}
+
+// "2025-08-01T12:34:56..." as "2025-08-01 12:34"
+fn short_time(timestamp: &str) -> String {
+    timestamp.get(..16).unwrap_or(timestamp).replace('T', " ")
+}
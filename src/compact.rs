@@ -0,0 +1,78 @@
+use anyhow::{Result, anyhow};
+
+use crate::{
+    anthropic::{self, AnthropicClient, RequestParams, StreamEvent, TurnMetrics},
+    tools,
+    ui::{MessageContent, ToolMode},
+};
+
+// The latest messages are always kept verbatim so the current thread stays intact
+const KEEP_RECENT: usize = 4;
+const SUMMARY_MAX_TOKENS: u32 = 2048;
+
+const SUMMARY_PROMPT: &str = "You compact long conversations so they fit in the context window. \
+Summarize the transcript you are given for the assistant that will continue the conversation. \
+Keep the user's goals, decisions, constraints, facts, file names, code and results that later \
+turns may depend on; drop pleasantries and anything superseded. Reply with the summary only.";
+
+// Where older turns end: the start of a user turn that leaves at least KEEP_RECENT
+// messages after it, with something other than an earlier summary before it. Tool
+// results never start the kept part, as they must follow the call that made them.
+pub fn split_point(messages: &[(String, Vec<MessageContent>)]) -> Option<usize> {
+    let limit = messages.len().checked_sub(KEEP_RECENT)?;
+    let split = (1..=limit)
+        .rev()
+        .find(|&i| messages[i].0 == "user" && !tools::is_tool_results(&messages[i].1))?;
+    messages[..split]
+        .iter()
+        .any(|(role, _)| role != "system")
+        .then_some(split)
+}
+
+// How a summary is presented to the API in place of the turns it replaced
+pub fn summary_message(summary: &str) -> String {
+    format!(
+        "Summary of the earlier part of this conversation (older messages were compacted):\n\n{}",
+        summary
+    )
+}
+
+// Ask the model for a summary of `transcript`, outside of the visible conversation
+pub async fn summarize(
+    client: &AnthropicClient,
+    transcript: String,
+) -> Result<(String, Option<TurnMetrics>)> {
+    let params = RequestParams {
+        max_tokens: Some(SUMMARY_MAX_TOKENS),
+        ..Default::default()
+    };
+    let (mut receiver, _cancellation) = client
+        .clone()
+        .with_tool_mode(ToolMode::None)
+        .send_message_stream(
+            vec![anthropic::Message::text("user", transcript)],
+            Some(SUMMARY_PROMPT.to_string()),
+            params,
+        )
+        .await?;
+
+    let mut summary = String::new();
+    let mut metrics = None;
+    while let Some(event) = receiver.recv().await {
+        match event {
+            StreamEvent::Text(text) => summary.push_str(&text),
+            StreamEvent::Error(error) => return Err(error.into()),
+            StreamEvent::Disconnected(reason) => {
+                return Err(anyhow!("connection lost ({})", reason));
+            }
+            StreamEvent::Metrics(turn) => metrics = Some(turn),
+            _ => {}
+        }
+    }
+
+    let summary = summary.trim();
+    if summary.is_empty() {
+        return Err(anyhow!("the summary came back empty"));
+    }
+    Ok((summary.to_string(), metrics))
+}
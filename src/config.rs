@@ -0,0 +1,253 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use crate::{
+    keymap::KeySpec,
+    theme::{SpinnerStyle, ThemeName},
+};
+
+// User configuration read from ~/.agnt/config.json; every key is optional
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub notifications: bool, // Show desktop notifications for long-running events
+    pub bell: bool,          // Ring the terminal bell when an answer finishes in the background
+    pub context_hooks: Vec<ContextHook>, // Shell commands whose output is added to every request
+    pub tts_command: Option<String>, // Text-to-speech command used by /speak; sentences arrive on stdin
+    pub system_prompt: Option<String>, // Replaces the built-in system prompt; may use placeholders
+    pub max_auto_continues: u32, // Follow-up requests sent when an answer hits max_tokens (0 disables)
+    pub schema_retries: u32,     // Corrections asked for when a --schema answer doesn't validate
+    pub stall_timeout_secs: u64, // Silence on a response stream before it is reported as stalled
+    pub connect_timeout_secs: u64, // Time allowed to establish a connection to the API
+    pub read_timeout_secs: u64,  // Silence on an open connection before it is dropped and retried
+    pub request_timeout_secs: Option<u64>, // Deadline for a whole answer (null for no limit)
+    pub session_budget_usd: Option<f64>, // Spend limit for one conversation
+    pub daily_budget_usd: Option<f64>, // Spend limit across all sessions for the current day
+    pub budget_action: BudgetAction, // What happens when a request would exceed a budget
+    pub max_tokens: Option<u32>, // Output token limit per request (default 4096)
+    pub web_search_max_uses: Option<u32>, // Searches Claude may run per request (null for no limit)
+    pub auto_compact: bool,      // Summarize older turns when the context nears the model's window
+    pub auto_compact_threshold: f64, // Fraction of the context window that triggers auto-compact
+    pub token_warning_threshold: Option<u64>, // Input tokens above which a request asks before sending
+    pub model: Option<String>, // Default model (alias or id) when ANTHROPIC_MODEL isn't set
+    pub provider: ProviderKind, // Where requests go; --provider overrides it
+    pub bedrock_region: Option<String>, // AWS region for Bedrock when AWS_REGION isn't set
+    pub ollama_host: Option<String>, // Ollama server when OLLAMA_HOST isn't set (default localhost:11434)
+    pub ollama_model: Option<String>, // Local model when OLLAMA_MODEL isn't set (default: newest installed)
+    pub serve_token: Option<String>, // API key `agnt serve` clients must send (--token overrides it)
+    pub overwrite_policy: OverwritePolicy, // What a download does when its file already exists
+    pub custom_commands: Vec<CustomCommand>, // Extra slash commands that expand into a prompt
+    pub tools: Vec<LocalTool>,       // Tools Claude may call, each run as a local shell command
+    pub max_tool_rounds: u32,        // Tool results sent back in a row before agnt stops the loop
+    pub shell_timeout_secs: u64,     // How long a run_shell command may run (--allow-shell)
+    pub allowed_paths: Vec<String>, // Paths in the --allow-files workspace the file tools may use (default: all)
+    pub max_output_lines: usize, // Lines of a code output shown before the rest is folded (0 for no limit)
+    pub theme: ThemeName,        // Base palette for the TUI: dark or light
+    pub theme_colors: BTreeMap<String, String>, // Per-role color overrides on top of the theme
+    pub spinner: SpinnerStyle,   // Loading animation while waiting: dots, braille or none
+    pub reduced_motion: bool, // Static "Thinking…" with the elapsed time and fewer redraws instead of animation
+    pub keymap: BTreeMap<String, KeySpec>, // Keys for input actions (send, quit, ...), replacing the defaults
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetAction {
+    #[default]
+    Confirm, // Ask before sending
+    Refuse, // Don't send at all
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    Anthropic, // api.anthropic.com with ANTHROPIC_API_KEY
+    Bedrock, // AWS Bedrock with AWS credentials
+    Ollama,  // A local Ollama server, no key needed
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverwritePolicy {
+    #[default]
+    Rename, // Save as name-1.ext, name-2.ext, ...
+    Overwrite, // Replace the existing file
+    Skip,      // Keep the existing file and don't download
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            notifications: false,
+            bell: false,
+            context_hooks: Vec::new(),
+            tts_command: None,
+            system_prompt: None,
+            max_auto_continues: 3,
+            schema_retries: 2,
+            stall_timeout_secs: 30,
+            connect_timeout_secs: 10,
+            read_timeout_secs: 120,
+            request_timeout_secs: Some(900),
+            session_budget_usd: None,
+            daily_budget_usd: None,
+            budget_action: BudgetAction::Confirm,
+            max_tokens: None,
+            web_search_max_uses: Some(5),
+            auto_compact: true,
+            auto_compact_threshold: 0.8,
+            token_warning_threshold: None,
+            model: None,
+            provider: ProviderKind::Anthropic,
+            bedrock_region: None,
+            ollama_host: None,
+            ollama_model: None,
+            serve_token: None,
+            overwrite_policy: OverwritePolicy::Rename,
+            custom_commands: Vec::new(),
+            tools: Vec::new(),
+            max_tool_rounds: 25,
+            shell_timeout_secs: 120,
+            allowed_paths: Vec::new(),
+            max_output_lines: 200,
+            theme: ThemeName::Dark,
+            spinner: SpinnerStyle::Dots,
+            reduced_motion: false,
+            theme_colors: BTreeMap::new(),
+            keymap: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextHook {
+    pub command: String,
+    #[serde(default)]
+    pub target: HookTarget,
+    #[serde(default)]
+    pub label: Option<String>, // Shown to Claude as the source of the output (defaults to the command)
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookTarget {
+    #[default]
+    System, // Appended to the system prompt
+    Message, // Prepended to the latest user message
+}
+
+// A user-defined slash command. Its message is `prompt` (with {{args}} replaced by
+// whatever follows the command), followed by the output of `command` if there is one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomCommand {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub command: Option<String>, // Shell command; its arguments are in $AGNT_ARGS
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+    5
+}
+
+// A tool Claude can call that runs on this machine. The call's input arrives as JSON on
+// stdin (and in $AGNT_TOOL_INPUT); stdout is sent back as the result, and a non-zero exit
+// marks it as an error, with stderr as the message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_input_schema")]
+    pub input_schema: Value, // JSON schema of the input Claude sends
+    pub command: String,
+    #[serde(default = "default_tool_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_input_schema() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+fn default_tool_timeout() -> u64 {
+    30
+}
+
+pub fn config_path() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("config.json")
+}
+
+// Load the config file, falling back to defaults when it is missing or invalid
+pub fn load() -> Config {
+    let path = config_path();
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Config::default(),
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(config) => {
+            log_debug!("Loaded config from {}", path.display());
+            config
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: Ignoring invalid config file {}: {}",
+                path.display(),
+                e
+            );
+            log_debug!("Invalid config file {}: {}", path.display(), e);
+            Config::default()
+        }
+    }
+}
+
+// Effective value of one setting
+pub fn get(key: &str) -> Result<Value> {
+    let config = serde_json::to_value(load())?;
+    config
+        .get(key)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Unknown setting {}", key))
+}
+
+// Change one setting in the config file, keeping the rest of the file as it is. The
+// value is parsed as JSON when it can be (numbers, true, null, lists), else kept as text.
+pub fn set(key: &str, raw_value: &str) -> Result<()> {
+    let defaults = serde_json::to_value(Config::default())?;
+    if defaults.get(key).is_none() {
+        anyhow::bail!("Unknown setting {}", key);
+    }
+    let value = serde_json::from_str(raw_value).unwrap_or(Value::String(raw_value.to_string()));
+
+    let path = config_path();
+    let mut file: serde_json::Map<String, Value> = match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("{} is not valid JSON: {}", path.display(), e))?,
+        Err(_) => serde_json::Map::new(),
+    };
+    file.insert(key.to_string(), value);
+
+    // Refuse values that would make the whole file fail to load
+    let updated = Value::Object(file);
+    serde_json::from_value::<Config>(updated.clone())
+        .map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(&updated)? + "\n")?;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use anyhow::{Result, anyhow, bail};
+use std::time::Duration;
+
+use crate::{config::CustomCommand, hooks};
+
+// Placeholder in a custom command's prompt for the text typed after the command
+const ARGS_PLACEHOLDER: &str = "{{args}}";
+
+// The message a custom command sends. Without an {{args}} placeholder, any arguments
+// are added at the end of the prompt.
+pub async fn expand(custom: &CustomCommand, args: &str) -> Result<String> {
+    let mut parts = Vec::new();
+    if let Some(prompt) = &custom.prompt {
+        if prompt.contains(ARGS_PLACEHOLDER) {
+            parts.push(prompt.replace(ARGS_PLACEHOLDER, args));
+        } else if args.is_empty() {
+            parts.push(prompt.clone());
+        } else {
+            parts.push(format!("{}\n\n{}", prompt, args));
+        }
+    } else if custom.command.is_none() {
+        parts.push(args.to_string());
+    }
+
+    if let Some(command) = &custom.command {
+        let mut shell = hooks::shell(command);
+        shell.env("AGNT_ARGS", args);
+        let output = tokio::time::timeout(Duration::from_secs(custom.timeout_secs), shell.output())
+            .await
+            .map_err(|_| anyhow!("`{}` timed out after {}s", command, custom.timeout_secs))?
+            .map_err(|e| anyhow!("failed to run `{}`: {}", command, e))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!(
+                "`{}` exited with {}: {}",
+                command,
+                output.status,
+                stderr.trim()
+            );
+        }
+        parts.push(
+            String::from_utf8_lossy(&output.stdout)
+                .trim_end()
+                .to_string(),
+        );
+    }
+
+    let message = parts.join("\n\n");
+    if message.trim().is_empty() {
+        bail!("/{} produced an empty prompt", custom.name);
+    }
+    Ok(message)
+}
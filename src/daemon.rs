@@ -0,0 +1,652 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    anthropic::{AnthropicClient, ApiError, RequestParams, StreamEvent},
+    config::Config,
+    hooks,
+    pipe_json::PipeEvent,
+    prompt,
+    session::{self, Session},
+    store::HistoryStore,
+    ui::MessageContent,
+    usage,
+};
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const API_ERROR: i64 = -32000; // The request to Claude failed; `data` has its kind and status
+const SESSION_BUSY: i64 = -32001; // Another request is still answering in that session
+const OVER_BUDGET: i64 = -32002; // The request would go over session_budget_usd or daily_budget_usd
+const REQUEST_CANCELLED: i64 = -32800; // As in LSP
+
+pub fn default_socket() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("daemon.sock")
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    fn api(error: ApiError) -> Self {
+        Self {
+            code: API_ERROR,
+            data: Some(json!({ "kind": error.kind, "status": error.status })),
+            message: error.message,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    message: String,
+    #[serde(default)]
+    session: Option<String>, // A session id or "last"; a new session when left out
+    #[serde(default)]
+    model: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+#[derive(Deserialize)]
+struct IdParams {
+    id: Value,
+}
+
+// What every connection shares: one client, whose HTTP connections stay open between
+// requests, and the sessions answered in so far
+pub struct Backend {
+    client: AnthropicClient,
+    config: Config,
+    sessions: Mutex<HashMap<String, Session>>,
+    busy: Mutex<HashSet<String>>, // Sessions with an answer under way
+}
+
+impl Backend {
+    pub fn new(client: AnthropicClient, config: Config) -> Self {
+        Self {
+            client,
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            busy: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn session(&self, name: Option<&str>) -> Result<Session, RpcError> {
+        let Some(name) = name else {
+            let now = chrono::Local::now().to_rfc3339();
+            return Ok(Session {
+                id: session::new_session_id(),
+                created_at: now.clone(),
+                updated_at: now,
+                messages: Vec::new(),
+                alternatives: None,
+            });
+        };
+        if let Some(session) = self.sessions.lock().unwrap().get(name) {
+            return Ok(session.clone());
+        }
+        session::load(name).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+    }
+}
+
+// One client of the backend: where its replies go, and its requests still running so
+// they can be cancelled
+#[derive(Clone)]
+pub struct Connection {
+    backend: Arc<Backend>,
+    out: mpsc::UnboundedSender<Value>,
+    running: Arc<Mutex<HashMap<String, CancellationToken>>>, // By request id
+}
+
+impl Connection {
+    pub fn new(backend: Arc<Backend>, out: mpsc::UnboundedSender<Value>) -> Self {
+        Self {
+            backend,
+            out,
+            running: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Handle one JSON-RPC message. sendMessage runs in its own task, so a connection can
+    // have several answers streaming and still cancel them.
+    pub fn handle(&self, text: &str) {
+        let request: Value = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                return self.reply(Some(Value::Null), Err(error));
+            }
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            let error = RpcError::new(INVALID_REQUEST, "No method");
+            return self.reply(Some(id.unwrap_or_default()), Err(error));
+        };
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+        log_debug!("Daemon request {}", method);
+        match method {
+            "sendMessage" => match serde_json::from_value::<SendParams>(params) {
+                Ok(params) => {
+                    let connection = self.clone();
+                    tokio::spawn(async move {
+                        let result = connection.send_message(id.clone(), params).await;
+                        connection.reply(id, result);
+                    });
+                }
+                Err(e) => self.reply(id, Err(RpcError::new(INVALID_PARAMS, e.to_string()))),
+            },
+            "cancel" => {
+                let result = parse::<IdParams>(params).map(|params| {
+                    let token = self.running.lock().unwrap().remove(&params.id.to_string());
+                    let cancelled = token.is_some();
+                    if let Some(token) = token {
+                        token.cancel();
+                    }
+                    json!({ "cancelled": cancelled })
+                });
+                self.reply(id, result);
+            }
+            "listSessions" => {
+                let result = parse::<ListParams>(params).map(list_sessions);
+                self.reply(id, result);
+            }
+            "getSession" => {
+                let result = parse::<IdParams>(params).and_then(|params| {
+                    let name = params.id.as_str().unwrap_or_default().to_string();
+                    let session = self.backend.session(Some(&name))?;
+                    Ok(serde_json::to_value(session).unwrap_or_default())
+                });
+                self.reply(id, result);
+            }
+            other => self.reply(
+                id,
+                Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("Unknown method {}", other),
+                )),
+            ),
+        }
+    }
+
+    // Stop every answer this connection started, once it goes away
+    pub fn cancel_all(&self) {
+        for (_, token) in self.running.lock().unwrap().drain() {
+            token.cancel();
+        }
+    }
+
+    fn send(&self, message: Value) {
+        let _ = self.out.send(message);
+    }
+
+    // Requests without an id are notifications and get no reply, unless they couldn't be
+    // read at all
+    fn reply(&self, id: Option<Value>, result: Result<Value, RpcError>) {
+        let Some(id) = id else {
+            return;
+        };
+        self.send(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => {
+                let mut body = json!({ "code": error.code, "message": error.message });
+                if let Some(data) = error.data {
+                    body["data"] = data;
+                }
+                json!({ "jsonrpc": "2.0", "id": id, "error": body })
+            }
+        });
+    }
+
+    // The answer streams as "event" notifications shaped like `--pipe --json` lines,
+    // with the id of the request they belong to
+    fn event(&self, id: &Option<Value>, event: &PipeEvent) {
+        let mut params = serde_json::to_value(event).unwrap_or_default();
+        params["id"] = id.clone().unwrap_or_default();
+        self.send(json!({ "jsonrpc": "2.0", "method": "event", "params": params }));
+    }
+
+    async fn send_message(&self, id: Option<Value>, params: SendParams) -> Result<Value, RpcError> {
+        let mut session = self.backend.session(params.session.as_deref())?;
+        if !self.backend.busy.lock().unwrap().insert(session.id.clone()) {
+            return Err(RpcError::new(
+                SESSION_BUSY,
+                format!("Session {} is still answering", session.id),
+            ));
+        }
+        let result = self.answer(&id, &mut session, params).await;
+        self.backend.busy.lock().unwrap().remove(&session.id);
+        result
+    }
+
+    async fn answer(
+        &self,
+        id: &Option<Value>,
+        session: &mut Session,
+        params: SendParams,
+    ) -> Result<Value, RpcError> {
+        let Backend { client, config, .. } = &*self.backend;
+        // Roles have to alternate, so a prompt that never got an answer takes the new one
+        let prompt = MessageContent::Text(params.message);
+        match session.messages.last_mut() {
+            Some((role, contents)) if role == "user" => contents.push(prompt),
+            _ => session.messages.push(("user".to_string(), vec![prompt])),
+        }
+        let mut messages = session::api_messages(&session.messages);
+        let mut system_prompt = prompt::render(
+            config
+                .system_prompt
+                .as_deref()
+                .unwrap_or(prompt::DEFAULT_SYSTEM_PROMPT),
+        );
+        hooks::apply(&config.context_hooks, &mut system_prompt, &mut messages).await;
+
+        let request = RequestParams {
+            model: params.model,
+            ..Default::default()
+        };
+        let model = request.model.as_deref().unwrap_or(client.model());
+        let spent = match config.session_budget_usd {
+            Some(_) => usage::spent_in_session(&session.id),
+            None => 0.0,
+        };
+        match usage::check_request(config, model, spent, &system_prompt, &messages) {
+            usage::BudgetCheck::Within => {}
+            usage::BudgetCheck::Warning(warning) => {
+                self.event(id, &PipeEvent::Status { message: &warning })
+            }
+            usage::BudgetCheck::Exceeded(reason) => {
+                return Err(RpcError::new(OVER_BUDGET, reason));
+            }
+        }
+        let (mut receiver, cancellation) = client
+            .send_message_stream(messages, Some(system_prompt), request)
+            .await
+            .map_err(|e| RpcError::new(API_ERROR, e.to_string()))?;
+        let key = id.clone().unwrap_or_default().to_string();
+        self.running
+            .lock()
+            .unwrap()
+            .insert(key.clone(), cancellation.clone());
+
+        let mut contents = Vec::new();
+        let mut text = String::new();
+        let mut metrics = None;
+        let mut failure = None;
+        while let Some(event) = receiver.recv().await {
+            match event {
+                StreamEvent::Text(delta) => {
+                    self.event(id, &PipeEvent::Text { text: &delta });
+                    text.push_str(&delta);
+                    match contents.last_mut() {
+                        Some(MessageContent::Text(last)) => last.push_str(&delta),
+                        _ => contents.push(MessageContent::Text(delta)),
+                    }
+                }
+                StreamEvent::CodeInput(code) => {
+                    self.event(id, &PipeEvent::CodeInput { code: &code });
+                    contents.push(MessageContent::Code { input: code });
+                }
+                StreamEvent::CodeOutput {
+                    stdout,
+                    stderr,
+                    return_code,
+                    files,
+                } => {
+                    self.event(
+                        id,
+                        &PipeEvent::CodeOutput {
+                            stdout: &stdout,
+                            stderr: &stderr,
+                            return_code,
+                        },
+                    );
+                    for (file_id, filename) in &files {
+                        self.event(id, &PipeEvent::File { file_id, filename });
+                    }
+                    contents.push(MessageContent::CodeOutput {
+                        stdout,
+                        stderr,
+                        return_code,
+                        files,
+                        spilled: None,
+                        downloads: Vec::new(),
+                    });
+                }
+                StreamEvent::CodeError(error) => {
+                    self.event(id, &PipeEvent::CodeError { error_code: &error });
+                    contents.push(MessageContent::CodeError(error));
+                }
+                StreamEvent::Stalled(idle) => {
+                    let message = format!("No data for {}s, still waiting", idle.as_secs());
+                    self.event(id, &PipeEvent::Status { message: &message });
+                }
+                StreamEvent::Metrics(turn) => {
+                    if let Err(e) = usage::record(&usage::UsageRecord::new(&session.id, &turn)) {
+                        log_debug!("Failed to record usage: {}", e);
+                    }
+                    self.event(id, &PipeEvent::usage(&turn));
+                    if let Some(reason) = &turn.stop_reason {
+                        contents.push(MessageContent::StopReason(reason.clone()));
+                    }
+                    metrics = Some(turn);
+                }
+                StreamEvent::Error(error) => failure = Some(RpcError::api(error)),
+                StreamEvent::Disconnected(reason) => {
+                    failure = Some(RpcError::new(
+                        API_ERROR,
+                        format!("Connection lost: {}", reason),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        self.running.lock().unwrap().remove(&key);
+        if cancellation.is_cancelled() {
+            return Err(RpcError::new(REQUEST_CANCELLED, "Cancelled"));
+        }
+        if let Some(failure) = failure {
+            return Err(failure);
+        }
+
+        session.messages.push(("assistant".to_string(), contents));
+        session.updated_at = chrono::Local::now().to_rfc3339();
+        if let Err(e) = HistoryStore::open().and_then(|mut store| store.save_session(session)) {
+            log_debug!("Failed to save daemon session: {}", e);
+        }
+        self.backend
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(session.id.clone(), session.clone());
+        Ok(json!({
+            "session": session.id,
+            "text": text,
+            "stop_reason": metrics.as_ref().and_then(|m| m.stop_reason.clone()),
+            "input_tokens": metrics.as_ref().map_or(0, |m| m.input_tokens),
+            "output_tokens": metrics.as_ref().map_or(0, |m| m.output_tokens),
+        }))
+    }
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn list_sessions(params: ListParams) -> Value {
+    let sessions = session::list();
+    let order = session::search(&sessions, params.query.as_deref().unwrap_or(""));
+    let entries: Vec<Value> = order
+        .into_iter()
+        .take(params.limit)
+        .map(|index| {
+            let session = &sessions[index];
+            json!({
+                "id": session.id,
+                "created_at": session.created_at,
+                "updated_at": session.updated_at,
+                "messages": session.messages.len(),
+                "opening": session.opening(),
+            })
+        })
+        .collect();
+    Value::Array(entries)
+}
+
+// How messages are told apart on a stream
+#[derive(Clone, Copy)]
+pub enum Framing {
+    Lines,         // One message per line, for the socket and `nc`
+    ContentLength, // A Content-Length header before each message, as in LSP
+}
+
+impl Framing {
+    // The next message's text; None once the stream ends
+    async fn read(self, reader: &mut (impl AsyncBufRead + Unpin)) -> Option<String> {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.ok()? == 0 {
+                return None;
+            }
+            let line = line.trim();
+            match self {
+                Framing::Lines if line.is_empty() => {}
+                Framing::Lines => return Some(line.to_string()),
+                // Headers end at a blank line; Content-Type is the only other one and unused
+                Framing::ContentLength if line.is_empty() => {
+                    if length.is_some() {
+                        break;
+                    }
+                }
+                Framing::ContentLength => {
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.trim().eq_ignore_ascii_case("content-length")
+                    {
+                        length = value.trim().parse::<usize>().ok();
+                    }
+                }
+            }
+        }
+        let mut body = vec![0; length?];
+        reader.read_exact(&mut body).await.ok()?;
+        Some(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn write(self, message: &Value) -> Vec<u8> {
+        let body = serde_json::to_vec(message).unwrap_or_default();
+        match self {
+            Framing::Lines => [body, b"\n".to_vec()].concat(),
+            Framing::ContentLength => [
+                format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes(),
+                body,
+            ]
+            .concat(),
+        }
+    }
+}
+
+// Serve one client until it closes its end; its answers still running are cancelled
+pub async fn serve(
+    backend: Arc<Backend>,
+    reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin + Send + 'static,
+    framing: Framing,
+) {
+    let (out, mut outgoing) = mpsc::unbounded_channel::<Value>();
+    let writing = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let bytes = framing.write(&message);
+            if writer.write_all(&bytes).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+    let connection = Connection::new(backend, out);
+    let mut reader = BufReader::new(reader);
+    while let Some(message) = framing.read(&mut reader).await {
+        connection.handle(&message);
+    }
+    connection.cancel_all();
+    drop(connection);
+    let _ = writing.await;
+}
+
+// `agnt daemon`: accept clients on a Unix socket that only this user can open, until
+// interrupted
+#[cfg(unix)]
+pub async fn run(client: AnthropicClient, config: Config, socket: &Path) -> Result<()> {
+    use std::{
+        fs,
+        os::unix::fs::{DirBuilderExt, PermissionsExt},
+    };
+    use tokio::net::{UnixListener, UnixStream};
+
+    if socket.exists() {
+        if UnixStream::connect(socket).await.is_ok() {
+            anyhow::bail!(
+                "An agnt daemon is already listening on {}",
+                socket.display()
+            );
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        fs::remove_file(socket)?;
+    }
+    let parent = match socket.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(parent)?;
+    // The socket is bound inside a directory only this user can enter and moved into
+    // place once it is 0600, so nobody else can connect while the mode is being set
+    let staging = parent.join(format!(".agnt-daemon-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("sock");
+    let bound = UnixListener::bind(&staged)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&staged, socket)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_dir_all(&staging);
+    let listener =
+        bound.map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", socket.display(), e))?;
+    eprintln!("Listening on {}; press Ctrl+C to stop", socket.display());
+
+    let backend = Arc::new(Backend::new(client, config));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let (reader, writer) = stream.into_split();
+                tokio::spawn(serve(backend.clone(), reader, writer, Framing::Lines));
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    let _ = fs::remove_file(socket);
+    Ok(())
+}
+
+// `agnt rpc`: serve the editor or plugin that started this process on its stdin and
+// stdout, until it closes stdin
+pub async fn run_stdio(client: AnthropicClient, config: Config) -> Result<()> {
+    let backend = Arc::new(Backend::new(client, config));
+    serve(
+        backend,
+        tokio::io::stdin(),
+        tokio::io::stdout(),
+        Framing::ContentLength,
+    )
+    .await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn run(_client: AnthropicClient, _config: Config, _socket: &Path) -> Result<()> {
+    anyhow::bail!("agnt daemon needs Unix domain sockets")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_requests_get_json_rpc_errors() {
+        let backend = Arc::new(Backend::new(
+            AnthropicClient::new(String::new()),
+            Config::default(),
+        ));
+        let (out, mut replies) = mpsc::unbounded_channel();
+        let connection = Connection::new(backend, out);
+
+        connection.handle("{not json");
+        connection.handle(r#"{"jsonrpc": "2.0", "id": 1, "method": "explode"}"#);
+        connection.handle(r#"{"jsonrpc": "2.0", "id": "a", "method": "cancel", "params": {}}"#);
+        connection
+            .handle(r#"{"jsonrpc": "2.0", "id": 2, "method": "cancel", "params": {"id": 9}}"#);
+        connection.handle(r#"{"jsonrpc": "2.0", "method": "explode"}"#);
+
+        assert_eq!(replies.try_recv().unwrap()["error"]["code"], PARSE_ERROR);
+        let unknown = replies.try_recv().unwrap();
+        assert_eq!(unknown["id"], 1);
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies.try_recv().unwrap()["error"]["code"], INVALID_PARAMS);
+        assert_eq!(
+            replies.try_recv().unwrap()["result"],
+            json!({ "cancelled": false })
+        );
+        // Notifications get no reply, not even an error
+        assert!(replies.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn content_length_framing_splits_messages() {
+        let first = r#"{"jsonrpc": "2.0", "id": 1, "method": "cancel", "params": {"id": "é"}}"#;
+        let mut input = format!(
+            "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}",
+            first.len(),
+            first
+        )
+        .into_bytes();
+        input.extend(Framing::ContentLength.write(&json!({ "id": 2 })));
+        input.extend(b"Content-Length: 40\r\n\r\n{\"cut\": true}");
+
+        let mut reader = BufReader::new(&input[..]);
+        let framing = Framing::ContentLength;
+        assert_eq!(framing.read(&mut reader).await.as_deref(), Some(first));
+        assert_eq!(
+            framing.read(&mut reader).await.as_deref(),
+            Some(r#"{"id":2}"#)
+        );
+        // A message cut short ends the stream
+        assert_eq!(framing.read(&mut reader).await, None);
+    }
+}
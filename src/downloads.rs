@@ -0,0 +1,259 @@
+use anyhow::Result;
+use chrono::Local;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::{
+    anthropic::AnthropicClient,
+    config::OverwritePolicy,
+    store::{Artifact, HistoryStore},
+};
+
+const MAX_CONCURRENT: usize = 3;
+// Progress updates are throttled so a fast download doesn't flood the event loop
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Hash, PartialEq)]
+pub enum DownloadState {
+    Queued,
+    Downloading { received: u64, total: Option<u64> },
+    Saved(PathBuf),
+    Skipped(PathBuf), // The file already existed and the overwrite policy is skip
+    Failed(String),
+}
+
+impl DownloadState {
+    pub fn describe(&self) -> String {
+        match self {
+            DownloadState::Queued => "queued".to_string(),
+            DownloadState::Downloading {
+                received,
+                total: Some(total),
+            } if *total > 0 => format!(
+                "downloading {}% ({} of {})",
+                received * 100 / total,
+                human_size(*received),
+                human_size(*total)
+            ),
+            DownloadState::Downloading { received, .. } => {
+                format!("downloading {}", human_size(*received))
+            }
+            DownloadState::Saved(path) => format!("saved to {}", path.display()),
+            DownloadState::Skipped(path) => format!("skipped, {} already exists", path.display()),
+            DownloadState::Failed(error) => format!("download failed: {}", error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DownloadUpdate {
+    Named {
+        file_id: String,
+        filename: String,
+    },
+    State {
+        file_id: String,
+        state: DownloadState,
+    },
+}
+
+// Saves files created by code execution, a few at a time, and reports what each
+// download is doing through the channel returned by `new`
+#[derive(Clone)]
+pub struct DownloadManager {
+    client: AnthropicClient,
+    policy: OverwritePolicy,
+    permits: Arc<Semaphore>,
+    tx: mpsc::Sender<DownloadUpdate>,
+}
+
+impl DownloadManager {
+    pub fn new(
+        client: AnthropicClient,
+        policy: OverwritePolicy,
+    ) -> (Self, mpsc::Receiver<DownloadUpdate>) {
+        let (tx, rx) = mpsc::channel(100);
+        let manager = Self {
+            client,
+            policy,
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT)),
+            tx,
+        };
+        (manager, rx)
+    }
+
+    // Each download is logged to the history store under `session_id`
+    pub fn enqueue(&self, file_id: String, output_dir: String, session_id: String) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = manager.permits.acquire().await else {
+                return;
+            };
+            let state = match manager.download(&file_id, &output_dir, &session_id).await {
+                Ok(state) => state,
+                Err(e) => {
+                    log_debug!("Error saving file {}: {}", file_id, e);
+                    DownloadState::Failed(e.to_string())
+                }
+            };
+            let _ = manager
+                .tx
+                .send(DownloadUpdate::State { file_id, state })
+                .await;
+        });
+    }
+
+    async fn download(
+        &self,
+        file_id: &str,
+        output_dir: &str,
+        session_id: &str,
+    ) -> Result<DownloadState> {
+        fs::create_dir_all(output_dir)?;
+        let filename = self.filename(file_id).await;
+        let path = Path::new(output_dir).join(clean_filename(&filename));
+        if self.policy == OverwritePolicy::Skip && path.exists() {
+            log_download(session_id, file_id, &filename, &path, "skipped");
+            return Ok(DownloadState::Skipped(path));
+        }
+
+        let mut last_update = Instant::now() - PROGRESS_INTERVAL;
+        let content = self
+            .client
+            .download_file_with_progress(file_id, |received, total| {
+                if last_update.elapsed() >= PROGRESS_INTERVAL {
+                    last_update = Instant::now();
+                    // Dropped when the channel is full; the next update supersedes it anyway
+                    let _ = self.tx.try_send(DownloadUpdate::State {
+                        file_id: file_id.to_string(),
+                        state: DownloadState::Downloading { received, total },
+                    });
+                }
+            })
+            .await?;
+
+        let (path, action) = match self.policy {
+            OverwritePolicy::Overwrite => {
+                let action = if path.exists() {
+                    "overwritten"
+                } else {
+                    "saved"
+                };
+                fs::write(&path, &content)?;
+                (path, action)
+            }
+            // A file that appeared while downloading is left alone, as with rename
+            OverwritePolicy::Rename | OverwritePolicy::Skip => save_new(&path, &content)?,
+        };
+        log_debug!(
+            "Downloaded: {}",
+            path.canonicalize().unwrap_or(path.clone()).display()
+        );
+        log_download(session_id, file_id, &filename, &path, action);
+        Ok(DownloadState::Saved(path))
+    }
+
+    // The real filename from the metadata API, retried once in case the file isn't ready yet
+    async fn filename(&self, file_id: &str) -> String {
+        for attempt in 0..2 {
+            match self.client.get_file_metadata(file_id).await {
+                Ok(metadata) => {
+                    let _ = self
+                        .tx
+                        .send(DownloadUpdate::Named {
+                            file_id: file_id.to_string(),
+                            filename: metadata.filename.clone(),
+                        })
+                        .await;
+                    return metadata.filename;
+                }
+                Err(e) => {
+                    log_debug!("Could not fetch file metadata for {}: {}", file_id, e);
+                    if attempt == 0 {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                    }
+                }
+            }
+        }
+        format!("{}.bin", file_id)
+    }
+}
+
+// Write to `path`, or to the first free "name-1.ext", "name-2.ext", ... next to it.
+// Files are created with create_new so concurrent downloads never pick the same name.
+pub fn save_new(path: &Path, content: &[u8]) -> Result<(PathBuf, &'static str)> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 0;
+    loop {
+        let candidate = if n == 0 {
+            path.to_path_buf()
+        } else {
+            path.with_file_name(format!("{}-{}{}", stem, n, extension))
+        };
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(mut file) => {
+                file.write_all(content)?;
+                return Ok((candidate, if n == 0 { "saved" } else { "renamed" }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn log_download(session_id: &str, file_id: &str, filename: &str, path: &Path, action: &str) {
+    let artifact = Artifact {
+        timestamp: Local::now().to_rfc3339(),
+        session_id: Some(session_id.to_string()),
+        file_id: file_id.to_string(),
+        filename: filename.to_string(),
+        path: path.display().to_string(),
+        action: action.to_string(),
+    };
+    if let Err(e) = HistoryStore::open().and_then(|store| store.record_artifact(&artifact)) {
+        log_debug!("Failed to log download of {}: {}", file_id, e);
+    }
+}
+
+// Only the last path component, with anything but alphanumerics, '.', '-' and '_'
+// replaced, so a name like "../x y" is saved as "x_y" inside the output directory
+fn clean_filename(filename: &str) -> String {
+    let name = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "unnamed_file".to_string());
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
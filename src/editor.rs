@@ -0,0 +1,177 @@
+// Cursor arithmetic for the input box. Cursors are byte offsets into the input that
+// always sit on a char boundary.
+
+// Undo steps kept for the input; the oldest are dropped first
+const MAX_UNDO: usize = 200;
+
+pub fn prev_char(text: &str, cursor: usize) -> usize {
+    text[..cursor]
+        .char_indices()
+        .next_back()
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+pub fn next_char(text: &str, cursor: usize) -> usize {
+    text[cursor..]
+        .chars()
+        .next()
+        .map(|c| cursor + c.len_utf8())
+        .unwrap_or(cursor)
+}
+
+// Start of the word before the cursor, skipping any whitespace or punctuation first
+pub fn prev_word(text: &str, cursor: usize) -> usize {
+    let mut chars = text[..cursor].char_indices().rev().peekable();
+    while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+    let mut start = chars.peek().map(|&(i, _)| i).unwrap_or(0);
+    for (i, c) in chars {
+        if !c.is_alphanumeric() {
+            break;
+        }
+        start = i;
+    }
+    start
+}
+
+// End of the word after the cursor, skipping any whitespace or punctuation first
+pub fn next_word(text: &str, cursor: usize) -> usize {
+    let mut chars = text[cursor..].char_indices().peekable();
+    while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+    let mut end = text.len() - cursor;
+    for (i, c) in chars {
+        if !c.is_alphanumeric() {
+            end = i;
+            break;
+        }
+    }
+    cursor + end
+}
+
+// Home/End work on the current line of a multi-line input
+pub fn line_start(text: &str, cursor: usize) -> usize {
+    text[..cursor].rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+pub fn line_end(text: &str, cursor: usize) -> usize {
+    text[cursor..]
+        .find('\n')
+        .map(|i| cursor + i)
+        .unwrap_or(text.len())
+}
+
+// Ctrl+U and Ctrl+K stop at the line's ends; from the very start or end they take the
+// line break, joining the line with its neighbour
+pub fn kill_start(text: &str, cursor: usize) -> usize {
+    match line_start(text, cursor) {
+        start if start == cursor => prev_char(text, cursor),
+        start => start,
+    }
+}
+
+pub fn kill_end(text: &str, cursor: usize) -> usize {
+    match line_end(text, cursor) {
+        end if end == cursor => next_char(text, cursor),
+        end => end,
+    }
+}
+
+// Earlier states of the input as (text, cursor), for Ctrl+Z and redo
+#[derive(Debug, Default)]
+pub struct UndoHistory {
+    undo: Vec<(String, usize)>,
+    redo: Vec<(String, usize)>,
+    typing: bool, // The last change typed part of a word, so the next one joins its step
+}
+
+impl UndoHistory {
+    // Remember the input before a change; runs of typed word characters are one step
+    pub fn record(&mut self, text: &str, cursor: usize, typing: bool) {
+        let joins = typing && self.typing;
+        self.typing = typing;
+        if joins {
+            return;
+        }
+        if self.undo.len() >= MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.undo.push((text.to_string(), cursor));
+        self.redo.clear();
+    }
+
+    // Start a new step with the next change, e.g. after the cursor moved
+    pub fn break_run(&mut self) {
+        self.typing = false;
+    }
+
+    // The state to go back to from `text`, skipping steps that didn't change it
+    pub fn undo(&mut self, text: &str, cursor: usize) -> Option<(String, usize)> {
+        self.typing = false;
+        while let Some(state) = self.undo.pop() {
+            if state.0 != text {
+                self.redo.push((text.to_string(), cursor));
+                return Some(state);
+            }
+        }
+        None
+    }
+
+    pub fn redo(&mut self, text: &str, cursor: usize) -> Option<(String, usize)> {
+        self.typing = false;
+        let state = self.redo.pop()?;
+        self.undo.push((text.to_string(), cursor));
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_words_undo_as_one_step() {
+        let mut history = UndoHistory::default();
+        let mut text = String::new();
+        for c in "hi there".chars() {
+            history.record(&text, text.len(), c.is_alphanumeric());
+            text.push(c);
+        }
+        let (text, _) = history.undo(&text, text.len()).unwrap();
+        assert_eq!(text, "hi ");
+        let (text, _) = history.undo(&text, text.len()).unwrap();
+        assert_eq!(text, "hi");
+        let (text, _) = history.redo(&text, text.len()).unwrap();
+        assert_eq!(text, "hi ");
+    }
+
+    #[test]
+    fn words_span_multibyte_chars() {
+        let text = "héllo wörld";
+        assert_eq!(prev_word(text, text.len()), "héllo ".len());
+        assert_eq!(prev_word(text, "héllo wö".len()), "héllo ".len());
+        assert_eq!(next_word(text, 0), "héllo".len());
+        assert_eq!(next_word(text, "héllo".len()), text.len());
+    }
+
+    #[test]
+    fn punctuation_before_a_word_is_skipped() {
+        let text = "call(foo.bar)";
+        assert_eq!(prev_word(text, text.len()), "call(foo.".len());
+        assert_eq!(prev_word(text, "call(foo.".len()), "call(".len());
+        assert_eq!(next_word(text, "call".len()), "call(foo".len());
+        assert_eq!(next_word(text, "call(foo".len()), "call(foo.bar".len());
+    }
+
+    #[test]
+    fn kills_join_lines_only_at_their_ends() {
+        let text = "naïve\ncafé";
+        let end_of_first = "naïve".len();
+        let start_of_second = end_of_first + 1;
+        assert_eq!(kill_start(text, text.len()), start_of_second);
+        assert_eq!(kill_start(text, start_of_second), end_of_first);
+        assert_eq!(kill_start(text, 0), 0);
+        assert_eq!(kill_end(text, 0), end_of_first);
+        assert_eq!(kill_end(text, end_of_first), start_of_second);
+        assert_eq!(kill_end(text, text.len()), text.len());
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+use crate::anthropic::ApiError;
+
+// Exit statuses, so scripts can tell why agnt failed
+pub const ERROR: u8 = 1; // Anything else: unreadable input, files that can't be written, ...
+pub const USAGE: u8 = 2; // Invalid flags or arguments (clap uses 2 as well)
+pub const AUTH: u8 = 3; // No API key or credentials, or the API rejected them
+pub const RATE_LIMITED: u8 = 4; // Rate limited, overloaded or unavailable (429/503/529), after retries
+pub const API: u8 = 5; // Any other API error, or the connection was lost for good
+pub const TRUNCATED: u8 = 6; // The answer was cut off at max_tokens (--strict)
+pub const SCHEMA: u8 = 7; // No answer matched --schema, after retries
+pub const BUDGET: u8 = 8; // The request would go over session_budget_usd or daily_budget_usd
+
+// Failures found before or after the request that have their own exit status
+#[derive(Debug)]
+pub enum Failure {
+    Usage(String),
+    Auth(String),
+    Truncated,
+    Schema(String),
+    Budget(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Usage(message)
+            | Failure::Auth(message)
+            | Failure::Schema(message)
+            | Failure::Budget(message) => f.write_str(message),
+            Failure::Truncated => {
+                f.write_str("The answer was cut off at max_tokens (--strict); raise the limit with --max-tokens")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+pub fn of(error: &anyhow::Error) -> u8 {
+    if let Some(error) = error.downcast_ref::<ApiError>() {
+        return match (error.status, error.kind.as_str()) {
+            (Some(401 | 403), _) | (_, "authentication_error" | "permission_error") => AUTH,
+            // The statuses the client retries, so "try again later" once retries run out
+            (Some(429 | 503 | 529), _) | (_, "rate_limit_error" | "overloaded_error") => {
+                RATE_LIMITED
+            }
+            _ => API,
+        };
+    }
+    match error.downcast_ref::<Failure>() {
+        Some(Failure::Usage(_)) => USAGE,
+        Some(Failure::Auth(_)) => AUTH,
+        Some(Failure::Truncated) => TRUNCATED,
+        Some(Failure::Schema(_)) => SCHEMA,
+        Some(Failure::Budget(_)) => BUDGET,
+        None => ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_errors_map_to_their_own_statuses() {
+        let api = |status, kind: &str| {
+            anyhow::Error::from(ApiError {
+                status,
+                kind: kind.to_string(),
+                message: String::new(),
+            })
+        };
+        assert_eq!(of(&api(Some(401), "authentication_error")), AUTH);
+        assert_eq!(of(&api(None, "overloaded_error")), RATE_LIMITED);
+        assert_eq!(of(&api(Some(503), "api_error")), RATE_LIMITED);
+        assert_eq!(of(&api(Some(500), "api_error")), API);
+        assert_eq!(of(&Failure::Truncated.into()), TRUNCATED);
+        assert_eq!(of(&anyhow::anyhow!("stdin is not UTF-8")), ERROR);
+    }
+}
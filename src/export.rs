@@ -0,0 +1,381 @@
+use anyhow::Result;
+use chrono::Local;
+use serde::Serialize;
+use std::path::Path;
+
+use crate::{session, ui::MessageContent};
+
+type Messages = [(String, Vec<MessageContent>)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "md" | "markdown" => Some(Format::Markdown),
+            "json" => Some(Format::Json),
+            "html" | "htm" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|ext| Self::from_name(&ext.to_string_lossy()))
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Json => "json",
+            Format::Html => "html",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonTranscript<'a> {
+    title: &'a str,
+    exported_at: String,
+    messages: Vec<JsonMessage<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    role: &'a str,
+    content: &'a [MessageContent],
+}
+
+pub fn render(format: Format, title: &str, messages: &Messages) -> Result<String> {
+    let messages = with_full_outputs(messages);
+    Ok(match format {
+        Format::Markdown => markdown(title, &messages),
+        Format::Html => html(title, &messages),
+        Format::Json => serde_json::to_string_pretty(&JsonTranscript {
+            title,
+            exported_at: Local::now().to_rfc3339(),
+            messages: messages
+                .iter()
+                .map(|(role, content)| JsonMessage { role, content })
+                .collect(),
+        })?,
+    })
+}
+
+// Code outputs moved to the session store are put back in full, so the transcript
+// doesn't end up with previews
+fn with_full_outputs(messages: &Messages) -> Vec<(String, Vec<MessageContent>)> {
+    let mut messages = messages.to_vec();
+    for content in messages
+        .iter_mut()
+        .flat_map(|(_, contents)| contents.iter_mut())
+    {
+        if let MessageContent::CodeOutput {
+            stdout,
+            stderr,
+            spilled,
+            ..
+        } = content
+            && let Some(blob_id) = spilled.as_deref()
+        {
+            match session::load_blob(blob_id) {
+                Ok(body) => {
+                    *stdout = body.stdout;
+                    *stderr = body.stderr;
+                    *spilled = None;
+                }
+                Err(e) => log_debug!("Export keeps the preview of blob {}: {}", blob_id, e),
+            }
+        }
+    }
+    messages
+}
+
+fn role_heading(role: &str) -> &str {
+    match role {
+        "user" => "You",
+        "assistant" => "Claude",
+        _ => "agnt",
+    }
+}
+
+// A fence longer than any backtick run in the body, so code containing ``` survives
+fn fence_for(body: &str) -> String {
+    let longest = body.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn fenced(language: &str, body: &str) -> String {
+    let fence = fence_for(body);
+    format!("{}{}\n{}\n{}\n\n", fence, language, body.trim_end(), fence)
+}
+
+fn markdown(title: &str, messages: &Messages) -> String {
+    let mut out = format!(
+        "# {}\n\n_Exported {}_\n\n",
+        title,
+        Local::now().format("%Y-%m-%d %H:%M")
+    );
+    for (role, contents) in messages {
+        out.push_str(&format!("## {}\n\n", role_heading(role)));
+        for content in contents {
+            match content {
+                MessageContent::Text(text) => {
+                    out.push_str(text.trim_end());
+                    out.push_str("\n\n");
+                }
+                MessageContent::Code { input } => out.push_str(&fenced("python", input)),
+                MessageContent::CodeOutput {
+                    stdout,
+                    stderr,
+                    return_code,
+                    files,
+                    ..
+                } => {
+                    if !stdout.is_empty() {
+                        out.push_str("**Output**\n\n");
+                        out.push_str(&fenced("text", stdout));
+                    }
+                    if !stderr.is_empty() {
+                        out.push_str("**Errors**\n\n");
+                        out.push_str(&fenced("text", stderr));
+                    }
+                    if *return_code != 0 {
+                        out.push_str(&format!("_Exit code {}_\n\n", return_code));
+                    }
+                    if !files.is_empty() {
+                        out.push_str("**Created files**\n\n");
+                        for (file_id, filename) in files {
+                            out.push_str(&format!("- {} (`{}`)\n", filename, file_id));
+                        }
+                        out.push('\n');
+                    }
+                }
+                MessageContent::CodeError(error) => {
+                    out.push_str(&format!("> **Code execution error:** {}\n\n", error))
+                }
+                MessageContent::ApiError(error) => {
+                    out.push_str(&format!("> **API error:** {}\n\n", error))
+                }
+                MessageContent::LocalError(error) => {
+                    out.push_str(&format!("> **Error:** {}\n\n", error))
+                }
+                MessageContent::Info(text) => {
+                    for line in text.lines() {
+                        out.push_str(&format!("> {}\n", line));
+                    }
+                    out.push('\n');
+                }
+                MessageContent::Summary(summary) => {
+                    out.push_str("**Summary of the earlier conversation**\n\n");
+                    out.push_str(summary.trim_end());
+                    out.push_str("\n\n");
+                }
+                MessageContent::StopReason(reason)
+                    if matches!(reason.as_str(), "end_turn" | "tool_use") => {}
+                MessageContent::StopReason(reason) => {
+                    out.push_str(&format!("_Stopped: {}_\n\n", reason))
+                }
+                MessageContent::Timing(_) => {}
+                MessageContent::Image {
+                    name, media_type, ..
+                } => out.push_str(&format!("_[image: {} ({})]_\n\n", name, media_type)),
+                MessageContent::Document {
+                    name, media_type, ..
+                } => out.push_str(&format!("_[document: {} ({})]_\n\n", name, media_type)),
+                MessageContent::Upload { name, file_id } => {
+                    out.push_str(&format!("_[uploaded: {} ({})]_\n\n", name, file_id))
+                }
+                MessageContent::File { path, content, .. } => {
+                    out.push_str(&format!("**File: {}**\n\n", path));
+                    out.push_str(&fenced("", content));
+                }
+                MessageContent::Context { label, content } => {
+                    out.push_str(&format!("**Context: {}**\n\n", label));
+                    out.push_str(&fenced("", content));
+                }
+                MessageContent::ToolUse { name, input, .. } => {
+                    out.push_str(&format!("**Tool call: {}**\n\n", name));
+                    out.push_str(&fenced("json", input));
+                }
+                MessageContent::ToolResult {
+                    name,
+                    content,
+                    is_error,
+                    ..
+                } => {
+                    let label = if *is_error {
+                        "Tool error"
+                    } else {
+                        "Tool result"
+                    };
+                    match name.as_str() {
+                        "" => out.push_str(&format!("**{}**\n\n", label)),
+                        name => out.push_str(&format!("**{}: {}**\n\n", label, name)),
+                    }
+                    out.push_str(&fenced("text", content));
+                }
+            }
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:52rem;margin:2rem auto;padding:0 1rem;color:#1f2328;line-height:1.5}\
+h1{font-size:1.5rem}.meta{color:#6e7781}\
+.message{border-left:4px solid #d0d7de;padding:.25rem 1rem;margin:1.5rem 0}\
+.user{border-color:#0969da}.assistant{border-color:#8250df}\
+.role{font-weight:600;margin:.5rem 0}.text{white-space:pre-wrap}\
+pre{background:#f6f8fa;padding:.75rem;overflow-x:auto;border-radius:6px}\
+.stderr{background:#fff1f0}.note{color:#6e7781;font-style:italic}.error{color:#cf222e}\
+img{max-width:100%;border-radius:6px}";
+
+fn html(title: &str, messages: &Messages) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n<p class=\"meta\">Exported {}</p>\n",
+        escape_html(title),
+        HTML_STYLE,
+        escape_html(title),
+        Local::now().format("%Y-%m-%d %H:%M")
+    );
+    for (role, contents) in messages {
+        out.push_str(&format!(
+            "<div class=\"message {}\">\n<div class=\"role\">{}</div>\n",
+            escape_html(role),
+            role_heading(role)
+        ));
+        for content in contents {
+            match content {
+                MessageContent::Text(text) => out.push_str(&format!(
+                    "<div class=\"text\">{}</div>\n",
+                    escape_html(text.trim_end())
+                )),
+                MessageContent::Code { input } => out.push_str(&format!(
+                    "<pre><code class=\"language-python\">{}</code></pre>\n",
+                    escape_html(input)
+                )),
+                MessageContent::CodeOutput {
+                    stdout,
+                    stderr,
+                    return_code,
+                    files,
+                    ..
+                } => {
+                    if !stdout.is_empty() {
+                        out.push_str(&format!("<pre>{}</pre>\n", escape_html(stdout)));
+                    }
+                    if !stderr.is_empty() {
+                        out.push_str(&format!(
+                            "<pre class=\"stderr\">{}</pre>\n",
+                            escape_html(stderr)
+                        ));
+                    }
+                    if *return_code != 0 {
+                        out.push_str(&format!(
+                            "<p class=\"note\">Exit code {}</p>\n",
+                            return_code
+                        ));
+                    }
+                    if !files.is_empty() {
+                        out.push_str("<p>Created files:</p>\n<ul>\n");
+                        for (file_id, filename) in files {
+                            out.push_str(&format!(
+                                "<li>{} <code>{}</code></li>\n",
+                                escape_html(filename),
+                                escape_html(file_id)
+                            ));
+                        }
+                        out.push_str("</ul>\n");
+                    }
+                }
+                MessageContent::CodeError(error) => out.push_str(&format!(
+                    "<p class=\"error\">Code execution error: {}</p>\n",
+                    escape_html(error)
+                )),
+                MessageContent::ApiError(error) => out.push_str(&format!(
+                    "<p class=\"error\">API error: {}</p>\n",
+                    escape_html(error)
+                )),
+                MessageContent::LocalError(error) => out.push_str(&format!(
+                    "<p class=\"error\">Error: {}</p>\n",
+                    escape_html(error)
+                )),
+                MessageContent::Info(text) => out.push_str(&format!(
+                    "<p class=\"note text\">{}</p>\n",
+                    escape_html(text)
+                )),
+                MessageContent::Summary(summary) => out.push_str(&format!(
+                    "<p class=\"role\">Summary of the earlier conversation</p>\n<div class=\"text\">{}</div>\n",
+                    escape_html(summary.trim_end())
+                )),
+                MessageContent::StopReason(reason) if matches!(reason.as_str(), "end_turn" | "tool_use") => {}
+                MessageContent::StopReason(reason) => out.push_str(&format!(
+                    "<p class=\"note\">Stopped: {}</p>\n",
+                    escape_html(reason)
+                )),
+                MessageContent::Timing(_) => {}
+                MessageContent::Image {
+                    name,
+                    media_type,
+                    data,
+                } => out.push_str(&format!(
+                    "<img alt=\"{}\" src=\"data:{};base64,{}\">\n",
+                    escape_html(name),
+                    escape_html(media_type),
+                    data
+                )),
+                MessageContent::Document { name, .. } => out.push_str(&format!(
+                    "<p class=\"note\">[document: {}]</p>\n",
+                    escape_html(name)
+                )),
+                MessageContent::Upload { name, file_id } => out.push_str(&format!(
+                    "<p class=\"note\">[uploaded: {} ({})]</p>\n",
+                    escape_html(name),
+                    escape_html(file_id)
+                )),
+                MessageContent::File { path, content, .. } => out.push_str(&format!(
+                    "<p class=\"role\">File: {}</p>\n<pre><code>{}</code></pre>\n",
+                    escape_html(path),
+                    escape_html(content)
+                )),
+                MessageContent::Context { label, content } => out.push_str(&format!(
+                    "<p class=\"role\">Context: {}</p>\n<pre><code>{}</code></pre>\n",
+                    escape_html(label),
+                    escape_html(content)
+                )),
+                MessageContent::ToolUse { name, input, .. } => out.push_str(&format!(
+                    "<p class=\"role\">Tool call: {}</p>\n<pre><code class=\"language-json\">{}</code></pre>\n",
+                    escape_html(name),
+                    escape_html(input)
+                )),
+                MessageContent::ToolResult {
+                    name,
+                    content,
+                    is_error,
+                    ..
+                } => out.push_str(&format!(
+                    "<p class=\"note\">{} {}</p>\n<pre{}>{}</pre>\n",
+                    if *is_error { "Tool error" } else { "Tool result" },
+                    escape_html(name),
+                    if *is_error { " class=\"stderr\"" } else { "" },
+                    escape_html(content)
+                )),
+            }
+        }
+        out.push_str("</div>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
@@ -0,0 +1,72 @@
+// Small subsequence matcher in the spirit of skim/fzf: every pattern character has to
+// appear in order, and the score rewards matches that are consecutive or start a word.
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_CONSECUTIVE: i64 = 12;
+const BONUS_WORD_START: i64 = 10;
+const BONUS_FIRST_CHAR: i64 = 8;
+const PENALTY_GAP: i64 = 2;
+
+// Returns the score and the char indices of `candidate` that matched, or None if the
+// pattern isn't a subsequence. Matching is case-insensitive.
+pub fn score(pattern: &str, candidate: &str) -> Option<(i64, Vec<usize>)> {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    if pattern.is_empty() {
+        return Some((0, Vec::new()));
+    }
+
+    let chars: Vec<char> = candidate.chars().collect();
+    let mut positions = Vec::with_capacity(pattern.len());
+    let mut total = 0;
+    let mut next = 0;
+
+    for &wanted in &pattern {
+        let found = (next..chars.len()).find(|&i| chars[i].to_lowercase().eq(Some(wanted)))?;
+
+        total += SCORE_MATCH;
+        if found == 0 {
+            total += BONUS_FIRST_CHAR;
+        }
+        if is_word_start(&chars, found) {
+            total += BONUS_WORD_START;
+        }
+        match positions.last() {
+            Some(&prev) if prev + 1 == found => total += BONUS_CONSECUTIVE,
+            Some(&prev) => total -= PENALTY_GAP * (found - prev - 1) as i64,
+            None => total -= PENALTY_GAP * found as i64,
+        }
+
+        positions.push(found);
+        next = found + 1;
+    }
+
+    // Prefer shorter candidates when the matched characters are equally good
+    total -= (chars.len() - pattern.len()) as i64;
+    Some((total, positions))
+}
+
+fn is_word_start(chars: &[char], index: usize) -> bool {
+    index == 0 || matches!(chars[index - 1], ' ' | '-' | '_' | '/' | '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_consecutive_and_word_start_matches_first() {
+        assert_eq!(score("", "anything"), Some((0, Vec::new())));
+        assert_eq!(score("xyz", "model"), None);
+        // Characters have to appear in order
+        assert_eq!(score("ml", "lm"), None);
+
+        let (_, positions) = score("SeH", "session-share").unwrap();
+        assert_eq!(positions, vec![0, 1, 9]);
+
+        let rank = |candidate| score("cl", candidate).unwrap().0;
+        assert!(rank("clear") > rank("cancel"));
+        assert!(rank("copy-last") > rank("cancel"));
+        // Equally good matches favour the shorter candidate
+        assert!(rank("clear") > rank("clear-all"));
+    }
+}
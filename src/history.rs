@@ -0,0 +1,55 @@
+use anyhow::Result;
+use std::{fs, path::PathBuf};
+
+// Oldest prompts are dropped beyond this many entries
+const MAX_ENTRIES: usize = 2000;
+// Very large pastes aren't worth recalling and would bloat the file
+const MAX_PROMPT_CHARS: usize = 20_000;
+
+pub fn history_path() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("history")
+}
+
+// Prompts from every session, oldest first. One JSON string per line so multi-line
+// prompts survive the round trip.
+pub fn load() -> Vec<String> {
+    let Ok(contents) = fs::read_to_string(history_path()) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+// Add a prompt to `entries` and the history file. A repeated prompt moves to the end
+// instead of being stored twice.
+pub fn add(entries: &mut Vec<String>, prompt: &str) -> Result<()> {
+    let prompt = prompt.trim();
+    if prompt.is_empty() || prompt.chars().count() > MAX_PROMPT_CHARS {
+        return Ok(());
+    }
+
+    entries.retain(|entry| entry != prompt);
+    entries.push(prompt.to_string());
+    if entries.len() > MAX_ENTRIES {
+        entries.drain(..entries.len() - MAX_ENTRIES);
+    }
+
+    let path = history_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut contents = String::new();
+    for entry in entries.iter() {
+        contents.push_str(&serde_json::to_string(entry)?);
+        contents.push('\n');
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::{
+    anthropic::{InputContent, Message},
+    config::{ContextHook, HookTarget},
+};
+
+// Run every configured context hook for this turn and inject its output: into the
+// system prompt, or as an extra text block ahead of the latest user message. Hooks
+// run concurrently; failures and timeouts are logged and simply contribute nothing.
+pub async fn apply(hooks: &[ContextHook], system_prompt: &mut String, messages: &mut [Message]) {
+    if hooks.is_empty() {
+        return;
+    }
+
+    let outputs = futures_util::future::join_all(hooks.iter().map(run_hook)).await;
+
+    let mut system_blocks = Vec::new();
+    let mut message_blocks = Vec::new();
+    for (hook, output) in hooks.iter().zip(outputs) {
+        let Some(output) = output else { continue };
+        let block = format!(
+            "<context source=\"{}\">\n{}\n</context>",
+            hook.label.as_deref().unwrap_or(&hook.command),
+            output.trim_end()
+        );
+        match hook.target {
+            HookTarget::System => system_blocks.push(block),
+            HookTarget::Message => message_blocks.push(block),
+        }
+    }
+
+    if !system_blocks.is_empty() {
+        system_prompt.push_str("\n\n");
+        system_prompt.push_str(&system_blocks.join("\n"));
+    }
+
+    // Tool results have to lead their message, so the context goes after them
+    if !message_blocks.is_empty()
+        && let Some(message) = messages.iter_mut().rev().find(|m| m.role == "user")
+    {
+        let position = message
+            .content
+            .iter()
+            .take_while(|block| matches!(block, InputContent::ToolResult { .. }))
+            .count();
+        message.content.insert(
+            position,
+            InputContent::Text {
+                text: message_blocks.join("\n"),
+            },
+        );
+    }
+}
+
+// `command` run by the platform shell, killed if the future running it is dropped
+pub fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    };
+    shell.kill_on_drop(true);
+    shell
+}
+
+async fn run_hook(hook: &ContextHook) -> Option<String> {
+    let mut command = shell(&hook.command);
+
+    match tokio::time::timeout(Duration::from_secs(hook.timeout_secs), command.output()).await {
+        Ok(Ok(output)) => {
+            if !output.status.success() {
+                log_debug!(
+                    "Context hook '{}' exited with {}",
+                    hook.command,
+                    output.status
+                );
+            }
+            Some(String::from_utf8_lossy(&output.stdout).to_string())
+        }
+        Ok(Err(e)) => {
+            log_debug!("Failed to run context hook '{}': {}", hook.command, e);
+            None
+        }
+        Err(_) => {
+            log_debug!(
+                "Context hook '{}' timed out after {}s",
+                hook.command,
+                hook.timeout_secs
+            );
+            None
+        }
+    }
+}
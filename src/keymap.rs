@@ -0,0 +1,299 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::BTreeMap, sync::OnceLock};
+
+use crate::config::Config;
+
+static KEYMAP: OnceLock<Keymap> = OnceLock::new();
+
+// What a remappable key does in the message input; the names are the config keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Send,
+    Newline,
+    DeleteWord,
+    KillLine,
+    KillToEnd,
+    Undo,
+    Redo,
+    Quit,
+    Help,
+    ToggleCodeExec,
+    ToggleWebSearch,
+    SelectionMode,
+    Editor,
+    HistorySearch,
+    Focus,
+    Retry,
+    Rephrase,
+    NewTab,
+    NextTab,
+    PrevTab,
+    ScrollUp,
+    ScrollDown,
+    ScrollTop,
+    ScrollBottom,
+}
+
+const DEFAULTS: &[(Action, &[&str])] = &[
+    (Action::Send, &["enter"]),
+    (Action::Newline, &["alt+enter"]),
+    // Many terminals send Ctrl+Backspace as Ctrl+H, which opens the help instead
+    (Action::DeleteWord, &["ctrl+backspace", "alt+backspace"]),
+    (Action::KillLine, &["ctrl+u"]),
+    (Action::KillToEnd, &["ctrl+k"]),
+    (Action::Undo, &["ctrl+z"]),
+    // Ctrl+Shift+Z only arrives from terminals that report it; Alt+Z works everywhere
+    (Action::Redo, &["ctrl+shift+z", "alt+z"]),
+    (Action::Quit, &["ctrl+c"]),
+    (Action::Help, &["ctrl+h"]),
+    (Action::ToggleCodeExec, &["ctrl+x"]),
+    (Action::ToggleWebSearch, &["ctrl+w"]),
+    (Action::SelectionMode, &["ctrl+s"]),
+    (Action::Editor, &["ctrl+g"]),
+    (Action::HistorySearch, &["ctrl+r"]),
+    (Action::Focus, &["ctrl+y"]),
+    (Action::Retry, &["ctrl+n"]),
+    (Action::Rephrase, &["ctrl+p"]),
+    (Action::NewTab, &["ctrl+t"]),
+    // Ctrl+Tab only arrives from terminals that report it; Ctrl+PageUp/Down work everywhere
+    (Action::NextTab, &["ctrl+tab", "ctrl+pagedown"]),
+    (Action::PrevTab, &["ctrl+backtab", "ctrl+pageup"]),
+    (Action::ScrollUp, &["pageup"]),
+    (Action::ScrollDown, &["pagedown"]),
+    (Action::ScrollTop, &["ctrl+home"]),
+    (Action::ScrollBottom, &["ctrl+end"]),
+];
+
+// One key or several for an action in the config's `keymap`; an empty list unbinds it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeySpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeySpec {
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            KeySpec::One(key) if key.is_empty() || key == "none" => Vec::new(),
+            KeySpec::One(key) => vec![key.as_str()],
+            KeySpec::Many(keys) => keys.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    // "ctrl+w", "alt+enter", "f2", "pageup", "ctrl+shift+k"
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut parts: Vec<&str> = text.split('+').collect();
+        // "ctrl++" binds the plus key
+        if text.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+        let name = parts.pop().filter(|name| !name.is_empty());
+        let name = name.ok_or_else(|| format!("No key in {:?}", text))?;
+        for part in parts {
+            modifiers |= match part.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" | "option" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("Unknown modifier {:?} in {:?}", part, text)),
+            };
+        }
+        let code = match name.to_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "space" => KeyCode::Char(' '),
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" | "ins" => KeyCode::Insert,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" | "pgup" => KeyCode::PageUp,
+            "pagedown" | "pgdn" => KeyCode::PageDown,
+            lower => match lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                Some(n @ 1..=12) => KeyCode::F(n),
+                _ => {
+                    let mut chars = lower.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => KeyCode::Char(c),
+                        _ => return Err(format!("Unknown key {:?}", name)),
+                    }
+                }
+            },
+        };
+        Ok(Self { code, modifiers })
+    }
+
+    fn matches(&self, event: &KeyEvent) -> bool {
+        // Terminals add Shift to uppercase letters and Shift+Tab on their own
+        let mut modifiers = event.modifiers;
+        let mut code = event.code;
+        if let KeyCode::Char(c) = code {
+            code = KeyCode::Char(c.to_ascii_lowercase());
+            if c.is_ascii_alphabetic() && !self.modifiers.contains(KeyModifiers::SHIFT) {
+                modifiers.remove(KeyModifiers::SHIFT);
+            }
+        }
+        if code == KeyCode::BackTab {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        code == self.code && modifiers == self.modifiers
+    }
+
+    // How the help modal shows it, e.g. "Ctrl+W"
+    pub fn label(&self) -> String {
+        let mut label = String::new();
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                label.push_str(name);
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => label.push_str("Space"),
+            KeyCode::Char(c) => label.push(c.to_ascii_uppercase()),
+            KeyCode::F(n) => label.push_str(&format!("F{}", n)),
+            KeyCode::PageUp => label.push_str("PgUp"),
+            KeyCode::PageDown => label.push_str("PgDn"),
+            KeyCode::BackTab => label.push_str("Shift+Tab"),
+            other => label.push_str(&format!("{:?}", other)),
+        }
+        label
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<(Key, Action)>,
+}
+
+impl Keymap {
+    // The defaults with the config's `keymap` entries replacing the keys of the actions
+    // they name; entries that don't parse are reported and left at their defaults
+    pub fn from_config(overrides: &BTreeMap<String, KeySpec>) -> (Self, Vec<String>) {
+        let mut keys: BTreeMap<Action, Vec<Key>> = DEFAULTS
+            .iter()
+            .map(|(action, keys)| {
+                (
+                    *action,
+                    keys.iter().map(|k| Key::parse(k).unwrap()).collect(),
+                )
+            })
+            .collect();
+        let mut errors = Vec::new();
+        for (name, spec) in overrides {
+            let action = match serde_json::from_value::<Action>(serde_json::json!(name)) {
+                Ok(action) => action,
+                Err(_) => {
+                    errors.push(format!("Unknown action {}", name));
+                    continue;
+                }
+            };
+            match spec.keys().into_iter().map(Key::parse).collect() {
+                Ok(parsed) => {
+                    keys.insert(action, parsed);
+                }
+                Err(e) => errors.push(format!("{}: {}", name, e)),
+            }
+        }
+        let bindings = keys
+            .into_iter()
+            .flat_map(|(action, keys)| keys.into_iter().map(move |key| (key, action)))
+            .collect();
+        (Self { bindings }, errors)
+    }
+
+    // Ctrl+Z also takes a Ctrl+Shift+Z press, so the key naming more modifiers wins
+    pub fn action(&self, event: &KeyEvent) -> Option<Action> {
+        self.bindings
+            .iter()
+            .filter(|(key, _)| key.matches(event))
+            .min_by_key(|(key, _)| Reverse(key.modifiers.bits().count_ones()))
+            .map(|(_, action)| *action)
+    }
+
+    // The first key bound to `action`, for hints and the help modal
+    pub fn label(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .find(|(_, bound)| *bound == action)
+            .map(|(key, _)| key.label())
+            .unwrap_or_else(|| "(unbound)".to_string())
+    }
+}
+
+pub fn init(config: &Config) {
+    let (keymap, errors) = Keymap::from_config(&config.keymap);
+    for error in errors {
+        eprintln!("Warning: Ignoring keymap entry: {}", error);
+        log_debug!("Ignoring keymap entry: {}", error);
+    }
+    let _ = KEYMAP.set(keymap);
+}
+
+pub fn current() -> &'static Keymap {
+    KEYMAP.get_or_init(|| Keymap::from_config(&BTreeMap::new()).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_move_actions_to_other_keys() {
+        let overrides = BTreeMap::from([
+            (
+                "toggle_web_search".to_string(),
+                KeySpec::One("alt+w".to_string()),
+            ),
+            (
+                "selection_mode".to_string(),
+                KeySpec::Many(vec!["f2".to_string()]),
+            ),
+            ("sned".to_string(), KeySpec::One("enter".to_string())),
+        ]);
+        let (keymap, errors) = Keymap::from_config(&overrides);
+        assert_eq!(errors, vec!["Unknown action sned".to_string()]);
+
+        let ctrl_w = KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL);
+        let alt_w = KeyEvent::new(KeyCode::Char('w'), KeyModifiers::ALT);
+        let f2 = KeyEvent::new(KeyCode::F(2), KeyModifiers::NONE);
+        let ctrl_shift_r = KeyEvent::new(
+            KeyCode::Char('R'),
+            KeyModifiers::CONTROL | KeyModifiers::SHIFT,
+        );
+        assert_eq!(keymap.action(&ctrl_w), None);
+        assert_eq!(keymap.action(&alt_w), Some(Action::ToggleWebSearch));
+        assert_eq!(keymap.action(&f2), Some(Action::SelectionMode));
+        assert_eq!(keymap.action(&ctrl_shift_r), Some(Action::HistorySearch));
+        let ctrl_shift_z = KeyEvent::new(
+            KeyCode::Char('Z'),
+            KeyModifiers::CONTROL | KeyModifiers::SHIFT,
+        );
+        let ctrl_z = KeyEvent::new(KeyCode::Char('z'), KeyModifiers::CONTROL);
+        assert_eq!(keymap.action(&ctrl_shift_z), Some(Action::Redo));
+        assert_eq!(keymap.action(&ctrl_z), Some(Action::Undo));
+        assert_eq!(keymap.label(Action::ToggleWebSearch), "Alt+W");
+        assert_eq!(keymap.label(Action::NextTab), "Ctrl+Tab");
+    }
+}
@@ -12,10 +12,10 @@ pub struct LoggerGuard;
 impl Drop for LoggerGuard {
     fn drop(&mut self) {
         // Ensure log file is flushed and closed properly
-        if let Ok(mut log_guard) = LOG_FILE.lock() {
-            if let Some(ref mut file) = *log_guard {
-                let _ = file.flush();
-            }
+        if let Ok(mut log_guard) = LOG_FILE.lock()
+            && let Some(ref mut file) = *log_guard
+        {
+            let _ = file.flush();
         }
     }
 }
@@ -1,31 +1,114 @@
 #[macro_use]
 mod logger;
 mod anthropic;
+mod auth;
+mod batch;
+mod bedrock;
+mod clipboard;
+mod codeblocks;
+mod commands;
+mod compact;
+mod config;
+mod custom_commands;
+mod daemon;
+mod downloads;
+mod editor;
+mod exit_code;
+mod export;
+mod fuzzy;
+mod history;
+mod hooks;
+mod keymap;
+mod mentions;
+mod models;
+mod notify;
+mod ollama;
+mod output_file;
+mod pipe_json;
+mod preview;
+mod pricing;
+mod project;
+mod prompt;
+mod review;
+mod schema;
+mod serve;
+mod session;
+mod sse;
+mod store;
+mod templates;
+mod text_editor;
+mod theme;
+mod tmux;
+mod tools;
+mod tts;
 mod ui;
+mod usage;
+mod watch;
+mod workspace;
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use commands::Command;
 use crossterm::{
     event::{
-        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, MouseEventKind,
+        self, DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture,
+        Event, EventStream, KeyCode, KeyEventKind, MouseButton, MouseEventKind,
     },
     execute,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
+use downloads::{DownloadManager, DownloadState, DownloadUpdate};
+use exit_code::Failure;
+use futures_util::StreamExt;
+use keymap::Action;
+use output_file::OutputFile;
+use pipe_json::PipeEvent;
 use ratatui::{Terminal, backend::CrosstermBackend};
 use std::{
     fs,
-    io::{self, Read, Write},
-    path::Path,
-    time::Duration,
+    io::{self, IsTerminal, Read, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    task::Poll,
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use store::HistoryStore;
+use tokio::{sync::mpsc, time::MissedTickBehavior};
 use tokio_util::sync::CancellationToken;
-use ui::{App, ToolMode};
+use ui::{App, Pane, ToolMode};
+
+// Upper bound on redraw rate (~30fps) while content is changing
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+// The same with reduced_motion, so streamed text arrives in a few larger steps
+const REDUCED_MOTION_FRAME_INTERVAL: Duration = Duration::from_millis(250);
+
+// Quiet time `agnt watch` waits for after a change, as editors and formatters often
+// write a file in several steps
+const WATCH_SETTLE: Duration = Duration::from_millis(300);
+
+// Subcommands that keep running until interrupted
+enum Service {
+    Watch {
+        globs: Vec<String>,
+        interval: Duration,
+    },
+    Daemon {
+        socket: PathBuf,
+    },
+    Serve {
+        address: SocketAddr,
+        token: Option<String>,
+    },
+    Rpc,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Run in pipe mode (read from stdin, write to stdout)
     #[arg(short, long)]
     pipe: bool,
@@ -35,21 +118,164 @@ struct Args {
     message: Option<String>,
 
     /// Enable code execution (requires Claude model that supports it)
-    #[arg(short = 'x', long)]
+    #[arg(short = 'x', long, global = true)]
     code_execution: bool,
 
     /// Enable web search (requires Claude model that supports it)
-    #[arg(short = 'w', long)]
+    #[arg(short = 'w', long, global = true)]
     web_search: bool,
 
+    /// Let Claude run shell commands in DIR (default: the current directory); each one is confirmed first
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = ".", global = true)]
+    allow_shell: Option<PathBuf>,
+
+    /// Let Claude read, list and (after showing a diff) write files under DIR (default: the current directory)
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = ".", global = true)]
+    allow_files: Option<PathBuf>,
+
+    /// Give Claude Anthropic's bash and text editor tools, working in DIR (default: the current directory); commands and edits are confirmed first
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = ".", global = true)]
+    coding_tools: Option<PathBuf>,
+
+    /// Start the conversation with the directory tree of DIR (default: the current directory, .gitignore respected) and its git status
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = ".", global = true)]
+    with_tree: Option<PathBuf>,
+
     /// Directory to save files created by code execution (default: ./output when code execution is enabled)
-    #[arg(short = 'o', long, value_name = "DIR")]
+    #[arg(short = 'o', long, value_name = "DIR", global = true)]
     output_dir: Option<String>,
+
+    /// Compact layout for running inside `tmux display-popup`; Esc closes agnt when idle
+    #[arg(long, global = true)]
+    popup: bool,
+
+    /// Print the request that would be sent (stdin as the message, API key redacted) as JSON, without sending it
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Continue a saved session by id ("last" for the most recent one)
+    #[arg(long, value_name = "ID", global = true)]
+    resume: Option<String>,
+
+    /// In pipe mode, continue a saved session (the most recent one without an ID) and save the exchange back into it; implies --pipe
+    #[arg(short = 'c', long = "continue", value_name = "ID", num_args = 0..=1, default_missing_value = "last", global = true)]
+    continue_session: Option<String>,
+
+    /// Sampling temperature (0-1) for every request
+    #[arg(long, value_name = "T", global = true)]
+    temperature: Option<f64>,
+
+    /// Nucleus sampling cutoff (0-1)
+    #[arg(long, value_name = "P", global = true)]
+    top_p: Option<f64>,
+
+    /// Only sample from the K most likely tokens
+    #[arg(long, value_name = "K", global = true)]
+    top_k: Option<u32>,
+
+    /// Maximum tokens per answer (default: config max_tokens, else 4096)
+    #[arg(long, value_name = "N", global = true)]
+    max_tokens: Option<u32>,
+
+    /// Stop generating when this text appears; repeat for several
+    #[arg(long = "stop", value_name = "TEXT", global = true)]
+    stop_sequences: Vec<String>,
+
+    /// Attach an image (PNG, JPEG, GIF, WebP) to the first message; repeat for several
+    #[arg(long = "image", value_name = "PATH", global = true)]
+    images: Vec<PathBuf>,
+
+    /// In pipe mode, also write the exchange as a transcript (.md, .json or .html)
+    #[arg(long, value_name = "PATH", global = true)]
+    export: Option<PathBuf>,
+
+    /// In pipe mode, print newline-delimited JSON events instead of plain text
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// In pipe mode, also write the answer as text to PATH, replacing it once the answer is complete
+    #[arg(long, value_name = "PATH", global = true)]
+    output_file: Option<PathBuf>,
+
+    /// Add the answer to the end of --output-file instead of replacing it
+    #[arg(long, requires = "output_file", global = true)]
+    append: bool,
+
+    /// In pipe mode, exit with status 6 when the answer is cut off at max_tokens
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// In pipe mode, make the answer JSON matching the JSON Schema in FILE and print only that
+    #[arg(long, value_name = "FILE", global = true, conflicts_with_all = ["json", "export"])]
+    schema: Option<PathBuf>,
+
+    /// Send each stdin line as its own request and print one JSON result per line
+    #[arg(long, conflicts_with = "schema")]
+    batch: bool,
+
+    /// Like --batch, but each line is a JSON object with a "prompt" and an optional "id"
+    #[arg(long, conflicts_with_all = ["batch", "schema"])]
+    batch_json: bool,
+
+    /// How many --batch requests run at once
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+
+    /// In pipe mode, take stdin as a file called NAME: text is attached under that name, and with code execution any file is uploaded for code to use
+    #[arg(long, value_name = "NAME", global = true)]
+    stdin_file: Option<String>,
+
+    /// Send --message (and any --image) as the whole prompt without reading stdin; implies --pipe
+    #[arg(long)]
+    no_stdin: bool,
+
+    /// What pipe mode reads from stdin: the question as text, or a JSON conversation
+    #[arg(long, value_enum, default_value_t = InputFormat::Text)]
+    input_format: InputFormat,
+
+    /// Build the prompt from ~/.agnt/prompts/NAME.md; stdin fills {{input}}; implies --pipe
+    #[arg(long, value_name = "NAME")]
+    template: Option<String>,
+
+    /// Value for a template variable (repeatable)
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Send requests to the Anthropic API, Claude on AWS Bedrock, or a local Ollama server (default: config provider)
+    #[arg(long, value_enum, global = true)]
+    provider: Option<config::ProviderKind>,
+
+    /// Move sessions not updated for DAYS days into the compressed archive pack, then exit
+    #[arg(long, value_name = "DAYS")]
+    archive_sessions: Option<u32>,
+}
+
+fn parse_var(raw: &str) -> Result<(String, String), String> {
+    raw.split_once('=')
+        .filter(|(name, _)| !name.trim().is_empty())
+        .map(|(name, value)| (name.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", raw))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum InputFormat {
+    Text,     // stdin is the question (or an image)
+    Messages, // stdin is a JSON array of {role, content} messages
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(exit_code::of(&e))
+        }
+    }
+}
+
+async fn run() -> Result<()> {
+    let mut args = Args::parse();
 
     // Initialize logger and keep guard alive for the duration of the program
     let _logger_guard = match logger::init_logger() {
@@ -74,29 +300,129 @@ async fn main() -> Result<()> {
     log_debug!("=== AGNT Started ===");
     log_debug!("Args: {:?}", args);
 
+    let config = config::load();
+    theme::init(&config);
+    keymap::init(&config);
+
+    // Subcommands that only work on local files don't need an API key
+    let command = args.command.take();
+    match command {
+        Some(Command::Sessions { command }) => return commands::sessions(command),
+        Some(Command::Usage { since }) => return commands::usage(since, args.json),
+        Some(Command::Config { command }) => return commands::config(command),
+        Some(Command::Auth { command }) => return commands::auth(command),
+        _ => {}
+    }
+
+    if let Some(days) = args.archive_sessions {
+        let archived = session::archive_old_sessions(days)?;
+        println!(
+            "Archived {} session(s) into {}",
+            archived,
+            session::archive_path().display()
+        );
+        return Ok(());
+    }
+
+    let provider = match args.provider.unwrap_or(config.provider) {
+        config::ProviderKind::Anthropic => anthropic::Provider::Anthropic,
+        config::ProviderKind::Bedrock => {
+            match bedrock::Bedrock::from_env(config.bedrock_region.clone()) {
+                Ok(bedrock) => anthropic::Provider::Bedrock(bedrock),
+                Err(e) => return Err(Failure::Auth(e.to_string()).into()),
+            }
+        }
+        config::ProviderKind::Ollama => {
+            anthropic::Provider::Ollama(ollama::Ollama::new(config.ollama_host.clone()))
+        }
+    };
+
     let api_key = match std::env::var("ANTHROPIC_API_KEY") {
         Ok(key) => key,
-        Err(_) => {
-            eprintln!("Error: ANTHROPIC_API_KEY environment variable is not set");
-            eprintln!("Please set your Anthropic API key:");
-            eprintln!("  export ANTHROPIC_API_KEY=your_api_key_here");
-            return Ok(());
-        }
+        // Bedrock uses AWS credentials and Ollama needs none
+        Err(_) if !matches!(provider, anthropic::Provider::Anthropic) => String::new(),
+        // Then the key saved by `agnt auth login`
+        Err(_) => match auth::stored_key() {
+            Some(key) => key,
+            // Nothing is sent in a dry run
+            None if args.dry_run => String::new(),
+            None => {
+                return Err(Failure::Auth(
+                    "ANTHROPIC_API_KEY environment variable is not set\n\
+                     Please set your Anthropic API key, or store it in the OS keychain:\n  \
+                     export ANTHROPIC_API_KEY=your_api_key_here\n  \
+                     agnt auth login"
+                        .to_string(),
+                )
+                .into());
+            }
+        },
     };
 
-    let model =
-        std::env::var("ANTHROPIC_MODEL").unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
-    log_debug!("Initialized with ANTHROPIC_MODEL: {}", model);
+    // ANTHROPIC_MODEL wins over the config file's model; Ollama has its own pair, and
+    // without either uses the newest installed model (looked up below)
+    let model = match &provider {
+        anthropic::Provider::Ollama(_) => std::env::var("OLLAMA_MODEL")
+            .ok()
+            .or_else(|| config.ollama_model.clone())
+            .unwrap_or_default(),
+        _ => match &config.model {
+            Some(model) if std::env::var("ANTHROPIC_MODEL").is_err() => models::resolve(model),
+            _ => anthropic::default_model(),
+        },
+    };
+    log_debug!("Initialized with model: {}", model);
 
     // Determine initial tool mode based on CLI flags
-    let initial_tool_mode = match (args.code_execution, args.web_search) {
+    let mut initial_tool_mode = match (args.code_execution, args.web_search) {
         (true, true) => ToolMode::Both,
         (true, false) => ToolMode::CodeExecution,
         (false, true) => ToolMode::WebSearch,
         (false, false) => ToolMode::None,
     };
+    if initial_tool_mode != ToolMode::None && !provider.supports_server_tools() {
+        eprintln!(
+            "Note: code execution and web search aren't available with {}; continuing without them",
+            provider.name()
+        );
+        initial_tool_mode = ToolMode::None;
+    }
+
+    let toolbox = build_toolbox(&args, &config).map_err(Failure::Usage)?;
 
-    let client = anthropic::AnthropicClient::new(api_key).with_tool_mode(initial_tool_mode);
+    let sampling = anthropic::RequestParams {
+        model: None,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        top_k: args.top_k,
+        stop_sequences: (!args.stop_sequences.is_empty()).then(|| args.stop_sequences.clone()),
+        max_tokens: args.max_tokens.or(config.max_tokens),
+    };
+    sampling
+        .validate()
+        .map_err(|e| Failure::Usage(e.to_string()))?;
+
+    let schema = args
+        .schema
+        .as_deref()
+        .map(schema::Schema::load)
+        .transpose()?;
+    let mut client = anthropic::AnthropicClient::new(api_key)
+        .with_provider(provider)
+        .with_model(model)
+        .with_sampling(sampling)
+        .with_tool_mode(initial_tool_mode)
+        .with_client_tools(toolbox.definitions())
+        .with_web_search_max_uses(config.web_search_max_uses)
+        .with_stall_timeout(Duration::from_secs(config.stall_timeout_secs.max(1)))
+        .with_timeouts(anthropic::Timeouts {
+            connect: Duration::from_secs(config.connect_timeout_secs.max(1)),
+            read: Duration::from_secs(config.read_timeout_secs.max(1)),
+            request: config
+                .request_timeout_secs
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+        });
 
     // Default output directory to "output" if code execution is enabled and no dir specified
     let output_dir = if matches!(initial_tool_mode, ToolMode::CodeExecution | ToolMode::Both) {
@@ -105,305 +431,1878 @@ async fn main() -> Result<()> {
         args.output_dir
     };
 
-    let result = if args.pipe {
+    // (label, content) blocks sent ahead of a pipe mode question, like a review's diff
+    let mut context = Vec::new();
+    let mut service = None;
+    let ask = match command {
+        Some(Command::Files { command }) => return commands::files(&client, command).await,
+        Some(Command::Models) => return commands::models(&client).await,
+        Some(Command::Ask { prompt }) => {
+            args.message = Some(prompt.join(" "));
+            true
+        }
+        Some(Command::Review { range }) => {
+            let review = review::collect(&range).map_err(anyhow::Error::msg)?;
+            args.message = Some(review.prompt());
+            args.no_stdin = true;
+            context.push((review.label, review.diff));
+            true
+        }
+        Some(Command::Watch {
+            globs,
+            message,
+            interval,
+        }) => {
+            args.message = Some(message);
+            service = Some(Service::Watch {
+                globs,
+                interval: Duration::from_secs_f64(interval.max(0.1)),
+            });
+            false
+        }
+        Some(Command::Serve { host, port, token }) => {
+            service = Some(Service::Serve {
+                address: SocketAddr::new(host, port),
+                token,
+            });
+            false
+        }
+        Some(Command::Rpc) => {
+            service = Some(Service::Rpc);
+            false
+        }
+        Some(Command::Daemon { socket }) => {
+            service = Some(Service::Daemon {
+                socket: socket.unwrap_or_else(daemon::default_socket),
+            });
+            false
+        }
+        _ => false,
+    };
+
+    if client.model().is_empty() {
+        let models = client.local_models().await?;
+        let Some(newest) = models.first() else {
+            anyhow::bail!("No models installed in Ollama; pull one with `ollama pull <model>`");
+        };
+        client = client.with_model(newest.name.clone());
+    }
+
+    if args.no_stdin && args.message.is_none() && args.images.is_empty() {
+        return Err(Failure::Usage(
+            "--no-stdin needs a prompt from --message or --image".to_string(),
+        )
+        .into());
+    }
+
+    if let Some(Service::Daemon { socket }) = service {
+        // Nobody is there to approve local tools, so the daemon answers without them
+        return daemon::run(client.with_client_tools(Vec::new()), config, &socket).await;
+    }
+
+    if let Some(Service::Rpc) = service {
+        return daemon::run_stdio(client.with_client_tools(Vec::new()), config).await;
+    }
+
+    if let Some(Service::Serve { address, token }) = service {
+        let token = token.or_else(|| config.serve_token.clone());
+        return serve::run(client.with_client_tools(Vec::new()), config, address, token).await;
+    }
+
+    if let Some(Service::Watch { globs, interval }) = service {
+        let round = |context| PipeOptions {
+            message: args.message.clone(),
+            images: Vec::new(),
+            output_dir: output_dir.clone(),
+            dry_run: false,
+            export: None,
+            json: args.json,
+            output_file: args.output_file.clone(),
+            append: args.append,
+            strict: false,
+            schema: None,
+            stdin_file: None,
+            continued: None,
+            input_format: InputFormat::Text,
+            read_stdin: false,
+            template: None,
+            vars: Vec::new(),
+            toolbox: toolbox.clone(),
+            context,
+        };
+        return run_watch(client, config, &globs, interval, round).await;
+    }
+
+    if args.batch || args.batch_json {
+        let mut input = String::new();
+        io::stdin()
+            .read_to_string(&mut input)
+            .map_err(|_| anyhow::anyhow!("--batch needs UTF-8 text on stdin"))?;
+        let inputs = batch::parse_inputs(&input, args.batch_json, args.message.as_deref())
+            .map_err(Failure::Usage)?;
+        let system_prompt = prompt::render(
+            config
+                .system_prompt
+                .as_deref()
+                .unwrap_or(prompt::DEFAULT_SYSTEM_PROMPT),
+        );
+        let total = inputs.len();
+        // The whole batch is checked against the budgets up front, as if it were one request
+        let prompts: Vec<anthropic::Message> = inputs
+            .iter()
+            .map(|input| anthropic::Message::text("user", input.prompt.clone()))
+            .collect();
+        match usage::check_request(&config, client.model(), 0.0, &system_prompt, &prompts) {
+            usage::BudgetCheck::Within => {}
+            usage::BudgetCheck::Warning(warning) => eprintln!("Warning: {}", warning),
+            usage::BudgetCheck::Exceeded(reason) => {
+                return Err(Failure::Budget(format!(
+                    "{} - batch not sent; raise the limit with `agnt config set`",
+                    reason
+                ))
+                .into());
+            }
+        }
+        let session_id = session::new_session_id();
+        // Batch answers never run tool calls, so the tools aren't offered
+        let failed = batch::run(
+            &client.with_client_tools(Vec::new()),
+            system_prompt,
+            inputs,
+            args.concurrency as usize,
+            &session_id,
+        )
+        .await?;
+        if failed > 0 {
+            anyhow::bail!("{} of {} batch requests failed", failed, total);
+        }
+        return Ok(());
+    }
+
+    let template = match args.template.as_deref() {
+        Some(_) if args.input_format == InputFormat::Messages => {
+            return Err(Failure::Usage(
+                "--template only works with --input-format text".to_string(),
+            )
+            .into());
+        }
+        Some(name) => Some(templates::load(name)?),
+        None => None,
+    };
+
+    // The schema's tool replaces any others, and every answer has to call it
+    if let Some(schema) = &schema {
+        client = client
+            .with_client_tools(vec![schema.tool()])
+            .with_forced_tool(schema::TOOL_NAME);
+    }
+
+    let result = if args.pipe
+        || args.dry_run
+        || args.no_stdin
+        || ask
+        || template.is_some()
+        || schema.is_some()
+        || args.stdin_file.is_some()
+        || args.continue_session.is_some()
+    {
         // Pipe mode: read from stdin, send to API, write to stdout
-        run_pipe_mode(client, args.message, output_dir).await
+        if let Some(dir) = &args.with_tree {
+            let snapshot = project::snapshot(dir).map_err(Failure::Usage)?;
+            context.insert(0, (format!("project tree of {}", dir.display()), snapshot));
+        }
+        let options = PipeOptions {
+            template,
+            vars: args.vars,
+            message: args.message,
+            images: args.images,
+            output_dir,
+            dry_run: args.dry_run,
+            export: args.export,
+            json: args.json,
+            output_file: args.output_file,
+            append: args.append,
+            strict: args.strict,
+            schema,
+            stdin_file: args.stdin_file,
+            continued: args
+                .continue_session
+                .as_deref()
+                .map(session::load)
+                .transpose()?,
+            input_format: args.input_format,
+            read_stdin: !args.no_stdin,
+            toolbox,
+            context,
+        };
+        run_pipe_mode(client, config, options).await
     } else {
+        // Load before entering the TUI so a bad id is reported on the normal terminal
+        let resumed = args.resume.as_deref().map(session::load).transpose()?;
         // Interactive TUI mode
-        run_tui_mode(client, output_dir).await
+        run_tui_mode(
+            client,
+            output_dir,
+            config,
+            toolbox,
+            args.popup,
+            resumed,
+            FirstAttachments {
+                images: args.images,
+                tree: args.with_tree,
+            },
+        )
+        .await
     };
 
     log_debug!("=== AGNT Terminated ===");
     result
 }
 
-async fn run_pipe_mode(
+// `agnt watch`: each time watched files change, ask again with their diffs attached,
+// until interrupted. A failed round is reported and watching goes on.
+async fn run_watch(
     client: anthropic::AnthropicClient,
-    prepend_message: Option<String>,
-    output_dir: Option<String>,
+    config: config::Config,
+    globs: &[String],
+    interval: Duration,
+    round: impl Fn(Vec<(String, String)>) -> PipeOptions,
 ) -> Result<()> {
-    // Read input from stdin
-    let mut input = String::new();
-    io::stdin().read_to_string(&mut input)?;
-
-    // Combine optional message with stdin input
-    let full_message = match prepend_message {
-        Some(msg) => format!("{} {}", msg, input),
-        None => input,
-    };
+    let root = std::env::current_dir()?;
+    let mut watcher = watch::Watcher::new(&root, globs).map_err(Failure::Usage)?;
+    eprintln!(
+        "Watching {} files for changes; press Ctrl+C to stop",
+        watcher.len()
+    );
+    loop {
+        tokio::time::sleep(interval).await;
+        if !watcher.poll().map_err(anyhow::Error::msg)? {
+            continue;
+        }
+        loop {
+            tokio::time::sleep(WATCH_SETTLE).await;
+            if !watcher.poll().map_err(anyhow::Error::msg)? {
+                break;
+            }
+        }
+        let changes = watcher.take();
+        if changes.is_empty() {
+            continue;
+        }
+        let names: Vec<&str> = changes.iter().map(|(name, _)| name.as_str()).collect();
+        eprintln!(
+            "\n── {} · {} ──",
+            chrono::Local::now().format("%H:%M:%S"),
+            names.join(", ")
+        );
+        let context = changes
+            .into_iter()
+            .map(|(name, diff)| (format!("changes to {}", name), diff))
+            .collect();
+        if let Err(e) = run_pipe_mode(client.clone(), config.clone(), round(context)).await {
+            eprintln!("Error: {:#}", e);
+        }
+    }
+}
 
-    // Create message and send to API
-    let messages = vec![anthropic::Message {
-        role: "user".to_string(),
-        content: full_message,
-    }];
+// Local tools from the config plus the built-in ones the --allow-shell, --allow-files
+// and --coding-tools directories switch on
+fn build_toolbox(args: &Args, config: &config::Config) -> Result<tools::Toolbox, String> {
+    let dir = |flag: &str, dir: &Option<PathBuf>| -> Result<Option<PathBuf>, String> {
+        let Some(dir) = dir else {
+            return Ok(None);
+        };
+        let dir = fs::canonicalize(dir)
+            .map_err(|e| format!("Cannot use {} directory {}: {}", flag, dir.display(), e))?;
+        if !dir.is_dir() {
+            return Err(format!("{} needs a directory, not {}", flag, dir.display()));
+        }
+        Ok(Some(dir))
+    };
+    let workspace =
+        |flag: &str, path: &Option<PathBuf>| -> Result<Option<workspace::Workspace>, String> {
+            dir(flag, path)?
+                .map(|dir| {
+                    workspace::Workspace::new(dir, &config.allowed_paths)
+                        .map_err(|e| format!("Invalid allowed_paths: {}", e))
+                })
+                .transpose()
+        };
+    Ok(tools::Toolbox {
+        tools: config.tools.clone(),
+        shell_dir: dir("--allow-shell", &args.allow_shell)?,
+        shell_timeout: Duration::from_secs(config.shell_timeout_secs.max(1)),
+        workspace: workspace("--allow-files", &args.allow_files)?,
+        editor: workspace("--coding-tools", &args.coding_tools)?.map(text_editor::TextEditor::new),
+    })
+}
 
-    // Use default system prompt for pipe mode
-    let default_prompt = "You are a helpful assistant. Your knowledge cut-off is March 2025. The current date and time is [DATE_TIME_WITH_WEEKDAY_AND_TIMEZONE]".to_string();
-    let system_prompt = Some(substitute_datetime_placeholder(&default_prompt));
-    let (mut receiver, _cancellation) = client.send_message_stream(messages, system_prompt).await?;
+// Everything pipe mode takes from the command line besides stdin
+struct PipeOptions {
+    message: Option<String>, // Prepended to stdin
+    images: Vec<PathBuf>,
+    output_dir: Option<String>,
+    dry_run: bool,
+    export: Option<PathBuf>,
+    json: bool, // Newline-delimited JSON events on stdout
+    output_file: Option<PathBuf>,
+    append: bool,                        // Add to output_file instead of replacing it
+    strict: bool,                        // Fail when the answer is cut off at max_tokens
+    schema: Option<schema::Schema>,      // Print only JSON that matches it
+    stdin_file: Option<String>,          // Name of the file stdin holds
+    continued: Option<session::Session>, // Saved conversation the prompt follows (--continue)
+    input_format: InputFormat,
+    read_stdin: bool, // False with --no-stdin
+    template: Option<templates::Template>,
+    vars: Vec<(String, String)>, // --var values for the template
+    toolbox: tools::Toolbox,
+    context: Vec<(String, String)>, // (label, content) blocks that go ahead of the question
+}
 
-    // Stream response to stdout
-    while let Some(event) = receiver.recv().await {
-        match event {
-            anthropic::StreamEvent::Text(text) => {
-                print!("{}", text);
-            }
-            anthropic::StreamEvent::CodeInput(code) => {
-                println!("\n```python\n{}\n```", code);
-            }
-            anthropic::StreamEvent::CodeOutput {
-                stdout,
-                stderr,
-                return_code,
-                files,
-            } => {
-                if !stdout.is_empty() {
-                    println!("\nOutput:\n{}", stdout);
-                }
-                if !stderr.is_empty() {
-                    eprintln!("\nError:\n{}", stderr);
-                }
-                if return_code != 0 {
-                    eprintln!("(Exit code: {})", return_code);
-                }
-                if !files.is_empty() {
-                    println!("\nCreated files:");
-                    // If code execution is enabled, always save files (default to ./output)
-                    let save_dir = output_dir.as_deref().unwrap_or("output");
-
-                    for (file_id, filename) in &files {
-                        println!("  - {} (ID: {})", filename, file_id);
-
-                        // Save file locally if file ID is valid
-                        if file_id.starts_with("file_") {
-                            // Clone the client to use in the async block
-                            let client_clone = client.clone();
-                            let dir_clone = save_dir.to_string();
-                            let file_id_clone = file_id.clone();
-
-                            // Create a dummy channel for pipe mode (we don't update UI)
-                            let (metadata_tx, _) = mpsc::channel::<(String, String)>(1);
-
-                            // Spawn a task to download the file asynchronously
-                            tokio::spawn(async move {
-                                if let Err(e) = download_and_save_file(
-                                    &client_clone,
-                                    &dir_clone,
-                                    &file_id_clone,
-                                    metadata_tx,
-                                )
-                                .await
-                                {
-                                    log_debug!("Error saving file: {}", e);
-                                }
-                            });
-                        } else {
-                            eprintln!(
-                                "Note: Cannot download file '{}' - file ID not available in streaming mode",
-                                filename
-                            );
-                        }
-                    }
+// Pipe mode runs tool calls in order; run_shell asks on the terminal first (stdin
+// is usually the piped input) and streams its output to stderr
+async fn run_pipe_tools(
+    toolbox: &tools::Toolbox,
+    calls: Vec<tools::ToolCall>,
+    json: bool,
+) -> Vec<ui::MessageContent> {
+    let mut results = Vec::new();
+    for call in calls {
+        if let Some(approval) = toolbox.approval(&call)
+            && !confirm_on_tty(&approval)
+        {
+            results.push(tools::result(call, Err(tools::DECLINED.to_string())));
+            continue;
+        }
+        let (events_tx, mut events_rx) = mpsc::channel(64);
+        let run = async move {
+            let result = toolbox
+                .run(call, &CancellationToken::new(), &events_tx)
+                .await;
+            drop(events_tx);
+            result
+        };
+        let echo = async {
+            while let Some(event) = events_rx.recv().await {
+                if let tools::ToolEvent::Output(line) = event
+                    && !json
+                {
+                    eprint!("{}", line);
                 }
             }
-            anthropic::StreamEvent::CodeError(error) => {
-                eprintln!("\nCode execution error: {}", error);
-            }
-            anthropic::StreamEvent::ContainerInfo { .. } => {
-                // Don't print container info in pipe mode
-            }
-            anthropic::StreamEvent::ConnectionStatus(_) => {
-                // Don't print connection status in pipe mode
-            }
-        }
-        use std::io::Write;
-        io::stdout().flush()?;
+        };
+        let (result, ()) = tokio::join!(run, echo);
+        results.push(result);
+    }
+    results
+}
+
+// Ask on the controlling terminal whether a call may run; no terminal means no
+fn confirm_on_tty(approval: &tools::Approval) -> bool {
+    let question = approval.question();
+    let tty = if cfg!(windows) { "CONIN$" } else { "/dev/tty" };
+    let Ok(tty) = fs::File::open(tty) else {
+        eprintln!("({} No terminal to confirm on, declining)", question);
+        return false;
+    };
+    if let tools::Approval::Write { diff, .. } = approval {
+        eprint!("\n{}", diff);
     }
-    println!(); // Add newline at end
+    eprint!("\n{} [y/N] ", question);
+    let mut answer = String::new();
+    let _ = io::BufRead::read_line(&mut io::BufReader::new(tty), &mut answer);
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
 
+// Text of the answer goes to stdout (unless it carries --json events) and to the
+// --output-file copy
+fn pipe_print(json: bool, output: &mut Option<OutputFile>, text: &str) -> Result<()> {
+    if !json {
+        print!("{}", text);
+    }
+    if let Some(output) = output {
+        output.write(text)?;
+    }
     Ok(())
 }
 
-async fn run_tui_mode(
+// Side notes (reconnects, stalls, truncation) go to stderr, or inline as status
+// events with --json
+fn pipe_notice(json: bool, message: &str) -> Result<()> {
+    if json {
+        pipe_json::emit(&PipeEvent::Status { message })
+    } else {
+        eprintln!("\n({})", message);
+        Ok(())
+    }
+}
+
+async fn run_pipe_mode(
     client: anthropic::AnthropicClient,
-    mut output_dir: Option<String>,
+    config: config::Config,
+    options: PipeOptions,
 ) -> Result<()> {
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    // Initially enable mouse capture
-    execute!(terminal.backend_mut(), EnableMouseCapture)?;
+    let PipeOptions {
+        message: prepend_message,
+        images: image_paths,
+        output_dir,
+        dry_run,
+        export: export_path,
+        json,
+        output_file,
+        append,
+        strict,
+        schema,
+        stdin_file,
+        continued,
+        input_format,
+        read_stdin,
+        template,
+        vars,
+        toolbox,
+        context: context_blocks,
+    } = options;
+
+    // --image files go first, then context such as the --with-tree snapshot; the
+    // question then comes from -m and stdin as usual
+    let mut content = Vec::new();
+    for path in &image_paths {
+        let (media_type, bytes) = anthropic::read_image(path)?;
+        content.push(anthropic::InputContent::image(media_type, &bytes));
+    }
+    for (label, text) in &context_blocks {
+        content.push(anthropic::InputContent::Text {
+            text: project::api_text(label, text),
+        });
+    }
 
-    let mut app = App {
-        tool_mode: client.tool_mode(),
-        ..Default::default()
-    };
+    let (mut messages, transcript_system) = match input_format {
+        InputFormat::Messages => {
+            if !read_stdin {
+                anyhow::bail!("--input-format messages reads the conversation from stdin");
+            }
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            let (mut messages, system) = anthropic::parse_transcript(&input)?;
+            // --image and -m add to the transcript's final user message
+            if let Some(text) = prepend_message {
+                content.push(anthropic::InputContent::Text { text });
+            }
+            if !content.is_empty() {
+                let Some(last) = messages.last_mut().filter(|m| m.role == "user") else {
+                    anyhow::bail!(
+                        "--message and --image need the transcript to end with a user message"
+                    );
+                };
+                last.content.extend(content);
+            }
+            (messages, system)
+        }
+        InputFormat::Text => {
+            // Read input from stdin as raw bytes so binary data isn't rejected up front. With
+            // -m or --image and nothing piped in, don't wait on the terminal for input.
+            let has_prompt =
+                prepend_message.is_some() || !image_paths.is_empty() || template.is_some();
+            let mut input = Vec::new();
+            if read_stdin && (!has_prompt || !io::stdin().is_terminal()) {
+                io::stdin().read_to_end(&mut input)?;
+            }
 
-    // If code execution is enabled but no output dir specified, default to "output"
-    if matches!(app.tool_mode, ToolMode::CodeExecution | ToolMode::Both) && output_dir.is_none() {
-        output_dir = Some("output".to_string());
-    }
+            // With --template, stdin only fills a variable and the rendered template is the input
+            if let Some(template) = &template {
+                let stdin = String::from_utf8(input)
+                    .map_err(|_| anyhow::anyhow!("--template needs UTF-8 text on stdin"))?;
+                input = template.fill(&vars, Some(stdin))?.into_bytes();
+            }
 
-    let res = run_app(&mut terminal, &mut app, &client, output_dir).await;
+            // Images are sent as a vision attachment, with the optional message as the question
+            if let Some(media_type) = anthropic::detect_image_media_type(&input) {
+                log_debug!(
+                    "Detected {} on stdin ({} bytes), sending as image",
+                    media_type,
+                    input.len()
+                );
+                let question =
+                    prepend_message.unwrap_or_else(|| "Describe this image.".to_string());
+                content.push(anthropic::InputContent::image(media_type, &input));
+                content.push(anthropic::InputContent::Text { text: question });
+            } else if anthropic::is_pdf(&input) {
+                // PDFs go in as a document block, which Claude reads text and pages of
+                if input.len() > anthropic::MAX_DOCUMENT_BYTES {
+                    anyhow::bail!(
+                        "The PDF on stdin is {} MB; documents must be under {} MB",
+                        input.len().div_ceil(1024 * 1024),
+                        anthropic::MAX_DOCUMENT_BYTES / (1024 * 1024)
+                    );
+                }
+                log_debug!("Detected a PDF on stdin ({} bytes)", input.len());
+                let question =
+                    prepend_message.unwrap_or_else(|| "Summarize this document.".to_string());
+                content.push(anthropic::InputContent::document(
+                    anthropic::PDF_MEDIA_TYPE,
+                    &input,
+                ));
+                content.push(anthropic::InputContent::Text { text: question });
+            } else if let Some(name) = stdin_file
+                .as_deref()
+                .filter(|_| matches!(client.tool_mode(), ToolMode::CodeExecution | ToolMode::Both))
+            {
+                // With code execution, a named file is uploaded for code to work on in the
+                // container, whatever it contains
+                let file = client.upload_bytes(name, input).await?;
+                pipe_notice(json, &format!("Uploaded {} as {}", name, file.id))?;
+                let question = prepend_message
+                    .unwrap_or_else(|| format!("Take a look at the attached file {}.", name));
+                content.push(anthropic::InputContent::ContainerUpload { file_id: file.id });
+                content.push(anthropic::InputContent::Text { text: question });
+            } else if let Some(name) = &stdin_file {
+                // A named text file goes ahead of the question, like an @mention
+                let input = String::from_utf8(input).map_err(|_| {
+                    anyhow::anyhow!(
+                        "{} is not text, an image or a PDF; add --code-execution to upload it for code to work on",
+                        name
+                    )
+                })?;
+                let question =
+                    prepend_message.unwrap_or_else(|| "Summarize this file.".to_string());
+                content.push(anthropic::InputContent::Text {
+                    text: mentions::api_text(name, &input),
+                });
+                content.push(anthropic::InputContent::Text { text: question });
+            } else {
+                let input = String::from_utf8(input).map_err(|_| {
+                    anyhow::anyhow!(
+                        "stdin is not UTF-8 text, a supported image (PNG, JPEG, GIF, WebP) or a PDF; name other files with --stdin-file and add --code-execution to upload them"
+                    )
+                })?;
+
+                // Combine optional message with stdin input
+                let full_message = match prepend_message {
+                    Some(msg) if input.trim().is_empty() => msg,
+                    Some(msg) => format!("{} {}", msg, input),
+                    None => input,
+                };
+                let full_message = if full_message.trim().is_empty() && !content.is_empty() {
+                    "Describe this image.".to_string()
+                } else {
+                    full_message
+                };
+                content.push(anthropic::InputContent::Text { text: full_message });
+            }
+            (
+                vec![anthropic::Message {
+                    role: "user".to_string(),
+                    content,
+                }],
+                None,
+            )
+        }
+    };
 
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    // What --export writes: the conversation as given (before context hooks) and the answer
+    let mut transcript: Vec<(String, Vec<ui::MessageContent>)> = messages
+        .iter()
+        .map(|message| {
+            let contents = message
+                .content
+                .iter()
+                .map(|block| match block {
+                    anthropic::InputContent::Text { text } => {
+                        ui::MessageContent::Text(text.clone())
+                    }
+                    anthropic::InputContent::Image { source } => ui::MessageContent::Image {
+                        name: "image".to_string(),
+                        media_type: source.media_type.clone(),
+                        data: source.data.clone(),
+                    },
+                    anthropic::InputContent::Document { source } => ui::MessageContent::Document {
+                        name: stdin_file.clone().unwrap_or_else(|| "document".to_string()),
+                        media_type: source.media_type.clone(),
+                        data: source.data.clone(),
+                    },
+                    anthropic::InputContent::ContainerUpload { file_id } => {
+                        ui::MessageContent::Upload {
+                            name: stdin_file.clone().unwrap_or_else(|| file_id.clone()),
+                            file_id: file_id.clone(),
+                        }
+                    }
+                    anthropic::InputContent::ToolUse { id, name, input } => {
+                        ui::MessageContent::ToolUse {
+                            id: id.clone(),
+                            name: name.clone(),
+                            input: input.to_string(),
+                        }
+                    }
+                    anthropic::InputContent::ToolResult {
+                        tool_use_id,
+                        content,
+                        is_error,
+                    } => ui::MessageContent::ToolResult {
+                        tool_use_id: tool_use_id.clone(),
+                        name: String::new(),
+                        content: content.clone(),
+                        is_error: *is_error,
+                    },
+                })
+                .collect();
+            (message.role.clone(), contents)
+        })
+        .collect();
+    let mut answer_contents: Vec<ui::MessageContent> = Vec::new();
+
+    // --continue puts the saved conversation ahead of the new prompt, and the exchange
+    // is saved back into the same session
+    let (session_id, created_at) = match continued {
+        Some(session) => {
+            let mut history = session::api_messages(&session.messages);
+            let mut saved = session.messages;
+            // Roles have to alternate, so a prompt that never got an answer takes the new one
+            if let Some(last) = history.last_mut().filter(|m| m.role == "user")
+                && messages.first().is_some_and(|m| m.role == "user")
+            {
+                last.content.extend(messages.remove(0).content);
+            }
+            if let Some((_, contents)) = saved.last_mut().filter(|(role, _)| role == "user")
+                && transcript.first().is_some_and(|(role, _)| role == "user")
+            {
+                contents.extend(transcript.remove(0).1);
+            }
+            history.append(&mut messages);
+            messages = history;
+            saved.append(&mut transcript);
+            transcript = saved;
+            (session.id, session.created_at)
+        }
+        None => (session::new_session_id(), chrono::Local::now().to_rfc3339()),
+    };
 
-    if let Err(err) = res {
-        eprintln!("Error: {:?}", err);
+    // A transcript's own system prompt wins over the configured one
+    let template = transcript_system
+        .as_deref()
+        .or(config.system_prompt.as_deref())
+        .unwrap_or(prompt::DEFAULT_SYSTEM_PROMPT);
+    let mut system_prompt = prompt::render(template);
+    hooks::apply(&config.context_hooks, &mut system_prompt, &mut messages).await;
+
+    if dry_run {
+        let request = client.dry_run(messages, Some(system_prompt), Default::default());
+        println!("{}", serde_json::to_string_pretty(&request)?);
+        return Ok(());
     }
 
-    Ok(())
-}
-
-async fn run_app(
-    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
-    app: &mut App,
-    client: &anthropic::AnthropicClient,
-    mut output_dir: Option<String>,
-) -> Result<()> {
-    // Remove the welcome message to keep the conversation clean
+    // Nobody is there to confirm going over a budget, so pipe mode stops instead
+    let mut budget_warned = false;
+    match usage::check_session_request(
+        &config,
+        client.model(),
+        &session_id,
+        &system_prompt,
+        &messages,
+    ) {
+        usage::BudgetCheck::Within => {}
+        usage::BudgetCheck::Warning(warning) => {
+            eprintln!("Warning: {}", warning);
+            budget_warned = true;
+        }
+        usage::BudgetCheck::Exceeded(reason) => {
+            return Err(Failure::Budget(format!(
+                "{} - request not sent; raise the limit with `agnt config set`",
+                reason
+            ))
+            .into());
+        }
+    }
 
-    let mut stream_receiver: Option<mpsc::Receiver<anthropic::StreamEvent>> = None;
-    let mut stream_cancellation: Option<CancellationToken> = None;
-    let (metadata_tx, mut metadata_rx) = mpsc::channel::<(String, String)>(100);
+    let mut output = output_file
+        .map(|path| OutputFile::create(&path, append))
+        .transpose()?;
+
+    if let Some(schema) = schema {
+        let value = schema::run(
+            &client,
+            &system_prompt,
+            messages,
+            &schema,
+            &config,
+            &session_id,
+        )
+        .await?;
+        pipe_print(false, &mut output, &format!("{:#}\n", value))?;
+        if let Some(output) = output {
+            output.finish()?;
+        }
+        return Ok(());
+    }
 
+    // Text of the answer so far, sent back as a prefill if it gets cut off by max_tokens
+    // or the connection drops. A transcript ending in an assistant turn starts it off.
+    let mut answer = String::new();
+    if let Some(prefill) = messages.last().filter(|m| m.role == "assistant") {
+        for block in &prefill.content {
+            if let anthropic::InputContent::Text { text } = block {
+                answer.push_str(text);
+            }
+        }
+    }
+    // The exchange is kept in the history store like a TUI session, so it can be resumed
+    let (downloads, mut download_rx) =
+        DownloadManager::new(client.clone(), config.overwrite_policy);
+    let mut continues = 0;
+    let mut reconnects = 0;
+    let mut tool_rounds = 0;
+    let mut truncated = false;
+    let mut follow_up = false;
+    let mut over_budget = None;
     loop {
-        // Update loading animation if waiting
-        if app.is_waiting {
-            app.update_loading_animation();
+        // Continuations, reconnects and tool rounds are requests of their own
+        if follow_up {
+            match usage::check_session_request(
+                &config,
+                client.model(),
+                &session_id,
+                &system_prompt,
+                &messages,
+            ) {
+                usage::BudgetCheck::Within => {}
+                usage::BudgetCheck::Warning(_) if budget_warned => {}
+                usage::BudgetCheck::Warning(warning) => {
+                    eprintln!("Warning: {}", warning);
+                    budget_warned = true;
+                }
+                usage::BudgetCheck::Exceeded(reason) => {
+                    over_budget = Some(reason);
+                    break;
+                }
+            }
         }
+        follow_up = true;
+        let (mut receiver, _cancellation) = client
+            .send_message_stream(
+                messages.clone(),
+                Some(system_prompt.clone()),
+                Default::default(),
+            )
+            .await?;
+        let mut stop_reason = None;
+        let mut dropped = None;
+        let mut api_error = None;
+
+        // Stream response to stdout
+        while let Some(event) = receiver.recv().await {
+            match event {
+                anthropic::StreamEvent::Text(text) => {
+                    if json {
+                        pipe_json::emit(&PipeEvent::Text { text: &text })?;
+                    }
+                    pipe_print(json, &mut output, &text)?;
+                    answer.push_str(&text);
+                    match answer_contents.last_mut() {
+                        Some(ui::MessageContent::Text(last)) => last.push_str(&text),
+                        _ => answer_contents.push(ui::MessageContent::Text(text)),
+                    }
+                }
+                anthropic::StreamEvent::CodeInput(code) => {
+                    if json {
+                        pipe_json::emit(&PipeEvent::CodeInput { code: &code })?;
+                    }
+                    pipe_print(json, &mut output, &format!("\n```python\n{}\n```\n", code))?;
+                    answer_contents.push(ui::MessageContent::Code { input: code });
+                }
+                anthropic::StreamEvent::CodeOutput {
+                    stdout,
+                    stderr,
+                    return_code,
+                    files,
+                } => {
+                    if json {
+                        pipe_json::emit(&PipeEvent::CodeOutput {
+                            stdout: &stdout,
+                            stderr: &stderr,
+                            return_code,
+                        })?;
+                    }
+                    if !stdout.is_empty() {
+                        pipe_print(json, &mut output, &format!("\nOutput:\n{}\n", stdout))?;
+                    }
+                    if !json {
+                        if !stderr.is_empty() {
+                            eprintln!("\nError:\n{}", stderr);
+                        }
+                        if return_code != 0 {
+                            eprintln!("(Exit code: {})", return_code);
+                        }
+                    }
+                    if !files.is_empty() {
+                        if !json {
+                            eprintln!("\nCreated files:");
+                        }
+                        // If code execution is enabled, always save files (default to ./output)
+                        let save_dir = output_dir.as_deref().unwrap_or("output");
 
-        terminal.draw(|f| ui::ui(f, app))?;
-
-        // Handle file metadata updates
-        if let Ok((file_id, filename)) = metadata_rx.try_recv() {
-            app.update_file_metadata(file_id, filename);
-        }
+                        for (file_id, filename) in &files {
+                            if json {
+                                pipe_json::emit(&PipeEvent::File { file_id, filename })?;
+                            } else {
+                                eprintln!("  - {} (ID: {})", filename, file_id);
+                            }
 
-        // Handle streaming chunks
-        if let Some(ref mut receiver) = stream_receiver {
-            match receiver.try_recv() {
-                Ok(event) => match event {
-                    anthropic::StreamEvent::Text(text) => {
-                        // Clear connection status once we start receiving content
-                        app.set_connection_status(None);
-                        app.append_streaming_text(&text);
-                    }
-                    anthropic::StreamEvent::CodeInput(code) => {
-                        app.add_streaming_code(code);
+                            // Save file locally if file ID is valid
+                            if file_id.starts_with("file_") {
+                                downloads.enqueue(
+                                    file_id.clone(),
+                                    save_dir.to_string(),
+                                    session_id.clone(),
+                                );
+                            } else {
+                                eprintln!(
+                                    "Note: Cannot download file '{}' - file ID not available in streaming mode",
+                                    filename
+                                );
+                            }
+                        }
                     }
-                    anthropic::StreamEvent::CodeOutput {
+                    answer_contents.push(ui::MessageContent::CodeOutput {
                         stdout,
                         stderr,
                         return_code,
                         files,
-                    } => {
-                        // Save files locally whenever files are created
-                        if !files.is_empty() {
-                            // Always use default output directory if none specified
-                            let dir = output_dir.as_deref().unwrap_or("output");
-
-                            for (file_id, _filename) in &files {
-                                // Only download files with valid file IDs
-                                if file_id.starts_with("file_") {
-                                    // Clone values for the async task
-                                    let client_clone = client.clone();
-                                    let dir_clone = dir.to_string();
-                                    let file_id_clone = file_id.clone();
-                                    let metadata_tx_clone = metadata_tx.clone();
-
-                                    // Spawn download task to avoid blocking the UI
-                                    tokio::spawn(async move {
-                                        match download_and_save_file(
-                                            &client_clone,
-                                            &dir_clone,
-                                            &file_id_clone,
-                                            metadata_tx_clone,
-                                        )
-                                        .await
-                                        {
-                                            Err(e) => {
-                                                log_debug!(
-                                                    "Error saving file {}: {}",
-                                                    file_id_clone,
-                                                    e
-                                                );
-                                            }
-                                            Ok(()) => {
-                                                // Success is already logged in download_and_save_file
-                                            }
-                                        }
-                                    });
-                                }
-                            }
-                        }
-                        app.add_streaming_output(stdout, stderr, return_code, files);
+                        spilled: None,
+                        downloads: Vec::new(),
+                    });
+                }
+                anthropic::StreamEvent::CodeError(error) => {
+                    if json {
+                        pipe_json::emit(&PipeEvent::CodeError { error_code: &error })?;
+                    } else {
+                        eprintln!("\nCode execution error: {}", error);
+                    }
+                    answer_contents.push(ui::MessageContent::CodeError(error));
+                }
+                anthropic::StreamEvent::ToolUse { id, name, input } => {
+                    if json {
+                        pipe_json::emit(&PipeEvent::ToolUse {
+                            id: &id,
+                            name: &name,
+                            input: serde_json::from_str(&input).unwrap_or_default(),
+                        })?;
+                    } else {
+                        eprintln!("\n({} {})", name, input);
                     }
-                    anthropic::StreamEvent::CodeError(error) => {
-                        app.add_streaming_error(error);
+                    answer_contents.push(ui::MessageContent::ToolUse { id, name, input });
+                }
+                anthropic::StreamEvent::ContainerInfo { .. } => {
+                    // Don't print container info in pipe mode
+                }
+                anthropic::StreamEvent::ConnectionStatus(_) => {
+                    // Don't print connection status in pipe mode
+                }
+                anthropic::StreamEvent::Disconnected(reason) => {
+                    dropped = Some(reason);
+                }
+                anthropic::StreamEvent::Error(error) => {
+                    api_error = Some(error);
+                }
+                anthropic::StreamEvent::Stalled(idle) => {
+                    pipe_notice(
+                        json,
+                        &format!("No data for {}s, still waiting", idle.as_secs()),
+                    )?;
+                }
+                anthropic::StreamEvent::StallCleared => {}
+                anthropic::StreamEvent::Metrics(metrics) => {
+                    // Metrics are already logged by the stream task
+                    if let Err(e) = usage::record(&usage::UsageRecord::new(&session_id, &metrics)) {
+                        log_debug!("Failed to record usage: {}", e);
                     }
-                    anthropic::StreamEvent::ContainerInfo { id, expires_at } => {
-                        app.set_container_info(id, expires_at);
+                    if json {
+                        pipe_json::emit(&PipeEvent::usage(&metrics))?;
                     }
-                    anthropic::StreamEvent::ConnectionStatus(status) => {
-                        app.set_connection_status(Some(status.clone()));
+                    stop_reason = metrics.stop_reason;
+                    if let Some(reason) = &stop_reason {
+                        answer_contents.retain(|c| !matches!(c, ui::MessageContent::StopReason(_)));
+                        answer_contents.push(ui::MessageContent::StopReason(reason.clone()));
                     }
-                },
-                Err(mpsc::error::TryRecvError::Disconnected) => {
-                    // Stream finished
-                    app.finish_streaming();
-                    app.is_waiting = false;
-                    stream_receiver = None;
-                    stream_cancellation = None;
-                }
-                Err(mpsc::error::TryRecvError::Empty) => {
-                    // No new chunks yet
                 }
             }
+            use std::io::Write;
+            io::stdout().flush()?;
         }
 
-        // Use shorter poll timeout when animating
-        let poll_timeout = if app.is_waiting {
-            Duration::from_millis(50)
-        } else {
-            Duration::from_millis(100)
-        };
-
-        if event::poll(poll_timeout)? {
-            match event::read()? {
-                Event::Key(key) => {
-                    if key.kind != KeyEventKind::Press {
-                        continue;
-                    }
+        if let Some(error) = api_error {
+            if json {
+                pipe_json::emit(&PipeEvent::error(&error))?;
+            } else if !answer.is_empty() {
+                // End the partial answer's line
+                println!();
+            }
+            return Err(error.into());
+        }
 
-                    // If help modal is shown, any key press closes it
-                    if app.show_help {
-                        app.toggle_help();
-                        continue;
+        if let Some(reason) = dropped {
+            if reconnects >= ui::MAX_RECONNECTS {
+                return Err(anthropic::ApiError {
+                    status: None,
+                    kind: "connection_error".to_string(),
+                    message: format!(
+                        "Connection lost ({}), gave up after {} reconnects",
+                        reason,
+                        ui::MAX_RECONNECTS
+                    ),
+                }
+                .into());
+            }
+            reconnects += 1;
+            pipe_notice(
+                json,
+                &format!(
+                    "Connection lost: {}, reconnecting {}/{}",
+                    reason,
+                    reconnects,
+                    ui::MAX_RECONNECTS
+                ),
+            )?;
+        } else if stop_reason.as_deref() == Some("max_tokens")
+            && continues < config.max_auto_continues
+            && !answer.trim().is_empty()
+        {
+            continues += 1;
+            log_debug!(
+                "Answer hit max_tokens, continuing ({}/{})",
+                continues,
+                config.max_auto_continues
+            );
+        } else if stop_reason.as_deref() == Some("tool_use")
+            && !tools::calls(&answer_contents).is_empty()
+        {
+            // Run the calls to local tools and send their results back
+            let calls = tools::calls(&answer_contents);
+            let exhausted = tool_rounds >= config.max_tool_rounds;
+            let results = if exhausted {
+                let reason = format!(
+                    "Stopped after {} rounds of tool calls (max_tool_rounds)",
+                    tool_rounds
+                );
+                pipe_notice(json, &reason)?;
+                tools::refuse(calls, &reason)
+            } else {
+                run_pipe_tools(&toolbox, calls, json).await
+            };
+            for result in &results {
+                if let ui::MessageContent::ToolResult {
+                    tool_use_id,
+                    name,
+                    content,
+                    is_error,
+                } = result
+                {
+                    if json {
+                        pipe_json::emit(&PipeEvent::ToolResult {
+                            tool_use_id,
+                            name,
+                            content,
+                            is_error: *is_error,
+                        })?;
+                    } else if *is_error {
+                        eprintln!("({} failed: {})", name, content);
                     }
+                }
+            }
 
-                    match key.code {
-                        KeyCode::Char('c')
-                            if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
-                        {
-                            log_debug!("User requested termination with Ctrl+C");
-                            return Ok(());
-                        }
-                        KeyCode::Char('h')
-                            if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
+            // The answer so far (with its calls) and the results become part of the
+            // conversation, and the next request picks up from there
+            if messages.last().is_some_and(|m| m.role == "assistant") {
+                messages.pop();
+            }
+            let mut content = Vec::new();
+            if !answer.trim().is_empty() {
+                content.push(anthropic::InputContent::Text {
+                    text: answer.trim_end().to_string(),
+                });
+            }
+            content.extend(answer_contents.iter().filter_map(tools::api_block));
+            messages.push(anthropic::Message {
+                role: "assistant".to_string(),
+                content,
+            });
+            messages.push(anthropic::Message {
+                role: "user".to_string(),
+                content: results.iter().filter_map(tools::api_block).collect(),
+            });
+            match transcript.last_mut() {
+                Some((role, contents)) if role == "assistant" => {
+                    contents.append(&mut answer_contents)
+                }
+                _ => transcript.push((
+                    "assistant".to_string(),
+                    std::mem::take(&mut answer_contents),
+                )),
+            }
+            transcript.push(("user".to_string(), results));
+            if exhausted {
+                break;
+            }
+            tool_rounds += 1;
+            if !answer.is_empty() {
+                pipe_print(json, &mut output, "\n\n")?;
+            }
+            answer.clear();
+            continues = 0;
+            reconnects = 0;
+            continue;
+        } else {
+            match stop_reason.as_deref() {
+                Some("refusal") => pipe_notice(json, "Claude declined to answer")?,
+                Some("max_tokens") => {
+                    truncated = true;
+                    pipe_notice(
+                        json,
+                        "Answer cut off at max_tokens; raise the limit with --max-tokens",
+                    )?
+                }
+                _ => {}
+            }
+            break;
+        }
+
+        // Resend with the answer so far as a prefill, so it picks up where it stopped
+        if messages.last().is_some_and(|m| m.role == "assistant") {
+            messages.pop();
+        }
+        if !answer.trim().is_empty() {
+            messages.push(anthropic::Message::text(
+                "assistant",
+                answer.trim_end().to_string(),
+            ));
+        }
+    }
+    pipe_print(json, &mut output, "\n")?; // Add newline at end
+    if let Some(output) = output {
+        output.finish()?;
+    }
+
+    // Wait for created files to be saved before exiting; the channel closes once the
+    // last download finishes
+    drop(downloads);
+    while let Some(update) = download_rx.recv().await {
+        let DownloadUpdate::State { file_id, state } = update else {
+            continue;
+        };
+        match &state {
+            DownloadState::Saved(path) | DownloadState::Skipped(path) if json => {
+                pipe_json::emit(&PipeEvent::Download {
+                    file_id: &file_id,
+                    path: Some(path.display().to_string()),
+                    skipped: matches!(state, DownloadState::Skipped(_)),
+                    error: None,
+                })?
+            }
+            DownloadState::Failed(error) if json => pipe_json::emit(&PipeEvent::Download {
+                file_id: &file_id,
+                path: None,
+                skipped: false,
+                error: Some(error),
+            })?,
+            DownloadState::Saved(_) | DownloadState::Skipped(_) | DownloadState::Failed(_) => {
+                eprintln!("{}: {}", file_id, state.describe())
+            }
+            _ => {}
+        }
+    }
+
+    // Nothing follows the tool results when max_tool_rounds stopped the loop
+    let stopped_at_results = transcript
+        .last()
+        .is_some_and(|(_, contents)| tools::is_tool_results(contents));
+    match transcript.last_mut() {
+        Some((role, contents)) if role == "assistant" => contents.extend(answer_contents),
+        _ if stopped_at_results && answer_contents.is_empty() => {}
+        _ => transcript.push(("assistant".to_string(), answer_contents)),
+    }
+    let session = session::Session {
+        id: session_id,
+        created_at,
+        updated_at: chrono::Local::now().to_rfc3339(),
+        messages: transcript.clone(),
+        alternatives: None,
+    };
+    if let Err(e) = HistoryStore::open().and_then(|mut store| store.save_session(&session)) {
+        log_debug!("Failed to save pipe session: {}", e);
+    }
+
+    if let Some(path) = export_path {
+        let format = export::Format::from_path(&path).unwrap_or(export::Format::Markdown);
+        fs::write(
+            &path,
+            export::render(format, "agnt transcript", &transcript)?,
+        )?;
+        log_debug!("Exported transcript to {}", path.display());
+    }
+
+    if let Some(reason) = over_budget {
+        return Err(Failure::Budget(format!(
+            "{} - answer stopped before the next request; raise the limit with `agnt config set`",
+            reason
+        ))
+        .into());
+    }
+    if strict && truncated {
+        return Err(Failure::Truncated.into());
+    }
+    Ok(())
+}
+
+// What the TUI's first message starts with: the --with-tree snapshot and --image files
+struct FirstAttachments {
+    images: Vec<PathBuf>,
+    tree: Option<PathBuf>,
+}
+
+async fn run_tui_mode(
+    client: anthropic::AnthropicClient,
+    mut output_dir: Option<String>,
+    config: config::Config,
+    toolbox: tools::Toolbox,
+    popup_mode: bool,
+    resumed: Option<session::Session>,
+    attachments: FirstAttachments,
+) -> Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    // Initially enable mouse capture; focus changes decide whether a finished answer
+    // rings the bell or notifies
+    execute!(
+        terminal.backend_mut(),
+        EnableMouseCapture,
+        EnableFocusChange
+    )?;
+
+    let mut app = App {
+        tool_mode: client.tool_mode(),
+        model: client.model().to_string(),
+        sampling: client.sampling().clone(),
+        // Last run's model list, until this run's arrives
+        available_models: match client.provider() {
+            anthropic::Provider::Anthropic => models::cached(),
+            _ => Vec::new(),
+        },
+        history: history::load(),
+        system_prompt: config
+            .system_prompt
+            .clone()
+            .unwrap_or_else(|| prompt::DEFAULT_SYSTEM_PROMPT.to_string()),
+        config,
+        toolbox,
+        popup_mode,
+        ..Default::default()
+    };
+    app.add_custom_commands();
+    if let Some(session) = resumed {
+        app.restore_session(session);
+    }
+    if let Some(dir) = attachments.tree {
+        app.attach_project(&dir.to_string_lossy());
+    }
+    for path in attachments.images {
+        app.attach_image(&path.to_string_lossy());
+    }
+
+    // Inside tmux, label the pane with the session and put the old title back afterwards
+    let previous_pane_title = tmux::pane_title();
+    tmux::set_pane_title(&app.pane_title());
+
+    // If code execution is enabled but no output dir specified, default to "output"
+    if matches!(app.tool_mode, ToolMode::CodeExecution | ToolMode::Both) && output_dir.is_none() {
+        output_dir = Some("output".to_string());
+    }
+
+    let res = run_app(&mut terminal, app, &client, output_dir).await;
+
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableFocusChange
+    )?;
+    terminal.show_cursor()?;
+
+    if let Some(title) = previous_pane_title {
+        tmux::set_pane_title(&title);
+    }
+
+    res
+}
+
+// One conversation in the TUI, with the stream and tool call running for it
+struct Tab {
+    id: usize, // Stable across closing other tabs; tags events from background tasks
+    app: App,
+    stream_receiver: Option<mpsc::Receiver<anthropic::StreamEvent>>,
+    stream_cancellation: Option<CancellationToken>,
+    tool_cancellation: Option<CancellationToken>,
+}
+
+impl Tab {
+    fn new(id: usize, app: App) -> Self {
+        Self {
+            id,
+            app,
+            stream_receiver: None,
+            stream_cancellation: None,
+            tool_cancellation: None,
+        }
+    }
+}
+
+enum TabAction {
+    New,
+    Cycle(isize),
+    Select(usize),
+}
+
+fn draw(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    tabs: &mut [Tab],
+    active: usize,
+) -> Result<()> {
+    let bar = ui::TabBar::new(tabs.iter().map(|tab| &tab.app), active);
+    terminal.draw(|f| ui::ui(f, &mut tabs[active].app, &bar))?;
+    Ok(())
+}
+
+async fn run_app(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    app: App,
+    client: &anthropic::AnthropicClient,
+    mut output_dir: Option<String>,
+) -> Result<()> {
+    let (downloads, mut download_rx) =
+        DownloadManager::new(client.clone(), app.config.overwrite_policy);
+    let mut tabs = vec![Tab::new(0, app)];
+    let mut active = 0;
+    let mut next_tab_id = 1;
+    // None until the terminal reports a focus change, which not all terminals do; until
+    // then the user counts as away so the bell and notifications still arrive
+    let mut focused: Option<bool> = None;
+    // Results of work done off the event loop come back tagged with their tab's id
+    let (compact_tx, mut compact_rx) = mpsc::channel(1);
+    let (count_tx, mut count_rx) = mpsc::channel(1);
+    let (tools_tx, mut tools_rx) = mpsc::channel(64);
+
+    // The /model picker offers whatever the Models API lists, fetched in the background
+    let (models_tx, mut models_rx) = mpsc::channel(1);
+    let models_client = client.clone();
+    tokio::spawn(async move {
+        let _ = models_tx.send(models_client.list_models().await).await;
+    });
+
+    let frame_interval = if tabs[active].app.config.reduced_motion {
+        REDUCED_MOTION_FRAME_INTERVAL
+    } else {
+        FRAME_INTERVAL
+    };
+    let mut terminal_events = EventStream::new();
+    let mut redraw_ticker = tokio::time::interval(frame_interval);
+    redraw_ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut needs_redraw = true;
+    let mut last_draw = Instant::now() - frame_interval;
+
+    loop {
+        // Update loading animation if waiting
+        let app = &mut tabs[active].app;
+        if app.is_waiting && app.update_loading_animation() {
+            needs_redraw = true;
+        }
+
+        // /close from the current tab
+        if std::mem::take(&mut tabs[active].app.queued_close) {
+            if tabs.len() == 1 {
+                tabs[active]
+                    .app
+                    .add_info("This is the only tab; Ctrl+C quits agnt".to_string());
+            } else {
+                let tab = tabs.remove(active);
+                for token in [tab.stream_cancellation, tab.tool_cancellation]
+                    .into_iter()
+                    .flatten()
+                {
+                    token.cancel();
+                }
+                tab.app.save_session();
+                active = active.min(tabs.len() - 1);
+                tmux::set_pane_title(&tabs[active].app.pane_title());
+            }
+            needs_redraw = true;
+        }
+
+        // Background tabs keep streaming and running tools; only the current one is drawn
+        for index in 0..tabs.len() {
+            // Calls to local tools run off the event loop, one at a time per tab; a
+            // confirmation waits here until it is answered in that tab
+            let tab = &mut tabs[index];
+            match tab.app.next_tool_step() {
+                Some(ui::ToolStep::Run(call)) => {
+                    let token = CancellationToken::new();
+                    tab.tool_cancellation = Some(token.clone());
+                    let toolbox = tab.app.toolbox.clone();
+                    let tools_tx = tools_tx.clone();
+                    let id = tab.id;
+                    tokio::spawn(async move {
+                        let (events_tx, mut events_rx) = mpsc::channel(64);
+                        let run = async move { toolbox.run(call, &token, &events_tx).await };
+                        let forward = async {
+                            while let Some(event) = events_rx.recv().await {
+                                let _ = tools_tx.send((id, event)).await;
+                            }
+                        };
+                        let (result, ()) = tokio::join!(run, forward);
+                        let _ = tools_tx.send((id, tools::ToolEvent::Done(result))).await;
+                    });
+                    needs_redraw = true;
+                }
+                Some(ui::ToolStep::Finished) => {
+                    finish_answer(&mut tab.app, focused != Some(true));
+                    needs_redraw = true;
+                }
+                None => {}
+            }
+
+            // Send whatever a keypress, slash command or confirmation queued. Draw first
+            // so the new message and spinner show while context hooks run.
+            if let Some(params) = tabs[index].app.take_queued_request() {
+                if index == active {
+                    draw(terminal, &mut tabs, active)?;
+                }
+                let tab = &mut tabs[index];
+                if let Some((receiver, cancellation)) =
+                    start_request(&mut tab.app, client, params, true).await
+                {
+                    tab.stream_receiver = Some(receiver);
+                    tab.stream_cancellation = Some(cancellation);
+                }
+                needs_redraw = true;
+            }
+
+            // `r` after a dropped connection continues the partial answer as a prefill
+            if std::mem::take(&mut tabs[index].app.queued_resume) {
+                if index == active {
+                    draw(terminal, &mut tabs, active)?;
+                }
+                let tab = &mut tabs[index];
+                let params = tab.app.last_request.clone();
+                if let Some((receiver, cancellation)) =
+                    start_request(&mut tab.app, client, params, false).await
+                {
+                    tab.stream_receiver = Some(receiver);
+                    tab.stream_cancellation = Some(cancellation);
+                }
+                needs_redraw = true;
+            }
+
+            // A custom slash command becomes a message once its prompt is expanded (which
+            // may run a shell command)
+            let app = &mut tabs[index].app;
+            if let Some((command, args)) = app.queued_custom_command.take()
+                && let Some(custom) = app.config.custom_commands.get(command).cloned()
+            {
+                app.set_connection_status(Some(format!("Running /{}...", custom.name)));
+                draw(terminal, &mut tabs, active)?;
+                let result = custom_commands::expand(&custom, &args).await;
+                let app = &mut tabs[index].app;
+                app.set_connection_status(None);
+                app.send_custom_command(result);
+                needs_redraw = true;
+            }
+
+            // Summaries for /compact and auto-compact are written off the event loop
+            let tab = &mut tabs[index];
+            if let Some((split, transcript)) = tab.app.take_queued_compaction() {
+                let client = client.clone().with_model(tab.app.model.clone());
+                let compact_tx = compact_tx.clone();
+                let id = tab.id;
+                tokio::spawn(async move {
+                    let result = compact::summarize(&client, transcript).await;
+                    let _ = compact_tx.send((id, split, result)).await;
+                });
+                needs_redraw = true;
+            }
+
+            // /tokens asks the API for an exact count of the next request
+            if let Some(prompt) = tab.app.queued_token_count.take() {
+                let mut messages = build_messages(&tab.app);
+                if !prompt.trim().is_empty() {
+                    messages.push(anthropic::Message::text("user", prompt));
+                }
+                let client = session_client(&tab.app, client);
+                let system_prompt = prompt::render(&tab.app.system_prompt);
+                let count_tx = count_tx.clone();
+                let id = tab.id;
+                tokio::spawn(async move {
+                    let result = client
+                        .count_tokens(messages, Some(system_prompt), Default::default())
+                        .await;
+                    let _ = count_tx.send((id, result)).await;
+                });
+            }
+        }
+
+        // Redraw only when something changed, and at most once per frame interval;
+        // the ticker below flushes changes that arrive in between
+        if needs_redraw && last_draw.elapsed() >= frame_interval {
+            draw(terminal, &mut tabs, active)?;
+            last_draw = Instant::now();
+            needs_redraw = false;
+        }
+
+        // Sleep until a terminal event, a stream event, a file metadata update, the redraw
+        // ticker (only while a change is waiting to be drawn) or the next animation frame
+        // (only while waiting for an answer); an idle app doesn't wake up at all
+        let app = &tabs[active].app;
+        let next_animation = tokio::time::Instant::from_std(app.next_animation_frame());
+        let animating = app.is_waiting;
+        let loop_event = tokio::select! {
+            maybe_event = terminal_events.next() => match maybe_event {
+                Some(Ok(event)) => LoopEvent::Terminal(event),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            (id, event) = recv_stream_event(&mut tabs) => LoopEvent::Stream(id, event),
+            Some(update) = download_rx.recv() => LoopEvent::Download(update),
+            Some((id, split, result)) = compact_rx.recv() => LoopEvent::Compacted(id, split, result),
+            Some((id, result)) = count_rx.recv() => LoopEvent::TokenCount(id, result),
+            Some(result) = models_rx.recv() => LoopEvent::Models(result),
+            Some((id, event)) = tools_rx.recv() => LoopEvent::Tool(id, event),
+            _ = redraw_ticker.tick(), if needs_redraw => LoopEvent::Tick,
+            _ = tokio::time::sleep_until(next_animation), if animating => LoopEvent::Tick,
+        };
+
+        // Events from background work go to their tab, if it is still open
+        let tab_id = match &loop_event {
+            LoopEvent::Stream(id, _)
+            | LoopEvent::Compacted(id, _, _)
+            | LoopEvent::TokenCount(id, _)
+            | LoopEvent::Tool(id, _) => Some(*id),
+            _ => None,
+        };
+        let Some(tab) = (match tab_id {
+            Some(id) => tabs.iter_mut().find(|tab| tab.id == id),
+            None => tabs.get_mut(active),
+        }) else {
+            continue;
+        };
+        let app = &mut tab.app;
+        let event = match loop_event {
+            LoopEvent::Terminal(event) => event,
+            LoopEvent::Stream(_, Some(event)) => {
+                handle_stream_event(app, event, output_dir.as_deref(), &downloads);
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::Stream(_, None) => {
+                // Stream finished
+                app.finish_streaming();
+                app.stalled = false;
+                tab.stream_receiver = None;
+                tab.stream_cancellation = None;
+                needs_redraw = true;
+
+                if tab.app.prepare_resume() {
+                    let params = tab.app.last_request.clone();
+                    if let Some((receiver, cancellation)) =
+                        start_request(&mut tab.app, client, params, false).await
+                    {
+                        tab.stream_receiver = Some(receiver);
+                        tab.stream_cancellation = Some(cancellation);
+                    }
+                    continue;
+                }
+
+                let app = &mut tab.app;
+                app.finish_alternative();
+
+                // Tool calls are run from the top of the loop and their results sent back
+                if !app.start_tool_round() {
+                    finish_answer(app, focused != Some(true));
+                }
+                continue;
+            }
+            LoopEvent::Tool(_, tools::ToolEvent::Output(text)) => {
+                app.append_tool_output(&text);
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::Tool(_, tools::ToolEvent::Done(result)) => {
+                tab.tool_cancellation = None;
+                tab.app.finish_tool_call(result);
+                needs_redraw = true;
+                continue;
+            }
+            // Downloads and the model list are shared by all tabs
+            LoopEvent::Download(DownloadUpdate::Named { file_id, filename }) => {
+                for tab in &mut tabs {
+                    tab.app
+                        .update_file_metadata(file_id.clone(), filename.clone());
+                }
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::Download(DownloadUpdate::State { file_id, state }) => {
+                if let DownloadState::Saved(path) = &state
+                    && app.config.notifications
+                {
+                    notify::send(
+                        "Download finished",
+                        &format!("Saved {} to {}", file_id, path.display()),
+                    );
+                }
+                for tab in &mut tabs {
+                    tab.app.update_download(&file_id, state.clone());
+                }
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::Compacted(_, split, result) => {
+                app.apply_compaction(split, result);
+                if !app.is_waiting {
+                    app.save_session();
+                }
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::TokenCount(_, result) => {
+                app.show_token_count(result);
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::Models(result) => {
+                match result {
+                    Ok(models) => {
+                        for tab in &mut tabs {
+                            tab.app.set_available_models(models.clone());
+                        }
+                    }
+                    // The picker keeps offering the built-in aliases
+                    Err(e) => log_debug!("Could not list models: {}", e),
+                }
+                needs_redraw = true;
+                continue;
+            }
+            LoopEvent::Tick => continue,
+        };
+
+        needs_redraw = true;
+        let mut tab_action = None;
+        let Tab {
+            app,
+            stream_cancellation,
+            tool_cancellation,
+            ..
+        } = &mut tabs[active];
+        match event {
+            Event::Key(key) => {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+
+                // If help modal is shown, any key press closes it
+                if app.show_help {
+                    app.toggle_help();
+                    continue;
+                }
+
+                // A pending confirmation consumes the next key: y accepts, anything else cancels
+                if app.confirmation.is_some() {
+                    app.resolve_confirmation(matches!(key.code, KeyCode::Char('y' | 'Y')));
+                    continue;
+                }
+
+                // The /model picker takes arrow keys, Enter and Esc until it closes
+                if app.model_picker.is_some() {
+                    match key.code {
+                        KeyCode::Up => app.move_model_picker(-1),
+                        KeyCode::Down | KeyCode::Tab => app.move_model_picker(1),
+                        KeyCode::Enter => app.close_model_picker(true),
+                        KeyCode::Esc => app.close_model_picker(false),
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                // The /params modal edits one field at a time until Enter or Esc
+                if let Some(editor) = &mut app.params_editor {
+                    match key.code {
+                        KeyCode::Up | KeyCode::BackTab => editor.select(-1),
+                        KeyCode::Down | KeyCode::Tab => editor.select(1),
+                        KeyCode::Char(c) => editor.selected_value().push(c),
+                        KeyCode::Backspace => {
+                            editor.selected_value().pop();
+                        }
+                        KeyCode::Enter => app.apply_params_editor(),
+                        KeyCode::Esc => app.params_editor = None,
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                // /sessions: typing filters, Enter loads the highlighted session
+                if let Some(browser) = &mut app.session_browser {
+                    match key.code {
+                        KeyCode::Up => browser.select(-1),
+                        KeyCode::Down | KeyCode::Tab => browser.select(1),
+                        KeyCode::PageUp => browser.select(-10),
+                        KeyCode::PageDown => browser.select(10),
+                        KeyCode::Char(c) => {
+                            browser.query.push(c);
+                            browser.refilter();
+                        }
+                        KeyCode::Backspace => {
+                            browser.query.pop();
+                            browser.refilter();
+                        }
+                        KeyCode::Enter => app.open_selected_session(),
+                        KeyCode::Esc => app.session_browser = None,
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                // The /prompt fill-in takes one value per template variable
+                if let Some(form) = &mut app.template_form {
+                    match key.code {
+                        KeyCode::Up | KeyCode::BackTab => form.select(-1),
+                        KeyCode::Down | KeyCode::Tab => form.select(1),
+                        KeyCode::Char(c) => form.selected_value().push(c),
+                        KeyCode::Backspace => {
+                            form.selected_value().pop();
+                        }
+                        KeyCode::Enter if form.is_last() => app.apply_template_form(),
+                        KeyCode::Enter => form.select(1),
+                        KeyCode::Esc => app.template_form = None,
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                // File preview popup: scroll, open externally with o, close with Esc
+                if let Some(preview) = &mut app.file_preview {
+                    let page = terminal.size()?.height as isize * 4 / 5 - 2;
+                    match key.code {
+                        KeyCode::Up | KeyCode::Char('k') => preview.scroll_by(-1),
+                        KeyCode::Down | KeyCode::Char('j') => preview.scroll_by(1),
+                        KeyCode::PageUp => preview.scroll_by(-page),
+                        KeyCode::PageDown | KeyCode::Char(' ') => preview.scroll_by(page),
+                        KeyCode::Home | KeyCode::Char('g') => preview.scroll_by(isize::MIN / 2),
+                        KeyCode::End | KeyCode::Char('G') => preview.scroll_by(isize::MAX / 2),
+                        KeyCode::Char('o') => {
+                            if let Some(path) = preview.path.clone() {
+                                app.file_preview = None;
+                                app.open_file(&path);
+                            }
+                        }
+                        KeyCode::Esc | KeyCode::Char('q') => app.file_preview = None,
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                // Copy focus: move between messages and code blocks and copy them with y;
+                // Enter previews or opens a downloaded file
+                if app.focus.is_some() {
+                    match key.code {
+                        KeyCode::Up | KeyCode::Char('k') => app.move_focus(-1),
+                        KeyCode::Down | KeyCode::Char('j') => app.move_focus(1),
+                        KeyCode::Home | KeyCode::Char('g') => app.move_focus(isize::MIN / 2),
+                        KeyCode::End | KeyCode::Char('G') => app.move_focus(isize::MAX / 2),
+                        KeyCode::Char('y') => app.copy_focused(),
+                        KeyCode::Char('b') => app.branch_focused(),
+                        KeyCode::Char('d') => app.delete_focused(),
+                        KeyCode::Char('o') => app.view_focused_output(),
+                        KeyCode::Char('s') => {
+                            app.save_focused_output(output_dir.as_deref().unwrap_or("output"))
+                        }
+                        KeyCode::Enter => app.activate_focused(),
+                        KeyCode::Esc | KeyCode::Char('q') => app.focus = None,
+                        KeyCode::Char('c')
+                            if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
                         {
-                            app.toggle_help();
+                            return Ok(());
                         }
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                // During a history search, typing refines the query and the search key
+                // (Ctrl+R) steps back
+                if app.history_search.is_some() {
+                    let ctrl = key.modifiers.contains(event::KeyModifiers::CONTROL);
+                    match key.code {
+                        _ if keymap::current().action(&key) == Some(Action::HistorySearch) => {
+                            app.history_search_next()
+                        }
+                        KeyCode::Char(c) if !ctrl => app.history_search_push(c),
+                        KeyCode::Backspace => app.history_search_pop(),
+                        KeyCode::Esc => app.finish_history_search(false),
+                        _ => app.finish_history_search(true),
+                    }
+                    continue;
+                }
+
+                // The message pane scrolls with the arrows, PageUp/PageDown and Home/End and
+                // picks a message with Enter; Tab, Shift+Tab and Esc go back to the input, and
+                // any other key goes back and is handled there
+                if app.pane == Pane::Messages
+                    && !key
+                        .modifiers
+                        .intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT)
+                {
+                    let page = app.page_lines.saturating_sub(1).max(1);
+                    match key.code {
+                        KeyCode::Up | KeyCode::Char('k') => app.scroll_up(1),
+                        KeyCode::Down | KeyCode::Char('j') => app.scroll_down(1),
+                        KeyCode::PageUp => app.scroll_up(page),
+                        KeyCode::PageDown | KeyCode::Char(' ') => app.scroll_down(page),
+                        KeyCode::Home | KeyCode::Char('g') => app.scroll_to_top(),
+                        KeyCode::End | KeyCode::Char('G') => app.scroll_to_bottom(),
+                        KeyCode::Enter => app.start_focus(),
+                        _ => app.pane = Pane::Input,
+                    }
+                    if app.pane == Pane::Messages
+                        || matches!(key.code, KeyCode::Tab | KeyCode::BackTab | KeyCode::Esc)
+                    {
+                        continue;
+                    }
+                }
+
+                // The slash menu matches on the whole command, so it is always edited at the end
+                if app.slash_command_state.is_some() {
+                    app.input_cursor = app.input.len();
+                }
+                let ctrl = key.modifiers.contains(event::KeyModifiers::CONTROL);
+                match keymap::current().action(&key) {
+                    Some(Action::Quit) => {
+                        log_debug!("User requested termination with the quit key");
+                        return Ok(());
+                    }
+                    Some(Action::Help) => app.toggle_help(),
+                    Some(Action::NextTab) => tab_action = Some(TabAction::Cycle(1)),
+                    Some(Action::PrevTab) => tab_action = Some(TabAction::Cycle(-1)),
+                    Some(Action::ScrollTop) => app.scroll_to_top(),
+                    Some(Action::ScrollBottom) => app.scroll_to_bottom(),
+                    Some(Action::ScrollUp) => app.scroll_up(10),
+                    Some(Action::ScrollDown) => app.scroll_down(10),
+                    Some(Action::SelectionMode) => {
+                        app.toggle_selection_mode();
+                        if app.selection_mode {
+                            // Disable mouse capture to allow text selection
+                            execute!(terminal.backend_mut(), DisableMouseCapture)?;
+                        } else {
+                            // Re-enable mouse capture for scrolling
+                            execute!(terminal.backend_mut(), EnableMouseCapture)?;
+                        }
+                    }
+                    Some(Action::Editor) => {
+                        // The event reader would steal the editor's keystrokes, so it is
+                        // dropped for the duration and replaced afterwards
+                        drop(std::mem::replace(&mut terminal_events, EventStream::new()));
+                        match compose_in_editor(terminal, &app.input, app.selection_mode) {
+                            Ok(Some(text)) => app.set_input(text),
+                            Ok(None) => {}
+                            Err(e) => app.add_local_error(format!("Editor failed: {}", e)),
+                        }
+                        needs_redraw = true;
+                    }
+                    Some(Action::ToggleCodeExec | Action::ToggleWebSearch)
+                        if !client.provider().supports_server_tools() =>
+                    {
+                        app.add_info(format!(
+                            "Code execution and web search aren't available with {}",
+                            client.provider().name()
+                        ));
+                    }
+                    Some(action @ (Action::ToggleCodeExec | Action::ToggleWebSearch)) => {
+                        if action == Action::ToggleCodeExec {
+                            app.toggle_code_execution();
+                        } else {
+                            app.toggle_web_search();
+                        }
+                        // If code execution is enabled and output_dir is None, set it to default
+                        if matches!(app.tool_mode, ToolMode::CodeExecution | ToolMode::Both)
+                            && output_dir.is_none()
+                        {
+                            output_dir = Some("output".to_string());
+                        }
+                    }
+                    Some(Action::HistorySearch) => {
+                        if app.slash_command_state.is_none() {
+                            app.history_search_next();
+                        }
+                    }
+                    Some(Action::Undo) => {
+                        app.undo_input();
+                        app.refresh_slash_command();
+                    }
+                    Some(Action::Redo) => {
+                        app.redo_input();
+                        app.refresh_slash_command();
+                    }
+                    Some(Action::Focus) => app.start_focus(),
+                    Some(Action::Retry) => {
+                        // Same as /retry without options; the draft in the input is kept
+                        app.retry_last_answer(Default::default());
+                    }
+                    Some(Action::Rephrase) => app.rephrase_refused(),
+                    Some(Action::NewTab) => tab_action = Some(TabAction::New),
+                    Some(Action::Newline) => app.insert_char('\n'),
+                    Some(Action::DeleteWord) => {
+                        app.delete_to(editor::prev_word);
+                        app.refresh_slash_command();
+                    }
+                    Some(Action::KillLine) => {
+                        app.delete_to(editor::kill_start);
+                        app.refresh_slash_command();
+                    }
+                    Some(Action::KillToEnd) => {
+                        app.delete_to(editor::kill_end);
+                        app.refresh_slash_command();
+                    }
+                    Some(Action::Send) if app.stalled => {
+                        // Retry a stalled stream: drop it and resume from the partial answer
+                        if let Some(token) = stream_cancellation.take() {
+                            app.stream_dropped = Some("stalled".to_string());
+                            token.cancel();
+                        }
+                    }
+                    Some(Action::Send) => {
+                        // Handle slash command execution
+                        if app.slash_command_state.is_some() {
+                            app.submit_slash_command();
+                        } else if !app.input.is_empty() && !app.is_waiting {
+                            // `!opus question` sends just this message to another model
+                            let (params, user_message) = match models::split_prefix(&app.input) {
+                                Some((model, question)) => (
+                                    anthropic::RequestParams {
+                                        model: Some(model),
+                                        ..Default::default()
+                                    },
+                                    question.to_string(),
+                                ),
+                                None => (Default::default(), app.input.clone()),
+                            };
+                            if let Err(e) = history::add(&mut app.history, &app.input) {
+                                log_debug!("Failed to save prompt history: {}", e);
+                            }
+                            app.clear_input();
+                            app.add_user_message(user_message);
+                            app.queued_request = Some(params);
+                        }
+                    }
+                    None => match key.code {
                         KeyCode::Esc => {
                             // Cancel slash command if active
                             if app.slash_command_state.is_some() {
@@ -413,14 +2312,39 @@ async fn run_app(
                                 // Cancel streaming if it's in progress
                                 token.cancel();
                                 // The stream will clean up on the next iteration
+                            } else if let Some(token) = tool_cancellation.take() {
+                                // Kill the running tool; the rest of the round is skipped
+                                token.cancel();
+                                app.cancel_tool_round();
+                            } else if app.popup_mode && !app.is_waiting {
+                                // Nothing to cancel: dismiss the popup
+                                log_debug!("Closing popup with Esc");
+                                return Ok(());
                             }
                         }
                         KeyCode::Tab => {
-                            // Navigate slash command suggestions
-                            if let Some(state) = &mut app.slash_command_state {
-                                state.next_suggestion();
+                            // Complete an @path or an argument, navigate slash command
+                            // suggestions, or else move to the message pane
+                            let completed = app.complete_mention() || app.complete_slash_argument();
+                            if !completed {
+                                match &mut app.slash_command_state {
+                                    Some(state) => state.next_suggestion(),
+                                    None => app.pane = Pane::Messages,
+                                }
                             }
                         }
+                        KeyCode::BackTab => match &mut app.slash_command_state {
+                            Some(state) => state.prev_suggestion(),
+                            None => app.pane = Pane::Messages,
+                        },
+                        // Ctrl+↑/↓ (or Alt+↑/↓) jump between messages
+                        KeyCode::Up | KeyCode::Down
+                            if key.modifiers.intersects(
+                                event::KeyModifiers::CONTROL | event::KeyModifiers::ALT,
+                            ) =>
+                        {
+                            app.jump_to_message(key.code == KeyCode::Down);
+                        }
                         KeyCode::Down => {
                             // Navigate slash command suggestions
                             if let Some(state) = &mut app.slash_command_state {
@@ -433,303 +2357,393 @@ async fn run_app(
                                 state.prev_suggestion();
                             }
                         }
-                        KeyCode::Char('s')
-                            if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                        KeyCode::Char(c @ '1'..='9')
+                            if key.modifiers.contains(event::KeyModifiers::ALT) =>
                         {
-                            app.toggle_selection_mode();
-                            if app.selection_mode {
-                                // Disable mouse capture to allow text selection
-                                execute!(terminal.backend_mut(), DisableMouseCapture)?;
-                            } else {
-                                // Re-enable mouse capture for scrolling
-                                execute!(terminal.backend_mut(), EnableMouseCapture)?;
-                            }
+                            tab_action = Some(TabAction::Select(c as usize - '1' as usize));
                         }
-                        KeyCode::Char('x')
-                            if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
-                        {
-                            app.toggle_code_execution();
-                            // If code execution is enabled and output_dir is None, set it to default
-                            if matches!(app.tool_mode, ToolMode::CodeExecution | ToolMode::Both)
-                                && output_dir.is_none()
-                            {
-                                output_dir = Some("output".to_string());
-                            }
+                        KeyCode::Left if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                            app.cycle_alternative(-1);
                         }
-                        KeyCode::Char('w')
-                            if key.modifiers.contains(event::KeyModifiers::CONTROL) =>
-                        {
-                            app.toggle_web_search();
-                            // If code execution is enabled and output_dir is None, set it to default
-                            if matches!(app.tool_mode, ToolMode::CodeExecution | ToolMode::Both)
-                                && output_dir.is_none()
-                            {
-                                output_dir = Some("output".to_string());
-                            }
+                        KeyCode::Right if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                            app.cycle_alternative(1);
                         }
-                        KeyCode::Enter if key.modifiers.intersects(event::KeyModifiers::ALT) => {
-                            app.input.push('\n');
+                        // Cursor movement; Ctrl+Left/Right or Alt+B/F move by word (Alt+Left/Right switch answers)
+                        KeyCode::Left if ctrl => app.move_cursor(editor::prev_word),
+                        KeyCode::Right if ctrl => app.move_cursor(editor::next_word),
+                        KeyCode::Char('b') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                            app.move_cursor(editor::prev_word);
                         }
-                        KeyCode::Enter => {
-                            // Handle slash command execution
-                            if let Some(state) = &app.slash_command_state {
-                                if let Some(cmd) = state.get_selected() {
-                                    app.execute_slash_command(cmd.action.clone());
-                                }
-                            } else if !app.input.is_empty() && !app.is_waiting {
-                                let user_message = app.input.clone();
-                                app.clear_input();
-                                app.add_message("user".to_string(), user_message.clone());
-                                app.is_waiting = true;
-                                app.auto_scroll = true; // Enable auto-scroll when sending a message
-                                app.start_streaming();
-
-                                // Force immediate redraw to show user message and streaming state
-                                terminal.draw(|f| ui::ui(f, app))?;
-
-                                let mut messages = vec![];
-                                for (role, contents) in &app.messages {
-                                    if role != "system" {
-                                        // Convert MessageContent back to text for API
-                                        let mut text_content = String::new();
-                                        for content in contents {
-                                            match content {
-                                                ui::MessageContent::Text(text) => {
-                                                    text_content.push_str(text);
-                                                }
-                                                _ => {
-                                                    // Skip non-text content when building messages
-                                                }
-                                            }
-                                        }
-                                        if !text_content.is_empty() {
-                                            messages.push(anthropic::Message {
-                                                role: role.clone(),
-                                                content: text_content,
-                                            });
-                                        }
-                                    }
-                                }
-
-                                // Create a new client with the current tool settings
-                                let client_with_tools =
-                                    client.clone().with_tool_mode(app.tool_mode);
-
-                                // send_message_stream now returns immediately with channel and cancellation token
-                                let system_prompt =
-                                    Some(substitute_datetime_placeholder(&app.system_prompt));
-                                match client_with_tools
-                                    .send_message_stream(messages, system_prompt)
-                                    .await
-                                {
-                                    Ok((receiver, cancellation)) => {
-                                        stream_receiver = Some(receiver);
-                                        stream_cancellation = Some(cancellation);
-                                    }
-                                    Err(e) => {
-                                        // This should rarely happen now as most errors are sent through the channel
-                                        app.finish_streaming();
-                                        app.add_api_error(format!(
-                                            "Failed to start request: {}",
-                                            e
-                                        ));
-                                        app.is_waiting = false;
-                                    }
-                                }
-                            }
+                        KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                            app.move_cursor(editor::next_word);
+                        }
+                        KeyCode::Left => app.move_cursor(editor::prev_char),
+                        KeyCode::Right => app.move_cursor(editor::next_char),
+                        KeyCode::Home => app.move_cursor(editor::line_start),
+                        KeyCode::End => app.move_cursor(editor::line_end),
+                        KeyCode::Char('a') if ctrl => app.move_cursor(editor::line_start),
+                        KeyCode::Char('e') if ctrl => app.move_cursor(editor::line_end),
+                        KeyCode::Delete if app.slash_command_state.is_none() => {
+                            app.delete_at_cursor();
                         }
+                        KeyCode::Char('r')
+                            if app.resume_offered
+                                && app.input.is_empty()
+                                && !app.is_waiting
+                                && key.modifiers.is_empty() =>
+                        {
+                            app.resume_dropped_answer();
+                        }
+                        // Control keys without a binding don't type their letter
+                        KeyCode::Char(_) if ctrl => {}
                         KeyCode::Char(c) => {
                             // Check if starting a slash command
                             if c == '/' && app.input.is_empty() && !app.is_waiting {
-                                app.input.push(c);
+                                app.insert_char(c);
                                 app.start_slash_command();
                             } else if app.slash_command_state.is_some() {
-                                app.input.push(c);
+                                app.insert_char(c);
                                 let input_copy = app.input.clone();
                                 app.update_slash_command(&input_copy[1..]); // Skip the '/'
                             } else {
-                                app.input.push(c);
+                                app.insert_char(c);
                             }
                         }
                         KeyCode::Backspace => {
-                            app.input.pop();
-
-                            // Update or cancel slash command state
-                            if app.slash_command_state.is_some() {
-                                if app.input.is_empty() {
-                                    app.cancel_slash_command();
-                                } else {
-                                    let input_copy = app.input.clone();
-                                    app.update_slash_command(&input_copy[1..]); // Skip the '/'
-                                }
-                            }
-                        }
-                        KeyCode::PageUp => {
-                            app.scroll_up(10);
-                        }
-                        KeyCode::PageDown => {
-                            app.scroll_down(10);
+                            app.delete_before_cursor();
+                            app.refresh_slash_command();
                         }
                         _ => {}
-                    }
+                    },
                 }
-                Event::Mouse(mouse) => {
-                    // Only handle mouse events when not in selection mode
-                    if !app.selection_mode {
-                        match mouse.kind {
-                            MouseEventKind::ScrollUp => {
-                                app.scroll_up(3);
-                            }
-                            MouseEventKind::ScrollDown => {
-                                app.scroll_down(3);
-                            }
-                            _ => {}
-                        }
-                    }
+            }
+            // Only handle mouse events when not in selection mode
+            Event::Mouse(mouse) if !app.selection_mode => match mouse.kind {
+                MouseEventKind::ScrollUp => {
+                    app.scroll_up(3);
+                }
+                MouseEventKind::ScrollDown => {
+                    app.scroll_down(3);
                 }
-                Event::Resize(_, _) => {
-                    terminal.clear()?;
+                MouseEventKind::Down(MouseButton::Left) if app.active_pane() != Pane::Modal => {
+                    app.click(mouse.column, mouse.row);
                 }
                 _ => {}
+            },
+            Event::Resize(_, _) => {
+                terminal.clear()?;
             }
+            Event::FocusGained => focused = Some(true),
+            Event::FocusLost => focused = Some(false),
+            _ => {}
         }
+
+        match tab_action {
+            Some(TabAction::New) => {
+                let app = tabs[active].app.new_tab();
+                tabs.push(Tab::new(next_tab_id, app));
+                next_tab_id += 1;
+                active = tabs.len() - 1;
+            }
+            Some(TabAction::Cycle(step)) => {
+                active = (active as isize + step).rem_euclid(tabs.len() as isize) as usize;
+            }
+            Some(TabAction::Select(index)) if index < tabs.len() => active = index,
+            _ => continue,
+        }
+        tmux::set_pane_title(&tabs[active].app.pane_title());
     }
 }
 
-async fn download_and_save_file(
-    client: &anthropic::AnthropicClient,
-    output_dir: &str,
-    file_id: &str,
-    metadata_tx: mpsc::Sender<(String, String)>,
-) -> Result<()> {
-    // Create output directory if it doesn't exist
-    fs::create_dir_all(output_dir)?;
-
-    // First, try to get the actual filename from the metadata API
-    let actual_filename = match client.get_file_metadata(file_id).await {
-        Ok(metadata) => {
-            let filename = metadata.filename;
-            // Send metadata update to UI
-            let _ = metadata_tx
-                .send((file_id.to_string(), filename.clone()))
-                .await;
-            filename
+// Persist the conversation after each completed turn; failures are only logged
+// Wrap up once the answer (including any tool rounds) is complete
+fn finish_answer(app: &mut App, away: bool) {
+    if let Some(speaker) = &mut app.speaker {
+        speaker.flush();
+    }
+    app.enforce_memory_cap();
+    app.maybe_auto_compact();
+    app.is_waiting = false;
+    app.save_session();
+    // Only worth interrupting for while the terminal is in the background
+    if !away {
+        return;
+    }
+    if app.config.bell {
+        notify::bell();
+    }
+    if app.config.notifications {
+        notify::send("Claude finished responding", &app.last_response_preview());
+    }
+}
+
+// Suspend the TUI and edit `draft` in $VISUAL/$EDITOR (vi if neither is set). Returns
+// the edited text, or None if the editor exited with an error.
+fn compose_in_editor(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    draft: &str,
+    selection_mode: bool,
+) -> Result<Option<String>> {
+    // A fresh file under ~/.agnt that only the user can read; create_new also refuses
+    // to follow a symlink left in its place
+    let path = dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join(format!("prompt-{}.md", std::process::id()));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Left over from a run that crashed with the editor open
+    let _ = fs::remove_file(&path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)?.write_all(draft.as_bytes())?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    log_debug!("Opening {} in {}", path.display(), editor);
+
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableFocusChange
+    )?;
+
+    // Run through the shell so EDITOR values with arguments ("code --wait") work
+    let status = if cfg!(windows) {
+        std::process::Command::new("cmd")
+            .arg("/C")
+            .arg(format!("{} \"{}\"", editor, path.display()))
+            .status()
+    } else {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&path)
+            .status()
+    };
+
+    enable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        EnterAlternateScreen,
+        EnableFocusChange
+    )?;
+    if !selection_mode {
+        execute!(terminal.backend_mut(), EnableMouseCapture)?;
+    }
+    terminal.clear()?;
+
+    let edited = match status {
+        Ok(status) if status.success() => {
+            let text = fs::read_to_string(&path)?;
+            // Editors add a final newline that would otherwise be sent along
+            Some(text.strip_suffix('\n').unwrap_or(&text).to_string())
+        }
+        Ok(status) => {
+            log_debug!("Editor exited with {}, keeping the draft", status);
+            None
         }
         Err(e) => {
-            log_debug!(
-                "Warning: Could not fetch file metadata for {}: {}",
-                file_id,
-                e
-            );
-            // Add a small delay and retry once in case the file isn't ready yet
-            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-            match client.get_file_metadata(file_id).await {
-                Ok(metadata) => {
-                    let filename = metadata.filename;
-                    // Send metadata update to UI
-                    let _ = metadata_tx
-                        .send((file_id.to_string(), filename.clone()))
-                        .await;
-                    filename
-                }
-                Err(_) => format!("{}.bin", file_id),
-            }
+            let _ = fs::remove_file(&path);
+            return Err(e.into());
         }
     };
+    let _ = fs::remove_file(&path);
+    Ok(edited)
+}
+
+// Everything the TUI loop can wake up for
+// Events from a tab's background work carry that tab's id first
+enum LoopEvent {
+    Terminal(Event),
+    Stream(usize, Option<anthropic::StreamEvent>),
+    Download(DownloadUpdate),
+    Compacted(
+        usize,
+        usize,
+        Result<(String, Option<anthropic::TurnMetrics>)>,
+    ),
+    TokenCount(usize, Result<u64>),
+    Models(Result<Vec<anthropic::ModelInfo>>),
+    Tool(usize, tools::ToolEvent), // Output and the result of the running tool call
+    Tick,
+}
 
-    // Sanitize filename to prevent path traversal and clean special characters
-    let safe_filename = Path::new(&actual_filename)
-        .file_name()
-        .unwrap_or_else(|| std::ffi::OsStr::new("unnamed_file"))
-        .to_string_lossy();
-
-    // Further clean the filename - replace problematic characters but keep the file extension
-    let cleaned_filename = safe_filename
-        .chars()
-        .map(|c| {
-            match c {
-                // Keep alphanumeric, dots, hyphens, and underscores
-                c if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' => c,
-                // Replace spaces with underscores
-                ' ' => '_',
-                // Replace other characters with underscores
-                _ => '_',
+// Receive from whichever tab's stream has an event, or wait forever when none is streaming
+async fn recv_stream_event(tabs: &mut [Tab]) -> (usize, Option<anthropic::StreamEvent>) {
+    std::future::poll_fn(|cx| {
+        for tab in tabs.iter_mut() {
+            if let Some(receiver) = &mut tab.stream_receiver
+                && let Poll::Ready(event) = receiver.poll_recv(cx)
+            {
+                return Poll::Ready((tab.id, event));
             }
-        })
-        .collect::<String>();
+        }
+        Poll::Pending
+    })
+    .await
+}
 
-    let filepath = Path::new(output_dir).join(&cleaned_filename);
+// Send the conversation so far and return the stream, or record the failure in the
+// conversation and return None
+// The conversation as the API sees it: text, images and summaries of compacted turns,
+// plus a partial answer to continue from
+fn build_messages(app: &App) -> Vec<anthropic::Message> {
+    let mut messages = session::api_messages(&app.messages);
+
+    // A partial answer (when continuing after max_tokens) is sent as a prefill for the
+    // model to pick up from; the API rejects prefills that end in whitespace
+    let partial = app.streaming_text();
+    if !partial.trim().is_empty() {
+        messages.push(anthropic::Message::text(
+            "assistant",
+            partial.trim_end().to_string(),
+        ));
+    }
+    messages
+}
 
-    // Try to download the actual file content
-    match client.download_file(file_id).await {
-        Ok(content) => {
-            // Write the actual file content
-            let mut file = fs::File::create(&filepath)?;
-            file.write_all(&content)?;
-            log_debug!(
-                "Downloaded: {}",
-                filepath
-                    .canonicalize()
-                    .unwrap_or(filepath.clone())
-                    .display()
-            );
+// The client with the session's current tool, model and sampling settings
+fn session_client(app: &App, client: &anthropic::AnthropicClient) -> anthropic::AnthropicClient {
+    client
+        .clone()
+        .with_tool_mode(app.tool_mode)
+        .with_model(app.model.clone())
+        .with_sampling(app.sampling.clone())
+}
+
+// `check_size` is false for continuations and reconnects of an answer already under way
+async fn start_request(
+    app: &mut App,
+    client: &anthropic::AnthropicClient,
+    params: anthropic::RequestParams,
+    check_size: bool,
+) -> Option<(mpsc::Receiver<anthropic::StreamEvent>, CancellationToken)> {
+    let mut messages = build_messages(app);
+    let client_with_tools = session_client(app, client);
+
+    let mut system_prompt = prompt::render(&app.system_prompt);
+    hooks::apply(&app.config.context_hooks, &mut system_prompt, &mut messages).await;
+
+    // Requests over the warning threshold are counted exactly and wait for a y/n
+    let approved = std::mem::take(&mut app.size_approved);
+    if check_size
+        && !approved
+        && let Some(threshold) = app.config.token_warning_threshold
+    {
+        match client_with_tools
+            .count_tokens(
+                messages.clone(),
+                Some(system_prompt.clone()),
+                params.clone(),
+            )
+            .await
+        {
+            Ok(tokens) if tokens > threshold => {
+                app.hold_large_request(params, tokens, threshold);
+                return None;
+            }
+            Ok(_) => {}
+            Err(e) => log_debug!("Token count failed, sending anyway: {}", e),
         }
+    }
+    app.last_request = params.clone();
+
+    // send_message_stream returns immediately with channel and cancellation token
+    match client_with_tools
+        .send_message_stream(messages, Some(system_prompt), params)
+        .await
+    {
+        Ok(stream) => Some(stream),
         Err(e) => {
-            // If download fails, create a placeholder file with error information
-            let mut file = fs::File::create(&filepath)?;
-            writeln!(
-                file,
-                "Failed to download file from Claude's code execution.\n\
-                \n\
-                File ID: {}\n\
-                Error: {}\n\
-                \n\
-                This could be due to:\n\
-                - The file API not being available yet\n\
-                - The file having expired\n\
-                - Authentication or permission issues\n\
-                \n\
-                You can try using the Anthropic Files API directly with the file ID above.",
-                file_id, e
-            )?;
-            log_debug!(
-                "Warning: Could not download file content, created placeholder instead: {}",
-                e
-            );
+            // This should rarely happen now as most errors are sent through the channel
+            app.finish_streaming();
+            app.add_api_error(format!("Failed to start request: {}", e));
+            app.is_waiting = false;
+            None
         }
     }
-
-    Ok(())
 }
 
-fn substitute_datetime_placeholder(prompt: &str) -> String {
-    use chrono::{Datelike, Local, Timelike};
-
-    let now = Local::now();
-    let weekday = match now.weekday() {
-        chrono::Weekday::Mon => "Monday",
-        chrono::Weekday::Tue => "Tuesday",
-        chrono::Weekday::Wed => "Wednesday",
-        chrono::Weekday::Thu => "Thursday",
-        chrono::Weekday::Fri => "Friday",
-        chrono::Weekday::Sat => "Saturday",
-        chrono::Weekday::Sun => "Sunday",
-    };
-
-    let datetime_str = format!(
-        "{}, {} {}, {} at {:02}:{:02}:{:02} {}",
-        weekday,
-        now.format("%B"),
-        now.day(),
-        now.year(),
-        now.hour12().1,
-        now.minute(),
-        now.second(),
-        if now.hour12().0 { "PM" } else { "AM" }
-    );
-
-    let timezone = now.format("%Z").to_string();
-    let full_datetime = format!("{} {}", datetime_str, timezone);
-
-    prompt.replace("[DATE_TIME_WITH_WEEKDAY_AND_TIMEZONE]", &full_datetime)
+fn handle_stream_event(
+    app: &mut App,
+    event: anthropic::StreamEvent,
+    output_dir: Option<&str>,
+    downloads: &DownloadManager,
+) {
+    match event {
+        anthropic::StreamEvent::Text(text) => {
+            // Clear connection status once we start receiving content
+            app.set_connection_status(None);
+            app.note_streamed_text(&text);
+            app.append_streaming_text(&text);
+            if let Some(speaker) = &mut app.speaker {
+                speaker.push(&text);
+            }
+        }
+        anthropic::StreamEvent::CodeInput(code) => {
+            app.add_streaming_code(code);
+        }
+        anthropic::StreamEvent::CodeOutput {
+            stdout,
+            stderr,
+            return_code,
+            files,
+        } => {
+            // Save files locally whenever files are created
+            if !files.is_empty() {
+                // Always use default output directory if none specified
+                let dir = output_dir.unwrap_or("output");
+
+                for (file_id, _filename) in &files {
+                    // Only download files with valid file IDs
+                    if file_id.starts_with("file_") {
+                        downloads.enqueue(file_id.clone(), dir.to_string(), app.session_id.clone());
+                    }
+                }
+            }
+            app.add_streaming_output(stdout, stderr, return_code, files);
+        }
+        anthropic::StreamEvent::CodeError(error) => {
+            app.add_streaming_error(error);
+        }
+        anthropic::StreamEvent::ToolUse { id, name, input } => {
+            app.add_streaming_tool_use(id, name, input);
+        }
+        anthropic::StreamEvent::ContainerInfo { id, expires_at } => {
+            app.set_container_info(id, expires_at);
+        }
+        anthropic::StreamEvent::ConnectionStatus(status) => {
+            app.set_connection_status(Some(status.clone()));
+        }
+        anthropic::StreamEvent::Disconnected(reason) => {
+            app.stream_dropped = Some(reason);
+        }
+        anthropic::StreamEvent::Stalled(idle) => {
+            app.stalled = true;
+            app.set_connection_status(Some(format!(
+                "Connection stalled (no data for {}s) · Enter: retry · Esc: cancel · or keep waiting",
+                idle.as_secs()
+            )));
+        }
+        anthropic::StreamEvent::StallCleared => {
+            app.stalled = false;
+            app.set_connection_status(None);
+        }
+        anthropic::StreamEvent::Metrics(metrics) => {
+            app.record_metrics(metrics);
+        }
+        anthropic::StreamEvent::Error(error) => {
+            // Keep whatever was streamed before the error, then show the error after it
+            app.finish_streaming();
+            app.add_api_error(error.to_string());
+        }
+    }
 }
@@ -0,0 +1,109 @@
+use std::{fs, path::Path};
+
+use crate::ui::expand_home;
+
+// Files bigger than this are cut before they go into the request
+pub const MAX_FILE_BYTES: usize = 100 * 1024;
+
+// Characters that end a sentence rather than a path, as in "see @notes.md."
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '"', '\''];
+
+// `@path` words in a message, in order and without repeats. Only paths to existing
+// files count, so "@someone" or an email address stays plain text.
+pub fn find(text: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let Some(path) = word.strip_prefix('@') else {
+            continue;
+        };
+        let trimmed = path.trim_end_matches(TRAILING_PUNCTUATION);
+        let found = [path, trimmed]
+            .into_iter()
+            .find(|path| !path.is_empty() && Path::new(&expand_home(path)).is_file());
+        if let Some(path) = found
+            && !paths.iter().any(|p| p == path)
+        {
+            paths.push(path.to_string());
+        }
+    }
+    paths
+}
+
+// A mentioned file's text, cut to MAX_FILE_BYTES. Binary files are refused.
+pub fn read(path: &str) -> Result<(String, bool), String> {
+    let bytes = fs::read(expand_home(path)).map_err(|e| format!("Cannot read @{}: {}", path, e))?;
+    let sniff = &bytes[..bytes.len().min(8192)];
+    if sniff.contains(&0) {
+        return Err(format!("@{} looks like a binary file; not attached", path));
+    }
+    let truncated = bytes.len() > MAX_FILE_BYTES;
+    let mut end = bytes.len().min(MAX_FILE_BYTES);
+    // Cut on a character boundary
+    while end > 0 && end < bytes.len() && (bytes[end] & 0xC0) == 0x80 {
+        end -= 1;
+    }
+    match String::from_utf8(bytes[..end].to_vec()) {
+        Ok(text) => Ok((text, truncated)),
+        Err(_) => Err(format!("@{} is not UTF-8 text; not attached", path)),
+    }
+}
+
+// How a mentioned file goes to the API: its own text block, tagged with the path
+pub fn api_text(path: &str, content: &str) -> String {
+    format!("<file path=\"{}\">\n{}\n</file>", path, content.trim_end())
+}
+
+// Paths that complete a partly typed `@path`, sorted, directories ending in a slash.
+// Hidden entries only show up once a dot has been typed.
+pub fn complete(partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(slash) => (&partial[..=slash], &partial[slash + 1..]),
+        None => ("", partial),
+    };
+    let listed = if dir.is_empty() {
+        ".".to_string()
+    } else {
+        expand_home(dir)
+    };
+    let Ok(entries) = fs::read_dir(listed) else {
+        return Vec::new();
+    };
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_existing_files_are_mentions() {
+        let dir = std::env::temp_dir().join(format!("agnt-mentions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("notes.md");
+        fs::write(&file, "hi").unwrap();
+        let path = file.display().to_string();
+
+        let text = format!("Summarize @{0}. Ask @someone, then @{0} again", path);
+        assert_eq!(find(&text), vec![path.clone()]);
+        assert_eq!(
+            complete(&format!("{}/no", dir.display())),
+            vec![path.clone()]
+        );
+
+        fs::write(&file, b"\x89PNG\0\0").unwrap();
+        assert!(read(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::{fs, path::PathBuf};
+
+use crate::anthropic::ModelInfo;
+
+// Short names accepted wherever a model is picked (ANTHROPIC_MODEL, the config file,
+// `!opus ...`, `/ask haiku ...`, `/retry --model sonnet`); anything else is passed
+// through as a full model id. These snapshots are used until the Models API has been
+// asked for newer ones.
+const ALIASES: &[(&str, &str)] = &[
+    ("opus", "claude-opus-4-1-20250805"),
+    ("sonnet", "claude-sonnet-4-20250514"),
+    ("haiku", "claude-3-5-haiku-20241022"),
+    ("latest", "claude-opus-4-1-20250805"),
+];
+
+// Every current model has a 200k token context window
+pub const CONTEXT_WINDOW: u64 = 200_000;
+
+// Aliases pick the newest snapshot in the last model list the Models API returned,
+// falling back to the built-in table
+pub fn resolve(name: &str) -> String {
+    let Some((alias, builtin)) = ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+    else {
+        return name.to_string();
+    };
+    newest(alias, &cached()).unwrap_or_else(|| builtin.to_string())
+}
+
+// The Models API lists newest first, so the first id of the family is the newest
+fn newest(alias: &str, available: &[ModelInfo]) -> Option<String> {
+    available
+        .iter()
+        .find(|model| alias == "latest" || model.id.contains(alias))
+        .map(|model| model.id.clone())
+}
+
+fn cache_path() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("models.json")
+}
+
+// The model list from the last successful Models API call
+pub fn cached() -> Vec<ModelInfo> {
+    fs::read_to_string(cache_path())
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_cache(available: &[ModelInfo]) {
+    let path = cache_path();
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, serde_json::to_string(available).unwrap_or_default()));
+    if let Err(e) = result {
+        log_debug!("Failed to cache model list in {}: {}", path.display(), e);
+    }
+}
+
+// One row of the /model picker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Choice {
+    pub label: String, // Alias, or the display name the Models API gives
+    pub id: String,
+    pub created: Option<String>, // Release date, for models from the Models API
+}
+
+// Entries offered by the /model picker: the models the Models API listed (newest
+// first) once they've been fetched, the built-in aliases until then. A custom current
+// model (e.g. from ANTHROPIC_MODEL) is listed too so it can be picked again.
+pub fn picker_entries(current: &str, available: &[ModelInfo]) -> Vec<Choice> {
+    let mut entries: Vec<Choice> = if available.is_empty() {
+        ALIASES
+            .iter()
+            .map(|(alias, model)| Choice {
+                label: alias.to_string(),
+                id: model.to_string(),
+                created: None,
+            })
+            .collect()
+    } else {
+        available
+            .iter()
+            .map(|model| Choice {
+                label: if model.display_name.is_empty() {
+                    model.id.clone()
+                } else {
+                    model.display_name.clone()
+                },
+                id: model.id.clone(),
+                created: model
+                    .created_at
+                    .as_deref()
+                    .map(|created| created.get(..10).unwrap_or(created).to_string()),
+            })
+            .collect()
+    };
+    if !entries.iter().any(|entry| entry.id == current) {
+        entries.push(Choice {
+            label: "current".to_string(),
+            id: current.to_string(),
+            created: None,
+        });
+    }
+    entries
+}
+
+// Completions for a model argument: the aliases, then any listed model ids
+pub fn completion_entries(available: &[ModelInfo]) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = ALIASES
+        .iter()
+        .map(|(alias, model)| (alias.to_string(), model.to_string()))
+        .collect();
+    entries.extend(
+        available
+            .iter()
+            .map(|model| (model.id.clone(), model.display_name.clone())),
+    );
+    entries
+}
+
+fn is_model_name(name: &str) -> bool {
+    name.starts_with("claude-")
+        || ALIASES
+            .iter()
+            .any(|(alias, _)| alias.eq_ignore_ascii_case(name))
+}
+
+// Split a `!opus question` style message into the model to use and the question.
+// Only known aliases and full model ids count, so "!important ..." stays a message.
+pub fn split_prefix(text: &str) -> Option<(String, &str)> {
+    let rest = text.strip_prefix('!')?;
+    let (name, question) = rest.split_once(char::is_whitespace)?;
+    let question = question.trim_start();
+    if !is_model_name(name) || question.is_empty() {
+        return None;
+    }
+    Some((resolve(name), question))
+}
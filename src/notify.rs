@@ -0,0 +1,27 @@
+use notify_rust::Notification;
+use std::io::{self, Write};
+
+// Show a desktop notification without blocking the caller; failures are only logged
+pub fn send(summary: &str, body: &str) {
+    let summary = summary.to_string();
+    let body = body.to_string();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = Notification::new()
+            .appname("agnt")
+            .summary(&summary)
+            .body(&body)
+            .show()
+        {
+            log_debug!("Failed to show desktop notification: {}", e);
+        }
+    });
+}
+
+// The terminal bell, which most terminals turn into a sound, a flash or an urgent
+// window hint
+pub fn bell() {
+    let mut stdout = io::stdout();
+    if let Err(e) = stdout.write_all(b"\x07").and_then(|()| stdout.flush()) {
+        log_debug!("Failed to ring the bell: {}", e);
+    }
+}
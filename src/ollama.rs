@@ -0,0 +1,281 @@
+use anyhow::{Result, anyhow, bail};
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::sse::SseEvent;
+
+const DEFAULT_HOST: &str = "localhost:11434";
+const DEFAULT_PORT: &str = "11434";
+
+// A local Ollama server
+#[derive(Debug, Clone)]
+pub struct Ollama {
+    pub base_url: String,
+}
+
+// A model installed on the server, from /api/tags
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalModel {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    #[serde(default)]
+    models: Vec<LocalModel>,
+}
+
+impl Ollama {
+    // `host` from the config, else OLLAMA_HOST, else localhost:11434. As with the ollama
+    // CLI, the scheme defaults to http and the port to 11434.
+    pub fn new(host: Option<String>) -> Self {
+        let host = host
+            .or_else(|| std::env::var("OLLAMA_HOST").ok())
+            .filter(|host| !host.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_HOST.to_string());
+        let host = host.trim().trim_end_matches('/');
+        let (scheme, authority) = match host.split_once("://") {
+            Some((scheme, authority)) => (scheme, authority),
+            None => ("http", host),
+        };
+        let base_url = if authority.contains(':') {
+            format!("{}://{}", scheme, authority)
+        } else {
+            format!("{}://{}:{}", scheme, authority, DEFAULT_PORT)
+        };
+        Self { base_url }
+    }
+
+    pub fn chat_url(&self) -> String {
+        format!("{}/api/chat", self.base_url)
+    }
+
+    // A Messages API request body in the form /api/chat takes: the system prompt becomes
+    // the first message, images move into each message's `images`, and sampling settings
+    // into `options`. Tools are left out; Ollama has no server-side tools.
+    pub fn chat_body(request: &Value) -> Value {
+        let mut messages = Vec::new();
+        if let Some(system) = request.get("system").and_then(Value::as_str) {
+            messages.push(json!({ "role": "system", "content": system }));
+        }
+        for message in request["messages"].as_array().into_iter().flatten() {
+            let mut texts = Vec::new();
+            let mut images = Vec::new();
+            for block in message["content"].as_array().into_iter().flatten() {
+                match block["type"].as_str() {
+                    Some("text") => texts.extend(block["text"].as_str()),
+                    Some("image") => images.extend(block["source"]["data"].as_str()),
+                    _ => {}
+                }
+            }
+            let mut entry = json!({ "role": message["role"], "content": texts.join("\n\n") });
+            if !images.is_empty() {
+                entry["images"] = images.into();
+            }
+            messages.push(entry);
+        }
+
+        let mut options = serde_json::Map::new();
+        for (from, to) in [
+            ("max_tokens", "num_predict"),
+            ("temperature", "temperature"),
+            ("top_p", "top_p"),
+            ("top_k", "top_k"),
+            ("stop_sequences", "stop"),
+        ] {
+            if let Some(value) = request.get(from) {
+                options.insert(to.to_string(), value.clone());
+            }
+        }
+        json!({
+            "model": request["model"],
+            "messages": messages,
+            "stream": true,
+            "options": options,
+        })
+    }
+
+    pub fn chat_stream(&self, client: &Client, request: &Value) -> RequestBuilder {
+        client.post(self.chat_url()).json(&Self::chat_body(request))
+    }
+
+    // Installed models, most recently pulled first
+    pub async fn list_models(&self, client: &Client) -> Result<Vec<LocalModel>> {
+        let response = client
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Cannot reach Ollama at {} (is `ollama serve` running?): {}",
+                    self.base_url,
+                    e
+                )
+            })?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("Ollama returned {}: {}", status, body);
+        }
+        let mut models = response.json::<TagsResponse>().await?.models;
+        models.sort_by(|a, b| b.modified_at.cmp(&a.modified_at));
+        Ok(models)
+    }
+}
+
+// One line of the newline-delimited JSON /api/chat streams
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    #[serde(default)]
+    message: Option<ChunkMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkMessage {
+    #[serde(default)]
+    content: String,
+}
+
+// Turns the /api/chat stream into the Messages API events the Anthropic stream
+// carries: one text block, then the stop reason and token counts when it's done
+#[derive(Debug, Default)]
+pub struct ChatStreamParser {
+    buffer: Vec<u8>,
+    started: bool,
+}
+
+impl ChatStreamParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<ChatChunk>(&line) {
+                Ok(chunk) => self.translate(chunk, &mut events),
+                Err(e) => log_debug!("Skipping Ollama stream line: {}", e),
+            }
+        }
+        events
+    }
+
+    fn translate(&mut self, chunk: ChatChunk, events: &mut Vec<SseEvent>) {
+        if let Some(message) = chunk.error {
+            events.push(event(json!({
+                "type": "error",
+                "error": { "type": "api_error", "message": message },
+            })));
+            return;
+        }
+        if !self.started {
+            self.started = true;
+            events.push(event(json!({ "type": "message_start", "message": {} })));
+            events.push(event(json!({
+                "type": "content_block_start",
+                "index": 0,
+                "content_block": { "type": "text", "text": "" },
+            })));
+        }
+        if let Some(message) = chunk.message.filter(|message| !message.content.is_empty()) {
+            events.push(event(json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": { "type": "text_delta", "text": message.content },
+            })));
+        }
+        if chunk.done {
+            let stop_reason = match chunk.done_reason.as_deref() {
+                Some("length") => "max_tokens",
+                _ => "end_turn",
+            };
+            events.push(event(json!({ "type": "content_block_stop", "index": 0 })));
+            events.push(event(json!({
+                "type": "message_delta",
+                "delta": { "stop_reason": stop_reason },
+                "usage": {
+                    "input_tokens": chunk.prompt_eval_count,
+                    "output_tokens": chunk.eval_count,
+                },
+            })));
+            events.push(event(json!({ "type": "message_stop" })));
+        }
+    }
+}
+
+fn event(data: Value) -> SseEvent {
+    SseEvent {
+        event: data["type"].as_str().map(str::to_string),
+        data: data.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_defaults_scheme_and_port() {
+        assert_eq!(
+            Ollama::new(Some("0.0.0.0".to_string())).base_url,
+            "http://0.0.0.0:11434"
+        );
+        assert_eq!(
+            Ollama::new(Some("https://gpu-box:8080/".to_string())).base_url,
+            "https://gpu-box:8080"
+        );
+    }
+
+    #[test]
+    fn chat_stream_becomes_message_events() {
+        let mut parser = ChatStreamParser::new();
+        let mut events = parser.push(
+            br#"{"message":{"role":"assistant","content":"Hel"},"done":false}
+{"message":{"role":"assistant","content":"lo"},"do"#,
+        );
+        events.extend(parser.push(
+            br#"ne":false}
+{"message":{"role":"assistant","content":""},"done":true,"done_reason":"length","prompt_eval_count":12,"eval_count":2}
+"#,
+        ));
+        let names: Vec<&str> = events
+            .iter()
+            .map(|event| event.event.as_deref().unwrap_or(""))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert!(events[3].data.contains(r#""text":"lo""#));
+        assert!(events[5].data.contains(r#""stop_reason":"max_tokens""#));
+        assert!(events[5].data.contains(r#""input_tokens":12"#));
+    }
+}
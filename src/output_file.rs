@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+// The --output-file copy of a pipe mode answer. It streams into a temporary file next to
+// the target, which replaces the target only once the answer is complete, so a reader
+// never sees half a report and a failed request leaves the old file alone.
+pub struct OutputFile {
+    path: PathBuf,
+    temp: PathBuf,
+    file: Option<BufWriter<File>>, // None once finished
+}
+
+impl OutputFile {
+    // With `append`, the temporary file starts as a copy of the current one
+    pub fn create(path: &Path, append: bool) -> Result<Self> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} is not a file path", path.display()))?;
+        let temp = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        if append && path.exists() {
+            fs::copy(path, &temp)
+                .with_context(|| format!("Cannot copy {} to append to it", path.display()))?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.create(true);
+        if append {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        let file = options
+            .open(&temp)
+            .with_context(|| format!("Cannot write next to {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            temp,
+            file: Some(BufWriter::new(file)),
+        })
+    }
+
+    pub fn write(&mut self, text: &str) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(text.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Move the finished answer into place
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            let file = file.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(&self.temp, &self.path)
+                .with_context(|| format!("Cannot replace {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    // Unfinished answers are thrown away
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_only_once_finished() {
+        let dir = std::env::temp_dir().join(format!("agnt-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("report.md");
+        fs::write(&path, "first\n").unwrap();
+
+        let mut output = OutputFile::create(&path, true).unwrap();
+        output.write("second\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\n");
+        output.finish().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+
+        let mut dropped = OutputFile::create(&path, false).unwrap();
+        dropped.write("lost").unwrap();
+        drop(dropped);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::{
+    anthropic::{ApiError, TurnMetrics},
+    pricing,
+};
+
+// One line of `--pipe --json` output. Every line is a complete JSON object tagged
+// with "type", so scripts can read stdout line by line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PipeEvent<'a> {
+    Text {
+        text: &'a str,
+    },
+    CodeInput {
+        code: &'a str,
+    },
+    CodeOutput {
+        stdout: &'a str,
+        stderr: &'a str,
+        return_code: i32,
+    },
+    CodeError {
+        error_code: &'a str,
+    },
+    ToolUse {
+        id: &'a str,
+        name: &'a str,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: &'a str,
+        name: &'a str,
+        content: &'a str,
+        is_error: bool,
+    },
+    File {
+        file_id: &'a str,
+        filename: &'a str,
+    },
+    Download {
+        file_id: &'a str,
+        path: Option<String>,
+        skipped: bool,
+        error: Option<&'a str>,
+    },
+    Status {
+        message: &'a str,
+    },
+    Usage {
+        model: &'a str,
+        input_tokens: u64,
+        output_tokens: u64,
+        cache_creation_tokens: u64,
+        cache_read_tokens: u64,
+        cost_usd: Option<f64>,
+        ttft_ms: Option<u128>,
+        duration_ms: u128,
+        stop_reason: Option<&'a str>,
+    },
+    Error {
+        kind: &'a str,
+        status: Option<u16>,
+        message: &'a str,
+    },
+}
+
+impl<'a> PipeEvent<'a> {
+    pub fn usage(metrics: &'a TurnMetrics) -> Self {
+        PipeEvent::Usage {
+            model: &metrics.model,
+            input_tokens: metrics.input_tokens,
+            output_tokens: metrics.output_tokens,
+            cache_creation_tokens: metrics.cache_creation_tokens,
+            cache_read_tokens: metrics.cache_read_tokens,
+            cost_usd: pricing::cost(
+                &metrics.model,
+                metrics.input_tokens,
+                metrics.output_tokens,
+                metrics.cache_creation_tokens,
+                metrics.cache_read_tokens,
+            ),
+            ttft_ms: metrics.ttft.map(|ttft| ttft.as_millis()),
+            duration_ms: metrics.duration.as_millis(),
+            stop_reason: metrics.stop_reason.as_deref(),
+        }
+    }
+
+    pub fn error(error: &'a ApiError) -> Self {
+        PipeEvent::Error {
+            kind: &error.kind,
+            status: error.status,
+            message: &error.message,
+        }
+    }
+}
+
+pub fn emit(event: &PipeEvent) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, event)?;
+    stdout.write_all(b"\n")?;
+    stdout.flush()?;
+    Ok(())
+}
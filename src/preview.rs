@@ -0,0 +1,157 @@
+use anyhow::{Result, anyhow};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+// Larger files are previewed up to this many bytes
+const MAX_PREVIEW_BYTES: u64 = 512 * 1024;
+
+// A downloaded file or a full code output shown in a scrollable popup
+#[derive(Debug, Clone)]
+pub struct FilePreview {
+    pub name: String,
+    pub path: Option<PathBuf>, // None for output that only exists in the conversation
+    pub lines: Vec<String>,
+    pub truncated: bool, // Only the first MAX_PREVIEW_BYTES were read
+    pub scroll: usize,
+}
+
+impl FilePreview {
+    // None when the file doesn't look like text and should be opened externally instead
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let mut bytes = Vec::new();
+        File::open(path)?
+            .take(MAX_PREVIEW_BYTES + 1)
+            .read_to_end(&mut bytes)?;
+        let truncated = bytes.len() as u64 > MAX_PREVIEW_BYTES;
+        bytes.truncate(MAX_PREVIEW_BYTES as usize);
+        if bytes.contains(&0) {
+            return Ok(None);
+        }
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            // A multi-byte char cut off at the limit is fine; anything else is binary
+            Err(e) if truncated && e.utf8_error().error_len().is_none() => {
+                let valid = e.utf8_error().valid_up_to();
+                let mut bytes = e.into_bytes();
+                bytes.truncate(valid);
+                String::from_utf8(bytes)?
+            }
+            Err(_) => return Ok(None),
+        };
+
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let lines = match extension.as_deref() {
+            Some("csv") => table_lines(&text, ','),
+            Some("tsv") => table_lines(&text, '\t'),
+            _ => text
+                .lines()
+                .map(|line| line.replace('\t', "    "))
+                .collect(),
+        };
+        Ok(Some(Self {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            path: Some(path.to_path_buf()),
+            lines,
+            truncated,
+            scroll: 0,
+        }))
+    }
+
+    pub fn from_text(name: String, text: &str) -> Self {
+        Self {
+            name,
+            path: None,
+            lines: text
+                .lines()
+                .map(|line| line.replace('\t', "    "))
+                .collect(),
+            truncated: false,
+            scroll: 0,
+        }
+    }
+
+    pub fn scroll_by(&mut self, delta: isize) {
+        let max = self.lines.len().saturating_sub(1) as isize;
+        self.scroll = (self.scroll as isize + delta).clamp(0, max) as usize;
+    }
+}
+
+// Rows of a CSV/TSV file with every column padded to its widest cell
+fn table_lines(text: &str, delimiter: char) -> Vec<String> {
+    let rows: Vec<Vec<String>> = text
+        .lines()
+        .map(|line| split_row(line, delimiter))
+        .collect();
+    let mut widths: Vec<usize> = Vec::new();
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            let width = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(max) => *max = (*max).max(width),
+                None => widths.push(width),
+            }
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+// One line of delimited text; quoted cells may contain the delimiter and "" escapes
+fn split_row(line: &str, delimiter: char) -> Vec<String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                cell.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => cells.push(std::mem::take(&mut cell)),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell);
+    cells
+}
+
+// Hand the file to the desktop's default application
+pub fn open_external(path: &Path) -> Result<()> {
+    let program = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+    let mut child = Command::new(program)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow!("failed to run {}: {}", program, e))?;
+    // Reaped in the background so it doesn't linger as a zombie
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use crate::anthropic::{InputContent, Message};
+
+// USD prices per million tokens for the Claude model families
+#[derive(Debug, Clone, Copy)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPricing {
+    // Prompt caching: writes cost 25% more than regular input, reads 90% less
+    pub fn cache_write(&self) -> f64 {
+        self.input * 1.25
+    }
+
+    pub fn cache_read(&self) -> f64 {
+        self.input * 0.1
+    }
+}
+
+pub fn for_model(model: &str) -> Option<ModelPricing> {
+    let model = model.to_lowercase();
+    let (input, output) = if model.contains("opus-4-5") {
+        (5.0, 25.0)
+    } else if model.contains("opus") {
+        (15.0, 75.0)
+    } else if model.contains("sonnet") {
+        (3.0, 15.0)
+    } else if model.contains("haiku-4") {
+        (1.0, 5.0)
+    } else if model.contains("3-5-haiku") || model.contains("3.5-haiku") {
+        (0.8, 4.0)
+    } else if model.contains("haiku") {
+        (0.25, 1.25)
+    } else {
+        return None;
+    };
+    Some(ModelPricing { input, output })
+}
+
+// Estimated cost in USD, or None for models without known pricing
+pub fn cost(
+    model: &str,
+    input_tokens: u64,
+    output_tokens: u64,
+    cache_creation_tokens: u64,
+    cache_read_tokens: u64,
+) -> Option<f64> {
+    let pricing = for_model(model)?;
+    let per_token = |tokens: u64, price: f64| tokens as f64 * price / 1_000_000.0;
+    Some(
+        per_token(input_tokens, pricing.input)
+            + per_token(output_tokens, pricing.output)
+            + per_token(cache_creation_tokens, pricing.cache_write())
+            + per_token(cache_read_tokens, pricing.cache_read()),
+    )
+}
+
+// Images are resized to about 1.15 megapixels at most, which costs up to ~1600 tokens;
+// the upper bound is used since dimensions aren't decoded
+pub const IMAGE_TOKENS_ESTIMATE: u64 = 1600;
+
+// Rough token count for text that hasn't been sent yet (about four characters per token)
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+// The same for a request about to go out
+pub fn estimate_request_tokens(system_prompt: &str, messages: &[Message]) -> u64 {
+    let content = messages.iter().flat_map(|message| &message.content);
+    estimate_tokens(system_prompt)
+        + content
+            .map(|block| match block {
+                InputContent::Text { text } => estimate_tokens(text),
+                InputContent::ToolUse { input, .. } => estimate_tokens(&input.to_string()),
+                InputContent::ToolResult { content, .. } => estimate_tokens(content),
+                _ => IMAGE_TOKENS_ESTIMATE,
+            })
+            .sum::<u64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prices_every_kind_of_token() {
+        let total = cost(
+            "claude-sonnet-4-5",
+            1_000_000,
+            1_000_000,
+            1_000_000,
+            1_000_000,
+        )
+        .unwrap();
+        assert!((total - (3.0 + 15.0 + 3.75 + 0.3)).abs() < 1e-9);
+
+        // Newer families are matched before the older ones they'd otherwise fall into
+        assert_eq!(for_model("claude-opus-4-5").unwrap().input, 5.0);
+        assert_eq!(for_model("claude-3-opus-20240229").unwrap().input, 15.0);
+        assert_eq!(for_model("claude-haiku-4-5").unwrap().output, 5.0);
+        assert_eq!(for_model("claude-3-5-haiku-latest").unwrap().output, 4.0);
+        assert!(cost("gpt-4o", 1, 1, 1, 1).is_none());
+
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("héllo"), 2);
+    }
+}
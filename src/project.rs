@@ -0,0 +1,94 @@
+use ignore::WalkBuilder;
+use std::{path::Path, process::Command};
+
+// Entries listed before the tree is cut short; big repos would otherwise fill the context
+const MAX_TREE_ENTRIES: usize = 300;
+
+// The layout of `dir` (hidden and .gitignored files left out) followed by its git
+// branch and status, for /dir and --with-tree
+pub fn snapshot(dir: &Path) -> Result<String, String> {
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    let mut out = format!("Directory tree of {}:\n{}", dir.display(), tree(dir));
+    if let Some(status) = git_status(dir) {
+        out.push_str(&format!("\n\ngit status --short --branch:\n{}", status));
+    }
+    Ok(out)
+}
+
+fn tree(dir: &Path) -> String {
+    let walk = WalkBuilder::new(dir)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    let mut lines = Vec::new();
+    let mut skipped = 0;
+    // The first entry is `dir` itself
+    for entry in walk.filter_map(|entry| entry.ok()).skip(1) {
+        if lines.len() >= MAX_TREE_ENTRIES {
+            skipped += 1;
+            continue;
+        }
+        let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+        lines.push(format!(
+            "{}{}{}",
+            "  ".repeat(entry.depth() - 1),
+            entry.file_name().to_string_lossy(),
+            if is_dir { "/" } else { "" }
+        ));
+    }
+    if skipped > 0 {
+        lines.push(format!("… {} more entries", skipped));
+    }
+    if lines.is_empty() {
+        return "(empty)".to_string();
+    }
+    lines.join("\n")
+}
+
+// How a snapshot goes to the API: its own text block ahead of the question
+pub fn api_text(label: &str, content: &str) -> String {
+    format!(
+        "<context label=\"{}\">\n{}\n</context>",
+        label,
+        content.trim_end()
+    )
+}
+
+// None outside a git repository or without git installed
+fn git_status(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--short", "--branch"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn tree_skips_ignored_and_hidden_files() {
+        let dir = std::env::temp_dir().join(format!("agnt-project-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("src/main.rs"), "").unwrap();
+        fs::write(dir.join("target/out"), "").unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+
+        assert_eq!(tree(&dir), "src/\n  main.rs");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::process::Command;
+
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant. Your knowledge cut-off is March 2025. The current date and time is [DATE_TIME_WITH_WEEKDAY_AND_TIMEZONE]";
+
+type Resolver = fn() -> String;
+
+// Placeholders a system prompt may contain. Each value is only computed when its
+// placeholder actually appears, so a prompt opts into the context it wants to carry.
+const PLACEHOLDERS: &[(&str, Resolver)] = &[
+    ("[DATE_TIME_WITH_WEEKDAY_AND_TIMEZONE]", datetime),
+    ("[CWD]", cwd),
+    ("[OS]", os),
+    ("[USERNAME]", username),
+    ("[GIT_BRANCH]", git_branch),
+    ("[SHELL]", shell),
+];
+
+pub fn render(template: &str) -> String {
+    let mut prompt = template.to_string();
+    for (placeholder, value) in PLACEHOLDERS {
+        if prompt.contains(placeholder) {
+            prompt = prompt.replace(placeholder, &value());
+        }
+    }
+    prompt
+}
+
+fn datetime() -> String {
+    use chrono::{Datelike, Local, Timelike};
+
+    let now = Local::now();
+    let weekday = match now.weekday() {
+        chrono::Weekday::Mon => "Monday",
+        chrono::Weekday::Tue => "Tuesday",
+        chrono::Weekday::Wed => "Wednesday",
+        chrono::Weekday::Thu => "Thursday",
+        chrono::Weekday::Fri => "Friday",
+        chrono::Weekday::Sat => "Saturday",
+        chrono::Weekday::Sun => "Sunday",
+    };
+
+    let datetime_str = format!(
+        "{}, {} {}, {} at {:02}:{:02}:{:02} {}",
+        weekday,
+        now.format("%B"),
+        now.day(),
+        now.year(),
+        now.hour12().1,
+        now.minute(),
+        now.second(),
+        if now.hour12().0 { "PM" } else { "AM" }
+    );
+
+    let timezone = now.format("%Z").to_string();
+    format!("{} {}", datetime_str, timezone)
+}
+
+fn cwd() -> String {
+    std::env::current_dir()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn os() -> String {
+    format!("{} ({})", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+fn username() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn git_branch() -> String {
+    let output = Command::new("git")
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "(not a git repository)".to_string(),
+    }
+}
+
+fn shell() -> String {
+    std::env::var("SHELL")
+        .or_else(|_| std::env::var("COMSPEC"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
@@ -0,0 +1,99 @@
+use std::{collections::HashMap, process::Command};
+
+use crate::templates::{self, Template};
+
+// Diffs bigger than this are cut before they go into the request
+const MAX_DIFF_BYTES: usize = 200 * 1024;
+
+// Used unless ~/.agnt/prompts/review.md exists; {{range}} is what was diffed
+const DEFAULT_PROMPT: &str = "Review the change in the attached diff ({{range}}) as a careful senior engineer. \
+Look for bugs, security problems, missing error handling and confusing code; skip style nits a formatter would catch.
+
+Write each comment on its own line starting with the file and line it is about, as `path:line: comment`, \
+using line numbers from the new version of the file. Finish with a one-line verdict on whether the change is ready to commit.";
+
+// A change to review: what `git diff` was given, and its output
+pub struct Review {
+    pub label: String,
+    pub diff: String,
+}
+
+// Staged changes without arguments, otherwise `git diff <args>` (a range like
+// main..HEAD, optionally followed by `-- paths`)
+pub fn collect(args: &[String]) -> Result<Review, String> {
+    let args: Vec<String> = if args.is_empty() {
+        vec!["--cached".to_string()]
+    } else {
+        args.to_vec()
+    };
+    let label = format!("git diff {}", args.join(" "));
+    let output = Command::new("git")
+        .arg("diff")
+        .args(&args)
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            label,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let mut diff = String::from_utf8_lossy(&output.stdout).into_owned();
+    if diff.trim().is_empty() {
+        return Err(match args.as_slice() {
+            [cached] if cached == "--cached" => {
+                "No staged changes to review; stage some with `git add` or give a range such as main..HEAD".to_string()
+            }
+            _ => format!("{} is empty; nothing to review", label),
+        });
+    }
+    if diff.len() > MAX_DIFF_BYTES {
+        let end = diff[..MAX_DIFF_BYTES].rfind('\n').unwrap_or(0);
+        diff.truncate(end);
+        diff.push_str("\n[diff truncated]");
+    }
+    Ok(Review { label, diff })
+}
+
+impl Review {
+    // The review request, from the user's review template when there is one
+    pub fn prompt(&self) -> String {
+        let template = templates::load("review").unwrap_or_else(|_| Template {
+            name: "review".to_string(),
+            body: DEFAULT_PROMPT.to_string(),
+        });
+        let values = HashMap::from([("range".to_string(), self.label.clone())]);
+        template.render(&values)
+    }
+}
+
+// Where a `path:line: comment` line's anchor is, so it can be highlighted. List
+// markers and backticks around the anchor are allowed.
+pub fn anchor(line: &str) -> Option<std::ops::Range<usize>> {
+    let rest = line.trim_start_matches([' ', '-', '*', '`']);
+    let start = line.len() - rest.len();
+    let end = start + rest.find([' ', '`']).unwrap_or(rest.len());
+    let word = line[start..end].trim_end_matches(':');
+    let (path, lines) = word.rsplit_once(':')?;
+    let is_lines = lines
+        .split('-')
+        .all(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    let is_path = path.contains(['.', '/']) && !path.contains(':') && !path.contains("//");
+    (is_lines && is_path).then_some(start..start + word.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchors_are_found_at_line_starts() {
+        let line = "- `src/main.rs:42`: unwrap can panic";
+        assert_eq!(anchor(line).map(|r| &line[r]), Some("src/main.rs:42"));
+        let line = "README.md:3-5: typo";
+        assert_eq!(anchor(line).map(|r| &line[r]), Some("README.md:3-5"));
+        assert_eq!(anchor("Note: this looks fine"), None);
+        assert_eq!(anchor("See https://example.com:80 for details"), None);
+    }
+}
@@ -0,0 +1,537 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Value, json};
+use std::{fs, path::Path};
+
+use crate::{
+    anthropic::{self, AnthropicClient, ClientTool, InputContent, Message, StreamEvent},
+    config::Config,
+    exit_code::Failure,
+    usage,
+};
+
+// The tool every --schema answer is forced to call; its input is the structured answer
+pub const TOOL_NAME: &str = "respond";
+
+// A --schema file. Tool inputs must be objects, so any other schema is sent wrapped in
+// an object with a single `value` property, which is unwrapped again once it validates.
+pub struct Schema {
+    tool_schema: Value,
+    wrapped: bool,
+}
+
+impl Schema {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read schema {}", path.display()))?;
+        let schema: Value = serde_json::from_str(&text)
+            .map_err(|e| Failure::Usage(format!("{} is not JSON: {}", path.display(), e)))?;
+        Self::new(schema).map_err(|e| Failure::Usage(format!("{}: {}", path.display(), e)).into())
+    }
+
+    fn new(mut schema: Value) -> Result<Self, String> {
+        let Some(object) = schema.as_object_mut() else {
+            return Err("a schema must be a JSON object".to_string());
+        };
+        if object.get("type").and_then(Value::as_str) == Some("object") {
+            return Ok(Self {
+                tool_schema: schema,
+                wrapped: false,
+            });
+        }
+        // Definitions move up to the wrapper so "#/$defs/..." references still resolve
+        let mut wrapper = Map::new();
+        for key in ["$defs", "definitions"] {
+            if let Some(definitions) = object.remove(key) {
+                wrapper.insert(key.to_string(), definitions);
+            }
+        }
+        wrapper.insert("type".to_string(), json!("object"));
+        wrapper.insert("properties".to_string(), json!({ "value": schema }));
+        wrapper.insert("required".to_string(), json!(["value"]));
+        Ok(Self {
+            tool_schema: Value::Object(wrapper),
+            wrapped: true,
+        })
+    }
+
+    pub fn tool(&self) -> ClientTool {
+        ClientTool {
+            tool_type: None,
+            name: TOOL_NAME.to_string(),
+            description: "Give the answer as structured data matching the input schema."
+                .to_string(),
+            input_schema: self.tool_schema.clone(),
+        }
+    }
+
+    // The answer to print, or what is wrong with the tool input
+    pub fn check(&self, input: &Value) -> Result<Value, Vec<String>> {
+        let mut errors = Vec::new();
+        validate(
+            &self.tool_schema,
+            &self.tool_schema,
+            input,
+            "$",
+            &[],
+            &mut errors,
+        );
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(if self.wrapped {
+            input["value"].clone()
+        } else {
+            input.clone()
+        })
+    }
+}
+
+// Check `value` against the common JSON Schema keywords (types, enum/const, object and
+// array shapes, lengths, ranges, $ref and the combinators); anything else is left to
+// the model, which sees the whole schema. `following` holds the $refs taken without
+// moving on to a nested value, so a reference back to one of them is reported instead
+// of followed forever.
+fn validate<'a>(
+    schema: &'a Value,
+    root: &'a Value,
+    value: &Value,
+    path: &str,
+    following: &[&'a str],
+    errors: &mut Vec<String>,
+) {
+    let Some(schema) = schema.as_object() else {
+        if schema == &json!(false) {
+            errors.push(format!("{}: not allowed here", path));
+        }
+        return;
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+        {
+            _ if following.contains(&reference) => {
+                errors.push(format!("{}: circular $ref {}", path, reference))
+            }
+            Some(target) => {
+                let following = [following, &[reference]].concat();
+                validate(target, root, value, path, &following, errors)
+            }
+            None => errors.push(format!("{}: cannot resolve $ref {}", path, reference)),
+        }
+    }
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            errors.push(format!(
+                "{}: expected {}, got {}",
+                path,
+                types.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array)
+        && !options.contains(value)
+    {
+        errors.push(format!(
+            "{}: must be one of {}",
+            path,
+            Value::from(options.clone())
+        ));
+    }
+    if let Some(expected) = schema.get("const")
+        && expected != value
+    {
+        errors.push(format!("{}: must be {}", path, expected));
+    }
+
+    match value {
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for name in schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                if !object.contains_key(name) {
+                    errors.push(format!("{}: missing required property {:?}", path, name));
+                }
+            }
+            for (name, item) in object {
+                let item_path = format!("{}.{}", path, name);
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(property) => validate(property, root, item, &item_path, &[], errors),
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate(additional, root, item, &item_path, &[], errors);
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (n, item) in items.iter().enumerate() {
+                    validate(
+                        item_schema,
+                        root,
+                        item,
+                        &format!("{}[{}]", path, n),
+                        &[],
+                        errors,
+                    );
+                }
+            }
+            check_count(schema, "Items", items.len(), "items", path, errors);
+        }
+        Value::String(text) => {
+            check_count(
+                schema,
+                "Length",
+                text.chars().count(),
+                "characters",
+                path,
+                errors,
+            );
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+            if let Some(minimum) = bound("minimum")
+                && number < minimum
+            {
+                errors.push(format!("{}: must be at least {}", path, minimum));
+            }
+            if let Some(maximum) = bound("maximum")
+                && number > maximum
+            {
+                errors.push(format!("{}: must be at most {}", path, maximum));
+            }
+            if let Some(minimum) = bound("exclusiveMinimum")
+                && number <= minimum
+            {
+                errors.push(format!("{}: must be more than {}", path, minimum));
+            }
+            if let Some(maximum) = bound("exclusiveMaximum")
+                && number >= maximum
+            {
+                errors.push(format!("{}: must be less than {}", path, maximum));
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for sub in all {
+            validate(sub, root, value, path, following, errors);
+        }
+    }
+    for (key, exactly_one) in [("anyOf", false), ("oneOf", true)] {
+        let Some(options) = schema.get(key).and_then(Value::as_array) else {
+            continue;
+        };
+        let matching = options
+            .iter()
+            .filter(|option| {
+                let mut option_errors = Vec::new();
+                validate(option, root, value, path, following, &mut option_errors);
+                option_errors.is_empty()
+            })
+            .count();
+        if matching == 0 || (exactly_one && matching > 1) {
+            errors.push(format!(
+                "{}: must match {} of the {} options, matches {}",
+                path,
+                if exactly_one {
+                    "exactly one"
+                } else {
+                    "at least one"
+                },
+                key,
+                matching
+            ));
+        }
+    }
+}
+
+// minItems/maxItems and minLength/maxLength
+fn check_count(
+    schema: &Map<String, Value>,
+    keyword: &str,
+    count: usize,
+    unit: &str,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    let limit = |key: String| schema.get(&key).and_then(Value::as_u64);
+    if let Some(min) = limit(format!("min{}", keyword))
+        && (count as u64) < min
+    {
+        errors.push(format!(
+            "{}: needs at least {} {}, has {}",
+            path, min, unit, count
+        ));
+    }
+    if let Some(max) = limit(format!("max{}", keyword))
+        && count as u64 > max
+    {
+        errors.push(format!(
+            "{}: allows at most {} {}, has {}",
+            path, max, unit, count
+        ));
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        other => type_name(value) == other,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// Ask until an answer validates, sending the problems back as the tool result each time;
+// gives up with Failure::Schema after schema_retries corrections. Each correction is a
+// larger request of its own, so it has to fit the budgets too.
+pub async fn run(
+    client: &AnthropicClient,
+    system_prompt: &str,
+    mut messages: Vec<Message>,
+    schema: &Schema,
+    config: &Config,
+    session_id: &str,
+) -> Result<Value> {
+    let mut attempt = 0;
+    loop {
+        if attempt > 0
+            && let usage::BudgetCheck::Exceeded(reason) = usage::check_session_request(
+                config,
+                client.model(),
+                session_id,
+                system_prompt,
+                &messages,
+            )
+        {
+            return Err(Failure::Budget(format!(
+                "{} - no correction asked for; raise the limit with `agnt config set`",
+                reason
+            ))
+            .into());
+        }
+        let (mut receiver, _cancellation) = client
+            .send_message_stream(
+                messages.clone(),
+                Some(system_prompt.to_string()),
+                Default::default(),
+            )
+            .await?;
+        let mut text = String::new();
+        let mut call = None;
+        while let Some(event) = receiver.recv().await {
+            match event {
+                StreamEvent::Text(delta) => text.push_str(&delta),
+                StreamEvent::ToolUse { id, name, input } if name == TOOL_NAME => {
+                    call = Some((id, input))
+                }
+                StreamEvent::Metrics(metrics) => {
+                    if let Err(e) = usage::record(&usage::UsageRecord::new(session_id, &metrics)) {
+                        log_debug!("Failed to record usage: {}", e);
+                    }
+                }
+                StreamEvent::Error(error) => return Err(error.into()),
+                StreamEvent::Disconnected(reason) => {
+                    return Err(anthropic::ApiError {
+                        status: None,
+                        kind: "connection_error".to_string(),
+                        message: format!("Connection lost: {}", reason),
+                    }
+                    .into());
+                }
+                _ => {}
+            }
+        }
+
+        // Models that don't support forced tool calls (local ones) may answer in text
+        let (answer, problems) = match call {
+            Some((id, input)) => {
+                let parsed = serde_json::from_str::<Value>(&input);
+                let problems = match &parsed {
+                    Ok(value) => match schema.check(value) {
+                        Ok(value) => return Ok(value),
+                        Err(problems) => problems,
+                    },
+                    Err(e) => vec![format!("the input is not valid JSON: {}", e)],
+                };
+                let answer = InputContent::ToolUse {
+                    id,
+                    name: TOOL_NAME.to_string(),
+                    input: parsed.unwrap_or_else(|_| json!({})),
+                };
+                (answer, problems)
+            }
+            None => {
+                let problems = match serde_json::from_str::<Value>(strip_fence(&text)) {
+                    Ok(value) => match schema.check(&wrap(schema, value)) {
+                        Ok(value) => return Ok(value),
+                        Err(problems) => problems,
+                    },
+                    Err(_) => vec![format!("call the {} tool with the answer", TOOL_NAME)],
+                };
+                let answer = InputContent::Text {
+                    text: if text.trim().is_empty() {
+                        "(no answer)".to_string()
+                    } else {
+                        text
+                    },
+                };
+                (answer, problems)
+            }
+        };
+        if attempt >= config.schema_retries {
+            return Err(Failure::Schema(format!(
+                "Giving up after {} attempts. {}",
+                attempt + 1,
+                describe(&problems)
+            ))
+            .into());
+        }
+        // The problems go back as the result of the call, or as a reply to a text answer
+        let correction = match &answer {
+            InputContent::ToolUse { id, .. } => InputContent::ToolResult {
+                tool_use_id: id.clone(),
+                content: describe(&problems),
+                is_error: true,
+            },
+            _ => InputContent::Text {
+                text: describe(&problems),
+            },
+        };
+        attempt += 1;
+        eprintln!(
+            "(answer did not match the schema, retrying {}/{})",
+            attempt, config.schema_retries
+        );
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: vec![answer],
+        });
+        messages.push(Message {
+            role: "user".to_string(),
+            content: vec![correction],
+        });
+    }
+}
+
+fn describe(problems: &[String]) -> String {
+    let mut text = "The answer does not match the schema:".to_string();
+    for problem in problems {
+        text.push_str(&format!("\n- {}", problem));
+    }
+    text
+}
+
+// A JSON answer given as text, with or without a ``` fence around it
+fn strip_fence(text: &str) -> &str {
+    let text = text.trim();
+    text.strip_prefix("```json")
+        .or_else(|| text.strip_prefix("```"))
+        .and_then(|inner| inner.strip_suffix("```"))
+        .unwrap_or(text)
+        .trim()
+}
+
+fn wrap(schema: &Schema, value: Value) -> Value {
+    if schema.wrapped {
+        json!({ "value": value })
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_where_the_answer_differs() {
+        let schema = Schema::new(json!({
+            "type": "object",
+            "properties": {
+                "sentiment": { "enum": ["positive", "negative"] },
+                "score": { "type": "integer", "minimum": 1, "maximum": 5 },
+                "tags": { "type": "array", "items": { "$ref": "#/$defs/tag" } }
+            },
+            "required": ["sentiment", "score"],
+            "additionalProperties": false,
+            "$defs": { "tag": { "type": "string", "minLength": 2 } }
+        }))
+        .unwrap();
+        let good = json!({ "sentiment": "positive", "score": 4, "tags": ["ok"] });
+        assert_eq!(schema.check(&good), Ok(good.clone()));
+
+        let bad = json!({ "sentiment": "meh", "tags": ["x", 3], "extra": true });
+        let problems = schema.check(&bad).unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                "$: missing required property \"score\"",
+                "$.extra: not allowed here",
+                "$.sentiment: must be one of [\"positive\",\"negative\"]",
+                "$.tags[0]: needs at least 2 characters, has 1",
+                "$.tags[1]: expected string, got number",
+            ]
+        );
+
+        let list = Schema::new(json!({ "type": "array", "items": { "type": "number" } })).unwrap();
+        assert_eq!(
+            list.check(&json!({ "value": [1, 2.5] })),
+            Ok(json!([1, 2.5]))
+        );
+    }
+
+    #[test]
+    fn circular_refs_are_reported_not_followed() {
+        let itself = Schema::new(json!({ "type": "object", "$ref": "#" })).unwrap();
+        assert_eq!(
+            itself.check(&json!({})).unwrap_err(),
+            vec!["$: circular $ref #"]
+        );
+
+        let cycle = Schema::new(json!({
+            "type": "object",
+            "properties": { "a": { "$ref": "#/$defs/a" } },
+            "$defs": { "a": { "$ref": "#/$defs/b" }, "b": { "anyOf": [{ "$ref": "#/$defs/a" }] } }
+        }))
+        .unwrap();
+        assert!(cycle.check(&json!({ "a": 1 })).is_err());
+
+        // Recursion through nested values ends with the value itself
+        let tree = Schema::new(json!({
+            "type": "object",
+            "properties": { "children": { "type": "array", "items": { "$ref": "#" } } }
+        }))
+        .unwrap();
+        let value = json!({ "children": [{ "children": [] }, { "children": [{}] }] });
+        assert_eq!(tree.check(&value), Ok(value.clone()));
+    }
+}
@@ -0,0 +1,770 @@
+use anyhow::Result;
+use bytes::Bytes;
+use futures_util::stream;
+use http_body_util::{BodyExt, Full, Limited, StreamBody, combinators::BoxBody};
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    body::{Frame, Incoming},
+    header,
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+    anthropic::{
+        AnthropicClient, ApiError, InputContent, Message, Provider, RequestParams, StreamEvent,
+        TurnMetrics,
+    },
+    config::Config,
+    hooks, models, prompt, session, usage,
+};
+
+// Largest request body accepted, enough for a few images as data: URLs
+const MAX_BODY_BYTES: usize = 32 * 1024 * 1024;
+
+type Body = BoxBody<Bytes, Infallible>;
+
+// The parts of an OpenAI chat completion request agnt translates; anything else, like
+// `n` or `tools`, is ignored
+#[derive(Deserialize)]
+struct ChatRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    stream_options: Option<StreamOptions>,
+    #[serde(default)]
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    max_completion_tokens: Option<u32>, // The newer name for max_tokens
+    #[serde(default)]
+    temperature: Option<f64>,
+    #[serde(default)]
+    top_p: Option<f64>,
+    #[serde(default)]
+    stop: Option<Value>, // A string or a list of them
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Value, // A string or a list of text and image_url parts
+}
+
+#[derive(Deserialize)]
+struct StreamOptions {
+    #[serde(default)]
+    include_usage: bool,
+}
+
+// An OpenAI request as Anthropic messages, the system prompt its system messages make up
+// (if any) and sampling parameters
+fn translate(
+    request: &ChatRequest,
+) -> Result<(Vec<Message>, Option<String>, RequestParams), String> {
+    let mut system = Vec::new();
+    let mut messages: Vec<Message> = Vec::new();
+    for message in &request.messages {
+        let role = match message.role.as_str() {
+            "system" | "developer" => {
+                system.push(text_of(&message.content)?);
+                continue;
+            }
+            "user" | "assistant" => message.role.as_str(),
+            other => {
+                return Err(format!(
+                    "Messages with role {:?} aren't supported; agnt serve doesn't run client tools",
+                    other
+                ));
+            }
+        };
+        let content = content_of(&message.content)?;
+        if content.is_empty() {
+            continue;
+        }
+        // Roles have to alternate, so neighbours with the same one are joined
+        match messages.last_mut() {
+            Some(last) if last.role == role => last.content.extend(content),
+            _ => messages.push(Message {
+                role: role.to_string(),
+                content,
+            }),
+        }
+    }
+    if messages.is_empty() {
+        return Err("No user or assistant messages".to_string());
+    }
+
+    let stop_sequences = match &request.stop {
+        None | Some(Value::Null) => None,
+        Some(Value::String(stop)) => Some(vec![stop.clone()]),
+        Some(Value::Array(stops)) => Some(
+            stops
+                .iter()
+                .map(|stop| stop.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("stop must be a string or a list of strings")?,
+        ),
+        Some(_) => return Err("stop must be a string or a list of strings".to_string()),
+    };
+    let params = RequestParams {
+        model: request.model.as_deref().and_then(claude_model),
+        // OpenAI's range is 0 to 2 and Anthropic's 0 to 1
+        temperature: request.temperature.map(|t| t.min(1.0)),
+        top_p: request.top_p,
+        top_k: None,
+        stop_sequences,
+        max_tokens: request.max_completion_tokens.or(request.max_tokens),
+    };
+    params.validate().map_err(|e| e.to_string())?;
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    Ok((messages, system, params))
+}
+
+// Names of Claude models and agnt's aliases for them are passed on; anything else, like
+// the gpt-4o a tool sends by default, gets the configured model
+fn claude_model(name: &str) -> Option<String> {
+    let model = models::resolve(name);
+    model.contains("claude").then_some(model)
+}
+
+fn text_of(content: &Value) -> Result<String, String> {
+    Ok(content_of(content)?
+        .into_iter()
+        .filter_map(|part| match part {
+            InputContent::Text { text } => Some(text),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn content_of(content: &Value) -> Result<Vec<InputContent>, String> {
+    let parts = match content {
+        Value::Null => return Ok(Vec::new()),
+        Value::String(text) => return Ok(text_part(text).into_iter().collect()),
+        Value::Array(parts) => parts,
+        _ => return Err("Message content must be a string or a list of parts".to_string()),
+    };
+    let mut content = Vec::new();
+    for part in parts {
+        match part.get("type").and_then(Value::as_str) {
+            Some("text") => {
+                let text = part.get("text").and_then(Value::as_str).unwrap_or_default();
+                content.extend(text_part(text));
+            }
+            Some("image_url") => {
+                let url = part
+                    .pointer("/image_url/url")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                content.push(data_url_image(url)?);
+            }
+            other => return Err(format!("Unsupported content part {:?}", other)),
+        }
+    }
+    Ok(content)
+}
+
+// The API rejects empty text blocks
+fn text_part(text: &str) -> Option<InputContent> {
+    (!text.is_empty()).then(|| InputContent::Text {
+        text: text.to_string(),
+    })
+}
+
+// Only inline images, as fetching URLs on a client's behalf isn't this server's business
+fn data_url_image(url: &str) -> Result<InputContent, String> {
+    let invalid = || "Images must be base64 data: URLs".to_string();
+    let rest = url.strip_prefix("data:").ok_or_else(invalid)?;
+    let (media_type, data) = rest.split_once(";base64,").ok_or_else(invalid)?;
+    Ok(InputContent::base64_image(media_type, data.to_string()))
+}
+
+fn finish_reason(stop_reason: Option<&str>) -> &'static str {
+    match stop_reason {
+        Some("max_tokens") => "length",
+        Some("refusal") => "content_filter",
+        _ => "stop",
+    }
+}
+
+fn usage_json(metrics: &TurnMetrics) -> Value {
+    let prompt_tokens =
+        metrics.input_tokens + metrics.cache_creation_tokens + metrics.cache_read_tokens;
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": metrics.output_tokens,
+        "total_tokens": prompt_tokens + metrics.output_tokens,
+    })
+}
+
+// Code execution has no place in an OpenAI answer, so it goes into the text as fenced blocks
+fn code_text(event: &StreamEvent) -> Option<String> {
+    match event {
+        StreamEvent::CodeInput(code) => {
+            Some(format!("\n\n```python\n{}\n```\n\n", code.trim_end()))
+        }
+        StreamEvent::CodeOutput { stdout, stderr, .. } => {
+            let output = format!("{}{}", stdout, stderr);
+            Some(format!("```\n{}\n```\n\n", output.trim_end()))
+        }
+        StreamEvent::CodeError(error) => Some(format!("(code execution failed: {})\n\n", error)),
+        _ => None,
+    }
+}
+
+fn full(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())).boxed())
+        .unwrap()
+}
+
+// Errors in OpenAI's shape, so clients show the message
+fn error_json(message: &str, kind: &str) -> Value {
+    json!({ "error": { "message": message, "type": kind, "code": Value::Null } })
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    full(status, error_json(message, "invalid_request_error"))
+}
+
+fn api_error(error: &ApiError) -> Response<Body> {
+    let status = error
+        .status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::BAD_GATEWAY);
+    full(status, error_json(&error.message, &error.kind))
+}
+
+// Turn away what a web page or another local user could send: a page can POST plain
+// text without a CORS preflight, and can reach us under its own domain through DNS
+// rebinding. So bodies must be declared JSON, Host must name this machine by address
+// (or any address when listening beyond loopback), and a configured token must match.
+fn screen(
+    headers: &HeaderMap,
+    has_body: bool,
+    token: Option<&str>,
+    public: bool,
+) -> Result<(), (StatusCode, &'static str)> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let host = header(header::HOST).map(host_name);
+    let trusted = match host {
+        Some("localhost") => true,
+        Some(name) => name
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| public || ip.is_loopback()),
+        None => false,
+    };
+    if !trusted {
+        return Err((StatusCode::FORBIDDEN, "Host must be a loopback address"));
+    }
+    if let Some(token) = token
+        && header(header::AUTHORIZATION).and_then(|value| value.strip_prefix("Bearer "))
+            != Some(token)
+    {
+        return Err((StatusCode::UNAUTHORIZED, "Missing or wrong API key"));
+    }
+    let json_body = header(header::CONTENT_TYPE).is_some_and(|value| {
+        let media_type = value.split(';').next().unwrap_or_default();
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    });
+    if has_body && !json_body {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/json",
+        ));
+    }
+    Ok(())
+}
+
+// "127.0.0.1:8080" -> "127.0.0.1", "[::1]:8080" -> "::1"
+fn host_name(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or_default();
+    }
+    host.split(':').next().unwrap_or_default()
+}
+
+struct Server {
+    client: AnthropicClient,
+    config: Config,
+    session_id: String, // Usage from every request is recorded under one session per run
+    requests: AtomicU64,
+    token: Option<String>, // Bearer token clients have to send, if any
+    public: bool,          // Listening beyond loopback
+}
+
+impl Server {
+    async fn handle(
+        self: Arc<Self>,
+        request: Request<Incoming>,
+    ) -> Result<Response<Body>, Infallible> {
+        log_debug!("Serve {} {}", request.method(), request.uri().path());
+        let has_body = request.method() == Method::POST;
+        if let Err((status, message)) = screen(
+            request.headers(),
+            has_body,
+            self.token.as_deref(),
+            self.public,
+        ) {
+            log_debug!("Refused a request: {}", message);
+            return Ok(error(status, message));
+        }
+        let path = request.uri().path().trim_end_matches('/').to_string();
+        Ok(match (request.method(), path.as_str()) {
+            (&Method::POST, "/v1/chat/completions") => self.chat(request).await,
+            (&Method::GET, "/v1/models") => self.models().await,
+            _ => error(
+                StatusCode::NOT_FOUND,
+                "Only /v1/chat/completions and /v1/models are served",
+            ),
+        })
+    }
+
+    async fn models(&self) -> Response<Body> {
+        let mut ids = Vec::new();
+        if matches!(self.client.provider(), Provider::Anthropic)
+            && let Ok(available) = self.client.list_models().await
+        {
+            ids.extend(available.into_iter().map(|model| model.id));
+        }
+        if ids.is_empty() {
+            ids.push(self.client.model().to_string());
+        }
+        let data: Vec<Value> = ids
+            .into_iter()
+            .map(|id| json!({ "id": id, "object": "model", "owned_by": "anthropic" }))
+            .collect();
+        full(StatusCode::OK, json!({ "object": "list", "data": data }))
+    }
+
+    async fn chat(&self, request: Request<Incoming>) -> Response<Body> {
+        let body = match Limited::new(request.into_body(), MAX_BODY_BYTES)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let request: ChatRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+        let (mut messages, system, params) = match translate(&request) {
+            Ok(translated) => translated,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e),
+        };
+        // A client's own system prompt wins over the configured one
+        let mut system_prompt = system.unwrap_or_else(|| {
+            prompt::render(
+                self.config
+                    .system_prompt
+                    .as_deref()
+                    .unwrap_or(prompt::DEFAULT_SYSTEM_PROMPT),
+            )
+        });
+        hooks::apply(
+            &self.config.context_hooks,
+            &mut system_prompt,
+            &mut messages,
+        )
+        .await;
+
+        let model = params
+            .model
+            .clone()
+            .unwrap_or_else(|| self.client.model().to_string());
+        let spent = match self.config.session_budget_usd {
+            Some(_) => usage::spent_in_session(&self.session_id),
+            None => 0.0,
+        };
+        match usage::check_request(&self.config, &model, spent, &system_prompt, &messages) {
+            usage::BudgetCheck::Within => {}
+            usage::BudgetCheck::Warning(warning) => eprintln!("Warning: {}", warning),
+            usage::BudgetCheck::Exceeded(reason) => {
+                return full(
+                    StatusCode::PAYMENT_REQUIRED,
+                    error_json(&format!("{} - request not sent", reason), "budget_exceeded"),
+                );
+            }
+        }
+        let (mut receiver, cancellation) = match self
+            .client
+            .send_message_stream(messages, Some(system_prompt), params)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => return error(StatusCode::BAD_GATEWAY, &e.to_string()),
+        };
+        // Wait for the answer to start, so a request the API turns down gets its status
+        let first = loop {
+            match receiver.recv().await {
+                Some(
+                    StreamEvent::ConnectionStatus(_)
+                    | StreamEvent::Stalled(_)
+                    | StreamEvent::StallCleared,
+                ) => continue,
+                event => break event,
+            }
+        };
+        if let Some(StreamEvent::Error(e)) = &first {
+            return api_error(e);
+        }
+
+        let completion = Completion {
+            id: format!(
+                "chatcmpl-{}-{}",
+                self.session_id,
+                self.requests.fetch_add(1, Ordering::Relaxed)
+            ),
+            created: chrono::Utc::now().timestamp(),
+            model,
+            session_id: self.session_id.clone(),
+        };
+        if !request.stream {
+            let mut events: Vec<StreamEvent> = first.into_iter().collect();
+            while let Some(event) = receiver.recv().await {
+                events.push(event);
+            }
+            return completion.whole(events);
+        }
+
+        let include_usage = request.stream_options.is_some_and(|o| o.include_usage);
+        let (sender, chunks) = mpsc::channel::<Bytes>(64);
+        tokio::spawn(async move {
+            // A client that hangs up stops the answer
+            if !completion
+                .stream(first, receiver, include_usage, &sender)
+                .await
+            {
+                cancellation.cancel();
+            }
+        });
+        let body = StreamBody::new(stream::unfold(chunks, |mut chunks| async move {
+            chunks
+                .recv()
+                .await
+                .map(|chunk| (Ok::<_, Infallible>(Frame::data(chunk)), chunks))
+        }));
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(BodyExt::boxed(body))
+            .unwrap()
+    }
+}
+
+struct Completion {
+    id: String,
+    created: i64,
+    model: String,
+    session_id: String,
+}
+
+impl Completion {
+    fn record(&self, metrics: &TurnMetrics) {
+        if let Err(e) = usage::record(&usage::UsageRecord::new(&self.session_id, metrics)) {
+            log_debug!("Failed to record usage: {}", e);
+        }
+    }
+
+    // The answer as one chat.completion
+    fn whole(&self, events: Vec<StreamEvent>) -> Response<Body> {
+        let mut content = String::new();
+        let mut metrics = None;
+        for event in events {
+            match event {
+                StreamEvent::Text(text) => content.push_str(&text),
+                StreamEvent::Metrics(turn) => {
+                    self.record(&turn);
+                    metrics = Some(turn);
+                }
+                StreamEvent::Error(e) => return api_error(&e),
+                StreamEvent::Disconnected(reason) => {
+                    return error(
+                        StatusCode::BAD_GATEWAY,
+                        &format!("Connection lost: {}", reason),
+                    );
+                }
+                event => content.extend(code_text(&event)),
+            }
+        }
+        let stop_reason = metrics.as_ref().and_then(|m| m.stop_reason.as_deref());
+        let mut body = json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": metrics.as_ref().map_or(self.model.as_str(), |m| m.model.as_str()),
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": finish_reason(stop_reason),
+            }],
+        });
+        if let Some(metrics) = &metrics {
+            body["usage"] = usage_json(metrics);
+        }
+        full(StatusCode::OK, body)
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Bytes {
+        self.event(json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        }))
+    }
+
+    fn event(&self, data: Value) -> Bytes {
+        Bytes::from(format!("data: {}\n\n", data))
+    }
+
+    // The answer as server-sent chat.completion.chunk events; false if the client went away
+    async fn stream(
+        &self,
+        mut first: Option<StreamEvent>,
+        mut receiver: mpsc::Receiver<StreamEvent>,
+        include_usage: bool,
+        sender: &mpsc::Sender<Bytes>,
+    ) -> bool {
+        let role = self.chunk(json!({ "role": "assistant", "content": "" }), None);
+        if sender.send(role).await.is_err() {
+            return false;
+        }
+        let mut metrics = None;
+        while let Some(event) = match first.take() {
+            Some(event) => Some(event),
+            None => receiver.recv().await,
+        } {
+            let chunk = match event {
+                StreamEvent::Text(text) => self.chunk(json!({ "content": text }), None),
+                StreamEvent::Metrics(turn) => {
+                    self.record(&turn);
+                    metrics = Some(turn);
+                    continue;
+                }
+                // Too late for a status, so errors arrive the way OpenAI streams them
+                StreamEvent::Error(e) => self.event(error_json(&e.message, &e.kind)),
+                StreamEvent::Disconnected(reason) => self.event(error_json(
+                    &format!("Connection lost: {}", reason),
+                    "connection_error",
+                )),
+                event => match code_text(&event) {
+                    Some(text) => self.chunk(json!({ "content": text }), None),
+                    None => continue,
+                },
+            };
+            if sender.send(chunk).await.is_err() {
+                return false;
+            }
+        }
+        let mut tail = Vec::new();
+        if let Some(metrics) = &metrics {
+            tail.push(self.chunk(
+                json!({}),
+                Some(finish_reason(metrics.stop_reason.as_deref())),
+            ));
+            if include_usage {
+                tail.push(self.event(json!({
+                    "id": self.id,
+                    "object": "chat.completion.chunk",
+                    "created": self.created,
+                    "model": metrics.model,
+                    "choices": [],
+                    "usage": usage_json(metrics),
+                })));
+            }
+        }
+        tail.push(Bytes::from_static(b"data: [DONE]\n\n"));
+        for chunk in tail {
+            if sender.send(chunk).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// `agnt serve`: answer OpenAI-style requests until interrupted
+pub async fn run(
+    client: AnthropicClient,
+    config: Config,
+    address: SocketAddr,
+    token: Option<String>,
+) -> Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", address, e))?;
+    eprintln!(
+        "Serving an OpenAI-compatible API at http://{}/v1; press Ctrl+C to stop",
+        address
+    );
+    let server = Arc::new(Server {
+        client,
+        config,
+        session_id: session::new_session_id(),
+        requests: AtomicU64::new(0),
+        token,
+        public: !address.ip().is_loopback(),
+    });
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |request| server.clone().handle(request));
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        log_debug!("Connection from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openai_requests_become_anthropic_messages() {
+        let request: ChatRequest = serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "temperature": 1.5,
+            "stop": "END",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hi" },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBOR" } },
+                ]},
+                { "role": "assistant", "content": "" },
+                { "role": "assistant", "content": "A logo." },
+            ],
+        }))
+        .unwrap();
+        let (messages, system, params) = translate(&request).unwrap();
+
+        assert_eq!(system.as_deref(), Some("Be brief."));
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant"]);
+        assert_eq!(messages[0].content.len(), 3);
+        assert!(matches!(
+            &messages[0].content[2],
+            InputContent::Image { source } if source.media_type == "image/png"
+        ));
+        assert_eq!(params.model, None);
+        assert_eq!(params.temperature, Some(1.0));
+        assert_eq!(params.stop_sequences, Some(vec!["END".to_string()]));
+
+        let remote: ChatRequest = serde_json::from_value(json!({
+            "messages": [{ "role": "user", "content": [
+                { "type": "image_url", "image_url": { "url": "https://example.com/a.png" } },
+            ]}],
+        }))
+        .unwrap();
+        assert!(translate(&remote).is_err());
+    }
+
+    #[test]
+    fn requests_a_browser_could_forge_are_refused() {
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name.clone(), value.parse().unwrap());
+            }
+            headers
+        };
+        let json = (header::CONTENT_TYPE, "application/json; charset=utf-8");
+        let local = (header::HOST, "127.0.0.1:8080");
+        let status = |headers: &HeaderMap, token, public| {
+            screen(headers, true, token, public)
+                .err()
+                .map(|(status, _)| status)
+        };
+
+        assert_eq!(
+            status(&headers(&[json.clone(), local.clone()]), None, false),
+            None
+        );
+        assert_eq!(
+            status(
+                &headers(&[json.clone(), (header::HOST, "[::1]:8080")]),
+                None,
+                false
+            ),
+            None
+        );
+        // A page posting text/plain, or reaching us through its own rebound domain
+        assert_eq!(
+            status(
+                &headers(&[(header::CONTENT_TYPE, "text/plain"), local.clone()]),
+                None,
+                false
+            ),
+            Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+        assert_eq!(
+            status(
+                &headers(&[json.clone(), (header::HOST, "evil.example:8080")]),
+                None,
+                true
+            ),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(
+                &headers(&[json.clone(), (header::HOST, "192.168.1.5:8080")]),
+                None,
+                false
+            ),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(
+                &headers(&[json.clone(), (header::HOST, "192.168.1.5:8080")]),
+                None,
+                true
+            ),
+            None
+        );
+
+        let with_key = headers(&[
+            json.clone(),
+            local.clone(),
+            (header::AUTHORIZATION, "Bearer s3cret"),
+        ]);
+        assert_eq!(status(&with_key, Some("s3cret"), false), None);
+        assert_eq!(
+            status(&headers(&[json, local]), Some("s3cret"), false),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use crate::{
+    anthropic, compact, fuzzy, mentions, project,
+    store::HistoryStore,
+    tools,
+    ui::{Alternatives, App, MessageContent},
+};
+
+// zstd level used for archive frames
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub created_at: String, // RFC 3339
+    pub updated_at: String, // RFC 3339
+    pub messages: Vec<(String, Vec<MessageContent>)>,
+    #[serde(default)]
+    pub alternatives: Option<Alternatives>, // Answers regenerated with /retry
+}
+
+impl Session {
+    pub fn from_app(app: &App) -> Self {
+        Self {
+            id: app.session_id.clone(),
+            created_at: app.session_created_at.clone(),
+            updated_at: Local::now().to_rfc3339(),
+            messages: app.messages.clone(),
+            alternatives: app.alternatives.clone(),
+        }
+    }
+
+    // Start of the first prompt, for listings
+    pub fn opening(&self) -> String {
+        self.messages
+            .iter()
+            .find(|(role, _)| role == "user")
+            .and_then(|(_, contents)| {
+                contents.iter().find_map(|content| match content {
+                    MessageContent::Text(text) => Some(text.lines().next().unwrap_or("")),
+                    _ => None,
+                })
+            })
+            .unwrap_or("")
+            .chars()
+            .take(60)
+            .collect()
+    }
+}
+
+// Saved turns as API messages, leaving out what is only shown in the conversation
+pub fn api_messages(conversation: &[(String, Vec<MessageContent>)]) -> Vec<anthropic::Message> {
+    let mut messages = vec![];
+    for (role, contents) in conversation {
+        // Compacted turns go back as their summary
+        if role == "system" {
+            for content in contents {
+                if let MessageContent::Summary(summary) = content {
+                    messages.push(anthropic::Message::text(
+                        "user",
+                        compact::summary_message(summary),
+                    ));
+                }
+            }
+        } else {
+            // Convert MessageContent back to text (and attached images) for the API,
+            // along with calls to local tools and their results
+            let mut images = Vec::new();
+            let mut files = Vec::new();
+            let mut tool_blocks = Vec::new();
+            let mut text_content = String::new();
+            for content in contents {
+                match content {
+                    MessageContent::Text(text) => {
+                        text_content.push_str(text);
+                    }
+                    MessageContent::Image {
+                        media_type, data, ..
+                    } => {
+                        images.push(anthropic::InputContent::base64_image(
+                            media_type,
+                            data.clone(),
+                        ));
+                    }
+                    MessageContent::Document {
+                        media_type, data, ..
+                    } => {
+                        images.push(anthropic::InputContent::base64_document(
+                            media_type,
+                            data.clone(),
+                        ));
+                    }
+                    MessageContent::Upload { file_id, .. } => {
+                        images.push(anthropic::InputContent::ContainerUpload {
+                            file_id: file_id.clone(),
+                        });
+                    }
+                    MessageContent::File { path, content, .. } => {
+                        files.push(anthropic::InputContent::Text {
+                            text: mentions::api_text(path, content),
+                        });
+                    }
+                    MessageContent::Context { label, content } => {
+                        files.push(anthropic::InputContent::Text {
+                            text: project::api_text(label, content),
+                        });
+                    }
+                    MessageContent::ToolUse { .. } | MessageContent::ToolResult { .. } => {
+                        tool_blocks.extend(tools::api_block(content));
+                    }
+                    _ => {
+                        // Code results, notes and API errors are display-only and
+                        // never go back to the API
+                    }
+                }
+            }
+            let mut message = anthropic::Message {
+                role: role.clone(),
+                content: Vec::new(),
+            };
+            if !text_content.is_empty() {
+                message.content = images;
+                message.content.extend(files);
+                message
+                    .content
+                    .push(anthropic::InputContent::Text { text: text_content });
+            }
+            // Tool results lead their turn; tool calls end the answer that made them
+            if role == "user" {
+                message.content.splice(0..0, tool_blocks);
+            } else {
+                message.content.extend(tool_blocks);
+            }
+            if !message.content.is_empty() {
+                messages.push(message);
+            }
+        }
+    }
+    messages
+}
+
+// Indices of the sessions matching `query` (fuzzy, against the opening prompt, id and
+// date), best match first; all of them in their original order for an empty query
+pub fn search(sessions: &[Session], query: &str) -> Vec<usize> {
+    let mut scored: Vec<(i64, usize)> = sessions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, session)| {
+            let haystack = format!(
+                "{} {} {}",
+                session.opening(),
+                session.id,
+                session.updated_at.get(..10).unwrap_or_default()
+            );
+            fuzzy::score(query.trim(), &haystack).map(|(score, _)| (score, i))
+        })
+        .collect();
+    scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    scored.into_iter().map(|(_, i)| i).collect()
+}
+
+// Full body of a code output that was moved out of memory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpilledOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+pub fn new_session_id() -> String {
+    format!(
+        "{}-{:04x}",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        std::process::id() & 0xffff
+    )
+}
+
+pub fn sessions_dir() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("sessions")
+}
+
+pub fn archive_path() -> PathBuf {
+    sessions_dir().join("archive.pack.zst")
+}
+
+pub fn save(session: &Session) -> Result<()> {
+    HistoryStore::open()?.save_session(session)
+}
+
+pub fn save_blob(blob_id: &str, body: &SpilledOutput) -> Result<()> {
+    HistoryStore::open()?.save_blob(blob_id, body)
+}
+
+pub fn load_blob(blob_id: &str) -> Result<SpilledOutput> {
+    HistoryStore::open()?.load_blob(blob_id)
+}
+
+// Load a saved session by id, or the most recently updated one for "last"
+pub fn load(id: &str) -> Result<Session> {
+    HistoryStore::open()?.load_session(id)
+}
+
+// Saved (not archived) sessions, most recently updated first
+pub fn list() -> Vec<Session> {
+    match HistoryStore::open().and_then(|store| store.list_sessions()) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            log_debug!("Failed to list sessions: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Move sessions that haven't been updated for `older_than_days` days out of the
+// database into the archive pack. Each archived session is appended as its own zstd
+// frame holding one JSON line, so the pack can be extended without rewriting it and
+// still decodes as a single stream.
+pub fn archive_old_sessions(older_than_days: u32) -> Result<usize> {
+    let store = HistoryStore::open()?;
+    let cutoff = Local::now() - chrono::Duration::days(older_than_days as i64);
+    let old: Vec<Session> = store
+        .list_sessions()?
+        .into_iter()
+        .filter(|session| {
+            DateTime::parse_from_rfc3339(&session.updated_at)
+                .map(|updated| updated < cutoff)
+                .unwrap_or(false)
+        })
+        .collect();
+    if old.is_empty() {
+        return Ok(0);
+    }
+
+    let archive = archive_path();
+    fs::create_dir_all(sessions_dir())?;
+    let mut pack = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&archive)?;
+    for session in &old {
+        let mut line = serde_json::to_vec(session)?;
+        line.push(b'\n');
+        pack.write_all(&zstd::encode_all(line.as_slice(), COMPRESSION_LEVEL)?)?;
+        pack.flush()?;
+        store.delete_session(&session.id)?;
+    }
+
+    log_debug!("Archived {} sessions into {}", old.len(), archive.display());
+    Ok(old.len())
+}
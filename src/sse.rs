@@ -0,0 +1,196 @@
+// Incremental parser for the server-sent events the Messages API streams. Bytes are
+// buffered until a whole line has arrived, so multi-byte characters split across
+// network chunks decode correctly; lines may end in LF or CRLF.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>, // The `event:` field, e.g. "content_block_delta" or "ping"
+    pub data: String,          // `data:` lines joined with newlines
+}
+
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Option<String>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Feed the next chunk and take the events it completes. An event still missing its
+    // blank line stays buffered; at the end of the stream it is dropped, as the spec says.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        let mut start = 0;
+        while let Some(len) = self.buffer[start..].iter().position(|&b| b == b'\n') {
+            let mut line = &self.buffer[start..start + len];
+            start += len + 1;
+            if let Some(stripped) = line.strip_suffix(b"\r") {
+                line = stripped;
+            }
+            let line = match std::str::from_utf8(line) {
+                Ok(line) => line.to_string(),
+                Err(e) => {
+                    log_debug!("Invalid UTF-8 in stream line: {}", e);
+                    String::from_utf8_lossy(line).into_owned()
+                }
+            };
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+        }
+        self.buffer.drain(..start);
+        events
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            // A blank line ends the event; one without data isn't dispatched
+            let event = self.event.take();
+            return self.data.take().map(|data| SseEvent { event, data });
+        }
+        if line.starts_with(':') {
+            return None; // Comment, used as a keep-alive
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            },
+            // id and retry only matter to clients that reconnect on their own
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT_STREAM: &[u8] = include_bytes!("../tests/fixtures/text_stream.sse");
+    const CODE_STREAM_CRLF: &[u8] = include_bytes!("../tests/fixtures/code_stream_crlf.sse");
+
+    fn parse_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut parser = SseParser::new();
+        chunks.iter().flat_map(|chunk| parser.push(chunk)).collect()
+    }
+
+    fn event_names(events: &[SseEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| event.event.as_deref().unwrap_or(""))
+            .collect()
+    }
+
+    #[test]
+    fn parses_recorded_text_stream() {
+        let events = parse_all(&[TEXT_STREAM]);
+        assert_eq!(
+            event_names(&events),
+            [
+                "message_start",
+                "content_block_start",
+                "ping",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert_eq!(
+            events[3].data,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Héllo"}}"#
+        );
+    }
+
+    #[test]
+    fn same_events_for_any_chunking() {
+        let whole = parse_all(&[TEXT_STREAM]);
+        for size in [1, 2, 3, 7, 64] {
+            let chunks: Vec<&[u8]> = TEXT_STREAM.chunks(size).collect();
+            assert_eq!(parse_all(&chunks), whole, "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn multibyte_character_split_across_chunks() {
+        let bytes = "data: ☃ snow\n\n".as_bytes();
+        let split = bytes.iter().position(|&b| b == 0xe2).unwrap() + 1;
+        let events = parse_all(&[&bytes[..split], &bytes[split..]]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "☃ snow");
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let events = parse_all(&[CODE_STREAM_CRLF]);
+        assert_eq!(
+            event_names(&events),
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "message_stop"
+            ]
+        );
+        assert!(events.iter().all(|event| !event.data.contains('\r')));
+        assert!(events[4].data.contains(r#""return_code":0"#));
+    }
+
+    #[test]
+    fn comments_and_unknown_fields_are_ignored() {
+        let events = parse_all(&[b": keep-alive\nid: 7\nretry: 100\nevent: ping\ndata: {}\n\n"]);
+        assert_eq!(
+            events,
+            [SseEvent {
+                event: Some("ping".to_string()),
+                data: "{}".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn multiple_data_lines_are_joined() {
+        let events = parse_all(&[b"data: first\ndata:second\ndata\n\n"]);
+        assert_eq!(events[0].data, "first\nsecond\n");
+        assert_eq!(events[0].event, None);
+    }
+
+    #[test]
+    fn blank_lines_without_data_dispatch_nothing() {
+        assert!(parse_all(&[b"\n\nevent: ping\n\n"]).is_empty());
+    }
+
+    #[test]
+    fn incomplete_event_stays_buffered() {
+        let mut parser = SseParser::new();
+        assert!(
+            parser
+                .push(b"event: message_stop\ndata: {\"type\"")
+                .is_empty()
+        );
+        let events = parser.push(b":\"message_stop\"}\n\n");
+        assert_eq!(events[0].data, r#"{"type":"message_stop"}"#);
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced_not_dropped() {
+        let events = parse_all(&[b"data: a\xffb\n\n"]);
+        assert_eq!(events[0].data, "a\u{fffd}b");
+    }
+}
@@ -0,0 +1,479 @@
+use anyhow::{Result, anyhow};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use serde::{Deserialize, de::DeserializeOwned};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    session::{self, Session, SpilledOutput},
+    ui::MessageContent,
+    usage::UsageRecord,
+};
+
+// Bumped whenever the schema changes; version 1 also imports the old per-file history
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    alternatives TEXT
+);
+CREATE TABLE IF NOT EXISTS messages (
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (session_id, position)
+);
+CREATE TABLE IF NOT EXISTS tool_results (
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    code TEXT,
+    stdout TEXT,
+    stderr TEXT,
+    return_code INTEGER,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS tool_results_session ON tool_results(session_id);
+CREATE TABLE IF NOT EXISTS blobs (
+    id TEXT PRIMARY KEY,
+    stdout TEXT NOT NULL,
+    stderr TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS usage (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    session_id TEXT NOT NULL,
+    model TEXT NOT NULL,
+    input_tokens INTEGER NOT NULL,
+    output_tokens INTEGER NOT NULL,
+    cache_creation_tokens INTEGER NOT NULL,
+    cache_read_tokens INTEGER NOT NULL,
+    cost_usd REAL
+);
+CREATE INDEX IF NOT EXISTS usage_timestamp ON usage(timestamp);
+CREATE TABLE IF NOT EXISTS artifacts (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    session_id TEXT,
+    file_id TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    path TEXT NOT NULL,
+    action TEXT NOT NULL
+);
+";
+
+// A file created by code execution and what happened when it was saved locally
+#[derive(Debug, Clone, Deserialize)]
+pub struct Artifact {
+    pub timestamp: String, // RFC 3339
+    #[serde(default)]
+    pub session_id: Option<String>,
+    pub file_id: String,
+    pub filename: String,
+    pub path: String,
+    pub action: String, // saved, renamed, overwritten or skipped
+}
+
+// Conversations, tool results, usage and downloaded files in ~/.agnt/agnt.db, shared
+// by the TUI and pipe mode. Opening is cheap, so callers open it per operation.
+pub struct HistoryStore {
+    conn: Connection,
+}
+
+pub fn db_path() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("agnt.db")
+}
+
+impl HistoryStore {
+    pub fn open() -> Result<Self> {
+        let path = db_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(&path)?;
+        // Several agnt processes may write at once; wait for each other instead of failing
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let mut store = Self { conn };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let version: i32 = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another process may have migrated while this one waited for the lock
+        let version: i32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < SCHEMA_VERSION {
+            tx.execute_batch(SCHEMA)?;
+            if version == 0 {
+                import_legacy(&tx)?;
+            }
+            tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Replace the stored copy of the session, including its tool results
+    pub fn save_session(&mut self, session: &Session) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        write_session(&tx, session)?;
+        tx.commit()?;
+        log_debug!("Saved session {} to {}", session.id, db_path().display());
+        Ok(())
+    }
+
+    // A saved session by id, or the most recently updated one for "last"
+    pub fn load_session(&self, id: &str) -> Result<Session> {
+        let row = if id == "last" {
+            self.conn
+                .query_row(
+                    "SELECT id, created_at, updated_at, alternatives FROM sessions
+                     ORDER BY updated_at DESC LIMIT 1",
+                    [],
+                    session_row,
+                )
+                .optional()?
+                .ok_or_else(|| anyhow!("No saved sessions in {}", db_path().display()))?
+        } else {
+            self.conn
+                .query_row(
+                    "SELECT id, created_at, updated_at, alternatives FROM sessions WHERE id = ?1",
+                    [id],
+                    session_row,
+                )
+                .optional()?
+                .ok_or_else(|| anyhow!("No session named {}", id))?
+        };
+        self.with_messages(row)
+    }
+
+    // Every saved (not archived) session, most recently updated first
+    pub fn list_sessions(&self) -> Result<Vec<Session>> {
+        let rows: Vec<SessionRow> = self
+            .conn
+            .prepare(
+                "SELECT id, created_at, updated_at, alternatives FROM sessions
+                 ORDER BY updated_at DESC",
+            )?
+            .query_map([], session_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        rows.into_iter()
+            .map(|row| self.with_messages(row))
+            .collect()
+    }
+
+    pub fn delete_session(&self, id: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM sessions WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn with_messages(&self, row: SessionRow) -> Result<Session> {
+        let (id, created_at, updated_at, alternatives) = row;
+        let messages = self
+            .conn
+            .prepare("SELECT role, content FROM messages WHERE session_id = ?1 ORDER BY position")?
+            .query_map([&id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (role, content) = row?;
+                Ok((role, serde_json::from_str(&content)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Session {
+            id,
+            created_at,
+            updated_at,
+            messages,
+            alternatives: alternatives
+                .map(|json| serde_json::from_str(&json))
+                .transpose()?,
+        })
+    }
+
+    // Blobs are content-addressed, so an existing blob is left as it is
+    pub fn save_blob(&self, blob_id: &str, body: &SpilledOutput) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO blobs (id, stdout, stderr) VALUES (?1, ?2, ?3)",
+            params![blob_id, body.stdout, body.stderr],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_blob(&self, blob_id: &str) -> Result<SpilledOutput> {
+        self.conn
+            .query_row(
+                "SELECT stdout, stderr FROM blobs WHERE id = ?1",
+                [blob_id],
+                |row| {
+                    Ok(SpilledOutput {
+                        stdout: row.get(0)?,
+                        stderr: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("No stored output {}", blob_id))
+    }
+
+    pub fn record_usage(&self, entry: &UsageRecord) -> Result<()> {
+        insert_usage(&self.conn, entry)
+    }
+
+    // All usage records, oldest first
+    pub fn usage(&self) -> Result<Vec<UsageRecord>> {
+        self.conn
+            .prepare(
+                "SELECT timestamp, session_id, model, input_tokens, output_tokens,
+                 cache_creation_tokens, cache_read_tokens, cost_usd FROM usage ORDER BY id",
+            )?
+            .query_map([], |row| {
+                Ok(UsageRecord {
+                    timestamp: row.get(0)?,
+                    session_id: row.get(1)?,
+                    model: row.get(2)?,
+                    input_tokens: row.get(3)?,
+                    output_tokens: row.get(4)?,
+                    cache_creation_tokens: row.get(5)?,
+                    cache_read_tokens: row.get(6)?,
+                    cost_usd: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    pub fn record_artifact(&self, artifact: &Artifact) -> Result<()> {
+        insert_artifact(&self.conn, artifact)
+    }
+}
+
+type SessionRow = (String, String, String, Option<String>);
+
+fn session_row(row: &rusqlite::Row) -> rusqlite::Result<SessionRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn write_session(conn: &Connection, session: &Session) -> Result<()> {
+    let alternatives = session
+        .alternatives
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    conn.execute(
+        "INSERT INTO sessions (id, created_at, updated_at, alternatives) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(id) DO UPDATE SET updated_at = ?3, alternatives = ?4",
+        params![
+            session.id,
+            session.created_at,
+            session.updated_at,
+            alternatives
+        ],
+    )?;
+    conn.execute("DELETE FROM messages WHERE session_id = ?1", [&session.id])?;
+    conn.execute(
+        "DELETE FROM tool_results WHERE session_id = ?1",
+        [&session.id],
+    )?;
+
+    let mut insert_message = conn.prepare(
+        "INSERT INTO messages (session_id, position, role, content) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    let mut insert_result = conn.prepare(
+        "INSERT INTO tool_results (session_id, position, code, stdout, stderr, return_code, error)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for (position, (role, contents)) in session.messages.iter().enumerate() {
+        insert_message.execute(params![
+            session.id,
+            position,
+            role,
+            serde_json::to_string(contents)?
+        ])?;
+
+        // Each output or error is stored with the code that produced it, for querying
+        let mut code: Option<&str> = None;
+        for content in contents {
+            match content {
+                MessageContent::Code { input } => code = Some(input),
+                MessageContent::CodeOutput {
+                    stdout,
+                    stderr,
+                    return_code,
+                    ..
+                } => {
+                    insert_result.execute(params![
+                        session.id,
+                        position,
+                        code.take(),
+                        stdout,
+                        stderr,
+                        return_code,
+                        None::<String>
+                    ])?;
+                }
+                MessageContent::CodeError(error) => {
+                    insert_result.execute(params![
+                        session.id,
+                        position,
+                        code.take(),
+                        None::<String>,
+                        None::<String>,
+                        None::<i32>,
+                        error
+                    ])?;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn insert_usage(conn: &Connection, entry: &UsageRecord) -> Result<()> {
+    conn.execute(
+        "INSERT INTO usage (timestamp, session_id, model, input_tokens, output_tokens,
+         cache_creation_tokens, cache_read_tokens, cost_usd)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            entry.timestamp,
+            entry.session_id,
+            entry.model,
+            entry.input_tokens,
+            entry.output_tokens,
+            entry.cache_creation_tokens,
+            entry.cache_read_tokens,
+            entry.cost_usd
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_artifact(conn: &Connection, artifact: &Artifact) -> Result<()> {
+    conn.execute(
+        "INSERT INTO artifacts (timestamp, session_id, file_id, filename, path, action)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            artifact.timestamp,
+            artifact.session_id,
+            artifact.file_id,
+            artifact.filename,
+            artifact.path,
+            artifact.action
+        ],
+    )?;
+    Ok(())
+}
+
+// Copy history kept by earlier versions (session files, spilled outputs, usage.jsonl and
+// downloads.jsonl) into the new database. The old files are left where they were.
+fn import_legacy(conn: &Connection) -> Result<()> {
+    let dir = session::sessions_dir();
+    let mut sessions = 0;
+    for path in fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+    {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.ends_with(".json.zst") || name.ends_with(".json")) {
+            continue;
+        }
+        match read_legacy::<Session>(&path) {
+            Ok(session) => {
+                write_session(conn, &session)?;
+                sessions += 1;
+            }
+            Err(e) => log_debug!("Skipping unreadable session {}: {}", path.display(), e),
+        }
+    }
+
+    let blob_dir = dir.join("blobs");
+    for path in fs::read_dir(&blob_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+    {
+        let Some(blob_id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json.zst"))
+        else {
+            continue;
+        };
+        match read_legacy::<SpilledOutput>(&path) {
+            Ok(body) => {
+                conn.execute(
+                    "INSERT OR IGNORE INTO blobs (id, stdout, stderr) VALUES (?1, ?2, ?3)",
+                    params![blob_id, body.stdout, body.stderr],
+                )?;
+            }
+            Err(e) => log_debug!("Skipping unreadable blob {}: {}", path.display(), e),
+        }
+    }
+
+    let agnt_dir = db_path().parent().map(PathBuf::from).unwrap_or_default();
+    let usage: Vec<UsageRecord> = read_jsonl(&agnt_dir.join("usage.jsonl"));
+    for entry in &usage {
+        insert_usage(conn, entry)?;
+    }
+    let artifacts: Vec<Artifact> = read_jsonl(&agnt_dir.join("downloads.jsonl"));
+    for artifact in &artifacts {
+        insert_artifact(conn, artifact)?;
+    }
+
+    if sessions + usage.len() + artifacts.len() > 0 {
+        log_debug!(
+            "Imported {} sessions, {} usage records and {} downloads into {}",
+            sessions,
+            usage.len(),
+            artifacts.len(),
+            db_path().display()
+        );
+    }
+    Ok(())
+}
+
+// Old session and blob files are zstd-compressed JSON, or plain JSON for early sessions
+fn read_legacy<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = fs::read(path)?;
+    let json = if path.extension().is_some_and(|ext| ext == "zst") {
+        zstd::decode_all(bytes.as_slice())?
+    } else {
+        bytes
+    };
+    Ok(serde_json::from_slice(&json)?)
+}
+
+// Lines that don't parse are skipped
+fn read_jsonl<T: DeserializeOwned>(path: &Path) -> Vec<T> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
@@ -0,0 +1,153 @@
+use anyhow::{Result, anyhow, bail};
+use std::{collections::HashMap, fs, path::PathBuf};
+
+// A reusable prompt from ~/.agnt/prompts/<name>.md with {{variable}} placeholders
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub body: String,
+}
+
+// Variable that pipe mode fills from stdin when --var doesn't set it
+pub const STDIN_VARIABLE: &str = "input";
+
+pub fn templates_dir() -> PathBuf {
+    dirs::home_dir()
+        .map(|home| home.join(".agnt"))
+        .unwrap_or_else(|| PathBuf::from(".agnt"))
+        .join("prompts")
+}
+
+// Template names, sorted
+pub fn list() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(templates_dir()) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "md"))
+        .filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn load(name: &str) -> Result<Template> {
+    let path = templates_dir().join(format!("{}.md", name));
+    let body = fs::read_to_string(&path).map_err(|e| {
+        let available = list();
+        if available.is_empty() {
+            anyhow!("Cannot read {}: {}", path.display(), e)
+        } else {
+            anyhow!(
+                "No prompt template '{}' (available: {})",
+                name,
+                available.join(", ")
+            )
+        }
+    })?;
+    Ok(Template {
+        name: name.to_string(),
+        body,
+    })
+}
+
+impl Template {
+    // Placeholder names in order of first appearance
+    pub fn variables(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for (_, name) in placeholders(&self.body) {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    // Placeholders without a value are left as they are
+    pub fn render(&self, values: &HashMap<String, String>) -> String {
+        let mut out = String::new();
+        let mut last = 0;
+        for (range, name) in placeholders(&self.body) {
+            out.push_str(&self.body[last..range.start]);
+            match values.get(name) {
+                Some(value) => out.push_str(value),
+                None => out.push_str(&self.body[range.clone()]),
+            }
+            last = range.end;
+        }
+        out.push_str(&self.body[last..]);
+        out
+    }
+
+    // Pipe mode: --var values first, then stdin for {{input}}, or for the only
+    // variable still unset
+    pub fn fill(&self, vars: &[(String, String)], stdin: Option<String>) -> Result<String> {
+        let mut values: HashMap<String, String> = vars.iter().cloned().collect();
+        let missing: Vec<String> = self
+            .variables()
+            .into_iter()
+            .filter(|name| !values.contains_key(name))
+            .collect();
+        if let Some(stdin) = stdin.filter(|text| !text.trim().is_empty()) {
+            let target = if missing.iter().any(|name| name == STDIN_VARIABLE) {
+                Some(STDIN_VARIABLE.to_string())
+            } else if missing.len() == 1 {
+                missing.first().cloned()
+            } else {
+                None
+            };
+            match target {
+                Some(name) => {
+                    values.insert(name, stdin);
+                }
+                None => bail!(
+                    "Template '{}' has no {{{{{}}}}} placeholder for stdin",
+                    self.name,
+                    STDIN_VARIABLE
+                ),
+            }
+        }
+
+        let missing: Vec<String> = self
+            .variables()
+            .into_iter()
+            .filter(|name| !values.contains_key(name))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Template '{}' needs values for: {} (pass --var NAME=VALUE)",
+                self.name,
+                missing.join(", ")
+            );
+        }
+        Ok(self.render(&values))
+    }
+}
+
+// Byte ranges and names of the {{name}} placeholders in `text`; names may be
+// surrounded by spaces and contain letters, digits, '_' and '-'
+fn placeholders(text: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find("{{") {
+        let start = offset + start;
+        let Some(len) = text[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + 2 + len + 2;
+        let name = text[start + 2..end - 2].trim();
+        if !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        {
+            found.push((start..end, name));
+            offset = end;
+        } else {
+            offset = start + 2;
+        }
+    }
+    found
+}
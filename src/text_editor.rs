@@ -0,0 +1,258 @@
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::workspace::{Workspace, unified_diff};
+
+// Anthropic's text editor tool (text_editor_20250124), working on files in the
+// --coding-tools workspace
+#[derive(Debug, Clone)]
+pub struct TextEditor {
+    pub workspace: Workspace,
+    history: Arc<Mutex<HashMap<PathBuf, Vec<Option<String>>>>>, // Contents before each edit (None: no file), for undo_edit
+}
+
+// What an editing command would do to a file, worked out before it is confirmed
+#[derive(Debug)]
+pub struct Change {
+    pub path: PathBuf,
+    pub before: Option<String>,
+    pub after: Option<String>, // None removes the file (undoing its creation)
+    undo: bool,
+}
+
+impl Change {
+    pub fn diff(&self, workspace: &Workspace) -> String {
+        unified_diff(
+            &workspace.display(&self.path),
+            self.before.as_deref().unwrap_or(""),
+            self.after.as_deref().unwrap_or(""),
+        )
+    }
+}
+
+impl TextEditor {
+    pub fn new(workspace: Workspace) -> Self {
+        Self {
+            workspace,
+            history: Arc::default(),
+        }
+    }
+
+    // The change an editing command would make; None for `view`, which changes nothing
+    pub fn change(&self, input: &Value) -> Result<Option<Change>, String> {
+        let command = input["command"].as_str().unwrap_or_default();
+        if command == "view" {
+            return Ok(None);
+        }
+        let path = self.workspace.allowed_path(&string(input, "path")?)?;
+        let name = self.workspace.display(&path);
+        let before = read(&path)?;
+        let existing = || {
+            before
+                .clone()
+                .ok_or_else(|| format!("{} does not exist", name))
+        };
+
+        let (after, undo) = match command {
+            "create" => (Some(string(input, "file_text")?), false),
+            "str_replace" => {
+                let text = existing()?;
+                let old = string(input, "old_str")?;
+                let new = input["new_str"].as_str().unwrap_or_default();
+                match text.matches(old.as_str()).count() {
+                    0 => return Err(format!("old_str was not found in {}", name)),
+                    1 => (Some(text.replacen(old.as_str(), new, 1)), false),
+                    n => {
+                        return Err(format!(
+                            "old_str appears {} times in {}; include more context to make it unique",
+                            n, name
+                        ));
+                    }
+                }
+            }
+            "insert" => {
+                let text = existing()?;
+                let line = input["insert_line"]
+                    .as_u64()
+                    .ok_or("insert needs an \"insert_line\" number")?
+                    as usize;
+                let mut lines: Vec<String> =
+                    text.split_inclusive('\n').map(str::to_string).collect();
+                if line > lines.len() {
+                    return Err(format!("{} has only {} lines", name, lines.len()));
+                }
+                // A last line without a newline would run into the inserted text
+                if line == lines.len()
+                    && let Some(last) = lines.last_mut()
+                    && !last.ends_with('\n')
+                {
+                    last.push('\n');
+                }
+                let mut new = string(input, "new_str")?;
+                if !new.ends_with('\n') {
+                    new.push('\n');
+                }
+                lines.insert(line, new);
+                (Some(lines.concat()), false)
+            }
+            "undo_edit" => {
+                let history = self.history.lock().unwrap();
+                match history.get(&path).and_then(|edits| edits.last()) {
+                    Some(previous) => (previous.clone(), true),
+                    None => return Err(format!("No edit of {} to undo", name)),
+                }
+            }
+            other => return Err(format!("Unknown text editor command {:?}", other)),
+        };
+        Ok(Some(Change {
+            path,
+            before,
+            after,
+            undo,
+        }))
+    }
+
+    pub fn run(&self, input: &Value) -> Result<String, String> {
+        let Some(change) = self.change(input)? else {
+            return self.view(input);
+        };
+        let name = self.workspace.display(&change.path);
+        match &change.after {
+            Some(text) => {
+                if let Some(parent) = change.path.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+                }
+                fs::write(&change.path, text)
+                    .map_err(|e| format!("Cannot write {}: {}", name, e))?
+            }
+            None => fs::remove_file(&change.path)
+                .map_err(|e| format!("Cannot remove {}: {}", name, e))?,
+        }
+
+        let mut history = self.history.lock().unwrap();
+        let edits = history.entry(change.path.clone()).or_default();
+        if change.undo {
+            edits.pop();
+            return Ok(format!("Undid the last edit of {}", name));
+        }
+        edits.push(change.before);
+        Ok(match input["command"].as_str() {
+            Some("create") => format!("Created {}", name),
+            _ => format!("Edited {}", name),
+        })
+    }
+
+    // A directory's entries, or a file's lines numbered from 1 (optionally just
+    // `view_range`, with -1 as the end meaning the last line)
+    fn view(&self, input: &Value) -> Result<String, String> {
+        let path = string(input, "path")?;
+        let resolved = self.workspace.allowed_path(&path);
+        if resolved.as_ref().is_ok_and(|resolved| resolved.is_dir()) {
+            return self.workspace.list(&path);
+        }
+        let text = self.workspace.read(&path)?;
+        let lines: Vec<&str> = text.lines().collect();
+        let (start, end) = match input["view_range"].as_array().map(Vec::as_slice) {
+            Some([start, end]) => {
+                let start = start.as_u64().unwrap_or(1).max(1) as usize;
+                let end = match end.as_i64() {
+                    Some(end) if end > 0 => (end as usize).min(lines.len()),
+                    _ => lines.len(),
+                };
+                (start, end)
+            }
+            _ => (1, lines.len()),
+        };
+        if start > end.max(1) {
+            return Err(format!(
+                "view_range is outside the file's {} lines",
+                lines.len()
+            ));
+        }
+        Ok(lines
+            .iter()
+            .enumerate()
+            .take(end)
+            .skip(start - 1)
+            .map(|(i, line)| format!("{:>6}\t{}", i + 1, line))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+fn string(input: &Value, key: &str) -> Result<String, String> {
+    input[key]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Needs a {:?} string", key))
+}
+
+fn read(path: &Path) -> Result<Option<String>, String> {
+    match fs::read(path) {
+        Ok(bytes) => String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|_| format!("{} is not a text file", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Cannot read {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn edits_apply_and_undo() {
+        let root = std::env::temp_dir().join(format!("agnt-editor-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let editor = TextEditor::new(Workspace::new(root.canonicalize().unwrap(), &[]).unwrap());
+        let file = root.join("notes.txt");
+
+        let run = |input: Value| editor.run(&input);
+        run(json!({"command": "create", "path": "notes.txt", "file_text": "one\nthree"})).unwrap();
+        run(json!({"command": "insert", "path": "notes.txt", "insert_line": 1, "new_str": "two"}))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "one\ntwo\nthree");
+        run(json!({"command": "insert", "path": "notes.txt", "insert_line": 3, "new_str": "four"}))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "one\ntwo\nthree\nfour\n"
+        );
+        assert!(
+            run(json!({"command": "str_replace", "path": "notes.txt", "old_str": "o"})).is_err()
+        );
+        run(json!({"command": "str_replace", "path": "notes.txt", "old_str": "two", "new_str": "2"}))
+            .unwrap();
+        assert_eq!(
+            run(json!({"command": "view", "path": "notes.txt", "view_range": [2, -1]})).unwrap(),
+            "     2\t2\n     3\tthree\n     4\tfour"
+        );
+
+        for _ in 0..3 {
+            run(json!({"command": "undo_edit", "path": "notes.txt"})).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), "one\nthree");
+        run(json!({"command": "undo_edit", "path": "notes.txt"})).unwrap();
+        assert!(!file.exists());
+
+        // Creating through a dangling link would write its target outside the workspace
+        #[cfg(unix)]
+        {
+            let outside = root.with_extension("outside");
+            std::os::unix::fs::symlink(&outside, root.join("link.txt")).unwrap();
+            assert!(
+                run(json!({"command": "create", "path": "link.txt", "file_text": "x"})).is_err()
+            );
+            assert!(!outside.exists());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
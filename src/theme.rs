@@ -0,0 +1,171 @@
+use ratatui::style::Color;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::OnceLock, time::Duration};
+
+use crate::config::Config;
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemeName {
+    #[default]
+    Dark, // Light text for terminals with a dark background
+    Light, // Dark text for terminals with a light background
+}
+
+// The indicator shown while waiting for the first part of an answer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpinnerStyle {
+    #[default]
+    Dots, // ●○○ bouncing between three dots
+    Braille, // A fast-turning braille spinner
+    None,    // Just the "Thinking..." text
+}
+
+impl SpinnerStyle {
+    pub fn frames(&self) -> &'static [&'static str] {
+        match self {
+            SpinnerStyle::Dots => &["●○○", "○●○", "○○●"],
+            SpinnerStyle::Braille => &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"],
+            SpinnerStyle::None => &[],
+        }
+    }
+
+    // Time between frames; None when nothing moves
+    pub fn interval(&self) -> Option<Duration> {
+        match self {
+            SpinnerStyle::Dots => Some(Duration::from_millis(300)),
+            SpinnerStyle::Braille => Some(Duration::from_millis(80)),
+            SpinnerStyle::None => None,
+        }
+    }
+}
+
+// Every color the TUI draws with, by what it is used for
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub text: Color,      // Input, tool output and popup entries
+    pub secondary: Color, // Answer text and descriptions
+    pub dim: Color,       // Borders, markers and hints
+    pub accent: Color,    // Titles, links and the selected entry's background
+    pub on_accent: Color, // Text drawn on the accent color
+    pub highlight: Color, // Key names, summaries and code execution mode
+    pub heading: Color,   // Help sections, code and file names
+    pub success: Color,   // Finished tools and downloads, added lines
+    pub warning: Color,   // Truncation, pending confirmations, selection mode
+    pub error: Color,     // Errors, failed tools, removed lines
+    pub popup_bg: Color,  // Background of the help modal, menus and pickers
+    pub shadow: Color,    // Drop shadow under popups
+    pub focus_bg: Color,  // Background of the message focused in copy mode
+}
+
+impl Theme {
+    pub fn named(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Dark => Self::dark(),
+            ThemeName::Light => Self::light(),
+        }
+    }
+
+    pub fn dark() -> Self {
+        Self {
+            text: Color::White,
+            secondary: Color::Gray,
+            dim: Color::DarkGray,
+            accent: Color::Cyan,
+            on_accent: Color::Black,
+            highlight: Color::Magenta,
+            heading: Color::Blue,
+            success: Color::Green,
+            warning: Color::Yellow,
+            error: Color::Red,
+            popup_bg: Color::Indexed(235),
+            shadow: Color::Indexed(233),
+            focus_bg: Color::Indexed(236),
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            text: Color::Black,
+            secondary: Color::Indexed(238),
+            dim: Color::Indexed(244),
+            accent: Color::Indexed(31),
+            on_accent: Color::White,
+            highlight: Color::Indexed(127),
+            heading: Color::Indexed(25),
+            success: Color::Indexed(28),
+            warning: Color::Indexed(130),
+            error: Color::Indexed(160),
+            popup_bg: Color::Indexed(254),
+            shadow: Color::Indexed(248),
+            focus_bg: Color::Indexed(253),
+        }
+    }
+
+    // The named theme with `theme_colors` overrides applied; colors are names
+    // ("magenta"), 256-color indices ("252") or hex ("#1e1e2e")
+    pub fn from_config(name: ThemeName, colors: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut theme = Self::named(name);
+        for (role, value) in colors {
+            let color: Color = value
+                .parse()
+                .map_err(|_| format!("Invalid color {:?} for {}", value, role))?;
+            let slot = match role.as_str() {
+                "text" => &mut theme.text,
+                "secondary" => &mut theme.secondary,
+                "dim" => &mut theme.dim,
+                "accent" => &mut theme.accent,
+                "on_accent" => &mut theme.on_accent,
+                "highlight" => &mut theme.highlight,
+                "heading" => &mut theme.heading,
+                "success" => &mut theme.success,
+                "warning" => &mut theme.warning,
+                "error" => &mut theme.error,
+                "popup_bg" => &mut theme.popup_bg,
+                "shadow" => &mut theme.shadow,
+                "focus_bg" => &mut theme.focus_bg,
+                _ => return Err(format!("Unknown theme color {}", role)),
+            };
+            *slot = color;
+        }
+        Ok(theme)
+    }
+}
+
+// Set the theme from the config once at startup; a bad override falls back to the
+// plain named theme
+pub fn init(config: &Config) {
+    let theme = Theme::from_config(config.theme, &config.theme_colors).unwrap_or_else(|e| {
+        eprintln!("Warning: Ignoring theme_colors: {}", e);
+        log_debug!("Ignoring theme_colors: {}", e);
+        Theme::named(config.theme)
+    });
+    let _ = THEME.set(theme);
+}
+
+pub fn current() -> &'static Theme {
+    THEME.get_or_init(Theme::dark)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_replace_single_colors() {
+        let colors = BTreeMap::from([
+            ("accent".to_string(), "#ff8800".to_string()),
+            ("popup_bg".to_string(), "252".to_string()),
+        ]);
+        let theme = Theme::from_config(ThemeName::Light, &colors).unwrap();
+        assert_eq!(theme.accent, Color::Rgb(0xff, 0x88, 0x00));
+        assert_eq!(theme.popup_bg, Color::Indexed(252));
+        assert_eq!(theme.text, Color::Black);
+
+        let bad = BTreeMap::from([("border".to_string(), "red".to_string())]);
+        assert!(Theme::from_config(ThemeName::Dark, &bad).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+pub fn is_active() -> bool {
+    std::env::var_os("TMUX").is_some()
+}
+
+// Current title of the pane agnt runs in, so it can be restored on exit
+pub fn pane_title() -> Option<String> {
+    if !is_active() {
+        return None;
+    }
+    let mut command = Command::new("tmux");
+    command.args(["display-message", "-p"]);
+    if let Ok(pane) = std::env::var("TMUX_PANE") {
+        command.args(["-t", &pane]);
+    }
+    let output = command
+        .arg("#{pane_title}")
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Set the title of the pane agnt runs in (shown by tmux in pane borders and status lines)
+pub fn set_pane_title(title: &str) {
+    if !is_active() {
+        return;
+    }
+    let mut command = Command::new("tmux");
+    command.args(["select-pane", "-T", title]);
+    if let Ok(pane) = std::env::var("TMUX_PANE") {
+        command.args(["-t", &pane]);
+    }
+    match command.stdout(Stdio::null()).stderr(Stdio::null()).status() {
+        Ok(status) if !status.success() => {
+            log_debug!("tmux select-pane -T exited with {}", status)
+        }
+        Err(e) => log_debug!("Failed to run tmux select-pane: {}", e),
+        _ => {}
+    }
+}
+
+// Store text in a new tmux paste buffer
+pub fn load_buffer(text: &str) -> Result<()> {
+    let mut child = Command::new("tmux")
+        .args(["load-buffer", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("tmux load-buffer exited with {}", status));
+    }
+    Ok(())
+}
@@ -0,0 +1,624 @@
+use serde_json::{Value, json};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    anthropic::{ClientTool, InputContent},
+    config::LocalTool,
+    hooks,
+    text_editor::TextEditor,
+    ui::MessageContent,
+    workspace::Workspace,
+};
+
+// Tool output past this is cut before it goes back to Claude
+const MAX_RESULT_BYTES: usize = 64 * 1024;
+
+// The built-in tools --allow-shell, --allow-files and --coding-tools add
+pub const SHELL_TOOL: &str = "run_shell";
+const READ_TOOL: &str = "read_file";
+const WRITE_TOOL: &str = "write_file";
+const LIST_TOOL: &str = "list_directory";
+pub const BASH_TOOL: &str = "bash";
+pub const EDITOR_TOOL: &str = "str_replace_editor";
+pub const DECLINED: &str = "The user declined to run this tool call";
+pub const DELETED: &str = "The user removed this tool result from the conversation";
+
+// Every tool Claude can call: the config's, plus run_shell when --allow-shell gave
+// it a working directory, the file tools when --allow-files gave them a workspace,
+// and Anthropic's bash and text editor tools with --coding-tools
+#[derive(Debug, Clone, Default)]
+pub struct Toolbox {
+    pub tools: Vec<LocalTool>,
+    pub shell_dir: Option<PathBuf>,
+    pub shell_timeout: Duration,
+    pub workspace: Option<Workspace>,
+    pub editor: Option<TextEditor>, // --coding-tools; bash runs in its workspace too
+}
+
+// What the user is shown and asked to allow before a call runs
+#[derive(Debug, Clone)]
+pub enum Approval {
+    Shell { command: String, dir: PathBuf }, // The exact command line and where it runs
+    Write { path: String, diff: String },    // Workspace-relative path and a unified diff
+}
+
+impl Approval {
+    pub fn question(&self) -> String {
+        match self {
+            Approval::Shell { command, .. } => format!("Run `{}`?", command),
+            Approval::Write { path, .. } => format!("Write {}?", path),
+        }
+    }
+}
+
+// Progress of a running call, for the TUI
+#[derive(Debug)]
+pub enum ToolEvent {
+    Output(String), // Lines of stdout/stderr as run_shell prints them
+    Done(MessageContent),
+}
+
+// A tool_use block from Claude's answer
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: String, // JSON object
+}
+
+impl Toolbox {
+    // The `tools` entries sent with every request. Built-in tools win over config tools
+    // of the same name.
+    pub fn definitions(&self) -> Vec<ClientTool> {
+        let builtin = self.builtin_definitions();
+        let mut definitions: Vec<ClientTool> = self
+            .tools
+            .iter()
+            .filter(|tool| !builtin.iter().any(|b| b.name == tool.name))
+            .map(|tool| ClientTool {
+                tool_type: None,
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                input_schema: tool.input_schema.clone(),
+            })
+            .collect();
+        definitions.extend(builtin);
+        definitions
+    }
+
+    fn builtin_definitions(&self) -> Vec<ClientTool> {
+        let mut definitions = Vec::new();
+        if self.editor.is_some() {
+            for (tool_type, name) in [
+                ("bash_20250124", BASH_TOOL),
+                ("text_editor_20250124", EDITOR_TOOL),
+            ] {
+                definitions.push(ClientTool {
+                    tool_type: Some(tool_type.to_string()),
+                    name: name.to_string(),
+                    description: String::new(),
+                    input_schema: Value::Null,
+                });
+            }
+        }
+        if let Some(dir) = &self.shell_dir {
+            definitions.push(ClientTool {
+                tool_type: None,
+                name: SHELL_TOOL.to_string(),
+                description: format!(
+                    "Run a shell command on the user's machine, in {}, and return its combined stdout and stderr. The user sees the exact command and confirms it before it runs, and may decline.",
+                    dir.display()
+                ),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "The command line to run with the system shell"
+                        }
+                    },
+                    "required": ["command"]
+                }),
+            });
+        }
+        if let Some(workspace) = &self.workspace {
+            let root = workspace.root.display();
+            let path = json!({
+                "type": "string",
+                "description": "Path relative to the workspace root"
+            });
+            definitions.push(ClientTool {
+                tool_type: None,
+                name: READ_TOOL.to_string(),
+                description: format!("Read a text file in the workspace at {}.", root),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": { "path": path },
+                    "required": ["path"]
+                }),
+            });
+            definitions.push(ClientTool {
+                tool_type: None,
+                name: LIST_TOOL.to_string(),
+                description: format!(
+                    "List a directory in the workspace at {}; subdirectories end with a slash.",
+                    root
+                ),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": { "path": path }
+                }),
+            });
+            definitions.push(ClientTool {
+                tool_type: None,
+                name: WRITE_TOOL.to_string(),
+                description: format!(
+                    "Create or replace a text file in the workspace at {} with the given content. The user reviews a diff of the change first and may decline.",
+                    root
+                ),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": path,
+                        "content": {
+                            "type": "string",
+                            "description": "The whole new content of the file"
+                        }
+                    },
+                    "required": ["path", "content"]
+                }),
+            });
+        }
+        definitions
+    }
+
+    // Commands and file changes wait for a yes from the user before they run. None for
+    // other calls, and for ones too malformed to show; those fail when run.
+    pub fn approval(&self, call: &ToolCall) -> Option<Approval> {
+        match call.name.as_str() {
+            SHELL_TOOL => Some(Approval::Shell {
+                command: shell_command(&call.input).ok()?,
+                dir: self.shell_dir.clone()?,
+            }),
+            BASH_TOOL => Some(Approval::Shell {
+                command: shell_command(&call.input).ok()?,
+                dir: self.editor.as_ref()?.workspace.root.clone(),
+            }),
+            EDITOR_TOOL => {
+                let editor = self.editor.as_ref()?;
+                let input = serde_json::from_str(&call.input).ok()?;
+                let change = editor.change(&input).ok()??;
+                Some(Approval::Write {
+                    path: editor.workspace.display(&change.path),
+                    diff: change.diff(&editor.workspace),
+                })
+            }
+            WRITE_TOOL => {
+                let workspace = self.workspace.as_ref()?;
+                let (path, content) = write_input(&call.input).ok()?;
+                let diff = workspace.diff(&path, &content).ok()?;
+                Some(Approval::Write { path, diff })
+            }
+            _ => None,
+        }
+    }
+
+    // Run one call, sending run_shell's output to `events` as it arrives. Cancelling
+    // `token` kills the command.
+    pub async fn run(
+        &self,
+        call: ToolCall,
+        token: &CancellationToken,
+        events: &mpsc::Sender<ToolEvent>,
+    ) -> MessageContent {
+        let execution = async {
+            match (call.name.as_str(), &self.shell_dir, &self.workspace) {
+                (SHELL_TOOL, Some(dir), _) => {
+                    let command = shell_command(&call.input)?;
+                    run_shell(&command, dir, self.shell_timeout, events).await
+                }
+                (BASH_TOOL, _, _) if let Some(editor) = &self.editor => {
+                    // Each command gets a fresh shell, so there is nothing to restart
+                    match shell_command(&call.input) {
+                        Err(_) if restart_input(&call.input) => {
+                            Ok("The bash tool has been restarted".to_string())
+                        }
+                        command => {
+                            let dir = &editor.workspace.root;
+                            run_shell(&command?, dir, self.shell_timeout, events).await
+                        }
+                    }
+                }
+                (EDITOR_TOOL, _, _) if let Some(editor) = &self.editor => {
+                    let input = serde_json::from_str(&call.input)
+                        .map_err(|e| format!("Invalid input: {}", e))?;
+                    editor.run(&input).map(truncate)
+                }
+                (READ_TOOL, _, Some(workspace)) => {
+                    workspace.read(&path_input(&call.input)?).map(truncate)
+                }
+                (LIST_TOOL, _, Some(workspace)) => workspace
+                    .list(&path_input(&call.input).unwrap_or_default())
+                    .map(truncate),
+                (WRITE_TOOL, _, Some(workspace)) => {
+                    let (path, content) = write_input(&call.input)?;
+                    workspace.write(&path, &content)
+                }
+                _ => match self.tools.iter().find(|t| t.name == call.name) {
+                    Some(tool) => run(tool, &call.input).await,
+                    None => Err(format!("No tool named {} is configured", call.name)),
+                },
+            }
+        };
+        let outcome = tokio::select! {
+            outcome = execution => outcome,
+            _ = token.cancelled() => Err("Cancelled by the user".to_string()),
+        };
+        result(call, outcome)
+    }
+}
+
+// The command line of a run_shell call
+fn shell_command(input: &str) -> Result<String, String> {
+    serde_json::from_str::<Value>(input)
+        .ok()
+        .and_then(|input| input["command"].as_str().map(str::to_string))
+        .filter(|command| !command.trim().is_empty())
+        .ok_or_else(|| "Needs a \"command\" string".to_string())
+}
+
+// How the TUI shows a bash or text editor call: the command line, or the edit with
+// removed lines marked "- " and added ones "+ ". None for other tools.
+pub fn describe(name: &str, input: &str) -> Option<String> {
+    let input: Value = serde_json::from_str(input).ok()?;
+    let text = |key: &str| input[key].as_str().unwrap_or_default();
+    let marked = |marker: &str, text: &str| -> Vec<String> {
+        text.lines()
+            .map(|line| format!("{} {}", marker, line))
+            .collect()
+    };
+    let lines = match (name, text("command")) {
+        (BASH_TOOL, _) if input["restart"] == true => vec!["(restart)".to_string()],
+        (BASH_TOOL, command) => marked("$", command),
+        (EDITOR_TOOL, "view") => match input["view_range"].as_array() {
+            Some(range) => vec![format!(
+                "view {} lines {}",
+                text("path"),
+                range
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join("-")
+            )],
+            None => vec![format!("view {}", text("path"))],
+        },
+        (EDITOR_TOOL, "create") => {
+            let mut lines = vec![format!("create {}", text("path"))];
+            lines.extend(marked("+", text("file_text")));
+            lines
+        }
+        (EDITOR_TOOL, "str_replace") => {
+            let mut lines = vec![format!("edit {}", text("path"))];
+            lines.extend(marked("-", text("old_str")));
+            lines.extend(marked("+", text("new_str")));
+            lines
+        }
+        (EDITOR_TOOL, "insert") => {
+            let mut lines = vec![format!(
+                "insert into {} after line {}",
+                text("path"),
+                input["insert_line"]
+            )];
+            lines.extend(marked("+", text("new_str")));
+            lines
+        }
+        (EDITOR_TOOL, command) => vec![format!("{} {}", command, text("path"))],
+        _ => return None,
+    };
+    Some(lines.join("\n"))
+}
+
+fn restart_input(input: &str) -> bool {
+    serde_json::from_str::<Value>(input).is_ok_and(|input| input["restart"] == true)
+}
+
+fn path_input(input: &str) -> Result<String, String> {
+    serde_json::from_str::<Value>(input)
+        .ok()
+        .and_then(|input| input["path"].as_str().map(str::to_string))
+        .ok_or_else(|| "Needs a \"path\" string".to_string())
+}
+
+fn write_input(input: &str) -> Result<(String, String), String> {
+    let content = serde_json::from_str::<Value>(input)
+        .ok()
+        .and_then(|input| input["content"].as_str().map(str::to_string))
+        .ok_or_else(|| format!("{} needs a \"content\" string", WRITE_TOOL))?;
+    Ok((path_input(input)?, content))
+}
+
+// Tool calls in an answer, in the order Claude made them
+pub fn calls(contents: &[MessageContent]) -> Vec<ToolCall> {
+    contents
+        .iter()
+        .filter_map(|content| match content {
+            MessageContent::ToolUse { id, name, input } => Some(ToolCall {
+                id: id.clone(),
+                name: name.clone(),
+                input: input.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+// Tool calls and results as the API takes them back; None for other content
+pub fn api_block(content: &MessageContent) -> Option<InputContent> {
+    match content {
+        MessageContent::ToolUse { id, name, input } => Some(InputContent::ToolUse {
+            id: id.clone(),
+            name: name.clone(),
+            input: serde_json::from_str(input).unwrap_or_else(|_| serde_json::json!({})),
+        }),
+        MessageContent::ToolResult {
+            tool_use_id,
+            content,
+            is_error,
+            ..
+        } => Some(InputContent::ToolResult {
+            tool_use_id: tool_use_id.clone(),
+            content: content.clone(),
+            is_error: *is_error,
+        }),
+        _ => None,
+    }
+}
+
+// A user turn that only carries tool results, as opposed to something the user typed
+pub fn is_tool_results(contents: &[MessageContent]) -> bool {
+    !contents.is_empty()
+        && contents
+            .iter()
+            .all(|content| matches!(content, MessageContent::ToolResult { .. }))
+}
+
+// Error results for calls that won't be run
+pub fn refuse(calls: Vec<ToolCall>, reason: &str) -> Vec<MessageContent> {
+    calls
+        .into_iter()
+        .map(|call| result(call, Err(reason.to_string())))
+        .collect()
+}
+
+pub fn result(call: ToolCall, outcome: Result<String, String>) -> MessageContent {
+    let is_error = outcome.is_err();
+    let content = outcome.unwrap_or_else(|error| error);
+    log_debug!(
+        "Tool {} ({}) returned {} bytes{}",
+        call.name,
+        call.id,
+        content.len(),
+        if is_error { " (error)" } else { "" }
+    );
+    MessageContent::ToolResult {
+        tool_use_id: call.id,
+        name: call.name,
+        content,
+        is_error,
+    }
+}
+
+async fn run(tool: &LocalTool, input: &str) -> Result<String, String> {
+    let mut command = hooks::shell(&tool.command);
+    command
+        .env("AGNT_TOOL_NAME", &tool.name)
+        .env("AGNT_TOOL_INPUT", input)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let execution = async {
+        let mut child = command.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Commands that ignore their input may exit before reading it
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+        child.wait_with_output().await
+    };
+    let output = tokio::time::timeout(Duration::from_secs(tool.timeout_secs), execution)
+        .await
+        .map_err(|_| format!("`{}` timed out after {}s", tool.command, tool.timeout_secs))?
+        .map_err(|e| format!("Failed to run `{}`: {}", tool.command, e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let detail = if stderr.trim().is_empty() {
+            stdout.trim()
+        } else {
+            stderr.trim()
+        };
+        return Err(truncate(format!(
+            "`{}` exited with {}\n{}",
+            tool.command, output.status, detail
+        )));
+    }
+    if stdout.trim().is_empty() {
+        return Ok("(no output)".to_string());
+    }
+    Ok(truncate(stdout.into_owned()))
+}
+
+// Run a command in `dir`, forwarding each line it prints (stdout and stderr, in the
+// order they arrive) and returning them all
+async fn run_shell(
+    command: &str,
+    dir: &Path,
+    timeout: Duration,
+    events: &mpsc::Sender<ToolEvent>,
+) -> Result<String, String> {
+    let mut shell = hooks::shell(command);
+    shell
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = shell
+        .spawn()
+        .map_err(|e| format!("Failed to run `{}`: {}", command, e))?;
+
+    let (lines_tx, mut lines_rx) = mpsc::channel(64);
+    forward_lines(child.stdout.take(), lines_tx.clone());
+    forward_lines(child.stderr.take(), lines_tx);
+
+    let mut output = String::new();
+    let execution = async {
+        while let Some(line) = lines_rx.recv().await {
+            output.push_str(&line);
+            let _ = events.send(ToolEvent::Output(line)).await;
+        }
+        child.wait().await
+    };
+    let status = tokio::time::timeout(timeout, execution)
+        .await
+        .map_err(|_| format!("`{}` timed out after {}s", command, timeout.as_secs()))?
+        .map_err(|e| format!("Failed to run `{}`: {}", command, e))?;
+
+    let output = truncate(output.trim_end().to_string());
+    match status.code() {
+        Some(0) if output.is_empty() => Ok("(no output)".to_string()),
+        Some(0) => Ok(output),
+        Some(code) => Err(format!("{}\n(exit code {})", output, code)
+            .trim()
+            .to_string()),
+        None => Err(format!("{}\n(killed by a signal)", output)
+            .trim()
+            .to_string()),
+    }
+}
+
+fn forward_lines(
+    pipe: Option<impl AsyncRead + Unpin + Send + 'static>,
+    lines: mpsc::Sender<String>,
+) {
+    let Some(pipe) = pipe else { return };
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        while let Ok(read) = reader.read_until(b'\n', &mut line).await
+            && read > 0
+        {
+            let text = String::from_utf8_lossy(&line).into_owned();
+            if lines.send(text).await.is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() <= MAX_RESULT_BYTES {
+        return text;
+    }
+    let total = text.len();
+    let mut end = MAX_RESULT_BYTES;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    text.push_str(&format!(
+        "\n[output truncated: {} of {} bytes shown]",
+        end, total
+    ));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool(name: &str, command: &str) -> LocalTool {
+        LocalTool {
+            name: name.to_string(),
+            description: String::new(),
+            input_schema: serde_json::json!({ "type": "object" }),
+            command: command.to_string(),
+            timeout_secs: 5,
+        }
+    }
+
+    fn call(id: &str, name: &str, input: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            name: name.to_string(),
+            input: input.to_string(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn every_call_gets_a_result() {
+        let toolbox = Toolbox {
+            tools: vec![tool("echo", "cat"), tool("fail", "echo oops >&2; exit 2")],
+            shell_dir: Some(std::env::temp_dir()),
+            shell_timeout: Duration::from_secs(5),
+            workspace: None,
+            editor: None,
+        };
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        let mut results = Vec::new();
+        for call in [
+            call("a", "echo", r#"{"x":1}"#),
+            call("b", "fail", "{}"),
+            call("c", "missing", "{}"),
+            call(
+                "d",
+                SHELL_TOOL,
+                r#"{"command":"echo one; echo two >&2; exit 3"}"#,
+            ),
+        ] {
+            results.push(
+                toolbox
+                    .run(call, &CancellationToken::new(), &events_tx)
+                    .await,
+            );
+        }
+        drop(events_tx);
+        let mut streamed = Vec::new();
+        while let Some(ToolEvent::Output(line)) = events_rx.recv().await {
+            streamed.push(line);
+        }
+        let outcomes: Vec<(&str, &str, bool)> = results
+            .iter()
+            .map(|result| match result {
+                MessageContent::ToolResult {
+                    tool_use_id,
+                    content,
+                    is_error,
+                    ..
+                } => (tool_use_id.as_str(), content.as_str(), *is_error),
+                other => panic!("not a tool result: {:?}", other),
+            })
+            .collect();
+        assert_eq!(outcomes[0], ("a", r#"{"x":1}"#, false));
+        assert_eq!(outcomes[1].0, "b");
+        assert!(outcomes[1].1.ends_with("oops") && outcomes[1].2);
+        assert_eq!(
+            outcomes[2],
+            ("c", "No tool named missing is configured", true)
+        );
+        assert_eq!(outcomes[3].0, "d");
+        assert!(outcomes[3].1.ends_with("(exit code 3)") && outcomes[3].2);
+        assert_eq!(streamed.len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use std::process::Stdio;
+use tokio::{io::AsyncWriteExt, process::Command, sync::mpsc};
+
+// Feeds completed sentences of the assistant's reply to a text-to-speech command.
+// Sentences are spoken one after another by a background task; the command gets each
+// sentence on stdin (which both `say` and `piper` read from when given no text).
+pub struct Speaker {
+    tx: mpsc::UnboundedSender<String>,
+    pending: String, // Text received since the last sentence boundary
+}
+
+impl Speaker {
+    pub fn new(command: String) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(sentence) = rx.recv().await {
+                if let Err(e) = speak(&command, &sentence).await {
+                    log_debug!("TTS command '{}' failed: {}", command, e);
+                }
+            }
+        });
+        Self {
+            tx,
+            pending: String::new(),
+        }
+    }
+
+    pub fn push(&mut self, text: &str) {
+        self.pending.push_str(text);
+        while let Some(end) = sentence_end(&self.pending) {
+            let rest = self.pending.split_off(end);
+            let sentence = std::mem::replace(&mut self.pending, rest);
+            self.send(&sentence);
+        }
+    }
+
+    // Speak whatever is left at the end of a turn, even without closing punctuation
+    pub fn flush(&mut self) {
+        let sentence = std::mem::take(&mut self.pending);
+        self.send(&sentence);
+    }
+
+    fn send(&self, sentence: &str) {
+        let sentence = sentence.trim();
+        // Skip fragments with nothing to pronounce, like a lone code fence or bullet
+        if sentence.chars().any(char::is_alphanumeric) {
+            let _ = self.tx.send(sentence.to_string());
+        }
+    }
+}
+
+// Byte index just past the first sentence terminator that is followed by whitespace
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let terminator = matches!(c, '.' | '!' | '?' | '\n');
+        if terminator
+            && let Some(&(_, next)) = chars.peek()
+            && (c == '\n' || next.is_whitespace())
+        {
+            return Some(i + c.len_utf8());
+        }
+    }
+    None
+}
+
+async fn speak(command: &str, sentence: &str) -> std::io::Result<()> {
+    let mut child = if cfg!(windows) {
+        let mut child = Command::new("cmd");
+        child.arg("/C").arg(command);
+        child
+    } else {
+        let mut child = Command::new("sh");
+        child.arg("-c").arg(command);
+        child
+    }
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(sentence.as_bytes()).await?;
+    }
+    child.wait().await?;
+    Ok(())
+}
@@ -1,9 +1,37 @@
 use ratatui::{
     Frame,
-    layout::{Alignment, Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    layout::{Alignment, Constraint, Direction, Layout, Position, Rect},
+    style::{Modifier, Style},
     text::{Line, Span, Text},
-    widgets::{Block, BorderType, Borders, Clear, List, ListItem, Paragraph, Wrap},
+    widgets::{
+        Block, BorderType, Borders, Clear, List, ListItem, ListState, Paragraph, Scrollbar,
+        ScrollbarOrientation, ScrollbarState, Wrap,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+};
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    anthropic::{self, DEFAULT_MAX_TOKENS, ModelInfo, RequestParams, TurnMetrics, default_model},
+    clipboard, codeblocks, compact,
+    config::{BudgetAction, Config},
+    downloads::{self, DownloadState},
+    editor, export, fuzzy,
+    keymap::{self, Action},
+    mentions, models,
+    preview::{self, FilePreview},
+    pricing, project, prompt, review, session,
+    templates::{self, Template},
+    theme, tmux,
+    tools::{self, ToolCall, Toolbox},
+    tts::Speaker,
+    usage,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -14,7 +42,7 @@ pub enum ToolMode {
     Both,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub enum MessageContent {
     Text(String),
     Code {
@@ -25,9 +53,54 @@ pub enum MessageContent {
         stderr: String,
         return_code: i32,
         files: Vec<(String, String)>, // (file_id, filename)
+        // Blob id when the full stdout/stderr was moved to disk and only a preview is kept here
+        #[serde(default)]
+        spilled: Option<String>,
+        // Progress of saving each file locally; only meaningful while the app is running
+        #[serde(skip)]
+        downloads: Vec<(String, DownloadState)>, // (file_id, state)
     },
     CodeError(String),
     ApiError(String),
+    LocalError(String), // A failure on this side (files, clipboard, editor); nothing for /retry to resend
+    Info(String),       // Local notices (e.g. /stats output), never sent to the API
+    Summary(String),    // Replaces compacted older turns; sent to the API as context
+    StopReason(String), // Why the answer ended (end_turn, max_tokens, refusal, ...)
+    Timing(String), // Duration, time to first token and tokens/sec of the request, shown under the answer
+    Image {
+        name: String, // File name shown in the conversation
+        media_type: String,
+        data: String, // Base64, kept so resumed sessions can resend it
+    },
+    Document {
+        name: String,
+        media_type: String, // application/pdf
+        data: String,       // Base64, kept so resumed sessions can resend it
+    },
+    Upload {
+        name: String,
+        file_id: String, // Files API file that code execution finds in its container
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: String, // JSON object
+    },
+    ToolResult {
+        tool_use_id: String,
+        name: String, // Of the tool that ran (empty when read from a transcript)
+        content: String,
+        is_error: bool,
+    },
+    File {
+        path: String, // As mentioned with @, relative to where agnt runs
+        content: String,
+        truncated: bool, // Cut to mentions::MAX_FILE_BYTES
+    },
+    Context {
+        label: String, // What was added, e.g. "project tree of ."
+        content: String,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -40,13 +113,56 @@ pub struct SlashCommand {
 #[derive(Debug, Clone, PartialEq)]
 pub enum SlashCommandAction {
     Clear,
+    Stats,
+    Copy,
+    Speak,
+    Retry,
+    SaveCode,
+    Ask,
+    Resume,
+    Model,
+    Attach,
+    Dir,
+    Review,
+    Continue,
+    Branch,
+    Checkpoint,
+    Rollback,
+    Close,
+    Export,
+    Params,
+    MaxTokens,
+    Compact,
+    Tokens,
+    Prompt,
+    Sessions,
+    Custom(usize), // Index into Config::custom_commands
+}
+
+// A completion for the argument being typed after a command name
+#[derive(Debug, Clone)]
+pub struct ArgSuggestion {
+    pub value: String,
+    pub description: String,
+}
+
+impl ArgSuggestion {
+    fn new(value: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            description: description.into(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SlashCommandState {
     pub input_buffer: String,
     pub suggestions: Vec<SlashCommand>,
-    pub selected_index: usize,
+    pub match_positions: Vec<Vec<usize>>, // Matched char indices in each suggestion's name
+    pub selected_index: usize,            // Into arg_suggestions once a command is chosen
+    pub command: Option<SlashCommand>,    // Set once a space follows the name; arguments come next
+    pub arg_suggestions: Vec<ArgSuggestion>, // Completions for the argument under the cursor
 }
 
 impl SlashCommandState {
@@ -54,29 +170,116 @@ impl SlashCommandState {
         Self {
             input_buffer: String::new(),
             suggestions: Vec::new(),
+            match_positions: Vec::new(),
             selected_index: 0,
+            command: None,
+            arg_suggestions: Vec::new(),
         }
     }
 
+    // Text after the command name, e.g. "--temperature 1.0" for "/retry --temperature 1.0"
+    pub fn args(&self) -> &str {
+        self.input_buffer
+            .split_once(char::is_whitespace)
+            .map(|(_, args)| args.trim())
+            .unwrap_or("")
+    }
+
     pub fn update_suggestions(&mut self, commands: &[SlashCommand]) {
-        self.suggestions = commands
+        let query = self
+            .input_buffer
+            .split(char::is_whitespace)
+            .next()
+            .unwrap_or("");
+        let mut scored: Vec<(i64, Vec<usize>, &SlashCommand)> = commands
             .iter()
-            .filter(|cmd| cmd.name.starts_with(&self.input_buffer))
-            .cloned()
+            .filter_map(|cmd| {
+                fuzzy::score(query, &cmd.name).map(|(score, positions)| (score, positions, cmd))
+            })
+            .collect();
+        // Stable sort keeps the declaration order for equal scores (and an empty query)
+        scored.sort_by_key(|(score, _, _)| std::cmp::Reverse(*score));
+
+        self.suggestions = scored.iter().map(|(_, _, cmd)| (*cmd).clone()).collect();
+        self.match_positions = scored
+            .into_iter()
+            .map(|(_, positions, _)| positions)
+            .collect();
+        self.selected_index = 0;
+
+        // After "/name " the command is fixed: an exact name, or else the best match
+        self.command = if self.input_buffer.contains(char::is_whitespace) {
+            commands
+                .iter()
+                .find(|cmd| cmd.name == query)
+                .or(self.suggestions.first())
+                .cloned()
+        } else {
+            None
+        };
+        self.arg_suggestions.clear();
+    }
+
+    // Position of the argument being typed and its text so far
+    pub fn current_arg(&self) -> (usize, &str) {
+        let args = self
+            .input_buffer
+            .split_once(char::is_whitespace)
+            .map(|(_, args)| args.trim_start())
+            .unwrap_or("");
+        let words = args.split_whitespace().count();
+        if args.is_empty() || args.ends_with(char::is_whitespace) {
+            (words, "")
+        } else {
+            (words - 1, args.split_whitespace().last().unwrap_or(""))
+        }
+    }
+
+    // Arguments before the one being typed
+    pub fn previous_args(&self) -> Vec<&str> {
+        let (index, _) = self.current_arg();
+        self.args().split_whitespace().take(index).collect()
+    }
+
+    // Keep the completions that start with what has been typed of the argument
+    pub fn set_arg_suggestions(&mut self, completions: Vec<ArgSuggestion>) {
+        let (_, partial) = self.current_arg();
+        let partial = partial.to_lowercase();
+        self.arg_suggestions = completions
+            .into_iter()
+            .filter(|arg| arg.value.to_lowercase().starts_with(&partial))
             .collect();
         self.selected_index = 0;
     }
 
+    // The input with the argument being typed replaced by the selected completion
+    pub fn complete_arg(&self) -> Option<String> {
+        let arg = self.arg_suggestions.get(self.selected_index)?;
+        let (_, partial) = self.current_arg();
+        let kept = &self.input_buffer[..self.input_buffer.len() - partial.len()];
+        Some(format!("/{}{} ", kept, arg.value))
+    }
+
+    fn visible_count(&self) -> usize {
+        if self.command.is_some() {
+            self.arg_suggestions.len()
+        } else {
+            self.suggestions.len()
+        }
+    }
+
     pub fn next_suggestion(&mut self) {
-        if !self.suggestions.is_empty() {
-            self.selected_index = (self.selected_index + 1) % self.suggestions.len();
+        let count = self.visible_count();
+        if count > 0 {
+            self.selected_index = (self.selected_index + 1) % count;
         }
     }
 
     pub fn prev_suggestion(&mut self) {
-        if !self.suggestions.is_empty() {
+        let count = self.visible_count();
+        if count > 0 {
             self.selected_index = if self.selected_index == 0 {
-                self.suggestions.len() - 1
+                count - 1
             } else {
                 self.selected_index - 1
             };
@@ -84,10 +287,16 @@ impl SlashCommandState {
     }
 
     pub fn get_selected(&self) -> Option<&SlashCommand> {
-        self.suggestions.get(self.selected_index)
+        match &self.command {
+            Some(command) => Some(command),
+            None => self.suggestions.get(self.selected_index),
+        }
     }
 }
 
+// How often the elapsed time is redrawn when no spinner moves
+const ELAPSED_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub struct App {
     pub input: String,
     pub messages: Vec<(String, Vec<MessageContent>)>, // (role, content parts)
@@ -96,27 +305,515 @@ pub struct App {
     pub scroll_position: usize,                 // Current scroll position
     pub auto_scroll: bool,                      // Whether to auto-scroll to bottom
     pub total_lines: usize,                     // Total number of lines in the conversation
-    pub selection_mode: bool,                   // Toggle for text selection mode
+    pub message_starts: Vec<usize>, // First wrapped line of each message at the last render
+    pub selection_mode: bool,       // Toggle for text selection mode
     pub container_info: Option<(String, String)>, // Container ID and expiration
-    pub tool_mode: ToolMode,                    // Currently active tools
-    pub loading_animation_frame: usize,         // Current frame of loading animation
+    pub tool_mode: ToolMode,        // Currently active tools
+    pub loading_animation_frame: usize, // Current frame of loading animation
     pub last_animation_update: std::time::Instant, // Time of last animation update
-    pub connection_status: Option<String>,      // Current connection status
-    pub show_help: bool,                        // Whether to show help modal
+    pub waiting_since: std::time::Instant, // When the current request was sent
+    pub connection_status: Option<String>, // Current connection status
+    pub show_help: bool,            // Whether to show help modal
     pub slash_command_state: Option<SlashCommandState>, // Slash command autocomplete state
-    pub available_commands: Vec<SlashCommand>,  // Available slash commands
-    pub system_prompt: String,                  // System prompt for the AI
+    pub available_commands: Vec<SlashCommand>, // Available slash commands
+    pub system_prompt: String,      // System prompt for the AI
+    pub turn_metrics: Vec<TurnMetrics>, // Latency metrics for each completed turn
+    pub layout_cache: Vec<CachedLayout>, // Lines and wrapped heights of finished messages, by index
+    pub session_id: String,         // Id of the session file this conversation is saved to
+    pub session_created_at: String, // RFC 3339 creation time of the session
+    pub config: Config,             // User configuration from ~/.agnt/config.json
+    pub popup_mode: bool,           // Compact layout for tmux display-popup
+    pub speaker: Option<Speaker>,   // Reads replies aloud while /speak is on
+    pub queued_request: Option<RequestParams>, // Request a slash command wants sent next
+    pub alternatives: Option<Alternatives>, // Regenerated answers to the last prompt
+    pub pending_alternative: Option<String>, // Label of the answer being regenerated
+    pub confirmation: Option<Confirmation>, // Action waiting for a y/n keypress
+    pub last_request: RequestParams, // Overrides used by the most recent request
+    pub auto_continues: u32,        // Continuations sent for the current answer
+    pub stream_dropped: Option<String>, // Why the current stream broke off, if it did
+    pub resume_offered: bool,       // Reconnects gave up mid-answer; r on an empty input resumes it
+    pub queued_resume: bool,        // Resume the dropped answer at the top of the event loop
+    pub reconnects: u32,            // Reconnects attempted for the current answer
+    pub stalled: bool,              // The response stream has gone quiet
+    pub budget_approved: bool,      // The queued request was confirmed despite a budget
+    pub budget_warned: bool,        // A budget nearing its limit was pointed out this session
+    pub history: Vec<String>,       // Prompts sent in any session, oldest first
+    pub history_search: Option<HistorySearch>, // Active Ctrl+R search
+    pub model: String,              // Model for messages that do not pick one themselves
+    pub model_picker: Option<usize>, // Highlighted entry while the /model picker is open
+    pub available_models: Vec<ModelInfo>, // From the Models API (or its cache); may be empty
+    pub attachments: Vec<MessageContent>, // Images from /attach and /dir snapshots, sent with the next message
+    pub input_cursor: usize,              // Byte offset of the cursor in `input`
+    pub focus: Option<Focus>, // Highlighted message or code block while copy focus is on
+    pub sampling: RequestParams, // Sampling settings for every request (CLI flags, /params)
+    pub params_editor: Option<ParamsEditor>, // Open /params modal
+    pub compacting: Option<usize>, // Older messages being summarized in the background
+    pub queued_compaction: Option<usize>, // Compaction the event loop should start next
+    pub size_approved: bool,  // The queued request was confirmed despite its token count
+    pub queued_token_count: Option<String>, // /tokens request for the event loop (extra prompt text)
+    pub file_preview: Option<FilePreview>,  // Downloaded file shown in the preview popup
+    pub template_form: Option<TemplateForm>, // Open /prompt variable fill-in
+    pub queued_custom_command: Option<(usize, String)>, // Custom command and its arguments to expand next
+    pub session_browser: Option<SessionBrowser>,        // Open /sessions modal
+    pub tool_rounds: u32, // Rounds of tool results sent back since the user last wrote
+    pub tool_round: Option<ToolRound>, // Tool calls being run for the last answer
+    pub toolbox: Toolbox, // Local tools, and run_shell with --allow-shell
+    pub mention_completion: Option<(Vec<String>, usize)>, // Matches for the @path being completed, and the one shown
+    pub checkpoints: Vec<usize>, // Message counts marked with /checkpoint, for /rollback
+    pub queued_close: bool,      // /close: close this tab at the top of the event loop
+    pub stream_rate: Option<(std::time::Instant, usize)>, // First text delta of the current request and characters since, for the live tok/s
+    pub input_undo: editor::UndoHistory, // Earlier states of the input for undo/redo
+    pub pane: Pane, // Where keys go when no popup is open: the input or the message pane
+    pub page_lines: usize, // Conversation lines that fit in the message pane, for paging
+    pub messages_area: Rect, // Where the conversation was last drawn, for mouse clicks
+    pub input_area: Rect, // Where the input box was last drawn
+}
+
+// Attempts to resume an answer whose stream dropped before giving up
+pub const MAX_RECONNECTS: u32 = 3;
+
+// Fields of the /params modal as typed; an empty field leaves the API default
+#[derive(Debug, Clone)]
+pub struct ParamsEditor {
+    pub values: [String; 4], // temperature, top_p, top_k, stop sequences (comma-separated)
+    pub selected: usize,
+    pub error: Option<String>, // Why the last Enter was rejected
+}
+
+// /sessions: saved sessions narrowed down by a fuzzy query as it is typed
+#[derive(Debug, Clone)]
+pub struct SessionBrowser {
+    pub sessions: Vec<session::Session>, // Most recent first, without the current one
+    pub query: String,
+    pub matches: Vec<usize>, // Indices into sessions, best match first
+    pub selected: usize,     // Index into matches
+}
+
+impl SessionBrowser {
+    pub fn new(sessions: Vec<session::Session>, query: &str) -> Self {
+        let mut browser = Self {
+            sessions,
+            query: query.to_string(),
+            matches: Vec::new(),
+            selected: 0,
+        };
+        browser.refilter();
+        browser
+    }
+
+    pub fn refilter(&mut self) {
+        self.matches = session::search(&self.sessions, &self.query);
+        self.selected = 0;
+    }
+
+    pub fn select(&mut self, delta: isize) {
+        if !self.matches.is_empty() {
+            self.selected =
+                (self.selected as isize + delta).clamp(0, self.matches.len() as isize - 1) as usize;
+        }
+    }
+
+    pub fn selected_session(&self) -> Option<&session::Session> {
+        self.sessions.get(*self.matches.get(self.selected)?)
+    }
+}
+
+// /prompt fill-in: one field per {{variable}} of the template, in order of appearance
+#[derive(Debug, Clone)]
+pub struct TemplateForm {
+    pub template: Template,
+    pub names: Vec<String>,
+    pub values: Vec<String>,
+    pub selected: usize,
+}
+
+impl TemplateForm {
+    pub fn new(template: Template) -> Self {
+        let names = template.variables();
+        Self {
+            values: vec![String::new(); names.len()],
+            names,
+            template,
+            selected: 0,
+        }
+    }
+
+    pub fn select(&mut self, delta: isize) {
+        self.selected =
+            (self.selected as isize + delta).rem_euclid(self.names.len() as isize) as usize;
+    }
+
+    pub fn selected_value(&mut self) -> &mut String {
+        &mut self.values[self.selected]
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.selected + 1 == self.names.len()
+    }
+
+    pub fn render(&self) -> String {
+        let values: HashMap<String, String> = self
+            .names
+            .iter()
+            .cloned()
+            .zip(self.values.iter().cloned())
+            .collect();
+        self.template.render(&values)
+    }
+}
+
+pub const PARAMS_FIELDS: [&str; 4] = ["temperature", "top_p", "top_k", "stop"];
+
+impl ParamsEditor {
+    pub fn new(params: &RequestParams) -> Self {
+        let show = |value: Option<String>| value.unwrap_or_default();
+        Self {
+            values: [
+                show(params.temperature.map(|v| v.to_string())),
+                show(params.top_p.map(|v| v.to_string())),
+                show(params.top_k.map(|v| v.to_string())),
+                show(params.stop_sequences.as_ref().map(|stops| stops.join(", "))),
+            ],
+            selected: 0,
+            error: None,
+        }
+    }
+
+    pub fn select(&mut self, delta: isize) {
+        self.selected =
+            (self.selected as isize + delta).rem_euclid(PARAMS_FIELDS.len() as isize) as usize;
+    }
+
+    pub fn selected_value(&mut self) -> &mut String {
+        &mut self.values[self.selected]
+    }
+
+    pub fn parse(&self) -> Result<RequestParams, String> {
+        fn field<T: std::str::FromStr>(name: &str, value: &str) -> Result<Option<T>, String> {
+            let value = value.trim();
+            if value.is_empty() {
+                return Ok(None);
+            }
+            value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid {}: {}", name, value))
+        }
+        let stops: Vec<String> = self.values[3]
+            .split(',')
+            .map(str::trim)
+            .filter(|stop| !stop.is_empty())
+            .map(str::to_string)
+            .collect();
+        let params = RequestParams {
+            model: None,
+            temperature: field(PARAMS_FIELDS[0], &self.values[0])?,
+            top_p: field(PARAMS_FIELDS[1], &self.values[1])?,
+            top_k: field(PARAMS_FIELDS[2], &self.values[2])?,
+            stop_sequences: (!stops.is_empty()).then_some(stops),
+            max_tokens: None,
+        };
+        params.validate().map_err(|e| e.to_string())?;
+        Ok(params)
+    }
+}
+
+// What takes the keys. Popups (help, pickers, forms, confirmations) take them while
+// open; otherwise Tab/Shift+Tab switch between the input and the message pane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pane {
+    Input,    // Typing edits the draft
+    Messages, // Arrows, PageUp/PageDown and Home/End scroll the conversation
+    Modal,
+}
+
+// Something that can be copied in focus mode (Ctrl+Y): a whole message, or a code
+// block, code cell, code output, tool result, attached file or downloaded file inside it
+#[derive(Debug, Clone)]
+pub struct FocusTarget {
+    pub message_index: usize,
+    pub part: FocusPart,
+    pub label: String,
+    pub text: String,
+    pub file: Option<PathBuf>, // Saved download that Enter previews or opens
+    pub output: Option<usize>, // Code output or tool result block that o views and s saves
+}
+
+// What d deletes for a focus target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FocusPart {
+    Message,      // The whole message
+    Block(usize), // A tool result or attached file, by index into the message's contents
+    Inner,        // Part of a message's text or output; nothing to delete on its own
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Focus {
+    pub target: usize, // Index into App::focus_targets()
+    pub reveal: bool,  // Scroll the target's message into view on the next draw
+}
+
+// Reverse-incremental search over the prompt history (Ctrl+R)
+#[derive(Debug, Clone, Default)]
+pub struct HistorySearch {
+    pub query: String,
+    pub match_index: Option<usize>, // Index into App::history of the current match
+    pub original_input: String,     // Restored when the search is cancelled
+}
+
+// An action that runs only after the user presses y; any other key cancels it
+#[derive(Debug, Clone)]
+pub enum Confirmation {
+    SaveCode(Vec<(PathBuf, String)>), // (path, contents) of each file to write
+    OverBudget(RequestParams),        // Request held back by a spend budget
+    LargeRequest(RequestParams),      // Request over token_warning_threshold
+    ToolCall(tools::Approval),        // run_shell command or write_file change Claude wants
+}
+
+// The calls of one answer, run one at a time
+#[derive(Debug, Default)]
+pub struct ToolRound {
+    pub pending: VecDeque<ToolCall>,
+    pub running: Option<String>, // tool_use_id of the call in progress
+    pub approved: bool,          // The user said yes to running the next call
+    pub cancelled: bool,
+}
+
+pub enum ToolStep {
+    Run(ToolCall),
+    Finished, // A cancelled round is over; nothing more will be sent
+}
+
+// Answers to the same prompt produced by /retry. The selected variant is the one
+// shown (and sent as history) at `message_index` in `messages`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alternatives {
+    pub message_index: usize,
+    pub variants: Vec<(String, Vec<MessageContent>)>, // (label, content)
+    pub selected: usize,
+}
+
+// Code outputs larger than this are moved to disk as soon as they arrive
+const SPILL_THRESHOLD_BYTES: usize = 64 * 1024;
+// Upper bound on code output bodies kept in memory across the whole conversation
+const MEMORY_CAP_BYTES: usize = 8 * 1024 * 1024;
+// Lines kept from the start and end of a spilled output
+const PREVIEW_HEAD_LINES: usize = 20;
+const PREVIEW_TAIL_LINES: usize = 5;
+const PREVIEW_MAX_LINE_CHARS: usize = 500;
+
+// Write the full output to the session store and replace it in place with a short preview.
+// Returns the blob id, or None (leaving the output untouched) if it couldn't be written.
+fn spill_code_output(stdout: &mut String, stderr: &mut String) -> Option<String> {
+    let mut hasher = DefaultHasher::new();
+    stdout.hash(&mut hasher);
+    stderr.hash(&mut hasher);
+    let blob_id = format!("{:016x}", hasher.finish());
+
+    let body = session::SpilledOutput {
+        stdout: std::mem::take(stdout),
+        stderr: std::mem::take(stderr),
+    };
+    match session::save_blob(&blob_id, &body) {
+        Ok(()) => {
+            *stdout = preview_text(&body.stdout);
+            *stderr = preview_text(&body.stderr);
+            Some(blob_id)
+        }
+        Err(e) => {
+            log_debug!("Failed to spill code output to disk: {}", e);
+            *stdout = body.stdout;
+            *stderr = body.stderr;
+            None
+        }
+    }
+}
+
+// 1240 → "1,240"
+fn group_digits(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+// First and last few lines of a large text, with overly long lines cut short
+fn preview_text(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let shorten = |line: &&str| -> String {
+        if line.chars().count() > PREVIEW_MAX_LINE_CHARS {
+            let cut: String = line.chars().take(PREVIEW_MAX_LINE_CHARS).collect();
+            format!("{}…", cut)
+        } else {
+            line.to_string()
+        }
+    };
+
+    if lines.len() <= PREVIEW_HEAD_LINES + PREVIEW_TAIL_LINES {
+        return lines.iter().map(shorten).collect::<Vec<_>>().join("\n");
+    }
+
+    let mut preview: Vec<String> = lines[..PREVIEW_HEAD_LINES].iter().map(shorten).collect();
+    preview.push(format!(
+        "… {} lines omitted …",
+        lines.len() - PREVIEW_HEAD_LINES - PREVIEW_TAIL_LINES
+    ));
+    preview.extend(
+        lines[lines.len() - PREVIEW_TAIL_LINES..]
+            .iter()
+            .map(shorten),
+    );
+    preview.join("\n")
+}
+
+// Lines of a finished message, valid while its content hash is unchanged, and their
+// wrapped height at `width`
+#[derive(Debug, Clone)]
+pub struct CachedLayout {
+    pub hash: u64,
+    pub width: usize,
+    pub height: usize,
+    pub lines: Vec<Line<'static>>,
 }
 
 impl Default for App {
     fn default() -> Self {
-        let available_commands = vec![SlashCommand {
-            name: "clear".to_string(),
-            description: "Clear the conversation history".to_string(),
-            action: SlashCommandAction::Clear,
-        }];
-
-        let default_system_prompt = "You are a helpful assistant. Your knowledge cut-off is March 2025. The current date and time is [DATE_TIME_WITH_WEEKDAY_AND_TIMEZONE]".to_string();
+        let available_commands = vec![
+            SlashCommand {
+                name: "clear".to_string(),
+                description: "Clear the conversation history".to_string(),
+                action: SlashCommandAction::Clear,
+            },
+            SlashCommand {
+                name: "stats".to_string(),
+                description: "Show latency and token statistics".to_string(),
+                action: SlashCommandAction::Stats,
+            },
+            SlashCommand {
+                name: "copy".to_string(),
+                description: "Copy the last response to the clipboard".to_string(),
+                action: SlashCommandAction::Copy,
+            },
+            SlashCommand {
+                name: "speak".to_string(),
+                description: "Toggle reading responses aloud".to_string(),
+                action: SlashCommandAction::Speak,
+            },
+            SlashCommand {
+                name: "retry".to_string(),
+                description: "Regenerate the last answer [--temperature T] [--top-p P] [--top-k K] [--stop S] [--model M]".to_string(),
+                action: SlashCommandAction::Retry,
+            },
+            SlashCommand {
+                name: "savecode".to_string(),
+                description: "Write code blocks from the last answer to files [all]".to_string(),
+                action: SlashCommandAction::SaveCode,
+            },
+            SlashCommand {
+                name: "ask".to_string(),
+                description: "Send one message to another model: /ask <model> <message>"
+                    .to_string(),
+                action: SlashCommandAction::Ask,
+            },
+            SlashCommand {
+                name: "resume".to_string(),
+                description: "Continue a saved session: /resume [id|last]; lists sessions without an id"
+                    .to_string(),
+                action: SlashCommandAction::Resume,
+            },
+            SlashCommand {
+                name: "sessions".to_string(),
+                description: "Browse saved sessions with fuzzy search [query]".to_string(),
+                action: SlashCommandAction::Sessions,
+            },
+            SlashCommand {
+                name: "model".to_string(),
+                description: "Switch the model for the rest of the conversation [name]"
+                    .to_string(),
+                action: SlashCommandAction::Model,
+            },
+            SlashCommand {
+                name: "attach".to_string(),
+                description: "Attach an image to the next message: /attach <path> | clear"
+                    .to_string(),
+                action: SlashCommandAction::Attach,
+            },
+            SlashCommand {
+                name: "dir".to_string(),
+                description: "Add the directory tree and git status to the next message [path]"
+                    .to_string(),
+                action: SlashCommandAction::Dir,
+            },
+            SlashCommand {
+                name: "branch".to_string(),
+                description: "Fork the conversation into a new session [message number]"
+                    .to_string(),
+                action: SlashCommandAction::Branch,
+            },
+            SlashCommand {
+                name: "checkpoint".to_string(),
+                description: "Mark the current point of the conversation for /rollback"
+                    .to_string(),
+                action: SlashCommandAction::Checkpoint,
+            },
+            SlashCommand {
+                name: "rollback".to_string(),
+                description: "Drop the messages after a checkpoint: /rollback <n>; lists them without n"
+                    .to_string(),
+                action: SlashCommandAction::Rollback,
+            },
+            SlashCommand {
+                name: "close".to_string(),
+                description: "Close this tab (Ctrl+T opens one)".to_string(),
+                action: SlashCommandAction::Close,
+            },
+            SlashCommand {
+                name: "continue".to_string(),
+                description: "Have Claude finish the last answer where it stopped".to_string(),
+                action: SlashCommandAction::Continue,
+            },
+            SlashCommand {
+                name: "review".to_string(),
+                description: "Review staged changes, or git diff [range] [-- paths]".to_string(),
+                action: SlashCommandAction::Review,
+            },
+            SlashCommand {
+                name: "export".to_string(),
+                description: "Save the conversation as a transcript: /export [md|json|html] [path]"
+                    .to_string(),
+                action: SlashCommandAction::Export,
+            },
+            SlashCommand {
+                name: "params".to_string(),
+                description: "Edit temperature, top_p, top_k and stop sequences".to_string(),
+                action: SlashCommandAction::Params,
+            },
+            SlashCommand {
+                name: "maxtokens".to_string(),
+                description: "Show or set the output token limit per answer [n]".to_string(),
+                action: SlashCommandAction::MaxTokens,
+            },
+            SlashCommand {
+                name: "compact".to_string(),
+                description: "Summarize older turns to free up context [auto]".to_string(),
+                action: SlashCommandAction::Compact,
+            },
+            SlashCommand {
+                name: "tokens".to_string(),
+                description: "Count the input tokens of the next request [prompt]".to_string(),
+                action: SlashCommandAction::Tokens,
+            },
+            SlashCommand {
+                name: "prompt".to_string(),
+                description: "Fill in a prompt template from ~/.agnt/prompts [name]".to_string(),
+                action: SlashCommandAction::Prompt,
+            },
+        ];
 
         Self {
             input: String::new(),
@@ -125,45 +822,320 @@ impl Default for App {
             streaming_content: Vec::new(),
             scroll_position: 0,
             auto_scroll: true,
+            message_starts: Vec::new(),
             total_lines: 0,
             selection_mode: false,
             container_info: None,
             tool_mode: ToolMode::None,
             loading_animation_frame: 0,
             last_animation_update: std::time::Instant::now(),
+            waiting_since: std::time::Instant::now(),
             connection_status: None,
             show_help: false,
             slash_command_state: None,
             available_commands,
-            system_prompt: default_system_prompt,
+            system_prompt: prompt::DEFAULT_SYSTEM_PROMPT.to_string(),
+            turn_metrics: Vec::new(),
+            layout_cache: Vec::new(),
+            session_id: session::new_session_id(),
+            session_created_at: chrono::Local::now().to_rfc3339(),
+            config: Config::default(),
+            popup_mode: false,
+            speaker: None,
+            queued_request: None,
+            alternatives: None,
+            pending_alternative: None,
+            confirmation: None,
+            last_request: RequestParams::default(),
+            auto_continues: 0,
+            stream_dropped: None,
+            resume_offered: false,
+            queued_resume: false,
+            reconnects: 0,
+            stalled: false,
+            budget_approved: false,
+            budget_warned: false,
+            history: Vec::new(),
+            history_search: None,
+            model: default_model(),
+            model_picker: None,
+            available_models: Vec::new(),
+            attachments: Vec::new(),
+            input_cursor: 0,
+            focus: None,
+            sampling: RequestParams::default(),
+            params_editor: None,
+            compacting: None,
+            file_preview: None,
+            queued_custom_command: None,
+            template_form: None,
+            session_browser: None,
+            queued_compaction: None,
+            size_approved: false,
+            queued_token_count: None,
+            tool_rounds: 0,
+            tool_round: None,
+            toolbox: Toolbox::default(),
+            mention_completion: None,
+            checkpoints: Vec::new(),
+            stream_rate: None,
+            input_undo: Default::default(),
+            pane: Pane::Input,
+            page_lines: 0,
+            messages_area: Rect::default(),
+            input_area: Rect::default(),
+            queued_close: false,
         }
     }
 }
 
 impl App {
-    pub fn add_message(&mut self, role: String, content: String) {
-        self.messages
-            .push((role, vec![MessageContent::Text(content)]));
-        // Auto-scroll will be handled during rendering
+    // A prompt from the input box, preceded by any images attached with /attach and the
+    // files it mentions as @path
+    pub fn add_user_message(&mut self, text: String) {
+        let mut contents = std::mem::take(&mut self.attachments);
+        for path in mentions::find(&text) {
+            match mentions::read(&path) {
+                Ok((content, truncated)) => contents.push(MessageContent::File {
+                    path,
+                    content,
+                    truncated,
+                }),
+                Err(e) => self.add_info(e),
+            }
+        }
+        contents.push(MessageContent::Text(text));
+        self.messages.push(("user".to_string(), contents));
+        self.tool_rounds = 0;
+    }
+
+    // /export [md|json|html] [path]: the format comes from the first argument or the
+    // path's extension, and the file defaults to agnt-<session id>.<ext> in the cwd
+    pub fn export_conversation(&mut self, args: &str) {
+        let mut words = args.split_whitespace();
+        let first = words.next();
+        let (format, path) = match first.and_then(export::Format::from_name) {
+            Some(format) => (Some(format), words.next()),
+            None => (None, first),
+        };
+        let path = path.map(|path| PathBuf::from(expand_home(path)));
+        let format = format
+            .or_else(|| path.as_deref().and_then(export::Format::from_path))
+            .unwrap_or(export::Format::Markdown);
+        let path = path.unwrap_or_else(|| {
+            PathBuf::from(format!("agnt-{}.{}", self.session_id, format.extension()))
+        });
+
+        let result = export::render(format, &self.session_id, &self.messages)
+            .and_then(|transcript| Ok(std::fs::write(&path, transcript)?));
+        match result {
+            Ok(()) => self.add_info(format!(
+                "Exported {} messages to {}",
+                self.messages.len(),
+                path.display()
+            )),
+            Err(e) => self.add_local_error(format!("Failed to export: {}", e)),
+        }
+    }
+
+    pub fn attach_image(&mut self, path: &str) {
+        let path = PathBuf::from(expand_home(path));
+        match anthropic::read_image(&path) {
+            Ok((media_type, bytes)) => {
+                use base64::Engine;
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.display().to_string());
+                self.add_info(format!(
+                    "Attached {} ({} KB); it will be sent with your next message",
+                    name,
+                    bytes.len().div_ceil(1024)
+                ));
+                self.attachments.push(MessageContent::Image {
+                    name,
+                    media_type: media_type.to_string(),
+                    data: base64::engine::general_purpose::STANDARD.encode(&bytes),
+                });
+            }
+            Err(e) => self.add_local_error(e.to_string()),
+        }
+    }
+
+    // /dir [path]: the project tree and git status go along with the next message
+    pub fn attach_project(&mut self, dir: &str) {
+        let dir = if dir.is_empty() { "." } else { dir };
+        match project::snapshot(Path::new(&expand_home(dir))) {
+            Ok(content) => {
+                self.add_info(format!(
+                    "Attached the tree and git status of {} ({} lines); they will be sent with your next message",
+                    dir,
+                    content.lines().count()
+                ));
+                self.attachments.push(MessageContent::Context {
+                    label: format!("project tree of {}", dir),
+                    content,
+                });
+            }
+            Err(e) => self.add_info(e),
+        }
+    }
+
+    // Tab after `@pa`: complete the path as far as it is unambiguous, then step through
+    // the matches on further presses. False when the cursor isn't on an @ word.
+    pub fn complete_mention(&mut self) -> bool {
+        let start = self.input[..self.input_cursor]
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + 1);
+        let Some(partial) = self.input[start..self.input_cursor].strip_prefix('@') else {
+            return false;
+        };
+
+        let completion = match self.mention_completion.take() {
+            // Still showing the match the last Tab picked: move on to the next one
+            Some((candidates, index)) if candidates.get(index).is_some_and(|c| c == partial) => {
+                let index = (index + 1) % candidates.len();
+                Some((candidates, index))
+            }
+            _ => {
+                let candidates = mentions::complete(partial);
+                let common = common_prefix(&candidates);
+                match candidates.len() {
+                    0 => None,
+                    1 => Some((candidates, 0)),
+                    _ if common.len() > partial.len() => {
+                        self.replace_mention(start, &common);
+                        return true;
+                    }
+                    _ => Some((candidates, 0)),
+                }
+            }
+        };
+        if let Some((candidates, index)) = completion {
+            self.replace_mention(start, &candidates[index].clone());
+            if candidates.len() > 1 {
+                self.mention_completion = Some((candidates, index));
+            }
+        }
+        true
+    }
+
+    fn replace_mention(&mut self, start: usize, path: &str) {
+        self.input_undo
+            .record(&self.input, self.input_cursor, false);
+        self.input
+            .replace_range(start..self.input_cursor, &format!("@{}", path));
+        self.input_cursor = start + 1 + path.len();
     }
 
     pub fn clear_input(&mut self) {
+        self.input_undo
+            .record(&self.input, self.input_cursor, false);
         self.input.clear();
+        self.input_cursor = 0;
+    }
+
+    // Replace the draft, leaving the cursor at its end
+    pub fn set_input(&mut self, text: String) {
+        self.input_undo
+            .record(&self.input, self.input_cursor, false);
+        self.input_cursor = text.len();
+        self.input = text;
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        self.input_undo
+            .record(&self.input, self.input_cursor, c.is_alphanumeric());
+        self.input.insert(self.input_cursor, c);
+        self.input_cursor += c.len_utf8();
+    }
+
+    // Backspace
+    pub fn delete_before_cursor(&mut self) {
+        if self.input_cursor == 0 {
+            return;
+        }
+        self.input_undo
+            .record(&self.input, self.input_cursor, false);
+        let start = editor::prev_char(&self.input, self.input_cursor);
+        self.input.replace_range(start..self.input_cursor, "");
+        self.input_cursor = start;
+    }
+
+    // Delete
+    pub fn delete_at_cursor(&mut self) {
+        if self.input_cursor == self.input.len() {
+            return;
+        }
+        self.input_undo
+            .record(&self.input, self.input_cursor, false);
+        let end = editor::next_char(&self.input, self.input_cursor);
+        self.input.replace_range(self.input_cursor..end, "");
+    }
+
+    pub fn move_cursor(&mut self, motion: fn(&str, usize) -> usize) {
+        self.input_cursor = motion(&self.input, self.input_cursor);
+        self.input_undo.break_run();
+    }
+
+    // Delete from the cursor to wherever `motion` would move it, in either direction
+    pub fn delete_to(&mut self, motion: fn(&str, usize) -> usize) {
+        let target = motion(&self.input, self.input_cursor);
+        let range = target.min(self.input_cursor)..target.max(self.input_cursor);
+        if range.is_empty() {
+            return;
+        }
+        self.input_undo
+            .record(&self.input, self.input_cursor, false);
+        self.input_cursor = range.start;
+        self.input.replace_range(range, "");
+    }
+
+    pub fn undo_input(&mut self) {
+        if let Some((text, cursor)) = self.input_undo.undo(&self.input, self.input_cursor) {
+            self.input = text;
+            self.input_cursor = cursor;
+        }
+    }
+
+    pub fn redo_input(&mut self) {
+        if let Some((text, cursor)) = self.input_undo.redo(&self.input, self.input_cursor) {
+            self.input = text;
+            self.input_cursor = cursor;
+        }
     }
 
     pub fn start_streaming(&mut self) {
         self.streaming_content.clear();
         self.loading_animation_frame = 0;
         self.last_animation_update = std::time::Instant::now();
+        self.waiting_since = std::time::Instant::now();
         // Auto-scroll will be handled during rendering
     }
 
-    pub fn update_loading_animation(&mut self) {
+    // How often the loading indicator changes: the spinner's pace, or once a second for
+    // the elapsed time when there is no spinner or motion is reduced
+    pub fn animation_interval(&self) -> std::time::Duration {
+        match self.config.spinner.interval() {
+            Some(interval) if !self.config.reduced_motion => interval,
+            _ => ELAPSED_INTERVAL,
+        }
+    }
+
+    // When the loading animation next needs to advance
+    pub fn next_animation_frame(&self) -> std::time::Instant {
+        self.last_animation_update + self.animation_interval()
+    }
+
+    // Returns true when the animation advanced to a new frame
+    pub fn update_loading_animation(&mut self) -> bool {
         let now = std::time::Instant::now();
-        if now.duration_since(self.last_animation_update).as_millis() >= 300 {
-            self.loading_animation_frame = (self.loading_animation_frame + 1) % 3;
+        if now >= self.next_animation_frame() {
+            self.loading_animation_frame = self.loading_animation_frame.wrapping_add(1);
             self.last_animation_update = now;
+            return true;
         }
+        false
     }
 
     pub fn append_streaming_text(&mut self, text: &str) {
@@ -181,6 +1153,11 @@ impl App {
             .push(MessageContent::Code { input: code });
     }
 
+    pub fn add_streaming_tool_use(&mut self, id: String, name: String, input: String) {
+        self.streaming_content
+            .push(MessageContent::ToolUse { id, name, input });
+    }
+
     pub fn add_streaming_output(
         &mut self,
         stdout: String,
@@ -188,14 +1165,68 @@ impl App {
         return_code: i32,
         files: Vec<(String, String)>,
     ) {
+        let mut stdout = stdout;
+        let mut stderr = stderr;
+        let mut spilled = None;
+        // Giant outputs go straight to disk instead of living in memory for the whole session
+        if stdout.len() + stderr.len() > SPILL_THRESHOLD_BYTES {
+            spilled = spill_code_output(&mut stdout, &mut stderr);
+        }
+
+        let downloads = files
+            .iter()
+            .filter(|(file_id, _)| file_id.starts_with("file_"))
+            .map(|(file_id, _)| (file_id.clone(), DownloadState::Queued))
+            .collect();
         self.streaming_content.push(MessageContent::CodeOutput {
             stdout,
             stderr,
             return_code,
             files,
+            spilled,
+            downloads,
         });
     }
 
+    // Spill the oldest code outputs to disk until the bodies kept in memory fit the cap
+    pub fn enforce_memory_cap(&mut self) {
+        let mut retained: usize = self
+            .messages
+            .iter()
+            .flat_map(|(_, contents)| contents)
+            .map(|content| match content {
+                MessageContent::CodeOutput {
+                    stdout,
+                    stderr,
+                    spilled: None,
+                    ..
+                } => stdout.len() + stderr.len(),
+                _ => 0,
+            })
+            .sum();
+
+        for (_, contents) in &mut self.messages {
+            for content in contents {
+                if retained <= MEMORY_CAP_BYTES {
+                    return;
+                }
+                if let MessageContent::CodeOutput {
+                    stdout,
+                    stderr,
+                    spilled: spilled @ None,
+                    ..
+                } = content
+                {
+                    let before = stdout.len() + stderr.len();
+                    if let Some(blob_id) = spill_code_output(stdout, stderr) {
+                        retained -= before - (stdout.len() + stderr.len());
+                        *spilled = Some(blob_id);
+                    }
+                }
+            }
+        }
+    }
+
     pub fn add_streaming_error(&mut self, error: String) {
         self.streaming_content
             .push(MessageContent::CodeError(error));
@@ -206,6 +1237,40 @@ impl App {
             .push(("system".to_string(), vec![MessageContent::ApiError(error)]));
     }
 
+    pub fn add_local_error(&mut self, error: String) {
+        self.messages.push((
+            "system".to_string(),
+            vec![MessageContent::LocalError(error)],
+        ));
+    }
+
+    pub fn add_info(&mut self, text: String) {
+        self.messages
+            .push(("system".to_string(), vec![MessageContent::Info(text)]));
+    }
+
+    pub fn record_metrics(&mut self, metrics: TurnMetrics) {
+        self.record_usage(&metrics);
+
+        if let Some(reason) = &metrics.stop_reason
+            && !self.streaming_content.is_empty()
+        {
+            self.streaming_content
+                .push(MessageContent::StopReason(reason.clone()));
+            self.streaming_content
+                .push(MessageContent::Timing(timing_summary(&metrics)));
+        }
+        self.stream_rate = None;
+        self.turn_metrics.push(metrics);
+    }
+
+    fn record_usage(&self, metrics: &TurnMetrics) {
+        let entry = usage::UsageRecord::new(&self.session_id, metrics);
+        if let Err(e) = usage::record(&entry) {
+            log_debug!("Failed to record usage: {}", e);
+        }
+    }
+
     pub fn set_container_info(&mut self, id: String, expires_at: String) {
         self.container_info = Some((id, expires_at));
     }
@@ -216,10 +1281,33 @@ impl App {
 
     pub fn finish_streaming(&mut self) {
         if !self.streaming_content.is_empty() {
-            let content = std::mem::take(&mut self.streaming_content);
+            let mut content = std::mem::take(&mut self.streaming_content);
+            // Answers from a model other than the session default say which one it was
+            if let Some(model) = &self.last_request.model {
+                let note = MessageContent::Info(format!("answered by {}", model));
+                if !matches!(content.first(), Some(MessageContent::Info(_))) {
+                    content.insert(0, note);
+                }
+            }
             self.messages.push(("assistant".to_string(), content));
         }
         self.connection_status = None;
+        self.stream_rate = None;
+    }
+
+    pub fn note_streamed_text(&mut self, text: &str) {
+        let (_, chars) = self
+            .stream_rate
+            .get_or_insert_with(|| (std::time::Instant::now(), 0));
+        *chars += text.chars().count();
+    }
+
+    // Estimated from the characters streamed so far, like pricing::estimate_tokens;
+    // the exact count only arrives with the final usage
+    pub fn live_tokens_per_sec(&self) -> Option<f64> {
+        let (start, chars) = self.stream_rate?;
+        let secs = start.elapsed().as_secs_f64();
+        (secs >= 0.5).then(|| chars.div_ceil(4) as f64 / secs)
     }
 
     pub fn scroll_up(&mut self, amount: usize) {
@@ -233,13 +1321,66 @@ impl App {
         self.auto_scroll = false;
     }
 
-    pub fn update_scroll_bounds(&mut self, total_lines: usize, visible_lines: usize) {
-        self.total_lines = total_lines;
-        let max_scroll = total_lines.saturating_sub(visible_lines);
-
-        // Auto-scroll to bottom if enabled
-        if self.auto_scroll {
-            self.scroll_position = max_scroll;
+    // Scroll so the previous or next message starts at the top of the view; past the
+    // first or last message this goes to the very top or bottom
+    pub fn jump_to_message(&mut self, forward: bool) {
+        let target = if forward {
+            self.message_starts
+                .iter()
+                .copied()
+                .find(|&start| start > self.scroll_position)
+        } else {
+            self.message_starts
+                .iter()
+                .rev()
+                .copied()
+                .find(|&start| start < self.scroll_position)
+        };
+        match target {
+            Some(start) => {
+                self.scroll_position = start;
+                self.auto_scroll = false;
+            }
+            None if forward => self.scroll_to_bottom(),
+            None => self.scroll_to_top(),
+        }
+    }
+
+    pub fn active_pane(&self) -> Pane {
+        let modal = self.show_help
+            || self.confirmation.is_some()
+            || self.model_picker.is_some()
+            || self.params_editor.is_some()
+            || self.session_browser.is_some()
+            || self.template_form.is_some()
+            || self.file_preview.is_some();
+        if modal {
+            Pane::Modal
+        } else if self.focus.is_some() {
+            // Picking a message to copy happens in the message pane
+            Pane::Messages
+        } else {
+            self.pane
+        }
+    }
+
+    pub fn scroll_to_top(&mut self) {
+        self.scroll_position = 0;
+        self.auto_scroll = false;
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.auto_scroll = true;
+    }
+
+    pub fn update_scroll_bounds(&mut self, total_lines: usize, visible_lines: usize) {
+        self.total_lines = total_lines;
+        self.page_lines = visible_lines;
+        let max_scroll = total_lines.saturating_sub(visible_lines);
+
+        // Auto-scroll to bottom if enabled
+        if self.auto_scroll {
+            self.scroll_position = max_scroll;
         }
 
         // Clamp scroll position to valid range
@@ -303,6 +1444,62 @@ impl App {
         }
     }
 
+    pub fn update_download(&mut self, file_id: &str, state: DownloadState) {
+        let contents = self
+            .messages
+            .iter_mut()
+            .flat_map(|(_, contents)| contents.iter_mut())
+            .chain(self.streaming_content.iter_mut());
+        for content in contents {
+            if let MessageContent::CodeOutput { downloads, .. } = content {
+                for (id, current) in downloads {
+                    if id == file_id {
+                        *current = state.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    // Custom commands from the config join the built-in ones; a name that is already
+    // taken by a built-in command is skipped
+    pub fn add_custom_commands(&mut self) {
+        for (index, custom) in self.config.custom_commands.iter().enumerate() {
+            let name = custom.name.trim_start_matches('/');
+            if self.available_commands.iter().any(|cmd| cmd.name == name) {
+                log_debug!("Custom command /{} clashes with an existing command", name);
+                continue;
+            }
+            let description = custom.description.clone().unwrap_or_else(|| {
+                custom
+                    .prompt
+                    .as_deref()
+                    .or(custom.command.as_deref())
+                    .unwrap_or("Custom command")
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+            self.available_commands.push(SlashCommand {
+                name: name.to_string(),
+                description,
+                action: SlashCommandAction::Custom(index),
+            });
+        }
+    }
+
+    // Send what a custom command expanded to as if it had been typed
+    pub fn send_custom_command(&mut self, result: anyhow::Result<String>) {
+        match result {
+            Ok(message) => {
+                self.add_user_message(message);
+                self.queued_request = Some(RequestParams::default());
+            }
+            Err(e) => self.add_local_error(format!("Custom command failed: {}", e)),
+        }
+    }
+
     pub fn start_slash_command(&mut self) {
         let mut state = SlashCommandState::new();
         state.update_suggestions(&self.available_commands);
@@ -310,9 +1507,107 @@ impl App {
     }
 
     pub fn update_slash_command(&mut self, input: &str) {
-        if let Some(state) = &mut self.slash_command_state {
-            state.input_buffer = input.to_string();
-            state.update_suggestions(&self.available_commands);
+        let Some(mut state) = self.slash_command_state.take() else {
+            return;
+        };
+        state.input_buffer = input.to_string();
+        state.update_suggestions(&self.available_commands);
+        if let Some(command) = &state.command {
+            let (index, _) = state.current_arg();
+            let completions = self.arg_completions(&command.action, index, &state.previous_args());
+            state.set_arg_suggestions(completions);
+        }
+        self.slash_command_state = Some(state);
+    }
+
+    // Tab after a command name: put the selected argument completion into the input
+    pub fn complete_slash_argument(&mut self) -> bool {
+        let Some(completed) = self
+            .slash_command_state
+            .as_ref()
+            .and_then(SlashCommandState::complete_arg)
+        else {
+            return false;
+        };
+        self.set_input(completed.clone());
+        self.update_slash_command(&completed[1..]);
+        true
+    }
+
+    // Enter in the slash menu: finish a partly typed argument, then run the command
+    pub fn submit_slash_command(&mut self) {
+        let partial = self
+            .slash_command_state
+            .as_ref()
+            .is_some_and(|state| state.command.is_some() && !state.current_arg().1.is_empty());
+        if partial {
+            self.complete_slash_argument();
+        }
+        if let Some(state) = &self.slash_command_state
+            && let Some(cmd) = state.get_selected()
+        {
+            let action = cmd.action.clone();
+            let args = state.args().to_string();
+            self.execute_slash_command(action, &args);
+        }
+    }
+
+    // Values the argument at `index` can take, with a short description of each
+    fn arg_completions(
+        &self,
+        action: &SlashCommandAction,
+        index: usize,
+        previous: &[&str],
+    ) -> Vec<ArgSuggestion> {
+        let model_names = || {
+            models::completion_entries(&self.available_models)
+                .into_iter()
+                .map(|(name, description)| ArgSuggestion::new(name, description))
+                .collect()
+        };
+        match (action, index) {
+            (SlashCommandAction::Model | SlashCommandAction::Ask, 0) => model_names(),
+            (SlashCommandAction::Export, 0) => vec![
+                ArgSuggestion::new("md", "Markdown"),
+                ArgSuggestion::new("json", "JSON"),
+                ArgSuggestion::new("html", "Standalone HTML page"),
+            ],
+            (SlashCommandAction::Compact, 0) => {
+                vec![ArgSuggestion::new("auto", "Toggle automatic compaction")]
+            }
+            (SlashCommandAction::SaveCode, 0) => vec![ArgSuggestion::new(
+                "all",
+                "Every code block, not just the last answer's",
+            )],
+            (SlashCommandAction::Attach, 0) => {
+                vec![ArgSuggestion::new("clear", "Remove all attachments")]
+            }
+            (SlashCommandAction::Prompt, 0) => templates::list()
+                .into_iter()
+                .map(|name| ArgSuggestion::new(name, "template"))
+                .collect(),
+            (SlashCommandAction::Resume, 0) => {
+                let mut completions = vec![ArgSuggestion::new("last", "Most recent session")];
+                completions.extend(
+                    session::list()
+                        .iter()
+                        .map(|session| ArgSuggestion::new(&session.id, session.opening())),
+                );
+                completions
+            }
+            (SlashCommandAction::Retry, _) if previous.last() == Some(&"--model") => model_names(),
+            (SlashCommandAction::Retry, _) if previous.len().is_multiple_of(2) => [
+                ("--temperature", "0-1"),
+                ("--top-p", "0-1"),
+                ("--top-k", "integer"),
+                ("--stop", "stop sequence"),
+                ("--model", "model for this answer"),
+            ]
+            .into_iter()
+            .filter(|(flag, _)| !previous.contains(flag))
+            .map(|(flag, description)| ArgSuggestion::new(flag, description))
+            .collect(),
+            _ => Vec::new(),
         }
     }
 
@@ -320,59 +1615,2167 @@ impl App {
         self.slash_command_state = None;
     }
 
-    pub fn execute_slash_command(&mut self, action: SlashCommandAction) {
+    // After deleting from (or undoing) a slash command, filter the menu again or close it
+    // once the input no longer is one
+    pub fn refresh_slash_command(&mut self) {
+        if self.slash_command_state.is_none() {
+            return;
+        }
+        if !self.input.starts_with('/') {
+            self.cancel_slash_command();
+        } else {
+            let input = self.input.clone();
+            self.update_slash_command(&input[1..]); // Skip the '/'
+        }
+    }
+
+    pub fn execute_slash_command(&mut self, action: SlashCommandAction, args: &str) {
         match action {
             SlashCommandAction::Clear => {
                 self.messages.clear();
+                self.resume_offered = false;
                 self.streaming_content.clear();
                 self.scroll_position = 0;
                 self.auto_scroll = true;
                 self.total_lines = 0;
                 self.container_info = None;
+                self.turn_metrics.clear();
+                self.budget_warned = false;
+                self.checkpoints.clear();
+                self.alternatives = None;
+                self.compacting = None;
+                self.queued_compaction = None;
+                // The cleared conversation continues in a fresh session file
+                self.session_id = session::new_session_id();
+                self.session_created_at = chrono::Local::now().to_rfc3339();
+                tmux::set_pane_title(&self.pane_title());
+            }
+            SlashCommandAction::Stats => {
+                let summary = self.stats_summary();
+                self.add_info(summary);
+            }
+            SlashCommandAction::Speak => {
+                if self.speaker.take().is_some() {
+                    self.add_info("Speech off".to_string());
+                } else if let Some(command) = self.config.tts_command.clone() {
+                    self.add_info(format!("Speech on (via `{}`)", command));
+                    self.speaker = Some(Speaker::new(command));
+                } else {
+                    self.add_info(
+                        "Set \"tts_command\" in ~/.agnt/config.json to enable speech".to_string(),
+                    );
+                }
+            }
+            SlashCommandAction::Retry => match parse_retry_args(args) {
+                Ok(params) => self.retry_last_answer(params),
+                Err(e) => self.add_info(format!(
+                    "{}\nUsage: /retry [--temperature T] [--top-p P] [--top-k K] [--stop S] [--model M]",
+                    e
+                )),
+            },
+            SlashCommandAction::SaveCode => self.plan_save_code(args == "all"),
+            SlashCommandAction::Ask => match args.split_once(char::is_whitespace) {
+                Some((model, message)) if !self.is_waiting && !message.trim().is_empty() => {
+                    self.add_user_message(message.trim().to_string());
+                    self.queued_request = Some(RequestParams {
+                        model: Some(models::resolve(model)),
+                        ..Default::default()
+                    });
+                }
+                _ => self.add_info("Usage: /ask <model> <message>".to_string()),
+            },
+            SlashCommandAction::Model if args.is_empty() => {
+                let entries = models::picker_entries(&self.model, &self.available_models);
+                let current = entries.iter().position(|entry| entry.id == self.model);
+                self.model_picker = Some(current.unwrap_or(0));
+            }
+            SlashCommandAction::Model => self.set_model(models::resolve(args)),
+            SlashCommandAction::Export => self.export_conversation(args),
+            SlashCommandAction::MaxTokens if args.is_empty() => {
+                let limit = self.sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+                self.add_info(format!("max_tokens is {}", limit));
+            }
+            SlashCommandAction::MaxTokens => match args.parse::<u32>() {
+                Ok(limit) if limit > 0 => {
+                    self.sampling.max_tokens = Some(limit);
+                    self.add_info(format!("max_tokens set to {}", limit));
+                }
+                _ => self.add_info("Usage: /maxtokens <positive number>".to_string()),
+            },
+            SlashCommandAction::Compact if args == "auto" => {
+                self.config.auto_compact = !self.config.auto_compact;
+                let state = if self.config.auto_compact { "on" } else { "off" };
+                self.add_info(format!("Auto-compact {}", state));
+            }
+            SlashCommandAction::Compact => self.request_compaction(true),
+            SlashCommandAction::Tokens => {
+                self.set_connection_status(Some("Counting tokens...".to_string()));
+                self.queued_token_count = Some(args.to_string());
             }
+            SlashCommandAction::Params => {
+                self.params_editor = Some(ParamsEditor::new(&self.sampling));
+            }
+            SlashCommandAction::Custom(_) if self.is_waiting => {
+                self.add_info("Wait for the current answer to finish first".to_string());
+            }
+            SlashCommandAction::Custom(index) => {
+                self.queued_custom_command = Some((index, args.to_string()));
+            }
+            SlashCommandAction::Prompt if args.is_empty() => {
+                let names = templates::list();
+                if names.is_empty() {
+                    self.add_info(format!(
+                        "No prompt templates yet. Add Markdown files with {{{{variable}}}} placeholders to {}",
+                        templates::templates_dir().display()
+                    ));
+                } else {
+                    self.add_info(format!("Prompt templates: {}", names.join(", ")));
+                }
+            }
+            SlashCommandAction::Prompt => match templates::load(args) {
+                Ok(template) if template.variables().is_empty() => self.set_input(template.body),
+                Ok(template) => self.template_form = Some(TemplateForm::new(template)),
+                Err(e) => self.add_info(e.to_string()),
+            },
+            SlashCommandAction::Attach if args == "clear" => {
+                let count = self.attachments.len();
+                self.attachments.clear();
+                self.add_info(format!("Removed {} attachment(s)", count));
+            }
+            SlashCommandAction::Attach if args.is_empty() => {
+                self.add_info("Usage: /attach <path to PNG, JPEG, GIF or WebP> | clear".to_string())
+            }
+            SlashCommandAction::Attach => self.attach_image(args),
+            SlashCommandAction::Dir => self.attach_project(args),
+            SlashCommandAction::Continue => self.continue_answer(),
+            SlashCommandAction::Close => self.queued_close = true,
+            SlashCommandAction::Branch | SlashCommandAction::Rollback
+                if self.is_waiting || self.compacting.is_some() =>
+            {
+                self.add_info("Wait for the current answer before changing the conversation".to_string())
+            }
+            SlashCommandAction::Branch if args.is_empty() => match self.messages.len() {
+                0 => self.add_info("Nothing to branch yet".to_string()),
+                count => self.branch_at(count - 1),
+            },
+            SlashCommandAction::Branch => match args.parse::<usize>() {
+                Ok(n) if (1..=self.messages.len()).contains(&n) => self.branch_at(n - 1),
+                _ => self.add_info(format!(
+                    "Usage: /branch [1-{}], or press b on a message in copy focus (Ctrl+Y)",
+                    self.messages.len()
+                )),
+            },
+            SlashCommandAction::Checkpoint => {
+                self.checkpoints.push(self.messages.len());
+                self.add_info(format!(
+                    "Checkpoint {} at message {}; /rollback {} returns here",
+                    self.checkpoints.len(),
+                    self.messages.len(),
+                    self.checkpoints.len()
+                ));
+            }
+            SlashCommandAction::Rollback if args.is_empty() => {
+                if self.checkpoints.is_empty() {
+                    self.add_info("No checkpoints yet; set one with /checkpoint".to_string());
+                } else {
+                    let listing: Vec<String> = self
+                        .checkpoints
+                        .iter()
+                        .enumerate()
+                        .map(|(i, count)| format!("  {}: after message {}", i + 1, count))
+                        .collect();
+                    self.add_info(format!("Checkpoints:\n{}", listing.join("\n")));
+                }
+            }
+            SlashCommandAction::Rollback => match args.parse::<usize>() {
+                Ok(n) if (1..=self.checkpoints.len()).contains(&n) => {
+                    let count = self.checkpoints[n - 1];
+                    let dropped = self.messages.len().saturating_sub(count);
+                    self.truncate_conversation(count);
+                    self.checkpoints.truncate(n);
+                    self.save_session();
+                    self.add_info(format!(
+                        "Rolled back to checkpoint {} ({} message(s) dropped)",
+                        n, dropped
+                    ));
+                }
+                _ => self.add_info(format!(
+                    "Usage: /rollback <1-{}>",
+                    self.checkpoints.len().max(1)
+                )),
+            },
+            SlashCommandAction::Review if self.is_waiting => {
+                self.add_info("Wait for the current answer before starting a review".to_string())
+            }
+            SlashCommandAction::Review => {
+                let range: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+                match review::collect(&range) {
+                    Ok(review) => {
+                        let prompt = review.prompt();
+                        self.attachments.push(MessageContent::Context {
+                            label: review.label,
+                            content: review.diff,
+                        });
+                        self.add_user_message(prompt);
+                        self.queued_request = Some(RequestParams::default());
+                    }
+                    Err(e) => self.add_info(e),
+                }
+            }
+            SlashCommandAction::Resume if self.is_waiting => {
+                self.add_info("Wait for the current answer before resuming a session".to_string())
+            }
+            SlashCommandAction::Sessions if self.is_waiting => {
+                self.add_info("Wait for the current answer before switching sessions".to_string())
+            }
+            SlashCommandAction::Sessions => {
+                let sessions: Vec<session::Session> = session::list()
+                    .into_iter()
+                    .filter(|session| session.id != self.session_id)
+                    .collect();
+                if sessions.is_empty() {
+                    self.add_info("No saved sessions yet".to_string());
+                } else {
+                    self.session_browser = Some(SessionBrowser::new(sessions, args));
+                }
+            }
+            SlashCommandAction::Resume if args.is_empty() => {
+                let listing = session_listing(&session::list(), &self.session_id);
+                self.add_info(listing);
+            }
+            SlashCommandAction::Resume => match session::load(args) {
+                Ok(session) => self.restore_session(session),
+                Err(e) => self.add_local_error(format!("Failed to resume session: {}", e)),
+            },
+            SlashCommandAction::Copy => match self.last_response_text() {
+                Some(text) => match clipboard::copy(&text) {
+                    Ok(method) => self.add_info(format!(
+                        "Copied {} characters to the clipboard (via {})",
+                        text.chars().count(),
+                        method
+                    )),
+                    Err(e) => self.add_local_error(format!("Failed to copy to clipboard: {}", e)),
+                },
+                None => self.add_info("Nothing to copy yet".to_string()),
+            },
         }
         self.slash_command_state = None;
         self.clear_input();
     }
 }
 
-pub fn ui(f: &mut Frame, app: &mut App) {
-    // Calculate input height based on content (min 3, max 10 lines)
-    let input_lines = app.input.split('\n').count().max(1);
-    let input_height = (input_lines + 2).clamp(3, 10) as u16; // +2 for borders
+impl App {
+    pub fn set_model(&mut self, model: String) {
+        self.add_info(format!("Now using {}", model));
+        self.model = model;
+    }
 
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([Constraint::Min(5), Constraint::Length(input_height)].as_ref())
-        .split(f.area());
+    pub fn move_model_picker(&mut self, delta: isize) {
+        let count = models::picker_entries(&self.model, &self.available_models).len() as isize;
+        if let Some(selected) = &mut self.model_picker {
+            *selected = (*selected as isize + delta).rem_euclid(count) as usize;
+        }
+    }
 
-    render_messages(f, app, chunks[0]);
-    render_input(f, app, chunks[1]);
+    // Enter picks the highlighted model, Esc (accept = false) keeps the current one
+    pub fn close_model_picker(&mut self, accept: bool) {
+        let Some(selected) = self.model_picker.take() else {
+            return;
+        };
+        if accept
+            && let Some(entry) =
+                models::picker_entries(&self.model, &self.available_models).get(selected)
+            && entry.id != self.model
+        {
+            self.set_model(entry.id.clone());
+        }
+    }
 
-    // Render slash command autocomplete menu if active
-    if let Some(state) = &app.slash_command_state {
-        render_slash_command_menu(f, state, chunks[1]);
+    // The Models API list replaces the built-in aliases, even with the picker open
+    pub fn set_available_models(&mut self, available: Vec<ModelInfo>) {
+        self.available_models = available;
+        if self.model_picker.is_some() {
+            let entries = models::picker_entries(&self.model, &self.available_models);
+            self.model_picker = Some(
+                entries
+                    .iter()
+                    .position(|entry| entry.id == self.model)
+                    .unwrap_or(0),
+            );
+        }
     }
 
-    // Render help modal if active
-    if app.show_help {
-        render_help_modal(f);
+    // Replace the conversation with a saved session; later answers keep saving to it
+    pub fn restore_session(&mut self, session: session::Session) {
+        let count = session.messages.len();
+        self.messages = session.messages;
+        self.alternatives = session.alternatives;
+        self.session_id = session.id;
+        self.session_created_at = session.created_at;
+        self.streaming_content.clear();
+        self.container_info = None;
+        self.turn_metrics.clear();
+        self.budget_warned = false;
+        self.checkpoints.clear();
+        self.layout_cache.clear();
+        self.scroll_position = 0;
+        self.auto_scroll = true;
+        tmux::set_pane_title(&self.pane_title());
+        self.add_info(format!(
+            "Resumed session {} ({} messages)",
+            self.session_id, count
+        ));
     }
 }
 
-fn render_messages(f: &mut Frame, app: &mut App, area: Rect) {
-    // Build lines and calculate total wrapped lines
-    let (lines, total_wrapped_lines) =
-        build_message_lines(app, area.width.saturating_sub(4) as usize);
+// Most recent sessions with their opening prompt, for /resume without an id
+fn session_listing(sessions: &[session::Session], current_id: &str) -> String {
+    const SHOWN: usize = 10;
+    let others: Vec<&session::Session> = sessions.iter().filter(|s| s.id != current_id).collect();
+    if others.is_empty() {
+        return "No saved sessions yet".to_string();
+    }
+    let mut listing = String::from("Saved sessions (/resume <id>):");
+    for session in others.iter().take(SHOWN) {
+        listing.push_str(&format!(
+            "\n  {}  {} messages  {}",
+            session.id,
+            session.messages.len(),
+            session.opening()
+        ));
+    }
+    if others.len() > SHOWN {
+        listing.push_str(&format!("\n  ... and {} older", others.len() - SHOWN));
+    }
+    listing
+}
+
+impl App {
+    // Ctrl+R: start a history search, or jump to the next older match
+    pub fn history_search_next(&mut self) {
+        let Some(search) = &self.history_search else {
+            self.history_search = Some(HistorySearch {
+                original_input: self.input.clone(),
+                ..Default::default()
+            });
+            self.update_history_match(self.history.len());
+            return;
+        };
+        let before = search.match_index.unwrap_or(self.history.len());
+        self.update_history_match(before);
+    }
+
+    pub fn history_search_push(&mut self, c: char) {
+        if let Some(search) = &mut self.history_search {
+            search.query.push(c);
+            self.update_history_match(self.history.len());
+        }
+    }
+
+    pub fn history_search_pop(&mut self) {
+        if let Some(search) = &mut self.history_search {
+            search.query.pop();
+            self.update_history_match(self.history.len());
+        }
+    }
+
+    // Newest entry before `before` that contains the query (case-insensitive).
+    // When nothing older matches, the current match is kept.
+    fn update_history_match(&mut self, before: usize) {
+        let Some(search) = &mut self.history_search else {
+            return;
+        };
+        let query = search.query.to_lowercase();
+        let found = (0..before.min(self.history.len()))
+            .rev()
+            .find(|&i| self.history[i].to_lowercase().contains(&query));
+        if found.is_some() || before == self.history.len() {
+            search.match_index = found;
+        }
+    }
+
+    // Text the input box shows during a search
+    pub fn history_search_text(&self) -> Option<&str> {
+        let search = self.history_search.as_ref()?;
+        Some(
+            search
+                .match_index
+                .and_then(|i| self.history.get(i))
+                .map(String::as_str)
+                .unwrap_or(""),
+        )
+    }
+
+    pub fn finish_history_search(&mut self, accept: bool) {
+        let Some(search) = self.history_search.take() else {
+            return;
+        };
+        let input = match search.match_index.and_then(|i| self.history.get(i)) {
+            Some(entry) if accept => entry.clone(),
+            _ => search.original_input,
+        };
+        self.set_input(input);
+    }
+
+    // Hand the queued request to the event loop, unless it would break a spend budget,
+    // in which case it is held for confirmation or refused depending on the config. A
+    // budget nearing its limit is pointed out once per session.
+    pub fn take_queued_request(&mut self) -> Option<RequestParams> {
+        let params = self.queued_request.take()?;
+        let approved = std::mem::take(&mut self.budget_approved);
+        let check = if approved {
+            usage::BudgetCheck::Within
+        } else {
+            self.check_budgets(&params)
+        };
+        match check {
+            usage::BudgetCheck::Within => {}
+            usage::BudgetCheck::Warning(warning) => {
+                if !std::mem::replace(&mut self.budget_warned, true) {
+                    self.add_info(warning);
+                }
+            }
+            usage::BudgetCheck::Exceeded(reason) => {
+                match self.config.budget_action {
+                    BudgetAction::Refuse => {
+                        self.add_api_error(format!("{} - request not sent", reason));
+                        self.abandon_request();
+                    }
+                    BudgetAction::Confirm => {
+                        self.add_info(format!(
+                            "{}\nPress y to send anyway, any other key to cancel",
+                            reason
+                        ));
+                        self.confirmation = Some(Confirmation::OverBudget(params));
+                    }
+                }
+                return None;
+            }
+        }
+
+        self.auto_continues = 0;
+        self.reconnects = 0;
+        self.resume_offered = false;
+        self.is_waiting = true;
+        self.auto_scroll = true;
+        self.start_streaming();
+        Some(params)
+    }
+
+    // Hold a request whose exact token count is over token_warning_threshold
+    pub fn hold_large_request(&mut self, params: RequestParams, tokens: u64, threshold: u64) {
+        self.is_waiting = false;
+        self.set_connection_status(None);
+        self.add_info(format!(
+            "This request is {} input tokens (warning threshold {})\nPress y to send anyway, any other key to cancel",
+            tokens, threshold
+        ));
+        self.confirmation = Some(Confirmation::LargeRequest(params));
+    }
+
+    pub fn show_token_count(&mut self, result: anyhow::Result<u64>) {
+        self.set_connection_status(None);
+        match result {
+            Ok(tokens) => {
+                let cost = pricing::cost(&self.model, tokens, 0, 0, 0)
+                    .map(|cost| format!(" · ~${:.4} at {}", cost, self.model))
+                    .unwrap_or_default();
+                self.add_info(format!(
+                    "Next request: {} input tokens · {}% of the context window{}",
+                    tokens,
+                    tokens * 100 / models::CONTEXT_WINDOW,
+                    cost
+                ));
+            }
+            Err(e) => self.add_api_error(format!("Token count failed: {}", e)),
+        }
+    }
+
+    // Undo the conversation changes made for a request that won't be sent
+    fn abandon_request(&mut self) {
+        if self.pending_alternative.is_some() {
+            // Puts the answer that /retry took out back in place
+            self.finish_alternative();
+        } else if self.messages.last().is_some_and(|(role, _)| role == "user")
+            && let Some((_, contents)) = self.messages.pop()
+        {
+            self.set_input(
+                contents
+                    .iter()
+                    .filter_map(|c| match c {
+                        MessageContent::Text(text) => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect(),
+            );
+        }
+    }
+
+    // Rough size of what every request carries: system prompt and conversation history
+    pub fn context_tokens(&self) -> u64 {
+        let history: u64 = self
+            .messages
+            .iter()
+            .flat_map(|(role, contents)| contents.iter().map(move |c| (role, c)))
+            .map(|(role, content)| match content {
+                MessageContent::Summary(summary) => pricing::estimate_tokens(summary),
+                _ if role == "system" => 0,
+                MessageContent::Text(text) => pricing::estimate_tokens(text),
+                MessageContent::Image { .. } => pricing::IMAGE_TOKENS_ESTIMATE,
+                MessageContent::File { content, .. } => pricing::estimate_tokens(content),
+                MessageContent::Context { content, .. } => pricing::estimate_tokens(content),
+                MessageContent::ToolUse { input, .. } => pricing::estimate_tokens(input),
+                MessageContent::ToolResult { content, .. } => pricing::estimate_tokens(content),
+                _ => 0,
+            })
+            .sum();
+        pricing::estimate_tokens(&self.system_prompt) + history
+    }
+
+    pub fn context_percent(&self) -> u64 {
+        self.context_tokens() * 100 / models::CONTEXT_WINDOW
+    }
+
+    // Rough size of the next request: the context plus the draft in the input box
+    pub fn estimate_input_tokens(&self) -> u64 {
+        self.context_tokens()
+            + pricing::estimate_tokens(&self.input)
+            + self
+                .attachments
+                .iter()
+                .map(|attachment| match attachment {
+                    MessageContent::Context { content, .. } => pricing::estimate_tokens(content),
+                    _ => pricing::IMAGE_TOKENS_ESTIMATE,
+                })
+                .sum::<u64>()
+    }
+
+    // Queue a summary of the older turns; `manual` is /compact, which reports why
+    // nothing happened
+    pub fn request_compaction(&mut self, manual: bool) {
+        if self.compacting.is_some() {
+            if manual {
+                self.add_info("Already compacting the conversation".to_string());
+            }
+            return;
+        }
+        let Some(split) = compact::split_point(&self.messages) else {
+            if manual {
+                self.add_info("Not enough conversation to compact yet".to_string());
+            }
+            return;
+        };
+        self.add_info(format!(
+            "Compacting {} earlier messages in the background (context at {}%)",
+            split,
+            self.context_percent()
+        ));
+        self.compacting = Some(split);
+        self.queued_compaction = Some(split);
+    }
+
+    // Start compacting on its own once the context nears the model's window
+    pub fn maybe_auto_compact(&mut self) {
+        let threshold = self.config.auto_compact_threshold.clamp(0.1, 1.0);
+        if self.config.auto_compact
+            && self.context_tokens() as f64 >= models::CONTEXT_WINDOW as f64 * threshold
+        {
+            self.request_compaction(false);
+        }
+    }
+
+    // The compaction to start, with the transcript of the messages it covers
+    pub fn take_queued_compaction(&mut self) -> Option<(usize, String)> {
+        let split = self.queued_compaction.take()?;
+        match export::render(
+            export::Format::Markdown,
+            "Conversation",
+            &self.messages[..split],
+        ) {
+            Ok(transcript) => Some((split, transcript)),
+            Err(e) => {
+                self.compacting = None;
+                self.add_api_error(format!("Compaction failed: {}", e));
+                None
+            }
+        }
+    }
+
+    // Swap the summarized messages for the summary once it arrives
+    pub fn apply_compaction(
+        &mut self,
+        split: usize,
+        result: anyhow::Result<(String, Option<TurnMetrics>)>,
+    ) {
+        // A /clear while the summary was being written makes it stale
+        if self.compacting != Some(split) || self.messages.len() < split {
+            return;
+        }
+        self.compacting = None;
+        let (summary, metrics) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.add_api_error(format!("Compaction failed: {}", e));
+                return;
+            }
+        };
+        if let Some(metrics) = &metrics {
+            self.record_usage(metrics);
+        }
+
+        let before = self.context_tokens();
+        self.messages.splice(
+            ..split,
+            [("system".to_string(), vec![MessageContent::Summary(summary)])],
+        );
+        self.alternatives = self
+            .alternatives
+            .take()
+            .filter(|alts| alts.message_index >= split)
+            .map(|mut alts| {
+                alts.message_index = alts.message_index - split + 1;
+                alts
+            });
+        // Checkpoints inside the summarized part have nothing left to return to
+        self.checkpoints = self
+            .checkpoints
+            .iter()
+            .filter(|&&checkpoint| checkpoint >= split)
+            .map(|checkpoint| checkpoint - split + 1)
+            .collect();
+        self.focus = None;
+        self.layout_cache.clear();
+        self.add_info(format!(
+            "Compacted {} messages: ~{} → ~{} tokens of context",
+            split,
+            before,
+            self.context_tokens()
+        ));
+    }
+
+    pub fn session_spend(&self) -> f64 {
+        self.turn_metrics.iter().filter_map(metrics_cost).sum()
+    }
+
+    fn check_budgets(&self, params: &RequestParams) -> usage::BudgetCheck {
+        let model = params.model.clone().unwrap_or_else(|| self.model.clone());
+        let estimate = pricing::cost(&model, self.estimate_input_tokens(), 0, 0, 0).unwrap_or(0.0);
+        usage::check_budgets(&self.config, self.session_spend(), estimate)
+    }
+
+    pub fn last_answer_refused(&self) -> bool {
+        self.messages.last().is_some_and(|(role, contents)| {
+            role == "assistant"
+                && contents
+                    .iter()
+                    .any(|c| matches!(c, MessageContent::StopReason(r) if r == "refusal"))
+        })
+    }
+
+    // Line up the tool calls the answer that just ended is waiting on, for the event loop
+    // to work through with next_tool_step. Calls that won't run (the answer was
+    // interrupted, or max_tool_rounds is used up) are answered with an error right away,
+    // so the conversation stays valid to send. False when there is nothing to run.
+    pub fn start_tool_round(&mut self) -> bool {
+        let Some((role, contents)) = self.messages.last() else {
+            return false;
+        };
+        if role != "assistant" {
+            return false;
+        }
+        let calls = tools::calls(contents);
+        if calls.is_empty() {
+            return false;
+        }
+        let interrupted = self
+            .turn_metrics
+            .last()
+            .is_none_or(|m| m.stop_reason.as_deref() != Some("tool_use"));
+        if interrupted || self.tool_rounds >= self.config.max_tool_rounds {
+            let reason = if interrupted {
+                "The answer was interrupted before the tool could run".to_string()
+            } else {
+                format!(
+                    "Stopped after {} rounds of tool calls (max_tool_rounds); send a message to continue",
+                    self.tool_rounds
+                )
+            };
+            self.messages
+                .push(("user".to_string(), tools::refuse(calls, &reason)));
+            if !interrupted {
+                self.add_info(reason);
+            }
+            return false;
+        }
+
+        self.tool_rounds += 1;
+        self.tool_round = Some(ToolRound {
+            pending: calls.into(),
+            ..Default::default()
+        });
+        true
+    }
+
+    // What the event loop should do next in the tool round: run a call, or wrap up a
+    // cancelled round. None while a call runs or waits for confirmation, and once the
+    // results have been queued to send back.
+    pub fn next_tool_step(&mut self) -> Option<ToolStep> {
+        let round = self.tool_round.as_mut()?;
+        if round.running.is_some() || self.confirmation.is_some() {
+            return None;
+        }
+        let Some(call) = round.pending.front() else {
+            let cancelled = round.cancelled;
+            self.tool_round = None;
+            self.set_connection_status(None);
+            if cancelled {
+                self.add_info("Tool run cancelled".to_string());
+                return Some(ToolStep::Finished);
+            }
+            self.queued_request = Some(self.last_request.clone());
+            return None;
+        };
+        if !round.approved
+            && let Some(approval) = self.toolbox.approval(call)
+        {
+            self.confirmation = Some(Confirmation::ToolCall(approval));
+            self.set_connection_status(Some("Waiting for confirmation...".to_string()));
+            return None;
+        }
+
+        let call = round.pending.pop_front()?;
+        round.approved = false;
+        round.running = Some(call.id.clone());
+        self.set_connection_status(Some(format!("Running {}...", call.name)));
+        self.push_tool_result(tools::result(call.clone(), Ok(String::new())));
+        Some(ToolStep::Run(call))
+    }
+
+    // Output a running call has printed so far, shown in its result box
+    pub fn append_tool_output(&mut self, text: &str) {
+        let Some(id) = self.tool_round.as_ref().and_then(|r| r.running.clone()) else {
+            return;
+        };
+        if let Some(MessageContent::ToolResult { content, .. }) = self.tool_result_mut(&id) {
+            content.push_str(text);
+        }
+    }
+
+    // The running call is done; its result replaces the streamed output
+    pub fn finish_tool_call(&mut self, result: MessageContent) {
+        let Some(round) = self.tool_round.as_mut() else {
+            return;
+        };
+        round.running = None;
+        if round.cancelled {
+            let rest: Vec<ToolCall> = round.pending.drain(..).collect();
+            for refused in tools::refuse(rest, "Cancelled by the user") {
+                self.push_tool_result(refused);
+            }
+        }
+        if let MessageContent::ToolResult { tool_use_id, .. } = &result
+            && let Some(slot) = self.tool_result_mut(&tool_use_id.clone())
+        {
+            *slot = result;
+        }
+    }
+
+    // Esc during a tool round: the running call is killed, and calls not yet started
+    // are answered as cancelled
+    pub fn cancel_tool_round(&mut self) {
+        let Some(round) = self.tool_round.as_mut() else {
+            return;
+        };
+        round.cancelled = true;
+        if round.running.is_none() {
+            let rest: Vec<ToolCall> = round.pending.drain(..).collect();
+            for refused in tools::refuse(rest, "Cancelled by the user") {
+                self.push_tool_result(refused);
+            }
+        }
+        if matches!(self.confirmation, Some(Confirmation::ToolCall(_))) {
+            self.confirmation = None;
+        }
+    }
+
+    // Results go in the user turn that follows the answer with the calls
+    fn push_tool_result(&mut self, result: MessageContent) {
+        match self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|(role, _)| role != "system")
+        {
+            Some((role, contents)) if role == "user" && tools::is_tool_results(contents) => {
+                contents.push(result)
+            }
+            _ => self.messages.push(("user".to_string(), vec![result])),
+        }
+    }
+
+    fn tool_result_mut(&mut self, id: &str) -> Option<&mut MessageContent> {
+        self.messages
+            .iter_mut()
+            .rev()
+            .take_while(|(role, _)| role != "assistant")
+            .flat_map(|(_, contents)| contents.iter_mut())
+            .find(|c| matches!(c, MessageContent::ToolResult { tool_use_id, .. } if tool_use_id == id))
+    }
+
+    // Drop a refused answer and its prompt, and put the prompt back in the input box
+    // so it can be resent as is or rephrased first
+    pub fn rephrase_refused(&mut self) {
+        if self.is_waiting || !self.last_answer_refused() {
+            return;
+        }
+        self.messages.pop();
+        if self.messages.last().is_some_and(|(role, _)| role == "user")
+            && let Some((_, contents)) = self.messages.pop()
+        {
+            self.set_input(
+                contents
+                    .iter()
+                    .filter_map(|c| match c {
+                        MessageContent::Text(text) => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect(),
+            );
+        }
+    }
+
+    // Decide whether the stream that just ended should be resumed: after a dropped
+    // connection, or when the answer was cut off by max_tokens. If so, the partial
+    // answer is moved back into the streaming area so the follow-up request (which
+    // sends it as a prefill) appends to the same message.
+    pub fn prepare_resume(&mut self) -> bool {
+        if let Some(reason) = self.stream_dropped.take() {
+            if self.reconnects >= MAX_RECONNECTS {
+                self.add_api_error(format!(
+                    "Connection lost ({}), gave up after {} reconnects. Press r to resume the answer",
+                    reason, MAX_RECONNECTS
+                ));
+                self.resume_offered = true;
+                return false;
+            }
+            if self.resume_over_budget() {
+                return false;
+            }
+            self.reconnects += 1;
+            self.resume_last_answer();
+            self.set_connection_status(Some(format!(
+                "Connection lost ({}), reconnecting ({}/{})...",
+                reason, self.reconnects, MAX_RECONNECTS
+            )));
+            return true;
+        }
+
+        let truncated = self
+            .turn_metrics
+            .last()
+            .is_some_and(|m| m.stop_reason.as_deref() == Some("max_tokens"));
+        if !truncated || self.auto_continues >= self.config.max_auto_continues {
+            return false;
+        }
+        if self
+            .messages
+            .last()
+            .is_none_or(|(role, _)| role != "assistant")
+        {
+            return false;
+        }
+        if self.resume_over_budget() {
+            return false;
+        }
+
+        self.auto_continues += 1;
+        self.resume_last_answer();
+        self.set_connection_status(Some(format!(
+            "Hit max_tokens, continuing ({}/{})...",
+            self.auto_continues, self.config.max_auto_continues
+        )));
+        true
+    }
+
+    // Resuming sends a request of its own, so an answer that would go over a budget is
+    // left where it stopped
+    fn resume_over_budget(&mut self) -> bool {
+        let usage::BudgetCheck::Exceeded(reason) = self.check_budgets(&self.last_request) else {
+            return false;
+        };
+        self.add_info(format!("{} - answer not continued", reason));
+        true
+    }
+
+    // `r` after reconnects gave up: drop the error notice and continue the partial answer
+    // (or resend the prompt if nothing arrived) with a fresh set of reconnects
+    pub fn resume_dropped_answer(&mut self) {
+        self.resume_offered = false;
+        if let Some((role, contents)) = self.messages.last()
+            && role == "system"
+            && matches!(contents.as_slice(), [MessageContent::ApiError(_)])
+        {
+            self.messages.pop();
+        }
+        self.reconnects = 0;
+        self.resume_last_answer();
+        self.is_waiting = true;
+        self.auto_scroll = true;
+        self.set_connection_status(Some("Resuming the answer...".to_string()));
+        self.queued_resume = true;
+    }
+
+    // Fork the conversation after message `index` into a new session; the original
+    // keeps its id and everything in it
+    pub fn branch_at(&mut self, index: usize) {
+        let original = self.session_id.clone();
+        self.save_session();
+        self.truncate_conversation(index + 1);
+        self.session_id = session::new_session_id();
+        self.session_created_at = chrono::Local::now().to_rfc3339();
+        self.save_session();
+        tmux::set_pane_title(&self.pane_title());
+        self.add_info(format!(
+            "Branched at message {} into session {}; the original stays as {} (/resume {})",
+            index + 1,
+            self.session_id,
+            original,
+            original
+        ));
+    }
+
+    // b in copy focus: branch after the highlighted message
+    pub fn branch_focused(&mut self) {
+        let Some(target) = self.focused_target() else {
+            return;
+        };
+        if self.is_waiting || self.compacting.is_some() {
+            self.add_info("Wait for the current answer before branching".to_string());
+            return;
+        }
+        self.branch_at(target.message_index);
+    }
+
+    // Keep only the first `count` messages, e.g. for /rollback and /branch
+    fn truncate_conversation(&mut self, count: usize) {
+        self.messages.truncate(count);
+        self.alternatives = self
+            .alternatives
+            .take()
+            .filter(|alts| alts.message_index < count);
+        self.checkpoints.retain(|&checkpoint| checkpoint <= count);
+        self.layout_cache.clear();
+        self.focus = None;
+        self.resume_offered = false;
+        self.scroll_to_bottom();
+    }
+
+    pub fn save_session(&self) {
+        if self.messages.is_empty() {
+            return;
+        }
+        if let Err(e) = session::save(&session::Session::from_app(self)) {
+            log_debug!("Failed to save session {}: {}", self.session_id, e);
+        }
+    }
+
+    // /continue: send the last answer back as a prefill so Claude picks up where it
+    // stopped, typically after it was cut off at max_tokens
+    pub fn continue_answer(&mut self) {
+        if self.is_waiting {
+            self.add_info("Wait for the current answer to finish first".to_string());
+            return;
+        }
+        let Some((_, contents)) = self.messages.last().filter(|(role, _)| role == "assistant")
+        else {
+            self.add_info("There is no answer to continue".to_string());
+            return;
+        };
+        if contents
+            .iter()
+            .any(|c| matches!(c, MessageContent::ToolUse { .. }))
+        {
+            self.add_info(
+                "The last answer is waiting on tool results; it can't be continued".to_string(),
+            );
+            return;
+        }
+        self.reconnects = 0;
+        self.resume_last_answer();
+        self.is_waiting = true;
+        self.auto_scroll = true;
+        self.set_connection_status(Some("Continuing the answer...".to_string()));
+        self.queued_resume = true;
+    }
+
+    fn resume_last_answer(&mut self) {
+        if self
+            .messages
+            .last()
+            .is_some_and(|(role, _)| role == "assistant")
+            && let Some((_, mut content)) = self.messages.pop()
+        {
+            // The continuation is appended, so the old end marker no longer applies
+            content.retain(|c| {
+                !matches!(c, MessageContent::StopReason(_) | MessageContent::Timing(_))
+            });
+            self.streaming_content = content;
+        }
+    }
+
+    // Text of the answer being streamed, sent as a prefill when continuing it
+    pub fn streaming_text(&self) -> String {
+        self.streaming_content
+            .iter()
+            .filter_map(|content| match content {
+                MessageContent::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Collect code blocks from the last answer (or every answer) and ask before writing them
+    fn plan_save_code(&mut self, whole_session: bool) {
+        let answers: Vec<&Vec<MessageContent>> = self
+            .messages
+            .iter()
+            .filter(|(role, _)| role == "assistant")
+            .map(|(_, contents)| contents)
+            .collect();
+        let answers = if whole_session {
+            &answers[..]
+        } else {
+            &answers[answers.len().saturating_sub(1)..]
+        };
+
+        let mut blocks = Vec::new();
+        for content in answers.iter().flat_map(|contents| contents.iter()) {
+            match content {
+                MessageContent::Text(text) => blocks.extend(codeblocks::extract(text)),
+                MessageContent::Code { input } => blocks.push(codeblocks::CodeBlock {
+                    language: Some("python".to_string()),
+                    filename: None,
+                    code: input.clone(),
+                }),
+                _ => {}
+            }
+        }
+
+        let files = codeblocks::plan_files(&blocks);
+        if files.is_empty() {
+            self.add_info("No code blocks found".to_string());
+            return;
+        }
+
+        let mut summary = format!("Save {} code block(s)?", files.len());
+        for (path, code) in &files {
+            let exists = if path.exists() { " (overwrites)" } else { "" };
+            summary.push_str(&format!(
+                "\n  {} - {} lines{}",
+                path.display(),
+                code.lines().count(),
+                exists
+            ));
+        }
+        summary.push_str("\nPress y to write the files, any other key to cancel");
+        self.add_info(summary);
+        self.confirmation = Some(Confirmation::SaveCode(files));
+    }
+
+    pub fn resolve_confirmation(&mut self, accepted: bool) {
+        let Some(confirmation) = self.confirmation.take() else {
+            return;
+        };
+        if let Confirmation::ToolCall(_) = confirmation {
+            let Some(round) = self.tool_round.as_mut() else {
+                return;
+            };
+            if accepted {
+                round.approved = true;
+            } else if let Some(call) = round.pending.pop_front() {
+                self.push_tool_result(tools::result(call, Err(tools::DECLINED.to_string())));
+            }
+            return;
+        }
+        if !accepted {
+            if matches!(
+                confirmation,
+                Confirmation::OverBudget(_) | Confirmation::LargeRequest(_)
+            ) {
+                self.abandon_request();
+            }
+            self.add_info("Cancelled".to_string());
+            return;
+        }
+
+        match confirmation {
+            Confirmation::OverBudget(params) => {
+                self.budget_approved = true;
+                self.queued_request = Some(params);
+            }
+            Confirmation::LargeRequest(params) => {
+                // The budget check already passed before the tokens were counted
+                self.budget_approved = true;
+                self.size_approved = true;
+                self.queued_request = Some(params);
+            }
+            Confirmation::ToolCall(_) => {}
+            Confirmation::SaveCode(files) => {
+                let mut written = Vec::new();
+                for (path, code) in files {
+                    let result = match path.parent() {
+                        Some(parent) if !parent.as_os_str().is_empty() => {
+                            std::fs::create_dir_all(parent)
+                        }
+                        _ => Ok(()),
+                    }
+                    .and_then(|_| std::fs::write(&path, code));
+                    match result {
+                        Ok(()) => written.push(path.display().to_string()),
+                        Err(e) => self.add_local_error(format!(
+                            "Failed to write {}: {}",
+                            path.display(),
+                            e
+                        )),
+                    }
+                }
+                if !written.is_empty() {
+                    self.add_info(format!("Wrote {}", written.join(", ")));
+                }
+            }
+        }
+    }
+
+    // Take the last answer out of the conversation and queue a request for a new one.
+    // The old answer is kept as an alternative that can be switched back to.
+    pub fn retry_last_answer(&mut self, params: RequestParams) {
+        if self.is_waiting {
+            return;
+        }
+        let Some(index) = self.messages.len().checked_sub(1) else {
+            self.add_info("Nothing to retry yet".to_string());
+            return;
+        };
+        // A request that failed outright is simply sent again
+        if index > 0
+            && self.messages[index - 1].0 == "user"
+            && matches!(
+                self.messages[index].1.as_slice(),
+                [MessageContent::ApiError(_)]
+            )
+        {
+            self.messages.pop();
+            self.queued_request = Some(params);
+            return;
+        }
+        if self.messages[index].0 != "assistant" {
+            self.add_info("The last message is not an answer from Claude".to_string());
+            return;
+        }
+
+        if self
+            .alternatives
+            .as_ref()
+            .is_none_or(|alts| alts.message_index != index)
+        {
+            self.alternatives = Some(Alternatives {
+                message_index: index,
+                variants: vec![("original".to_string(), self.messages[index].1.clone())],
+                selected: 0,
+            });
+        }
+        self.messages.pop();
+        self.pending_alternative = Some(params.describe());
+        self.queued_request = Some(params);
+    }
+
+    // Called when a stream ends: file a regenerated answer under the alternatives,
+    // or put the previous answer back (ahead of any error) if the retry produced nothing
+    pub fn finish_alternative(&mut self) {
+        let Some(label) = self.pending_alternative.take() else {
+            return;
+        };
+        let Some(alts) = &mut self.alternatives else {
+            return;
+        };
+
+        let index = alts.message_index;
+        match self.messages.get(index) {
+            Some((role, content)) if role == "assistant" => {
+                alts.variants.push((label, content.clone()));
+                alts.selected = alts.variants.len() - 1;
+            }
+            _ if index <= self.messages.len() => {
+                let (_, content) = &alts.variants[alts.selected];
+                self.messages
+                    .insert(index, ("assistant".to_string(), content.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    // Only the latest answer can be swapped; later turns were built on top of it,
+    // while notices and errors after it don't matter
+    fn is_latest_answer(&self, index: usize) -> bool {
+        self.messages
+            .get(index)
+            .is_some_and(|(role, _)| role == "assistant")
+            && self.messages[index + 1..]
+                .iter()
+                .all(|(role, _)| role == "system")
+    }
+
+    // Show the previous (-1) or next (+1) alternative of the last answer
+    pub fn cycle_alternative(&mut self, step: isize) {
+        if self.is_waiting {
+            return;
+        }
+        let Some(index) = self.alternatives.as_ref().map(|alts| alts.message_index) else {
+            return;
+        };
+        if !self.is_latest_answer(index) {
+            return;
+        }
+        let Some(alts) = &mut self.alternatives else {
+            return;
+        };
+        if alts.variants.len() < 2 {
+            return;
+        }
+
+        let count = alts.variants.len() as isize;
+        alts.selected = (alts.selected as isize + step).rem_euclid(count) as usize;
+        self.messages[alts.message_index].1 = alts.variants[alts.selected].1.clone();
+    }
+
+    // Extra text shown in a message header, e.g. which alternative is displayed
+    fn message_annotation(&self, index: usize) -> Option<String> {
+        let alts = self.alternatives.as_ref()?;
+        if alts.message_index != index || alts.variants.len() < 2 {
+            return None;
+        }
+        let hint = if self.is_latest_answer(index) {
+            " · Alt+←/→"
+        } else {
+            ""
+        };
+        Some(format!(
+            "‹{}/{}› {}{}",
+            alts.selected + 1,
+            alts.variants.len(),
+            alts.variants[alts.selected].0,
+            hint
+        ))
+    }
+
+    // Text parts of the most recent assistant message
+    fn last_response_text(&self) -> Option<String> {
+        let (_, contents) = self
+            .messages
+            .iter()
+            .rev()
+            .find(|(role, _)| role == "assistant")?;
+        let text = contents
+            .iter()
+            .filter_map(|content| match content {
+                MessageContent::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        (!text.is_empty()).then_some(text)
+    }
+
+    // Enter in the /params modal; an invalid value keeps the modal open
+    // Enter in /sessions: load the highlighted session in place of the current one
+    pub fn open_selected_session(&mut self) {
+        let Some(browser) = self.session_browser.take() else {
+            return;
+        };
+        if let Some(session) = browser.selected_session() {
+            self.restore_session(session.clone());
+        }
+    }
+
+    // The filled-in template goes into the input box to be reviewed and sent
+    pub fn apply_template_form(&mut self) {
+        if let Some(form) = self.template_form.take() {
+            self.set_input(form.render());
+        }
+    }
+
+    pub fn apply_params_editor(&mut self) {
+        let Some(editor) = &mut self.params_editor else {
+            return;
+        };
+        match editor.parse() {
+            Ok(mut params) => {
+                self.params_editor = None;
+                // max_tokens has its own command and isn't part of the modal
+                params.max_tokens = self.sampling.max_tokens;
+                let summary = if params == RequestParams::default() {
+                    "API defaults".to_string()
+                } else {
+                    params.describe()
+                };
+                self.sampling = params;
+                self.add_info(format!("Sampling: {}", summary));
+            }
+            Err(e) => editor.error = Some(e),
+        }
+    }
+
+    // Copyable targets in conversation order; local notices are skipped
+    pub fn focus_targets(&self) -> Vec<FocusTarget> {
+        let mut targets = Vec::new();
+        for (index, (role, contents)) in self.messages.iter().enumerate() {
+            if role == "system" {
+                continue;
+            }
+            let text = contents
+                .iter()
+                .filter_map(|content| match content {
+                    MessageContent::Text(text) => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            let who = if tools::is_tool_results(contents) {
+                "tool results"
+            } else if role == "user" {
+                "your message"
+            } else {
+                "answer"
+            };
+            if !text.trim().is_empty() {
+                targets.push(FocusTarget {
+                    message_index: index,
+                    part: FocusPart::Message,
+                    label: who.to_string(),
+                    text: text.clone(),
+                    file: None,
+                    output: None,
+                });
+            }
+            let blocks = codeblocks::extract(&text);
+            for (n, block) in blocks.iter().enumerate() {
+                let name = block
+                    .filename
+                    .clone()
+                    .or_else(|| block.language.clone())
+                    .unwrap_or_else(|| "text".to_string());
+                targets.push(FocusTarget {
+                    message_index: index,
+                    part: FocusPart::Inner,
+                    label: format!("{} › code block {}/{} ({})", who, n + 1, blocks.len(), name),
+                    text: block.code.clone(),
+                    file: None,
+                    output: None,
+                });
+            }
+            for (block, content) in contents.iter().enumerate() {
+                let mut output = None;
+                let (part, label, text) = match content {
+                    MessageContent::Code { input } => {
+                        (FocusPart::Inner, "code cell".to_string(), input)
+                    }
+                    MessageContent::CodeOutput { stdout, .. } if !stdout.is_empty() => {
+                        output = Some(block);
+                        (FocusPart::Inner, "code output".to_string(), stdout)
+                    }
+                    MessageContent::ToolResult { name, content, .. } => {
+                        output = Some(block);
+                        (FocusPart::Block(block), format!("{} result", name), content)
+                    }
+                    MessageContent::File { path, content, .. } => {
+                        (FocusPart::Block(block), format!("file {}", path), content)
+                    }
+                    MessageContent::Context { label, content } => {
+                        (FocusPart::Block(block), label.clone(), content)
+                    }
+                    _ => continue,
+                };
+                targets.push(FocusTarget {
+                    message_index: index,
+                    part,
+                    label: format!("{} › {}", who, label),
+                    text: text.clone(),
+                    file: None,
+                    output,
+                });
+            }
+            for content in contents {
+                let MessageContent::CodeOutput { downloads, .. } = content else {
+                    continue;
+                };
+                for (_, state) in downloads {
+                    if let DownloadState::Saved(path) = state {
+                        let name = path.file_name().unwrap_or(path.as_os_str());
+                        targets.push(FocusTarget {
+                            message_index: index,
+                            part: FocusPart::Inner,
+                            label: format!("{} › file {}", who, name.to_string_lossy()),
+                            text: path.display().to_string(),
+                            file: Some(path.clone()),
+                            output: None,
+                        });
+                    }
+                }
+            }
+        }
+        targets
+    }
+
+    // Ctrl+Y: start on the newest target
+    pub fn start_focus(&mut self) {
+        match self.focus_targets().len() {
+            0 => self.add_info("Nothing to copy yet".to_string()),
+            count => {
+                self.focus = Some(Focus {
+                    target: count - 1,
+                    reveal: true,
+                })
+            }
+        }
+    }
+
+    pub fn move_focus(&mut self, delta: isize) {
+        let count = self.focus_targets().len();
+        if let Some(focus) = &mut self.focus
+            && count > 0
+        {
+            focus.target = (focus.target as isize + delta).clamp(0, count as isize - 1) as usize;
+            focus.reveal = true;
+        }
+    }
+
+    pub fn focused_target(&self) -> Option<FocusTarget> {
+        let focus = self.focus?;
+        self.focus_targets().into_iter().nth(focus.target)
+    }
+
+    pub fn copy_focused(&mut self) {
+        let Some(target) = self.focused_target() else {
+            return;
+        };
+        match clipboard::copy(&target.text) {
+            Ok(method) => self.add_info(format!(
+                "Copied {} ({} characters, via {})",
+                target.label,
+                target.text.chars().count(),
+                method
+            )),
+            Err(e) => self.add_local_error(format!("Failed to copy to clipboard: {}", e)),
+        }
+    }
+
+    // d in focus mode: remove the highlighted message, attached file or tool result from
+    // the conversation, so it is no longer sent. A tool result's output is replaced by a
+    // note instead, since the API needs an answer to every tool call.
+    pub fn delete_focused(&mut self) {
+        let Some(target) = self.focused_target() else {
+            return;
+        };
+        if self.is_waiting || self.compacting.is_some() {
+            self.add_info("Wait for the current answer before deleting messages".to_string());
+            return;
+        }
+        let index = target.message_index;
+        match target.part {
+            FocusPart::Inner => {
+                self.add_info(
+                    "Only whole messages, attached files and tool results can be deleted"
+                        .to_string(),
+                );
+                return;
+            }
+            FocusPart::Block(block) => {
+                let contents = &mut self.messages[index].1;
+                if let MessageContent::ToolResult {
+                    content, is_error, ..
+                } = &mut contents[block]
+                {
+                    *content = tools::DELETED.to_string();
+                    *is_error = false;
+                } else {
+                    contents.remove(block);
+                }
+            }
+            FocusPart::Message => {
+                // Tool calls go together with their results
+                let with_results = self.messages[index]
+                    .1
+                    .iter()
+                    .any(|c| matches!(c, MessageContent::ToolUse { .. }))
+                    && self
+                        .messages
+                        .get(index + 1)
+                        .is_some_and(|(_, contents)| tools::is_tool_results(contents));
+                let count = if with_results { 2 } else { 1 };
+                self.messages.drain(index..index + count);
+                self.alternatives = self
+                    .alternatives
+                    .take()
+                    .filter(|alts| !(index..index + count).contains(&alts.message_index))
+                    .map(|mut alts| {
+                        if alts.message_index > index {
+                            alts.message_index -= count;
+                        }
+                        alts
+                    });
+                for checkpoint in &mut self.checkpoints {
+                    if *checkpoint > index {
+                        *checkpoint = checkpoint.saturating_sub(count).max(index);
+                    }
+                }
+            }
+        }
+        self.layout_cache.clear();
+        self.add_info(format!("Deleted {}", target.label));
+        // Stay near the same place in the list
+        let count = self.focus_targets().len();
+        match &mut self.focus {
+            Some(_) if count == 0 => self.focus = None,
+            Some(focus) => {
+                focus.target = focus.target.min(count - 1);
+                focus.reveal = true;
+            }
+            None => {}
+        }
+    }
+
+    // Enter in focus mode: preview a downloaded text file, open any other file with
+    // the system's default application, and copy everything else
+    pub fn activate_focused(&mut self) {
+        let Some(target) = self.focused_target() else {
+            return;
+        };
+        let Some(path) = target.file else {
+            self.copy_focused();
+            return;
+        };
+        match FilePreview::load(&path) {
+            Ok(Some(preview)) => self.file_preview = Some(preview),
+            Ok(None) => self.open_file(&path),
+            Err(e) => self.add_local_error(format!("Cannot read {}: {}", path.display(), e)),
+        }
+    }
+
+    // The full text of the focused code output or tool result, with a name for it; on a
+    // whole message, its last code output
+    fn focused_output(&self) -> Option<(String, String)> {
+        let target = self.focused_target()?;
+        let contents = &self.messages[target.message_index].1;
+        let block = target.output.or_else(|| {
+            contents
+                .iter()
+                .rposition(|content| matches!(content, MessageContent::CodeOutput { .. }))
+        })?;
+        let text = match &contents[block] {
+            MessageContent::CodeOutput {
+                stdout,
+                stderr,
+                spilled,
+                ..
+            } => {
+                let (stdout, stderr) = match spilled.as_deref().map(session::load_blob) {
+                    Some(Ok(body)) => (body.stdout, body.stderr),
+                    Some(Err(e)) => {
+                        log_debug!("Showing the preview of a spilled output: {}", e);
+                        (stdout.clone(), stderr.clone())
+                    }
+                    None => (stdout.clone(), stderr.clone()),
+                };
+                [stdout, stderr]
+                    .into_iter()
+                    .filter(|text| !text.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            MessageContent::ToolResult { content, .. } => content.clone(),
+            _ => return None,
+        };
+        let label = match target.output {
+            Some(_) => target.label,
+            None => format!("{} › code output", target.label),
+        };
+        Some((label, text))
+    }
+
+    // o in focus mode: show the whole output in the preview popup
+    pub fn view_focused_output(&mut self) {
+        match self.focused_output() {
+            Some((label, text)) => self.file_preview = Some(FilePreview::from_text(label, &text)),
+            None => self.add_info("Only code outputs and tool results can be viewed".to_string()),
+        }
+    }
+
+    // s in focus mode: write the whole output to a new file in `dir`
+    pub fn save_focused_output(&mut self, dir: &str) {
+        let Some((label, text)) = self.focused_output() else {
+            self.add_info("Only code outputs and tool results can be saved".to_string());
+            return;
+        };
+        let saved = std::fs::create_dir_all(dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| {
+                downloads::save_new(&Path::new(dir).join("output.txt"), text.as_bytes())
+            });
+        match saved {
+            Ok((path, _)) => self.add_info(format!(
+                "Saved {} ({} lines) to {}",
+                label,
+                text.lines().count(),
+                path.display()
+            )),
+            Err(e) => self.add_local_error(format!("Failed to save {}: {}", label, e)),
+        }
+    }
+
+    // A left click: on the input it focuses the input; on a message it starts copy focus
+    // there, and on a downloaded file it previews or opens it as well
+    pub fn click(&mut self, column: u16, row: u16) {
+        let position = Position::new(column, row);
+        if self.input_area.contains(position) {
+            self.focus = None;
+            self.pane = Pane::Input;
+            return;
+        }
+        let area = self.messages_area;
+        // The top border holds the title
+        if !area.contains(position) || row == area.y {
+            return;
+        }
+        let line = self.scroll_position + (row - area.y - 1) as usize;
+        let Some(index) = self.message_starts.iter().rposition(|&start| start <= line) else {
+            return;
+        };
+        // The answer still streaming has no layout yet and can't be focused
+        let Some(layout) = self.layout_cache.get(index) else {
+            return;
+        };
+
+        // Find the clicked line among the message's wrapped lines
+        let width = messages_block(self.popup_mode).inner(area).width as usize;
+        let mut row_in_message = line - self.message_starts[index];
+        let clicked = layout.lines.iter().find(|line| {
+            let rows = wrapped_line_count(std::slice::from_ref(*line), width);
+            if row_in_message < rows {
+                return true;
+            }
+            row_in_message -= rows;
+            false
+        });
+        let clicked_text: String = clicked
+            .map(|line| {
+                line.spans
+                    .iter()
+                    .map(|span| span.content.as_ref())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let targets = self.focus_targets();
+        let file_target = targets.iter().position(|target| {
+            target.message_index == index
+                && target.file.as_ref().is_some_and(|path| {
+                    path.file_name()
+                        .is_some_and(|name| clicked_text.contains(&*name.to_string_lossy()))
+                })
+        });
+        let Some(target) = file_target.or_else(|| {
+            targets
+                .iter()
+                .position(|target| target.message_index == index)
+        }) else {
+            return;
+        };
+        self.pane = Pane::Messages;
+        self.focus = Some(Focus {
+            target,
+            reveal: false,
+        });
+        if file_target.is_some() {
+            self.activate_focused();
+        }
+    }
+
+    pub fn open_file(&mut self, path: &Path) {
+        match preview::open_external(path) {
+            Ok(()) => self.add_info(format!("Opened {}", path.display())),
+            Err(e) => self.add_local_error(format!("Cannot open {}: {}", path.display(), e)),
+        }
+    }
+
+    // A fresh conversation with this one's settings, for a new tab
+    pub fn new_tab(&self) -> App {
+        let mut app = App {
+            tool_mode: self.tool_mode,
+            model: self.model.clone(),
+            sampling: self.sampling.clone(),
+            available_models: self.available_models.clone(),
+            history: self.history.clone(),
+            system_prompt: self.system_prompt.clone(),
+            config: self.config.clone(),
+            toolbox: self.toolbox.clone(),
+            popup_mode: self.popup_mode,
+            ..Default::default()
+        };
+        app.add_custom_commands();
+        app
+    }
+
+    // Start of the first prompt, or "new chat", for the tab bar
+    fn tab_label(&self) -> String {
+        let opening = self
+            .messages
+            .iter()
+            .find(|(role, contents)| role == "user" && !tools::is_tool_results(contents))
+            .and_then(|(_, contents)| {
+                contents.iter().find_map(|content| match content {
+                    MessageContent::Text(text) => text.lines().next(),
+                    _ => None,
+                })
+            })
+            .unwrap_or("new chat");
+        let mut label: String = opening.chars().take(20).collect();
+        if opening.chars().count() > 20 {
+            label.push('…');
+        }
+        label
+    }
+
+    pub fn pane_title(&self) -> String {
+        format!("agnt: {}", self.session_id)
+    }
+
+    // Short single-line excerpt of the last response, for notifications
+    pub fn last_response_preview(&self) -> String {
+        let text = self.last_response_text().unwrap_or_default();
+        let first_line = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+        if first_line.chars().count() > 100 {
+            format!("{}…", first_line.chars().take(100).collect::<String>())
+        } else {
+            first_line.to_string()
+        }
+    }
+
+    fn stats_summary(&self) -> String {
+        let user_messages = self.messages.iter().filter(|(r, _)| r == "user").count();
+        let assistant_messages = self
+            .messages
+            .iter()
+            .filter(|(r, _)| r == "assistant")
+            .count();
+        let blocks = self.messages.iter().flat_map(|(_, content)| content.iter());
+        let mut tool_calls = 0;
+        let mut files_generated = 0;
+        for block in blocks {
+            match block {
+                MessageContent::Code { .. } => tool_calls += 1,
+                MessageContent::CodeOutput { files, .. } => files_generated += files.len(),
+                _ => {}
+            }
+        }
+
+        let mut summary = format!(
+            "Session stats\nMessages: {} user / {} assistant\nTool invocations: {}, files generated: {}",
+            user_messages, assistant_messages, tool_calls, files_generated
+        );
+
+        let turns = self.turn_metrics.len();
+        if turns == 0 {
+            summary.push_str("\nNo completed turns yet");
+            return summary;
+        }
+
+        let input_tokens: u64 = self.turn_metrics.iter().map(|m| m.input_tokens).sum();
+        let output_tokens: u64 = self.turn_metrics.iter().map(|m| m.output_tokens).sum();
+        let cache_creation: u64 = self
+            .turn_metrics
+            .iter()
+            .map(|m| m.cache_creation_tokens)
+            .sum();
+        let cache_read: u64 = self.turn_metrics.iter().map(|m| m.cache_read_tokens).sum();
+        summary.push_str(&format!(
+            "\nTokens: {} in / {} out",
+            input_tokens, output_tokens
+        ));
+
+        // Cache hit rate is the share of prompt tokens served from the cache
+        let prompt_tokens = input_tokens + cache_creation + cache_read;
+        if prompt_tokens > 0 {
+            summary.push_str(&format!(
+                "\nCache: {} read / {} written, hit rate {:.1}%",
+                cache_read,
+                cache_creation,
+                cache_read as f64 / prompt_tokens as f64 * 100.0
+            ));
+        }
+
+        // Turns against models without known pricing make the total unknowable
+        let costs: Option<Vec<f64>> = self.turn_metrics.iter().map(metrics_cost).collect();
+        match costs {
+            Some(costs) => summary.push_str(&format!(
+                "\nEstimated cost: ${:.4}",
+                costs.iter().sum::<f64>()
+            )),
+            None => summary.push_str("\nEstimated cost: n/a"),
+        }
+
+        let ttfts: Vec<f64> = self
+            .turn_metrics
+            .iter()
+            .filter_map(|m| m.ttft.map(|d| d.as_secs_f64()))
+            .collect();
+        let avg_ttft = if ttfts.is_empty() {
+            "n/a".to_string()
+        } else {
+            format!("{:.2}s", ttfts.iter().sum::<f64>() / ttfts.len() as f64)
+        };
+        let total_duration: f64 = self
+            .turn_metrics
+            .iter()
+            .map(|m| m.duration.as_secs_f64())
+            .sum();
+        let total_bytes: usize = self.turn_metrics.iter().map(|m| m.bytes).sum();
+        summary.push_str(&format!(
+            "\nTurns: {}, avg TTFT {}, avg duration {:.2}s, {} bytes received",
+            turns,
+            avg_ttft,
+            total_duration / turns as f64,
+            total_bytes
+        ));
+
+        if let Some(last) = self.turn_metrics.last() {
+            summary.push_str(&format!(
+                "\nLast turn: TTFT {}, duration {:.2}s, {} bytes, {} output tokens, {}",
+                last.ttft
+                    .map(|d| format!("{:.2}s", d.as_secs_f64()))
+                    .unwrap_or_else(|| "n/a".to_string()),
+                last.duration.as_secs_f64(),
+                last.bytes,
+                last.output_tokens,
+                last.tokens_per_sec()
+                    .map(|rate| format!("{:.0} tok/s", rate))
+                    .unwrap_or_else(|| "n/a tok/s".to_string())
+            ));
+        }
+
+        summary
+    }
+}
+
+// The line under each answer, e.g. "took 5.2s · 0.84s to first token · 312 tokens · 60 tok/s"
+fn timing_summary(metrics: &TurnMetrics) -> String {
+    let took = if metrics.duration.as_secs() < 60 {
+        format!("{:.1}s", metrics.duration.as_secs_f64())
+    } else {
+        format_elapsed(metrics.duration)
+    };
+    let mut parts = vec![format!("took {}", took)];
+    if let Some(first) = metrics.first_text.or(metrics.ttft) {
+        parts.push(format!("{:.2}s to first token", first.as_secs_f64()));
+    }
+    parts.push(format!("{} tokens", metrics.output_tokens));
+    if let Some(rate) = metrics.tokens_per_sec() {
+        parts.push(format!("{:.0} tok/s", rate));
+    }
+    parts.join(" · ")
+}
+
+// Whole seconds for the live timer: "42s", "3m 05s", "1h 02m"
+fn format_elapsed(elapsed: std::time::Duration) -> String {
+    let secs = elapsed.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn metrics_cost(metrics: &TurnMetrics) -> Option<f64> {
+    pricing::cost(
+        &metrics.model,
+        metrics.input_tokens,
+        metrics.output_tokens,
+        metrics.cache_creation_tokens,
+        metrics.cache_read_tokens,
+    )
+}
+
+// `~/shots/a.png` -> `/home/me/shots/a.png`, since paths typed in the TUI aren't
+// expanded by a shell
+// Longest start shared by all the strings
+fn common_prefix(strings: &[String]) -> String {
+    let Some(first) = strings.first() else {
+        return String::new();
+    };
+    let mut end = first.len();
+    for other in &strings[1..] {
+        end = first
+            .char_indices()
+            .zip(other.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(end);
+    }
+    first[..end].to_string()
+}
+
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn parse_retry_args(args: &str) -> Result<RequestParams, String> {
+    let mut params = RequestParams::default();
+    let mut words = args.split_whitespace();
+    while let Some(flag) = words.next() {
+        let value = words
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", flag))?;
+        let invalid = || format!("Invalid value for {}: {}", flag, value);
+        match flag {
+            "--temperature" | "-t" => {
+                params.temperature = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "--top-p" => params.top_p = Some(value.parse().map_err(|_| invalid())?),
+            "--top-k" => params.top_k = Some(value.parse().map_err(|_| invalid())?),
+            "--model" | "-m" => params.model = Some(models::resolve(value)),
+            "--stop" => params
+                .stop_sequences
+                .get_or_insert_with(Vec::new)
+                .push(value.to_string()),
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+    params.validate().map_err(|e| e.to_string())?;
+    Ok(params)
+}
+
+// The open conversations, shown above the messages once there is more than one
+pub struct TabBar {
+    tabs: Vec<(String, bool)>, // Label, and whether an answer is streaming there
+    active: usize,
+}
+
+impl TabBar {
+    pub fn new<'a>(apps: impl Iterator<Item = &'a App>, active: usize) -> Self {
+        Self {
+            tabs: apps.map(|app| (app.tab_label(), app.is_waiting)).collect(),
+            active,
+        }
+    }
+}
+
+pub fn ui(f: &mut Frame, app: &mut App, tab_bar: &TabBar) {
+    // Calculate input height based on content, including wrapped lines (min 3, max 10 lines)
+    let input_width = f.area().width.saturating_sub(2) as usize;
+    let input_lines: usize = app
+        .input
+        .split('\n')
+        .map(|line| wrapped_rows(line, input_width))
+        .sum();
+    // Popups are small, so the input box grows less there
+    let max_input_height = if app.popup_mode { 5 } else { 10 };
+    let input_height = (input_lines + 2).clamp(3, max_input_height) as u16; // +2 for borders
+
+    let bar_height = if tab_bar.tabs.len() > 1 { 1 } else { 0 };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(bar_height),
+                Constraint::Min(5),
+                Constraint::Length(input_height),
+            ]
+            .as_ref(),
+        )
+        .split(f.area());
+    if bar_height > 0 {
+        render_tab_bar(f, tab_bar, chunks[0]);
+    }
+    let chunks = &chunks[1..];
+
+    app.messages_area = chunks[0];
+    app.input_area = chunks[1];
+    render_messages(f, app, chunks[0]);
+    render_input(f, app, chunks[1]);
+
+    // Render slash command autocomplete menu if active
+    if let Some(state) = &app.slash_command_state {
+        render_slash_command_menu(f, state, chunks[1]);
+    }
+
+    if let Some(editor) = &app.params_editor {
+        render_params_editor(f, editor);
+    }
+
+    if let Some(form) = &app.template_form {
+        render_template_form(f, form);
+    }
+
+    if let Some(browser) = &app.session_browser {
+        render_session_browser(f, browser);
+    }
+
+    if let Some(preview) = &app.file_preview {
+        render_file_preview(f, preview);
+    }
+
+    if let Some(selected) = app.model_picker {
+        render_model_picker(f, app, selected, chunks[1]);
+    }
+
+    if let Some(Confirmation::ToolCall(approval)) = &app.confirmation {
+        render_tool_approval(f, approval);
+    }
+
+    // Render help modal if active
+    if app.show_help {
+        render_help_modal(f);
+    }
+}
+
+fn render_tab_bar(f: &mut Frame, tab_bar: &TabBar, area: Rect) {
+    let theme = theme::current();
+    let mut spans = Vec::new();
+    for (index, (label, streaming)) in tab_bar.tabs.iter().enumerate() {
+        let style = if index == tab_bar.active {
+            Style::default()
+                .fg(theme.on_accent)
+                .bg(theme.accent)
+                .add_modifier(Modifier::BOLD)
+        } else if *streaming {
+            Style::default().fg(theme.warning)
+        } else {
+            Style::default().fg(theme.dim)
+        };
+        let marker = if *streaming { " ●" } else { "" };
+        spans.push(Span::styled(
+            format!(" {} {}{} ", index + 1, label, marker),
+            style,
+        ));
+        spans.push(Span::raw(" "));
+    }
+    f.render_widget(Paragraph::new(Line::from(spans)), area);
+}
+
+// Number of lines above and below the viewport that are materialized anyway,
+// so inaccuracies in the wrap estimate don't leave gaps at the edges
+const OVERSCAN_LINES: usize = 20;
+
+// Popups drop the side and bottom borders to leave more room for text
+fn messages_block<'a>(popup_mode: bool) -> Block<'a> {
+    Block::default().borders(if popup_mode {
+        Borders::TOP
+    } else {
+        Borders::ALL
+    })
+}
+
+fn render_messages(f: &mut Frame, app: &mut App, area: Rect) {
+    let theme = theme::current();
+    let block = messages_block(app.popup_mode);
+    // Wrapping is estimated at the width the paragraph will really get
+    let inner = block.inner(area);
+    let available_width = inner.width as usize;
+    let visible_lines = inner.height as usize;
+
+    // Wrapped height of every finished message, plus the streaming tail
+    let heights = message_heights(app, available_width);
+    let streaming_lines = build_streaming_lines(app);
+    let streaming_height = wrapped_line_count(&streaming_lines, available_width);
+
+    let mut start = 0;
+    app.message_starts.clear();
+    for height in &heights {
+        app.message_starts.push(start);
+        start += height;
+    }
+    if streaming_height > 0 {
+        app.message_starts.push(start);
+    }
 
-    let visible_lines = area.height.saturating_sub(2) as usize;
+    // The spacer after the final block is never rendered
+    let total_wrapped_lines = (heights.iter().sum::<usize>() + streaming_height).saturating_sub(1);
 
     // Update scroll bounds with actual wrapped line count
     app.update_scroll_bounds(total_wrapped_lines, visible_lines);
 
+    // In focus mode the message holding the focused target is highlighted, and scrolled
+    // to when the focus has just moved
+    let focused = app.focused_target();
+    let focused_message = focused.as_ref().map(|target| target.message_index);
+    if let Some(index) = focused_message
+        && let Some(focus) = &mut app.focus
+        && focus.reveal
+    {
+        focus.reveal = false;
+        let start: usize = heights[..index].iter().sum();
+        let end = start + heights[index];
+        if start < app.scroll_position || end > app.scroll_position + visible_lines {
+            app.scroll_position = start;
+            app.auto_scroll = false;
+            app.update_scroll_bounds(total_wrapped_lines, visible_lines);
+        }
+    }
+
+    // Only materialize the messages that overlap the viewport (plus overscan)
+    let window_start = app.scroll_position.saturating_sub(OVERSCAN_LINES);
+    let window_end = app.scroll_position + visible_lines + OVERSCAN_LINES;
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut first_line_offset = None;
+    let mut block_start = 0;
+    for (idx, height) in heights.iter().enumerate() {
+        let block_end = block_start + height;
+        if block_end > window_start && block_start < window_end {
+            first_line_offset.get_or_insert(block_start);
+            let block = app.layout_cache[idx].lines.clone();
+            if focused_message == Some(idx) {
+                lines.extend(block.into_iter().map(|mut line| {
+                    if !line.spans.is_empty() {
+                        line.style = line.style.bg(theme.focus_bg);
+                    }
+                    line
+                }));
+            } else {
+                lines.extend(block);
+            }
+        }
+        block_start = block_end;
+    }
+    if block_start < window_end {
+        first_line_offset.get_or_insert(block_start);
+        lines.extend(streaming_lines);
+    }
+
+    // Remove trailing empty lines
+    while lines.last().is_some_and(|l| l.spans.is_empty()) {
+        lines.pop();
+    }
+
     // Create title
     let title = if app.selection_mode {
         "agnt (SELECTION MODE - Press Ctrl+S to exit)".to_string()
+    } else if let Some(target) = &focused {
+        let enter = if target.file.is_some() {
+            " · Enter open"
+        } else if target.output.is_some() {
+            " · o view · s save"
+        } else {
+            ""
+        };
+        format!(
+            "agnt (COPY: {} · ↑/↓ move · y copy{} · b branch · d delete · Esc done)",
+            target.label, enter
+        )
     } else {
         let mut title_parts = vec!["agnt".to_string()];
 
@@ -404,73 +3807,186 @@ fn render_messages(f: &mut Frame, app: &mut App, area: Rect) {
         title_parts.join(" ")
     };
 
+    // Scroll relative to the first materialized line
+    let relative_scroll = app
+        .scroll_position
+        .saturating_sub(first_line_offset.unwrap_or(0));
+
+    // The focused pane's border stands out
+    let border_color = if app.active_pane() == Pane::Messages {
+        theme.accent
+    } else {
+        theme.dim
+    };
+
     // Create the messages paragraph with scrolling
     let messages = Paragraph::new(Text::from(lines))
         .block(
-            Block::default()
-                .borders(Borders::ALL)
+            block
                 .title(title)
-                .border_style(Style::default().fg(Color::DarkGray)),
+                .border_style(Style::default().fg(border_color)),
         )
         .wrap(Wrap { trim: true })
-        .scroll((app.scroll_position as u16, 0));
+        .scroll((relative_scroll as u16, 0));
 
     f.render_widget(messages, area);
+
+    // Scrollbar over the right border, below the title
+    if total_wrapped_lines > visible_lines {
+        let max_scroll = total_wrapped_lines - visible_lines;
+        let mut state = ScrollbarState::new(max_scroll + 1)
+            .position(app.scroll_position)
+            .viewport_content_length(visible_lines);
+        let track = Rect {
+            y: area.y + 1,
+            height: visible_lines as u16,
+            ..area
+        };
+        f.render_stateful_widget(
+            Scrollbar::new(ScrollbarOrientation::VerticalRight)
+                .begin_symbol(None)
+                .end_symbol(None)
+                .track_style(Style::default().fg(theme.dim))
+                .thumb_style(Style::default().fg(theme.secondary)),
+            track,
+            &mut state,
+        );
+    }
 }
 
-fn build_message_lines(app: &App, available_width: usize) -> (Vec<Line<'static>>, usize) {
-    let mut lines: Vec<Line> = Vec::new();
+// Wrapped heights of all finished messages; only messages whose content changed are laid
+// out again, and a width change only re-wraps the cached lines
+fn message_heights(app: &mut App, available_width: usize) -> Vec<usize> {
+    app.layout_cache.truncate(app.messages.len());
 
-    for (role, contents) in &app.messages {
-        match role.as_str() {
-            "user" => {
-                // User message header
-                lines.push(Line::from(vec![Span::styled(
-                    "▶ You".to_string(),
-                    Style::default()
-                        .fg(Color::Cyan)
-                        .add_modifier(Modifier::BOLD),
-                )]));
+    let mut heights = Vec::with_capacity(app.messages.len());
+    for (idx, (role, contents)) in app.messages.iter().enumerate() {
+        let annotation = app.message_annotation(idx);
+        let mut hasher = DefaultHasher::new();
+        role.hash(&mut hasher);
+        contents.hash(&mut hasher);
+        annotation.hash(&mut hasher);
+        let hash = hasher.finish();
 
-                // User message content
-                for content in contents {
-                    render_content(&mut lines, content, "  ");
-                }
+        if let Some(cached) = app.layout_cache.get_mut(idx)
+            && cached.hash == hash
+        {
+            if cached.width != available_width {
+                cached.width = available_width;
+                cached.height = wrapped_line_count(&cached.lines, available_width);
             }
-            "assistant" => {
-                // Claude message header
-                lines.push(Line::from(vec![Span::styled(
-                    "◆ Claude".to_string(),
-                    Style::default()
-                        .fg(Color::Yellow)
-                        .add_modifier(Modifier::BOLD),
-                )]));
+            heights.push(cached.height);
+            continue;
+        }
 
-                // Claude message content
-                for content in contents {
-                    render_content(&mut lines, content, "  ");
-                }
+        let lines = build_block_lines(
+            role,
+            contents,
+            annotation.as_deref(),
+            app.config.max_output_lines,
+        );
+        let height = wrapped_line_count(&lines, available_width);
+        let entry = CachedLayout {
+            hash,
+            width: available_width,
+            height,
+            lines,
+        };
+        if idx < app.layout_cache.len() {
+            app.layout_cache[idx] = entry;
+        } else {
+            app.layout_cache.push(entry);
+        }
+        heights.push(height);
+    }
+
+    heights
+}
+
+// Lines for a single finished message, including the spacer line after it
+fn build_block_lines(
+    role: &str,
+    contents: &[MessageContent],
+    annotation: Option<&str>,
+    output_cap: usize,
+) -> Vec<Line<'static>> {
+    let theme = theme::current();
+    let mut lines: Vec<Line> = Vec::new();
+
+    match role {
+        "user" if tools::is_tool_results(contents) => {
+            // Results agnt sends back on the user's behalf
+            lines.push(Line::from(vec![Span::styled(
+                "⚙ Tools".to_string(),
+                Style::default()
+                    .fg(theme.success)
+                    .add_modifier(Modifier::BOLD),
+            )]));
+            for content in contents {
+                render_content(&mut lines, content, "  ", output_cap);
             }
-            "system" => {
-                // System messages (API errors, etc.) - render without header
-                for content in contents {
-                    render_content(&mut lines, content, "");
-                }
+        }
+        "user" => {
+            // User message header
+            lines.push(Line::from(vec![Span::styled(
+                "▶ You".to_string(),
+                Style::default()
+                    .fg(theme.accent)
+                    .add_modifier(Modifier::BOLD),
+            )]));
+
+            // User message content
+            for content in contents {
+                render_content(&mut lines, content, "  ", output_cap);
             }
-            _ => {}
         }
+        "assistant" => {
+            // Claude message header
+            let mut header = vec![Span::styled(
+                "◆ Claude".to_string(),
+                Style::default()
+                    .fg(theme.warning)
+                    .add_modifier(Modifier::BOLD),
+            )];
+            if let Some(annotation) = annotation {
+                header.push(Span::styled(
+                    format!("  {}", annotation),
+                    Style::default().fg(theme.dim),
+                ));
+            }
+            lines.push(Line::from(header));
 
-        // Add spacing between messages
-        lines.push(Line::from(""));
+            // Claude message content
+            for content in contents {
+                render_content(&mut lines, content, "  ", output_cap);
+            }
+        }
+        "system" => {
+            // System messages (API errors, etc.) - render without header
+            for content in contents {
+                render_content(&mut lines, content, "", output_cap);
+            }
+        }
+        _ => {}
     }
 
+    // Add spacing between messages
+    lines.push(Line::from(""));
+    lines
+}
+
+// Lines for the in-progress response (empty when nothing is streaming)
+fn build_streaming_lines(app: &App) -> Vec<Line<'static>> {
+    let theme = theme::current();
+    let mut lines: Vec<Line> = Vec::new();
+
     // Add streaming content if present OR if waiting for response
     if !app.streaming_content.is_empty() || app.is_waiting {
         // Streaming header
         lines.push(Line::from(vec![Span::styled(
             "◆ Claude".to_string(),
             Style::default()
-                .fg(Color::Yellow)
+                .fg(theme.warning)
                 .add_modifier(Modifier::BOLD),
         )]));
 
@@ -478,76 +3994,91 @@ fn build_message_lines(app: &App, available_width: usize) -> (Vec<Line<'static>>
             || (app.streaming_content.len() == 1
                 && matches!(&app.streaming_content[0], MessageContent::Text(t) if t.is_empty()))
         {
-            // Render loading animation
-            let dots = match app.loading_animation_frame % 3 {
-                0 => "●○○",
-                1 => "○●○",
-                2 => "○○●",
-                _ => "●○○",
-            };
-
-            // Show connection status if available, otherwise show "Thinking..."
-            let status_text = if let Some(ref status) = app.connection_status {
-                format!(" {}", status)
+            // Render loading animation, unless motion is reduced
+            let frames = app.config.spinner.frames();
+            let spinner = if app.config.reduced_motion || frames.is_empty() {
+                String::new()
             } else {
-                " Thinking...".to_string()
+                format!("{} ", frames[app.loading_animation_frame % frames.len()])
             };
 
+            // Show connection status if available, otherwise show "Thinking…", with the
+            // time since the request was sent
+            let status_text = format!(
+                "{} {}",
+                app.connection_status.as_deref().unwrap_or("Thinking…"),
+                format_elapsed(app.waiting_since.elapsed())
+            );
+
             lines.push(Line::from(vec![
                 Span::raw("  ".to_string()),
                 Span::styled(
-                    dots.to_string(),
+                    spinner,
                     Style::default()
-                        .fg(Color::Yellow)
+                        .fg(theme.warning)
                         .add_modifier(Modifier::BOLD),
                 ),
                 Span::styled(
                     status_text,
                     Style::default()
-                        .fg(Color::DarkGray)
+                        .fg(theme.dim)
                         .add_modifier(Modifier::ITALIC),
                 ),
             ]));
         } else {
             for content in &app.streaming_content {
-                render_content(&mut lines, content, "  ");
+                render_content(&mut lines, content, "  ", app.config.max_output_lines);
+            }
+            // Reconnect and stall notices would otherwise be hidden once text has arrived
+            if let Some(status) = &app.connection_status {
+                let color = if app.stalled {
+                    theme.warning
+                } else {
+                    theme.dim
+                };
+                lines.push(Line::from(vec![
+                    Span::raw("  ".to_string()),
+                    Span::styled(
+                        status.clone(),
+                        Style::default().fg(color).add_modifier(Modifier::ITALIC),
+                    ),
+                ]));
             }
         }
         lines.push(Line::from(""));
     }
 
-    // Remove trailing empty lines
-    while lines.last().is_some_and(|l| l.spans.is_empty()) {
-        lines.pop();
-    }
+    lines
+}
 
-    // Calculate actual wrapped lines
+// Calculate how many visual lines the given logical lines occupy
+fn wrapped_line_count(lines: &[Line], available_width: usize) -> usize {
     let mut total_wrapped_lines = 0;
-    for line in &lines {
+    for line in lines {
         let line_text = line
             .spans
             .iter()
             .map(|span| span.content.as_ref())
             .collect::<String>();
 
-        if line_text.is_empty() {
-            total_wrapped_lines += 1;
-        } else {
-            // Calculate how many visual lines this logical line will occupy
-            let line_width = line_text.chars().count();
-            let wrapped_count = line_width.div_ceil(available_width);
-            total_wrapped_lines += wrapped_count.max(1);
-        }
+        total_wrapped_lines += wrapped_rows(&line_text, available_width);
     }
+    total_wrapped_lines
+}
 
-    (lines, total_wrapped_lines)
+// Terminal rows `text` takes when wrapped at `width` columns; wide characters (CJK,
+// emoji) take two columns and combining marks none
+fn wrapped_rows(text: &str, width: usize) -> usize {
+    text.width().div_ceil(width.max(1)).max(1)
 }
 
 fn render_input(f: &mut Frame, app: &App, area: Rect) {
+    let theme = theme::current();
+    let keymap = keymap::current();
     let (input_title, border_color) = if app.selection_mode {
         (
-            "Input (SELECTION MODE - text can be selected)",
-            Color::Yellow,
+            "Input (SELECTION MODE - text can be selected)".to_string(),
+            theme.warning,
         )
     } else if app.is_waiting {
         let waiting_text = match app.tool_mode {
@@ -558,18 +4089,85 @@ fn render_input(f: &mut Frame, app: &App, area: Rect) {
             ToolMode::Both => "Input (waiting for response with code + web search... Esc: cancel)",
             ToolMode::None => "Input (waiting for response... Esc: cancel)",
         };
-        (waiting_text, Color::DarkGray)
+        let mut title = format!(
+            "{} · {}",
+            waiting_text,
+            format_elapsed(app.waiting_since.elapsed())
+        );
+        if let Some(rate) = app.live_tokens_per_sec() {
+            title.push_str(&format!(" · ~{:.0} tok/s", rate));
+        }
+        (title, theme.dim)
     } else {
         let border_color = match app.tool_mode {
-            ToolMode::CodeExecution | ToolMode::Both => Color::Magenta, // Pink/red color for code execution
-            ToolMode::WebSearch => Color::Blue,                         // Blue for web search
-            ToolMode::None => Color::Cyan,
+            ToolMode::CodeExecution | ToolMode::Both => theme.highlight, // Pink/red color for code execution
+            ToolMode::WebSearch => theme.heading,                        // Blue for web search
+            ToolMode::None => theme.accent,
+        };
+        // Pre-send estimate for the draft, priced at the session's current model
+        let title = if app.input.trim().is_empty() || app.slash_command_state.is_some() {
+            format!(
+                "Input ({}: help, {}: exit)",
+                keymap.label(Action::Help),
+                keymap.label(Action::Quit)
+            )
+        } else {
+            let tokens = app.estimate_input_tokens();
+            let cost = pricing::cost(&app.model, tokens, 0, 0, 0)
+                .map(|cost| format!(", ~${:.4}", cost))
+                .unwrap_or_default();
+            format!(
+                "Input ({}: help) · ~{} tokens in{}",
+                keymap.label(Action::Help),
+                tokens,
+                cost
+            )
+        };
+        let title = match app.attachments.len() {
+            0 => title,
+            n => format!("{} · {} attachment(s)", title, n),
+        };
+        // Context use only shows once it starts to matter
+        let title = match app.context_percent() {
+            _ if app.compacting.is_some() => format!("{} · compacting...", title),
+            percent if percent >= 50 => format!("{} · context {}%", title, percent),
+            _ => title,
         };
-        ("Input (Ctrl+H: help, Ctrl+C: exit)", border_color)
+        (title, border_color)
+    };
+
+    // A history search shows the current match in place of the draft
+    let (text, input_title) = match &app.history_search {
+        Some(search) => {
+            let status = if search.match_index.is_some() || search.query.is_empty() {
+                ""
+            } else {
+                " (no match)"
+            };
+            (
+                app.history_search_text().unwrap_or(""),
+                format!(
+                    "History search: \"{}\"{} · Ctrl+R: older · Enter: use · Esc: cancel",
+                    search.query, status
+                ),
+            )
+        }
+        None => (app.input.as_str(), input_title),
+    };
+
+    let input_focused = app.active_pane() != Pane::Messages;
+    let (input_title, border_color) = if input_focused {
+        (input_title, border_color)
+    } else {
+        (
+            "Input (Tab: back to the input · ↑/↓ PgUp/PgDn Home/End: scroll · Enter: pick a message)"
+                .to_string(),
+            theme.dim,
+        )
     };
 
-    let input = Paragraph::new(app.input.as_str())
-        .style(Style::default().fg(Color::White))
+    let input = Paragraph::new(text)
+        .style(Style::default().fg(theme.text))
         .block(
             Block::default()
                 .borders(Borders::ALL)
@@ -579,12 +4177,21 @@ fn render_input(f: &mut Frame, app: &App, area: Rect) {
         .wrap(Wrap { trim: true });
 
     f.render_widget(input, area);
+    if !input_focused {
+        return;
+    }
 
-    // Calculate cursor position for multi-line input
+    // Calculate cursor position for multi-line input from the text before the cursor
+    // (a history search match is shown with the cursor at its end).
     // Split by \n to handle trailing newlines properly
-    let lines: Vec<&str> = app.input.split('\n').collect();
+    let cursor = if app.history_search.is_some() {
+        text.len()
+    } else {
+        app.input_cursor.min(text.len())
+    };
+    let lines: Vec<&str> = text[..cursor].split('\n').collect();
     let current_line = lines.len().saturating_sub(1);
-    let last_line_len = lines.last().map(|l| l.len()).unwrap_or(0);
+    let last_line_width = lines.last().map(|l| l.width()).unwrap_or(0);
 
     // Account for wrapped lines
     let available_width = area.width.saturating_sub(2) as usize; // -2 for borders
@@ -595,35 +4202,58 @@ fn render_input(f: &mut Frame, app: &App, area: Rect) {
             break;
         }
         // Calculate wrapped lines for this line
-        let wrapped_count = line.len().div_ceil(available_width).max(1);
-        cursor_y += wrapped_count as u16;
+        let full_line = text.split('\n').nth(i).unwrap_or(line);
+        cursor_y += wrapped_rows(full_line, available_width) as u16;
     }
 
     // Calculate x position on the last line
-    let cursor_x = area.x + 1 + (last_line_len % available_width) as u16;
+    let cursor_x = area.x + 1 + (last_line_width % available_width.max(1)) as u16;
 
     f.set_cursor_position((cursor_x, cursor_y));
 }
 
-fn render_content(lines: &mut Vec<Line<'static>>, content: &MessageContent, prefix: &str) {
+// `output_cap` is how many lines of a code output are shown (0 shows them all)
+fn render_content(
+    lines: &mut Vec<Line<'static>>,
+    content: &MessageContent,
+    prefix: &str,
+    output_cap: usize,
+) {
+    let theme = theme::current();
     match content {
         MessageContent::Text(text) => {
+            let text_style = Style::default().fg(theme.secondary);
             for line in text.lines() {
-                lines.push(Line::from(vec![
-                    Span::raw(prefix.to_string()),
-                    Span::styled(line.to_string(), Style::default().fg(Color::Gray)),
-                ]));
+                // Review comments start with the file:line they are about
+                let spans = match review::anchor(line) {
+                    Some(anchor) => vec![
+                        Span::raw(prefix.to_string()),
+                        Span::styled(line[..anchor.start].to_string(), text_style),
+                        Span::styled(
+                            line[anchor.clone()].to_string(),
+                            Style::default()
+                                .fg(theme.accent)
+                                .add_modifier(Modifier::BOLD),
+                        ),
+                        Span::styled(line[anchor.end..].to_string(), text_style),
+                    ],
+                    None => vec![
+                        Span::raw(prefix.to_string()),
+                        Span::styled(line.to_string(), text_style),
+                    ],
+                };
+                lines.push(Line::from(spans));
             }
         }
         MessageContent::Code { input } => {
             // Code header
             lines.push(Line::from(vec![
                 Span::raw(prefix.to_string()),
-                Span::styled("┌─ ".to_string(), Style::default().fg(Color::DarkGray)),
+                Span::styled("┌─ ".to_string(), Style::default().fg(theme.dim)),
                 Span::styled(
                     "Python Code".to_string(),
                     Style::default()
-                        .fg(Color::Green)
+                        .fg(theme.success)
                         .add_modifier(Modifier::BOLD),
                 ),
             ]));
@@ -632,18 +4262,15 @@ fn render_content(lines: &mut Vec<Line<'static>>, content: &MessageContent, pref
             for (idx, line) in input.lines().enumerate() {
                 lines.push(Line::from(vec![
                     Span::raw(prefix.to_string()),
-                    Span::styled("│ ".to_string(), Style::default().fg(Color::DarkGray)),
-                    Span::styled(
-                        format!("{:3} ", idx + 1),
-                        Style::default().fg(Color::DarkGray),
-                    ),
-                    Span::styled(line.to_string(), Style::default().fg(Color::Blue)),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
+                    Span::styled(format!("{:3} ", idx + 1), Style::default().fg(theme.dim)),
+                    Span::styled(line.to_string(), Style::default().fg(theme.heading)),
                 ]));
             }
 
             lines.push(Line::from(vec![
                 Span::raw(prefix.to_string()),
-                Span::styled("└─".to_string(), Style::default().fg(Color::DarkGray)),
+                Span::styled("└─".to_string(), Style::default().fg(theme.dim)),
             ]));
         }
         MessageContent::CodeOutput {
@@ -651,11 +4278,13 @@ fn render_content(lines: &mut Vec<Line<'static>>, content: &MessageContent, pref
             stderr,
             return_code,
             files,
+            spilled,
+            downloads,
         } => {
             // Output header
             lines.push(Line::from(vec![
                 Span::raw(prefix.to_string()),
-                Span::styled("┌─ ".to_string(), Style::default().fg(Color::DarkGray)),
+                Span::styled("┌─ ".to_string(), Style::default().fg(theme.dim)),
                 Span::styled(
                     if *return_code == 0 {
                         "Output".to_string()
@@ -664,45 +4293,68 @@ fn render_content(lines: &mut Vec<Line<'static>>, content: &MessageContent, pref
                     },
                     Style::default()
                         .fg(if *return_code == 0 {
-                            Color::Green
+                            theme.success
                         } else {
-                            Color::Red
+                            theme.error
                         })
                         .add_modifier(Modifier::BOLD),
                 ),
             ]));
 
-            // Stdout
-            if !stdout.is_empty() {
-                for line in stdout.lines() {
+            // Stdout, then stderr, up to the cap
+            let mut shown = 0;
+            let mut hidden = 0;
+            for (text, color) in [(stdout, theme.text), (stderr, theme.error)] {
+                for line in text.lines() {
+                    if output_cap > 0 && shown >= output_cap {
+                        hidden += 1;
+                        continue;
+                    }
+                    shown += 1;
                     lines.push(Line::from(vec![
                         Span::raw(prefix.to_string()),
-                        Span::styled("│ ".to_string(), Style::default().fg(Color::DarkGray)),
-                        Span::styled(line.to_string(), Style::default().fg(Color::White)),
+                        Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
+                        Span::styled(line.to_string(), Style::default().fg(color)),
                     ]));
                 }
             }
+            if hidden > 0 {
+                lines.push(Line::from(vec![
+                    Span::raw(prefix.to_string()),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
+                    Span::styled(
+                        format!(
+                            "… {} more lines (press o to view / s to save)",
+                            group_digits(hidden)
+                        ),
+                        Style::default().fg(theme.warning),
+                    ),
+                ]));
+            }
 
-            // Stderr
-            if !stderr.is_empty() {
-                for line in stderr.lines() {
-                    lines.push(Line::from(vec![
-                        Span::raw(prefix.to_string()),
-                        Span::styled("│ ".to_string(), Style::default().fg(Color::DarkGray)),
-                        Span::styled(line.to_string(), Style::default().fg(Color::Red)),
-                    ]));
-                }
+            // Note that only a preview is shown for outputs moved to disk
+            if spilled.is_some() {
+                lines.push(Line::from(vec![
+                    Span::raw(prefix.to_string()),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
+                    Span::styled(
+                        "(preview only - press o to view / s to save the full output)".to_string(),
+                        Style::default()
+                            .fg(theme.dim)
+                            .add_modifier(Modifier::ITALIC),
+                    ),
+                ]));
             }
 
             // Files
             if !files.is_empty() {
                 lines.push(Line::from(vec![
                     Span::raw(prefix.to_string()),
-                    Span::styled("│ ".to_string(), Style::default().fg(Color::DarkGray)),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
                     Span::styled(
                         "Created files:".to_string(),
                         Style::default()
-                            .fg(Color::Cyan)
+                            .fg(theme.accent)
                             .add_modifier(Modifier::BOLD),
                     ),
                 ]));
@@ -714,49 +4366,294 @@ fn render_content(lines: &mut Vec<Line<'static>>, content: &MessageContent, pref
                         filename.clone()
                     };
 
-                    lines.push(Line::from(vec![
+                    let mut spans = vec![
                         Span::raw(prefix.to_string()),
-                        Span::styled("│   • ".to_string(), Style::default().fg(Color::DarkGray)),
-                        Span::styled(display_name, Style::default().fg(Color::Blue)),
-                        Span::styled(" (ID: ".to_string(), Style::default().fg(Color::DarkGray)),
+                        Span::styled("│   • ".to_string(), Style::default().fg(theme.dim)),
+                        Span::styled(display_name, Style::default().fg(theme.heading)),
+                        Span::styled(" (ID: ".to_string(), Style::default().fg(theme.dim)),
                         Span::styled(
                             file_id[..8.min(file_id.len())].to_string(),
-                            Style::default().fg(Color::DarkGray),
+                            Style::default().fg(theme.dim),
                         ),
-                        Span::styled("...)".to_string(), Style::default().fg(Color::DarkGray)),
-                    ]));
+                        Span::styled("...)".to_string(), Style::default().fg(theme.dim)),
+                    ];
+                    if let Some((_, state)) = downloads.iter().find(|(id, _)| id == file_id) {
+                        let color = match state {
+                            DownloadState::Queued => theme.dim,
+                            DownloadState::Downloading { .. } => theme.warning,
+                            DownloadState::Saved(_) => theme.success,
+                            DownloadState::Skipped(_) => theme.dim,
+                            DownloadState::Failed(_) => theme.error,
+                        };
+                        spans.push(Span::styled(
+                            format!(" · {}", state.describe()),
+                            Style::default().fg(color),
+                        ));
+                    }
+                    lines.push(Line::from(spans));
                 }
             }
 
             lines.push(Line::from(vec![
                 Span::raw(prefix.to_string()),
-                Span::styled("└─".to_string(), Style::default().fg(Color::DarkGray)),
+                Span::styled("└─".to_string(), Style::default().fg(theme.dim)),
             ]));
         }
         MessageContent::CodeError(error) => {
             lines.push(Line::from(vec![
                 Span::raw(prefix.to_string()),
                 Span::styled(
-                    "⚠ Code Execution Error: ".to_string(),
-                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                    "⚠ Code Execution Error: ".to_string(),
+                    Style::default()
+                        .fg(theme.error)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(error.to_string(), Style::default().fg(theme.error)),
+            ]));
+        }
+        MessageContent::ApiError(error) => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    "❌ API Error: ".to_string(),
+                    Style::default()
+                        .fg(theme.error)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(error.to_string(), Style::default().fg(theme.error)),
+            ]));
+        }
+        MessageContent::LocalError(error) => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    "❌ Error: ".to_string(),
+                    Style::default()
+                        .fg(theme.error)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(error.to_string(), Style::default().fg(theme.error)),
+            ]));
+        }
+        MessageContent::StopReason(reason) if reason == "refusal" => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    " ⊘ Claude declined to answer ".to_string(),
+                    Style::default()
+                        .fg(theme.text)
+                        .bg(theme.highlight)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(
+                    format!(
+                        "  {} to edit and resend the prompt",
+                        keymap::current().label(Action::Rephrase)
+                    ),
+                    Style::default().fg(theme.highlight),
+                ),
+            ]));
+        }
+        MessageContent::StopReason(reason) => {
+            // Truncated answers stand out a little; normal endings stay subtle
+            let style = if reason == "max_tokens" {
+                Style::default().fg(theme.warning)
+            } else {
+                Style::default().fg(theme.dim)
+            };
+            let label = match reason.as_str() {
+                "max_tokens" => {
+                    "⚠ cut off at max_tokens · /continue to finish it, /maxtokens to raise the limit"
+                        .to_string()
+                }
+                other => other.to_string(),
+            };
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(format!("· {}", label), style),
+            ]));
+        }
+        MessageContent::Timing(summary) => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(format!("· {}", summary), Style::default().fg(theme.dim)),
+            ]));
+        }
+        MessageContent::Image {
+            name,
+            media_type,
+            data,
+        } => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    format!(
+                        "[image: {} · {} · {} KB]",
+                        name,
+                        media_type,
+                        (data.len() * 3 / 4).div_ceil(1024)
+                    ),
+                    Style::default().fg(theme.highlight),
+                ),
+            ]));
+        }
+        MessageContent::Document { name, data, .. } => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    format!(
+                        "[document: {} · {} KB]",
+                        name,
+                        (data.len() * 3 / 4).div_ceil(1024)
+                    ),
+                    Style::default().fg(theme.highlight),
+                ),
+            ]));
+        }
+        MessageContent::Upload { name, file_id } => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    format!("[uploaded: {} · {}]", name, file_id),
+                    Style::default().fg(theme.highlight),
+                ),
+            ]));
+        }
+        MessageContent::File {
+            path,
+            content,
+            truncated,
+        } => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    format!(
+                        "[file: {} · {} lines · {} KB{}]",
+                        path,
+                        content.lines().count(),
+                        content.len().div_ceil(1024),
+                        if *truncated { ", truncated" } else { "" }
+                    ),
+                    Style::default().fg(theme.accent),
+                ),
+            ]));
+        }
+        MessageContent::Context { label, content } => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    format!("[context: {} · {} lines]", label, content.lines().count()),
+                    Style::default().fg(theme.accent),
+                ),
+            ]));
+        }
+        MessageContent::Summary(summary) => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled(
+                    "≡ Summary of the earlier conversation".to_string(),
+                    Style::default()
+                        .fg(theme.highlight)
+                        .add_modifier(Modifier::BOLD),
+                ),
+            ]));
+            for line in summary.lines() {
+                lines.push(Line::from(vec![
+                    Span::raw(prefix.to_string()),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.highlight)),
+                    Span::styled(line.to_string(), Style::default().fg(theme.secondary)),
+                ]));
+            }
+        }
+        MessageContent::Info(text) => {
+            for line in text.lines() {
+                lines.push(Line::from(vec![
+                    Span::raw(prefix.to_string()),
+                    Span::styled(
+                        line.to_string(),
+                        Style::default()
+                            .fg(theme.dim)
+                            .add_modifier(Modifier::ITALIC),
+                    ),
+                ]));
+            }
+        }
+        MessageContent::ToolUse { name, input, .. } => {
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled("┌─ ".to_string(), Style::default().fg(theme.dim)),
+                Span::styled(
+                    format!("⚙ {}", name),
+                    Style::default()
+                        .fg(theme.success)
+                        .add_modifier(Modifier::BOLD),
+                ),
+            ]));
+            // Commands and edits read better as such than as their JSON input
+            let described = tools::describe(name, input);
+            let input = described.clone().unwrap_or_else(|| {
+                serde_json::from_str::<serde_json::Value>(input)
+                    .and_then(|value| serde_json::to_string_pretty(&value))
+                    .unwrap_or_else(|_| input.clone())
+            });
+            for line in preview_text(&input).lines() {
+                let color = match line.get(..2) {
+                    Some("+ ") if described.is_some() => theme.success,
+                    Some("- ") if described.is_some() => theme.error,
+                    _ => theme.heading,
+                };
+                lines.push(Line::from(vec![
+                    Span::raw(prefix.to_string()),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
+                    Span::styled(line.to_string(), Style::default().fg(color)),
+                ]));
+            }
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled("└─".to_string(), Style::default().fg(theme.dim)),
+            ]));
+        }
+        MessageContent::ToolResult {
+            name,
+            content,
+            is_error,
+            ..
+        } => {
+            let (label, color) = match (name.is_empty(), *is_error) {
+                (true, false) => ("Result".to_string(), theme.success),
+                (true, true) => ("Result (Error)".to_string(), theme.error),
+                (false, false) => (format!("{} result", name), theme.success),
+                (false, true) => (format!("{} result (Error)", name), theme.error),
+            };
+            lines.push(Line::from(vec![
+                Span::raw(prefix.to_string()),
+                Span::styled("┌─ ".to_string(), Style::default().fg(theme.dim)),
+                Span::styled(
+                    label,
+                    Style::default().fg(color).add_modifier(Modifier::BOLD),
                 ),
-                Span::styled(error.to_string(), Style::default().fg(Color::Red)),
             ]));
-        }
-        MessageContent::ApiError(error) => {
+            // Only a preview; Claude gets the whole output
+            let text_color = if *is_error { theme.error } else { theme.text };
+            for line in preview_text(content).lines() {
+                lines.push(Line::from(vec![
+                    Span::raw(prefix.to_string()),
+                    Span::styled("│ ".to_string(), Style::default().fg(theme.dim)),
+                    Span::styled(line.to_string(), Style::default().fg(text_color)),
+                ]));
+            }
             lines.push(Line::from(vec![
                 Span::raw(prefix.to_string()),
-                Span::styled(
-                    "❌ API Error: ".to_string(),
-                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
-                ),
-                Span::styled(error.to_string(), Style::default().fg(Color::Red)),
+                Span::styled("└─".to_string(), Style::default().fg(theme.dim)),
             ]));
         }
     }
 }
 
 fn render_help_modal(f: &mut Frame) {
+    let theme = theme::current();
+    let keymap = keymap::current();
+    let key = |action| format!("  {:<14}", keymap.label(action));
     let area = centered_rect(60, 80, f.area());
 
     // Clear the area behind the modal
@@ -767,95 +4664,209 @@ fn render_help_modal(f: &mut Frame) {
         Line::from(vec![Span::styled(
             "agnt Help",
             Style::default()
-                .fg(Color::Blue)
+                .fg(theme.heading)
                 .add_modifier(Modifier::BOLD),
         )]),
         Line::from(""),
         Line::from(vec![Span::styled(
             "Message Input",
             Style::default()
-                .fg(Color::Blue)
+                .fg(theme.heading)
                 .add_modifier(Modifier::BOLD),
         )]),
         Line::from(vec![
-            Span::styled("  Enter         ", Style::default().fg(Color::Magenta)),
-            Span::styled("Send message", Style::default().fg(Color::Black)),
+            Span::styled(key(Action::Send), Style::default().fg(theme.highlight)),
+            Span::styled("Send message", Style::default().fg(theme.text)),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::Newline), Style::default().fg(theme.highlight)),
+            Span::styled("Insert newline", Style::default().fg(theme.text)),
+        ]),
+        Line::from(vec![
+            Span::styled("  ←/→ Home/End  ", Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Move the cursor (Ctrl+A/Ctrl+E: line start/end)",
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("  Ctrl+←/→      ", Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Move by word (also Alt+B/Alt+F)",
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::Undo), Style::default().fg(theme.highlight)),
+            Span::styled(
+                format!("Undo an edit ({}: redo)", keymap.label(Action::Redo)),
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled(
+                key(Action::DeleteWord),
+                Style::default().fg(theme.highlight),
+            ),
+            Span::styled(
+                format!(
+                    "Delete the word before the cursor ({}/{}: to line start/end)",
+                    keymap.label(Action::KillLine),
+                    keymap.label(Action::KillToEnd)
+                ),
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::Editor), Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Compose the message in $EDITOR",
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("  Esc           ", Style::default().fg(theme.highlight)),
+            Span::styled("Cancel streaming response", Style::default().fg(theme.text)),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::NewTab), Style::default().fg(theme.highlight)),
+            Span::styled("New tab", Style::default().fg(theme.text)),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::NextTab), Style::default().fg(theme.highlight)),
+            Span::styled(
+                format!(
+                    "Next tab ({}: previous; Alt+1-9 picks one)",
+                    keymap.label(Action::PrevTab)
+                ),
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::Focus), Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Pick a message or code block to copy (↑/↓, y; o/s view or save an output)",
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::Retry), Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Regenerate the last answer (/retry)",
+                Style::default().fg(theme.text),
+            ),
         ]),
         Line::from(vec![
-            Span::styled("  Alt+Enter     ", Style::default().fg(Color::Magenta)),
-            Span::styled("Insert newline", Style::default().fg(Color::Black)),
+            Span::styled(key(Action::Rephrase), Style::default().fg(theme.highlight)),
+            Span::styled(
+                "After a refusal: put the prompt back to edit and resend",
+                Style::default().fg(theme.text),
+            ),
         ]),
         Line::from(vec![
-            Span::styled("  Esc           ", Style::default().fg(Color::Magenta)),
             Span::styled(
-                "Cancel streaming response",
-                Style::default().fg(Color::Black),
+                key(Action::HistorySearch),
+                Style::default().fg(theme.highlight),
             ),
+            Span::styled("Search prompt history", Style::default().fg(theme.text)),
         ]),
         Line::from(""),
         Line::from(vec![Span::styled(
             "Navigation",
             Style::default()
-                .fg(Color::Blue)
+                .fg(theme.heading)
                 .add_modifier(Modifier::BOLD),
         )]),
         Line::from(vec![
-            Span::styled("  Page Up       ", Style::default().fg(Color::Magenta)),
-            Span::styled("Scroll up 10 lines", Style::default().fg(Color::Black)),
+            Span::styled("  Tab/Shift+Tab ", Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Switch between the input and the message pane",
+                Style::default().fg(theme.text),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled(key(Action::ScrollUp), Style::default().fg(theme.highlight)),
+            Span::styled("Scroll up 10 lines", Style::default().fg(theme.text)),
+        ]),
+        Line::from(vec![
+            Span::styled(
+                key(Action::ScrollDown),
+                Style::default().fg(theme.highlight),
+            ),
+            Span::styled("Scroll down 10 lines", Style::default().fg(theme.text)),
+        ]),
+        Line::from(vec![
+            Span::styled("  Mouse Wheel   ", Style::default().fg(theme.highlight)),
+            Span::styled("Scroll up/down 3 lines", Style::default().fg(theme.text)),
         ]),
         Line::from(vec![
-            Span::styled("  Page Down     ", Style::default().fg(Color::Magenta)),
-            Span::styled("Scroll down 10 lines", Style::default().fg(Color::Black)),
+            Span::styled("  Mouse Click   ", Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Pick a message (or open a downloaded file)",
+                Style::default().fg(theme.text),
+            ),
         ]),
         Line::from(vec![
-            Span::styled("  Mouse Wheel   ", Style::default().fg(Color::Magenta)),
-            Span::styled("Scroll up/down 3 lines", Style::default().fg(Color::Black)),
+            Span::styled("  Alt+←/→       ", Style::default().fg(theme.highlight)),
+            Span::styled(
+                "Switch between /retry alternatives",
+                Style::default().fg(theme.text),
+            ),
         ]),
         Line::from(""),
         Line::from(vec![Span::styled(
             "Modes",
             Style::default()
-                .fg(Color::Blue)
+                .fg(theme.heading)
                 .add_modifier(Modifier::BOLD),
         )]),
         Line::from(vec![
-            Span::styled("  Ctrl+S        ", Style::default().fg(Color::Magenta)),
+            Span::styled(
+                key(Action::SelectionMode),
+                Style::default().fg(theme.highlight),
+            ),
             Span::styled(
                 "Toggle selection mode (for copying text)",
-                Style::default().fg(Color::Black),
+                Style::default().fg(theme.text),
             ),
         ]),
         Line::from(vec![
-            Span::styled("  Ctrl+X        ", Style::default().fg(Color::Magenta)),
+            Span::styled(
+                key(Action::ToggleCodeExec),
+                Style::default().fg(theme.highlight),
+            ),
             Span::styled(
                 "Toggle code execution mode",
-                Style::default().fg(Color::Black),
+                Style::default().fg(theme.text),
             ),
         ]),
         Line::from(vec![
-            Span::styled("  Ctrl+W        ", Style::default().fg(Color::Magenta)),
-            Span::styled("Toggle web search mode", Style::default().fg(Color::Black)),
+            Span::styled(
+                key(Action::ToggleWebSearch),
+                Style::default().fg(theme.highlight),
+            ),
+            Span::styled("Toggle web search mode", Style::default().fg(theme.text)),
         ]),
         Line::from(""),
         Line::from(vec![Span::styled(
             "General",
             Style::default()
-                .fg(Color::Blue)
+                .fg(theme.heading)
                 .add_modifier(Modifier::BOLD),
         )]),
         Line::from(vec![
-            Span::styled("  Ctrl+H        ", Style::default().fg(Color::Magenta)),
-            Span::styled("Show/hide this help", Style::default().fg(Color::Black)),
+            Span::styled(key(Action::Help), Style::default().fg(theme.highlight)),
+            Span::styled("Show/hide this help", Style::default().fg(theme.text)),
         ]),
         Line::from(vec![
-            Span::styled("  Ctrl+C        ", Style::default().fg(Color::Magenta)),
-            Span::styled("Quit agnt", Style::default().fg(Color::Black)),
+            Span::styled(key(Action::Quit), Style::default().fg(theme.highlight)),
+            Span::styled("Quit agnt", Style::default().fg(theme.text)),
         ]),
         Line::from(""),
         Line::from(vec![Span::styled(
             "Press any key to close this help",
             Style::default()
-                .fg(Color::DarkGray)
+                .fg(theme.dim)
                 .add_modifier(Modifier::ITALIC),
         )]),
     ];
@@ -865,8 +4876,8 @@ fn render_help_modal(f: &mut Frame) {
             Block::default()
                 .title(" Help ")
                 .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::Cyan))
-                .style(Style::default().bg(Color::Indexed(252))),
+                .border_style(Style::default().fg(theme.accent))
+                .style(Style::default().bg(theme.popup_bg)),
         )
         .alignment(Alignment::Left)
         .wrap(Wrap { trim: true });
@@ -874,7 +4885,39 @@ fn render_help_modal(f: &mut Frame) {
     f.render_widget(help, area);
 }
 
+// Split `text` into spans, styling the chars at `positions` (char indices) differently
+fn highlight_matches(
+    text: &str,
+    positions: &[usize],
+    base: Style,
+    highlight: Style,
+) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut current = String::new();
+    let mut current_matched = false;
+
+    for (i, c) in text.chars().enumerate() {
+        let matched = positions.contains(&i);
+        if matched != current_matched && !current.is_empty() {
+            let style = if current_matched { highlight } else { base };
+            spans.push(Span::styled(std::mem::take(&mut current), style));
+        }
+        current_matched = matched;
+        current.push(c);
+    }
+    if !current.is_empty() {
+        let style = if current_matched { highlight } else { base };
+        spans.push(Span::styled(current, style));
+    }
+    spans
+}
+
 fn render_slash_command_menu(f: &mut Frame, state: &SlashCommandState, input_area: Rect) {
+    let theme = theme::current();
+    if let Some(command) = &state.command {
+        render_argument_menu(f, state, command, input_area);
+        return;
+    }
     if state.suggestions.is_empty() {
         return;
     }
@@ -883,7 +4926,7 @@ fn render_slash_command_menu(f: &mut Frame, state: &SlashCommandState, input_are
     let max_cmd_width = state
         .suggestions
         .iter()
-        .map(|cmd| cmd.name.len() + cmd.description.len() + 7) // +7 for "/ - " and some padding
+        .map(|cmd| cmd.name.width() + cmd.description.width() + 7) // +7 for "/ - " and some padding
         .max()
         .unwrap_or(20);
 
@@ -911,7 +4954,7 @@ fn render_slash_command_menu(f: &mut Frame, state: &SlashCommandState, input_are
     };
 
     if shadow_area.width > 0 && shadow_area.height > 0 {
-        let shadow = Block::default().style(Style::default().bg(Color::Indexed(233))); // Very dark shadow
+        let shadow = Block::default().style(Style::default().bg(theme.shadow)); // Very dark shadow
         f.render_widget(shadow, shadow_area);
     }
 
@@ -923,50 +4966,489 @@ fn render_slash_command_menu(f: &mut Frame, state: &SlashCommandState, input_are
         .map(|(i, cmd)| {
             let is_selected = i == state.selected_index;
 
+            let positions = state
+                .match_positions
+                .get(i)
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+
             let content = if is_selected {
+                let name_style = Style::default()
+                    .fg(theme.on_accent)
+                    .bg(theme.accent)
+                    .add_modifier(Modifier::BOLD);
+                let mut spans = vec![Span::styled(" /", name_style)];
+                spans.extend(highlight_matches(
+                    &cmd.name,
+                    positions,
+                    name_style,
+                    name_style.add_modifier(Modifier::UNDERLINED),
+                ));
+                spans.push(Span::styled(
+                    format!(" - {} ", cmd.description),
+                    Style::default().fg(theme.on_accent).bg(theme.accent),
+                ));
+                Line::from(spans)
+            } else {
+                let name_style = Style::default()
+                    .fg(theme.success)
+                    .add_modifier(Modifier::BOLD);
+                let mut spans = vec![Span::raw(" "), Span::styled("/", name_style)];
+                spans.extend(highlight_matches(
+                    &cmd.name,
+                    positions,
+                    name_style,
+                    name_style
+                        .fg(theme.warning)
+                        .add_modifier(Modifier::UNDERLINED),
+                ));
+                spans.push(Span::styled(" - ", Style::default().fg(theme.dim)));
+                spans.push(Span::styled(
+                    &cmd.description,
+                    Style::default().fg(theme.secondary),
+                ));
+                spans.push(Span::raw(" "));
+                Line::from(spans)
+            };
+
+            ListItem::new(content)
+        })
+        .collect();
+
+    let list = List::new(items).block(
+        Block::default()
+            .title("┤ Commands ├")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(Style::default().fg(theme.dim))
+            .style(Style::default().bg(theme.popup_bg)), // Very dark gray background
+    );
+
+    f.render_widget(list, menu_area);
+}
+
+// Completions for the argument being typed, scrolled to keep the selection visible
+fn render_argument_menu(
+    f: &mut Frame,
+    state: &SlashCommandState,
+    command: &SlashCommand,
+    input_area: Rect,
+) {
+    let theme = theme::current();
+    if state.arg_suggestions.is_empty() {
+        return;
+    }
+    let width = state
+        .arg_suggestions
+        .iter()
+        .map(|arg| arg.value.width() + arg.description.width() + 6)
+        .max()
+        .unwrap_or(20)
+        .max(command.name.len() + 8) as u16;
+    let height = (state.arg_suggestions.len() as u16 + 2).min(10);
+    let area = Rect {
+        x: input_area.x,
+        y: input_area.y.saturating_sub(height),
+        width: (width + 2).min(input_area.width.saturating_sub(2)),
+        height,
+    };
+    f.render_widget(Clear, area);
+
+    let items: Vec<ListItem> = state
+        .arg_suggestions
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let line = if i == state.selected_index {
+                let style = Style::default().fg(theme.on_accent).bg(theme.accent);
                 Line::from(vec![
                     Span::styled(
-                        format!(" /{}", cmd.name),
-                        Style::default()
-                            .fg(Color::Black)
-                            .bg(Color::Cyan)
-                            .add_modifier(Modifier::BOLD),
-                    ),
-                    Span::styled(
-                        format!(" - {} ", cmd.description),
-                        Style::default().fg(Color::Black).bg(Color::Cyan),
+                        format!(" {}", arg.value),
+                        style.add_modifier(Modifier::BOLD),
                     ),
+                    Span::styled(format!(" - {} ", arg.description), style),
                 ])
             } else {
                 Line::from(vec![
-                    Span::raw(" "),
                     Span::styled(
-                        format!("/{}", cmd.name),
+                        format!(" {}", arg.value),
                         Style::default()
-                            .fg(Color::Green)
+                            .fg(theme.success)
                             .add_modifier(Modifier::BOLD),
                     ),
-                    Span::styled(" - ", Style::default().fg(Color::DarkGray)),
-                    Span::styled(&cmd.description, Style::default().fg(Color::Gray)),
-                    Span::raw(" "),
+                    Span::styled(" - ", Style::default().fg(theme.dim)),
+                    Span::styled(
+                        arg.description.clone(),
+                        Style::default().fg(theme.secondary),
+                    ),
                 ])
             };
-
-            ListItem::new(content)
+            ListItem::new(line)
         })
         .collect();
 
     let list = List::new(items).block(
         Block::default()
-            .title("┤ Commands ├")
+            .title(format!("┤ /{} · Tab: complete ├", command.name))
             .title_alignment(Alignment::Center)
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded)
-            .border_style(Style::default().fg(Color::DarkGray))
-            .style(Style::default().bg(Color::Indexed(235))), // Very dark gray background
+            .border_style(Style::default().fg(theme.dim))
+            .style(Style::default().bg(theme.popup_bg)),
     );
+    let mut list_state = ListState::default().with_selected(Some(state.selected_index));
+    f.render_stateful_widget(list, area, &mut list_state);
+}
 
-    f.render_widget(list, menu_area);
+fn render_params_editor(f: &mut Frame, editor: &ParamsEditor) {
+    let theme = theme::current();
+    let area = centered_rect(50, 40, f.area());
+    f.render_widget(Clear, area);
+
+    let mut lines = vec![Line::from("")];
+    for (i, (name, value)) in PARAMS_FIELDS.iter().zip(&editor.values).enumerate() {
+        let selected = i == editor.selected;
+        let label_style = if selected {
+            Style::default()
+                .fg(theme.accent)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(theme.secondary)
+        };
+        let value_span = if value.is_empty() && !selected {
+            Span::styled("(API default)", Style::default().fg(theme.dim))
+        } else {
+            Span::styled(
+                format!("{}{}", value, if selected { "▏" } else { "" }),
+                Style::default().fg(theme.text),
+            )
+        };
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!(" {} {:<12} ", if selected { "›" } else { " " }, name),
+                label_style,
+            ),
+            value_span,
+        ]));
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        " temperature, top_p: 0-1 · top_k: integer · stop: comma-separated",
+        Style::default().fg(theme.dim),
+    )));
+    if let Some(error) = &editor.error {
+        lines.push(Line::from(Span::styled(
+            format!(" {}", error),
+            Style::default().fg(theme.error),
+        )));
+    }
+
+    let modal = Paragraph::new(lines).block(
+        Block::default()
+            .title("┤ Sampling (↑/↓ field, Enter apply, Esc cancel) ├")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(Style::default().fg(theme.dim))
+            .style(Style::default().bg(theme.popup_bg)),
+    );
+    f.render_widget(modal, area);
+}
+
+fn render_session_browser(f: &mut Frame, browser: &SessionBrowser) {
+    let theme = theme::current();
+    let area = centered_rect(80, 70, f.area());
+    f.render_widget(Clear, area);
+    let block = Block::default()
+        .title(format!(
+            "┤ Sessions {}/{} (type to filter, ↑/↓ select, Enter open, Esc cancel) ├",
+            browser.matches.len(),
+            browser.sessions.len()
+        ))
+        .title_alignment(Alignment::Center)
+        .borders(Borders::ALL)
+        .border_type(BorderType::Rounded)
+        .border_style(Style::default().fg(theme.dim))
+        .style(Style::default().bg(theme.popup_bg));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(2), Constraint::Min(1)])
+        .split(inner);
+    f.render_widget(
+        Paragraph::new(Line::from(vec![
+            Span::styled(" › ", Style::default().fg(theme.accent)),
+            Span::styled(
+                format!("{}▏", browser.query),
+                Style::default().fg(theme.text),
+            ),
+        ])),
+        rows[0],
+    );
+
+    let items: Vec<ListItem> = browser
+        .matches
+        .iter()
+        .filter_map(|&i| browser.sessions.get(i))
+        .map(|session| {
+            let updated = session
+                .updated_at
+                .get(..16)
+                .unwrap_or(&session.updated_at)
+                .replace('T', " ");
+            ListItem::new(Line::from(vec![
+                Span::styled(format!(" {}  ", updated), Style::default().fg(theme.dim)),
+                Span::styled(
+                    format!("{:>4} msgs  ", session.messages.len()),
+                    Style::default().fg(theme.dim),
+                ),
+                Span::styled(session.opening(), Style::default().fg(theme.text)),
+                Span::styled(format!("  {}", session.id), Style::default().fg(theme.dim)),
+            ]))
+        })
+        .collect();
+    let list = List::new(items).highlight_style(
+        Style::default()
+            .bg(theme.accent)
+            .fg(theme.on_accent)
+            .add_modifier(Modifier::BOLD),
+    );
+    let mut list_state = ListState::default().with_selected(Some(browser.selected));
+    f.render_stateful_widget(list, rows[1], &mut list_state);
+}
+
+fn render_template_form(f: &mut Frame, form: &TemplateForm) {
+    let theme = theme::current();
+    let area = centered_rect(60, 50, f.area());
+    f.render_widget(Clear, area);
+
+    let width = form.names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let mut lines = vec![Line::from("")];
+    for (i, (name, value)) in form.names.iter().zip(&form.values).enumerate() {
+        let selected = i == form.selected;
+        let label_style = if selected {
+            Style::default()
+                .fg(theme.accent)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(theme.secondary)
+        };
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!(
+                    " {} {:<width$} ",
+                    if selected { "›" } else { " " },
+                    name,
+                    width = width
+                ),
+                label_style,
+            ),
+            Span::styled(
+                format!("{}{}", value, if selected { "▏" } else { "" }),
+                Style::default().fg(theme.text),
+            ),
+        ]));
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        " Enter on the last field puts the prompt in the input box",
+        Style::default().fg(theme.dim),
+    )));
+
+    let modal = Paragraph::new(lines).block(
+        Block::default()
+            .title(format!(
+                "┤ Template {} (↑/↓ field, Enter next, Esc cancel) ├",
+                form.template.name
+            ))
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(Style::default().fg(theme.dim))
+            .style(Style::default().bg(theme.popup_bg)),
+    );
+    f.render_widget(modal, area);
+}
+
+fn render_tool_approval(f: &mut Frame, approval: &tools::Approval) {
+    let theme = theme::current();
+    let (height, title, intro, body, answer) = match approval {
+        tools::Approval::Shell { command, dir } => (
+            40,
+            "┤ Run shell command? ├",
+            format!(" Claude wants to run this in {}:", dir.display()),
+            command,
+            " y: run · any other key: decline",
+        ),
+        tools::Approval::Write { path, diff } => (
+            80,
+            "┤ Write file? ├",
+            format!(" Claude wants to write {}:", path),
+            diff,
+            " y: write · any other key: decline",
+        ),
+    };
+    let area = centered_rect(70, height, f.area());
+    f.render_widget(Clear, area);
+
+    let mut lines = vec![
+        Line::from(""),
+        Line::from(Span::styled(intro, Style::default().fg(theme.secondary))),
+        Line::from(""),
+    ];
+    // Room for the body between the intro and the key hint
+    let room = (area.height as usize).saturating_sub(8);
+    let body_lines: Vec<&str> = body.lines().collect();
+    for line in body_lines.iter().take(room) {
+        let style = match approval {
+            tools::Approval::Shell { .. } => Style::default()
+                .fg(theme.warning)
+                .add_modifier(Modifier::BOLD),
+            tools::Approval::Write { .. } if line.starts_with("@@") => {
+                Style::default().fg(theme.accent)
+            }
+            tools::Approval::Write { .. } if line.starts_with('+') => {
+                Style::default().fg(theme.success)
+            }
+            tools::Approval::Write { .. } if line.starts_with('-') => {
+                Style::default().fg(theme.error)
+            }
+            tools::Approval::Write { .. } => Style::default().fg(theme.secondary),
+        };
+        lines.push(Line::from(Span::styled(format!("   {}", line), style)));
+    }
+    if body_lines.len() > room {
+        lines.push(Line::from(Span::styled(
+            format!("   … {} more lines", body_lines.len() - room),
+            Style::default().fg(theme.dim),
+        )));
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        answer,
+        Style::default().fg(theme.dim),
+    )));
+
+    let modal = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title(title)
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(Style::default().fg(theme.dim))
+            .style(Style::default().bg(theme.popup_bg)),
+    );
+    f.render_widget(modal, area);
+}
+
+fn render_file_preview(f: &mut Frame, preview: &FilePreview) {
+    let theme = theme::current();
+    let area = centered_rect(80, 80, f.area());
+    f.render_widget(Clear, area);
+
+    let mut lines: Vec<Line> = preview
+        .lines
+        .iter()
+        .map(|line| Line::from(line.clone()))
+        .collect();
+    if preview.truncated {
+        lines.push(Line::from(Span::styled(
+            "… (file truncated; press o to open it in full)",
+            Style::default().fg(theme.dim),
+        )));
+    }
+    let modal = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .title(format!(
+                    "┤ {} · line {}/{} (↑/↓ scroll, {}Esc close) ├",
+                    preview.name,
+                    preview.scroll + 1,
+                    preview.lines.len().max(1),
+                    if preview.path.is_some() {
+                        "o open, "
+                    } else {
+                        ""
+                    }
+                ))
+                .title_alignment(Alignment::Center)
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded)
+                .border_style(Style::default().fg(theme.dim))
+                .style(Style::default().bg(theme.popup_bg)),
+        )
+        .scroll((preview.scroll.min(u16::MAX as usize) as u16, 0));
+    f.render_widget(modal, area);
+}
+
+fn render_model_picker(f: &mut Frame, app: &App, selected: usize, input_area: Rect) {
+    let theme = theme::current();
+    let current = app.model.as_str();
+    let entries = models::picker_entries(current, &app.available_models);
+    let label_width = entries
+        .iter()
+        .map(|entry| entry.label.width())
+        .max()
+        .unwrap_or(0)
+        .max(8);
+    let width = entries
+        .iter()
+        .map(|entry| {
+            label_width + entry.id.width() + entry.created.as_ref().map_or(0, |c| c.len() + 2) + 6
+        })
+        .max()
+        .unwrap_or(20) as u16;
+    let height = (entries.len() as u16 + 2).min(input_area.y.max(3));
+    let area = Rect {
+        x: input_area.x,
+        y: input_area.y.saturating_sub(height),
+        width: (width + 4).min(input_area.width.saturating_sub(2)),
+        height,
+    };
+    f.render_widget(Clear, area);
+
+    let items: Vec<ListItem> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let marker = if entry.id == current { "●" } else { " " };
+            let mut text = format!(
+                " {} {:<width$} {} ",
+                marker,
+                entry.label,
+                entry.id,
+                width = label_width
+            );
+            if let Some(created) = &entry.created {
+                text.push_str(&format!(" {} ", created));
+            }
+            let style = if i == selected {
+                Style::default()
+                    .fg(theme.on_accent)
+                    .bg(theme.accent)
+                    .add_modifier(Modifier::BOLD)
+            } else {
+                Style::default().fg(theme.secondary)
+            };
+            ListItem::new(Line::from(Span::styled(text, style)))
+        })
+        .collect();
+
+    let list = List::new(items).block(
+        Block::default()
+            .title("┤ Model (Enter: select, Esc: cancel) ├")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(Style::default().fg(theme.dim))
+            .style(Style::default().bg(theme.popup_bg)),
+    );
+    // Long lists from the Models API scroll to keep the highlight visible
+    let mut list_state = ListState::default().with_selected(Some(selected));
+    f.render_stateful_widget(list, area, &mut list_state);
 }
 
 // Helper function to center a rect
@@ -989,3 +5471,104 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
         ])
         .split(popup_layout[1])[1]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answered(answer: &str) -> App {
+        App {
+            messages: vec![
+                (
+                    "user".to_string(),
+                    vec![MessageContent::Text("hi".to_string())],
+                ),
+                (
+                    "assistant".to_string(),
+                    vec![MessageContent::Text(answer.to_string())],
+                ),
+            ],
+            ..App::default()
+        }
+    }
+
+    fn answer(app: &mut App, text: &str) {
+        app.messages.push((
+            "assistant".to_string(),
+            vec![MessageContent::Text(text.to_string())],
+        ));
+    }
+
+    // The role and text of a message, or "error" for a failed request
+    fn shown(app: &App, index: usize) -> (&str, &str) {
+        let (role, content) = &app.messages[index];
+        match content.as_slice() {
+            [MessageContent::Text(text)] => (role, text),
+            [MessageContent::ApiError(_)] => (role, "error"),
+            _ => panic!("unexpected content"),
+        }
+    }
+
+    #[test]
+    fn deleting_nothing_keeps_redo() {
+        let mut app = App::default();
+        app.set_input("hi".to_string());
+        app.undo_input();
+        assert_eq!(app.input, "");
+        app.delete_before_cursor();
+        app.delete_at_cursor();
+        app.delete_to(editor::prev_word);
+        app.redo_input();
+        assert_eq!(app.input, "hi");
+        app.delete_at_cursor();
+        app.undo_input();
+        assert_eq!(app.input, "");
+    }
+
+    #[test]
+    fn retried_answers_become_alternatives() {
+        let mut app = answered("first");
+        app.retry_last_answer(RequestParams::default());
+        assert_eq!(app.messages.len(), 1);
+        assert!(app.queued_request.is_some());
+
+        answer(&mut app, "second");
+        app.finish_alternative();
+        let alts = app.alternatives.as_ref().unwrap();
+        assert_eq!((alts.variants.len(), alts.selected), (2, 1));
+
+        app.cycle_alternative(1);
+        assert_eq!(shown(&app, 1), ("assistant", "first"));
+        app.cycle_alternative(-1);
+        assert_eq!(shown(&app, 1), ("assistant", "second"));
+
+        // A new turn builds on the shown answer, which then can't be swapped
+        app.messages.push((
+            "user".to_string(),
+            vec![MessageContent::Text("more".to_string())],
+        ));
+        app.cycle_alternative(1);
+        assert_eq!(shown(&app, 1), ("assistant", "second"));
+    }
+
+    #[test]
+    fn failed_retry_keeps_the_original_answer() {
+        let mut app = answered("first");
+        app.retry_last_answer(RequestParams::default());
+        app.add_api_error("overloaded".to_string());
+        app.finish_alternative();
+        assert_eq!(shown(&app, 1), ("assistant", "first"));
+        assert_eq!(shown(&app, 2), ("system", "error"));
+
+        // A partial answer cut off by an error can be swapped back for the original,
+        // and the error is never overwritten
+        let mut app = answered("first");
+        app.retry_last_answer(RequestParams::default());
+        answer(&mut app, "sec");
+        app.add_api_error("connection reset".to_string());
+        app.finish_alternative();
+        app.cycle_alternative(1);
+        assert_eq!(shown(&app, 1), ("assistant", "first"));
+        assert_eq!(shown(&app, 2), ("system", "error"));
+    }
+}
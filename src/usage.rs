@@ -0,0 +1,351 @@
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, TimeDelta};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    anthropic::{Message, TurnMetrics},
+    config::Config,
+    pricing,
+    store::HistoryStore,
+};
+
+// Share of a budget at which agnt starts warning about it
+const BUDGET_WARNING: f64 = 0.8;
+
+// One completed request, stored in ~/.agnt/agnt.db so spend can be totalled
+// across sessions (daily budgets, usage reports)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub timestamp: String, // RFC 3339
+    pub session_id: String,
+    pub model: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub cost_usd: Option<f64>, // None for models without known pricing
+}
+
+impl UsageRecord {
+    pub fn new(session_id: &str, metrics: &TurnMetrics) -> Self {
+        Self {
+            timestamp: Local::now().to_rfc3339(),
+            session_id: session_id.to_string(),
+            model: metrics.model.clone(),
+            input_tokens: metrics.input_tokens,
+            output_tokens: metrics.output_tokens,
+            cache_creation_tokens: metrics.cache_creation_tokens,
+            cache_read_tokens: metrics.cache_read_tokens,
+            cost_usd: pricing::cost(
+                &metrics.model,
+                metrics.input_tokens,
+                metrics.output_tokens,
+                metrics.cache_creation_tokens,
+                metrics.cache_read_tokens,
+            ),
+        }
+    }
+
+    fn time(&self) -> Option<DateTime<Local>> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .ok()
+            .map(|time| time.with_timezone(&Local))
+    }
+
+    fn is_since(&self, cutoff: DateTime<Local>) -> bool {
+        self.time().is_some_and(|time| time >= cutoff)
+    }
+}
+
+// Requests, tokens and cost added up over some records
+#[derive(Debug, Default, Serialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub cost_usd: f64,
+    pub unpriced: u64, // Requests to models without known pricing, left out of cost_usd
+}
+
+impl Totals {
+    fn add(&mut self, entry: &UsageRecord) {
+        self.requests += 1;
+        self.input_tokens += entry.input_tokens;
+        self.output_tokens += entry.output_tokens;
+        self.cache_creation_tokens += entry.cache_creation_tokens;
+        self.cache_read_tokens += entry.cache_read_tokens;
+        match entry.cost_usd {
+            Some(cost) => self.cost_usd += cost,
+            None => self.unpriced += 1,
+        }
+    }
+}
+
+// `agnt usage`: records totalled overall and per model, local day and session
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub since: Option<String>, // RFC 3339; None for everything recorded
+    pub total: Totals,
+    pub models: BTreeMap<String, Totals>,
+    pub days: BTreeMap<String, Totals>, // By YYYY-MM-DD
+    pub sessions: BTreeMap<String, Totals>,
+}
+
+pub fn report(records: &[UsageRecord], since: Option<DateTime<Local>>) -> Report {
+    let mut report = Report {
+        since: since.map(|time| time.to_rfc3339()),
+        ..Default::default()
+    };
+    for entry in records {
+        let Some(time) = entry.time() else {
+            continue;
+        };
+        if since.is_some_and(|since| time < since) {
+            continue;
+        }
+        report.total.add(entry);
+        let day = time.format("%Y-%m-%d").to_string();
+        report
+            .models
+            .entry(entry.model.clone())
+            .or_default()
+            .add(entry);
+        report.days.entry(day).or_default().add(entry);
+        report
+            .sessions
+            .entry(entry.session_id.clone())
+            .or_default()
+            .add(entry);
+    }
+    report
+}
+
+// --since: an age such as 7d, 24h, 2w or 30m, or a date (YYYY-MM-DD, from local midnight)
+pub fn parse_since(text: &str, now: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    let text = text.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|time| time.and_local_timezone(Local).earliest())
+            .ok_or_else(|| format!("No local midnight on {}", text));
+    }
+    let invalid = || {
+        format!(
+            "Invalid --since {:?}; use an age like 7d, 24h, 2w or 30m, or a date like 2026-01-31",
+            text
+        )
+    };
+    let (split, _) = text.char_indices().last().ok_or_else(invalid)?;
+    let (count, unit) = text.split_at(split);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let age = match unit {
+        "m" => TimeDelta::try_minutes(count),
+        "h" => TimeDelta::try_hours(count),
+        "d" => TimeDelta::try_days(count),
+        "w" => TimeDelta::try_weeks(count),
+        _ => None,
+    };
+    age.and_then(|age| now.checked_sub_signed(age))
+        .ok_or_else(invalid)
+}
+
+pub fn record(entry: &UsageRecord) -> Result<()> {
+    HistoryStore::open()?.record_usage(entry)
+}
+
+pub fn load() -> Vec<UsageRecord> {
+    match HistoryStore::open().and_then(|store| store.usage()) {
+        Ok(records) => records,
+        Err(e) => {
+            log_debug!("Failed to load usage: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn spent_since(cutoff: DateTime<Local>) -> f64 {
+    load()
+        .iter()
+        .filter(|entry| entry.is_since(cutoff))
+        .filter_map(|entry| entry.cost_usd)
+        .sum()
+}
+
+pub fn spent_today() -> f64 {
+    let midnight = Local::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .and_then(|time| time.and_local_timezone(Local).earliest());
+    match midnight {
+        Some(midnight) => spent_since(midnight),
+        None => 0.0,
+    }
+}
+
+// What a session has spent so far, for sessions that outlive one process (pipe mode -c)
+pub fn spent_in_session(session_id: &str) -> f64 {
+    load()
+        .iter()
+        .filter(|entry| entry.session_id == session_id)
+        .filter_map(|entry| entry.cost_usd)
+        .sum()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BudgetCheck {
+    Within,
+    Warning(String),  // The request takes a budget past BUDGET_WARNING
+    Exceeded(String), // The request would go over a budget
+}
+
+// Check a request estimated to cost `estimate` against the session and daily budgets,
+// given what the session has spent so far
+pub fn check_budgets(config: &Config, session_spent: f64, estimate: f64) -> BudgetCheck {
+    let daily = config.daily_budget_usd.map(|limit| (limit, spent_today()));
+    assess(
+        config
+            .session_budget_usd
+            .map(|limit| (limit, session_spent)),
+        daily,
+        estimate,
+    )
+}
+
+// The same for a request that is ready to go out, from its estimated input cost
+pub fn check_request(
+    config: &Config,
+    model: &str,
+    session_spent: f64,
+    system_prompt: &str,
+    messages: &[Message],
+) -> BudgetCheck {
+    let tokens = pricing::estimate_request_tokens(system_prompt, messages);
+    let estimate = pricing::cost(model, tokens, 0, 0, 0).unwrap_or(0.0);
+    check_budgets(config, session_spent, estimate)
+}
+
+// check_request for the next request of a session, counting what it has spent so far
+// (earlier runs with -c and the requests of the current answer)
+pub fn check_session_request(
+    config: &Config,
+    model: &str,
+    session_id: &str,
+    system_prompt: &str,
+    messages: &[Message],
+) -> BudgetCheck {
+    let spent = if config.session_budget_usd.is_some() {
+        spent_in_session(session_id)
+    } else {
+        0.0
+    };
+    check_request(config, model, spent, system_prompt, messages)
+}
+
+// (limit, spent) for each budget that is set
+fn assess(session: Option<(f64, f64)>, daily: Option<(f64, f64)>, estimate: f64) -> BudgetCheck {
+    let budgets = [("Session", "", session), ("Daily", " today", daily)];
+    for (name, period, budget) in budgets {
+        if let Some((limit, spent)) = budget
+            && spent + estimate > limit
+        {
+            return BudgetCheck::Exceeded(format!(
+                "{} budget of ${:.2} reached (spent ${:.4}{}, this request ~${:.4})",
+                name, limit, spent, period, estimate
+            ));
+        }
+    }
+    for (name, period, budget) in budgets {
+        if let Some((limit, spent)) = budget
+            && spent + estimate >= limit * BUDGET_WARNING
+        {
+            return BudgetCheck::Warning(format!(
+                "{} budget of ${:.2} is {:.0}% used (spent ${:.4}{}, this request ~${:.4})",
+                name,
+                limit,
+                (spent + estimate) / limit * 100.0,
+                spent,
+                period,
+                estimate
+            ));
+        }
+    }
+    BudgetCheck::Within
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets_warn_before_they_run_out() {
+        assert_eq!(assess(None, None, 5.0), BudgetCheck::Within);
+        assert_eq!(
+            assess(Some((1.0, 0.5)), Some((10.0, 2.0)), 0.1),
+            BudgetCheck::Within
+        );
+
+        let BudgetCheck::Warning(warning) = assess(Some((1.0, 0.5)), Some((10.0, 8.0)), 0.1) else {
+            panic!("expected a warning");
+        };
+        assert_eq!(
+            warning,
+            "Daily budget of $10.00 is 81% used (spent $8.0000 today, this request ~$0.1000)"
+        );
+        // Going over one budget matters more than nearing another
+        assert!(matches!(
+            assess(Some((1.0, 0.85)), Some((10.0, 9.95)), 0.1),
+            BudgetCheck::Exceeded(reason) if reason.starts_with("Daily budget of $10.00 reached")
+        ));
+    }
+
+    #[test]
+    fn reports_total_per_model_day_and_session() {
+        let record = |timestamp: &str, session_id: &str, model: &str, cost_usd| UsageRecord {
+            timestamp: timestamp.to_string(),
+            session_id: session_id.to_string(),
+            model: model.to_string(),
+            input_tokens: 100,
+            output_tokens: 10,
+            cache_creation_tokens: 0,
+            cache_read_tokens: 5,
+            cost_usd,
+        };
+        let local = |text: &str| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+                .and_local_timezone(Local)
+                .unwrap()
+        };
+        let records = [
+            record(&local("2026-03-01").to_rfc3339(), "a", "opus", Some(0.5)),
+            record(&local("2026-03-02").to_rfc3339(), "a", "sonnet", Some(0.25)),
+            record(&local("2026-03-02").to_rfc3339(), "b", "llama3", None),
+            record("not a time", "c", "sonnet", Some(1.0)),
+        ];
+
+        let all = report(&records, None);
+        assert_eq!(all.total.requests, 3);
+        assert_eq!(all.total.input_tokens, 300);
+        assert_eq!(all.total.cost_usd, 0.75);
+        assert_eq!(all.total.unpriced, 1);
+        assert_eq!(all.models["sonnet"].requests, 1);
+        assert_eq!(all.days["2026-03-02"].requests, 2);
+        assert_eq!(all.sessions["a"].cost_usd, 0.75);
+
+        let since = parse_since("2026-03-02", Local::now()).unwrap();
+        let recent = report(&records, Some(since));
+        assert_eq!(recent.total.requests, 2);
+        assert!(!recent.models.contains_key("opus"));
+
+        let now = local("2026-03-10");
+        assert_eq!(parse_since("7d", now).unwrap(), local("2026-03-03"));
+        assert_eq!(parse_since("1w", now).unwrap(), local("2026-03-03"));
+        assert!(parse_since("7", now).is_err());
+        assert!(parse_since("d", now).is_err());
+    }
+}
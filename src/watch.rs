@@ -0,0 +1,191 @@
+use ignore::{WalkBuilder, overrides::OverrideBuilder};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::workspace::unified_diff;
+
+// Files larger than this are reported as changed without their contents
+const MAX_FILE_BYTES: u64 = 256 * 1024;
+
+// Files sent with their diff in one round; any others are only named
+const MAX_DIFFS: usize = 20;
+
+#[derive(PartialEq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+// Polls the files under `root` that match the globs (every file git doesn't ignore
+// when there are none). Each file's text as of the last round is kept, so a round
+// can send what changed as a diff however many times the file was saved in between.
+pub struct Watcher {
+    root: PathBuf,
+    globs: Vec<String>,
+    stamps: BTreeMap<PathBuf, Stamp>,
+    baseline: BTreeMap<PathBuf, Option<String>>, // None for binary and oversized files
+    pending: BTreeSet<PathBuf>,                  // Changed since the last round
+}
+
+impl Watcher {
+    pub fn new(root: &Path, globs: &[String]) -> Result<Self, String> {
+        let mut watcher = Self {
+            root: root.to_path_buf(),
+            globs: globs.to_vec(),
+            stamps: BTreeMap::new(),
+            baseline: BTreeMap::new(),
+            pending: BTreeSet::new(),
+        };
+        watcher.stamps = watcher.scan()?;
+        watcher.baseline = watcher
+            .stamps
+            .keys()
+            .map(|path| (path.clone(), read_text(path)))
+            .collect();
+        Ok(watcher)
+    }
+
+    pub fn len(&self) -> usize {
+        self.stamps.len()
+    }
+
+    fn scan(&self) -> Result<BTreeMap<PathBuf, Stamp>, String> {
+        let mut overrides = OverrideBuilder::new(&self.root);
+        for glob in &self.globs {
+            overrides
+                .add(glob)
+                .map_err(|e| format!("Invalid glob {:?}: {}", glob, e))?;
+        }
+        let overrides = overrides.build().map_err(|e| e.to_string())?;
+        let walk = WalkBuilder::new(&self.root)
+            .require_git(false)
+            .overrides(overrides)
+            .build();
+        Ok(walk
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let stamp = Stamp {
+                    modified: metadata.modified().ok(),
+                    len: metadata.len(),
+                };
+                Some((entry.into_path(), stamp))
+            })
+            .collect())
+    }
+
+    // Look for changes; true if any turned up since the last poll
+    pub fn poll(&mut self) -> Result<bool, String> {
+        let current = self.scan()?;
+        for (path, stamp) in &current {
+            if self.stamps.get(path) != Some(stamp) {
+                self.pending.insert(path.clone());
+            }
+        }
+        for path in self.stamps.keys() {
+            if !current.contains_key(path) {
+                self.pending.insert(path.clone());
+            }
+        }
+        let changed = current != self.stamps;
+        self.stamps = current;
+        Ok(changed)
+    }
+
+    // (file, diff) for everything that changed since the last round, which starts a new
+    // one; files saved without any change in their text are left out
+    pub fn take(&mut self) -> Vec<(String, String)> {
+        let mut changes = Vec::new();
+        let mut unlisted = Vec::new();
+        for path in std::mem::take(&mut self.pending) {
+            let name = path
+                .strip_prefix(&self.root)
+                .unwrap_or(&path)
+                .display()
+                .to_string();
+            let old = self.baseline.remove(&path);
+            let diff = if self.stamps.contains_key(&path) {
+                let new = read_text(&path);
+                let diff = match (old, &new) {
+                    (Some(Some(old)), Some(new)) if old == *new => None,
+                    (Some(Some(old)), Some(new)) => Some(unified_diff(&name, &old, new)),
+                    (_, Some(new)) => Some(unified_diff(&name, "", new)),
+                    (_, None) => Some(format!(
+                        "(binary or over {} KB; contents left out)",
+                        MAX_FILE_BYTES / 1024
+                    )),
+                };
+                self.baseline.insert(path, new);
+                diff
+            } else {
+                Some("(deleted)".to_string())
+            };
+            let Some(diff) = diff else {
+                continue;
+            };
+            if changes.len() < MAX_DIFFS {
+                changes.push((name, diff));
+            } else {
+                unlisted.push(name);
+            }
+        }
+        if !unlisted.is_empty() {
+            changes.push((
+                "other files".to_string(),
+                format!("(diffs left out)\n{}", unlisted.join("\n")),
+            ));
+        }
+        changes
+    }
+}
+
+fn read_text(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    if metadata.len() > MAX_FILE_BYTES {
+        return None;
+    }
+    String::from_utf8(fs::read(path).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_report_diffs_against_the_last_round() {
+        let dir = std::env::temp_dir().join(format!("agnt-watch-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/a.rs"), "one\n").unwrap();
+        fs::write(dir.join("notes.md"), "skip\n").unwrap();
+
+        let mut watcher = Watcher::new(&dir, &["src/**/*.rs".to_string()]).unwrap();
+        assert_eq!(watcher.len(), 1);
+        assert!(!watcher.poll().unwrap());
+
+        fs::write(dir.join("src/a.rs"), "one\ntwo\n").unwrap();
+        fs::write(dir.join("src/b.rs"), "new\n").unwrap();
+        fs::write(dir.join("notes.md"), "still skipped\n").unwrap();
+        assert!(watcher.poll().unwrap());
+        fs::write(dir.join("src/a.rs"), "one\ntwo\nthree\n").unwrap();
+        assert!(watcher.poll().unwrap());
+
+        let changes = watcher.take();
+        let names: Vec<&str> = changes.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["src/a.rs", "src/b.rs"]);
+        assert!(changes[0].1.contains("+two\n+three\n"));
+        assert!(changes[1].1.contains("+new\n"));
+
+        fs::remove_file(dir.join("src/b.rs")).unwrap();
+        assert!(watcher.poll().unwrap());
+        assert_eq!(
+            watcher.take(),
+            vec![("src/b.rs".to_string(), "(deleted)".to_string())]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use similar::TextDiff;
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+// The directory --allow-files opens to the file tools. Paths from Claude are resolved
+// inside it (symlinks included), and `allowed_paths` can narrow it further.
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub root: PathBuf,     // Canonical
+    allowed: Vec<PathBuf>, // Resolved allowed_paths; empty allows the whole root
+}
+
+impl Workspace {
+    pub fn new(root: PathBuf, allowed_paths: &[String]) -> Result<Self, String> {
+        let mut workspace = Self {
+            root,
+            allowed: Vec::new(),
+        };
+        workspace.allowed = allowed_paths
+            .iter()
+            .map(|path| workspace.resolve(path))
+            .collect::<Result<_, _>>()?;
+        Ok(workspace)
+    }
+
+    // A path as the tools show it: relative to the root
+    pub fn display(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+            Ok(relative) => relative.display().to_string(),
+            Err(_) => path.display().to_string(),
+        }
+    }
+
+    pub fn read(&self, path: &str) -> Result<String, String> {
+        let path = self.allowed_path(path)?;
+        let bytes =
+            fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        String::from_utf8(bytes).map_err(|_| format!("{} is not a text file", self.display(&path)))
+    }
+
+    // Entries one per line, directories first and marked with a trailing slash. Parents
+    // of allowed paths can be listed, showing only the way to them.
+    pub fn list(&self, path: &str) -> Result<String, String> {
+        let dir = self.resolve(path)?;
+        if !self.is_allowed(&dir) && !self.leads_to_allowed(&dir) {
+            return Err(format!(
+                "{} is not in the allowed paths",
+                self.display(&dir)
+            ));
+        }
+        let entries =
+            fs::read_dir(&dir).map_err(|e| format!("Cannot list {}: {}", dir.display(), e))?;
+        let mut listing: Vec<(bool, String)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let path = entry.path();
+                self.is_allowed(&path) || self.leads_to_allowed(&path)
+            })
+            .map(|entry| {
+                let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+                let name = entry.file_name().to_string_lossy().into_owned();
+                (!is_dir, if is_dir { format!("{}/", name) } else { name })
+            })
+            .collect();
+        listing.sort();
+        if listing.is_empty() {
+            return Ok(format!("{} is empty", self.display(&dir)));
+        }
+        Ok(listing
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    // What writing `content` to `path` would change, as a unified diff
+    pub fn diff(&self, path: &str, content: &str) -> Result<String, String> {
+        let path = self.allowed_path(path)?;
+        let old = match fs::read(&path) {
+            Ok(bytes) => String::from_utf8(bytes)
+                .map_err(|_| format!("{} is not a text file", self.display(&path)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e)),
+        };
+        Ok(unified_diff(&self.display(&path), &old, content))
+    }
+
+    pub fn write(&self, path: &str, content: &str) -> Result<String, String> {
+        let path = self.allowed_path(path)?;
+        let existed = path.exists();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+        }
+        fs::write(&path, content).map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+        Ok(format!(
+            "{} {} ({} bytes)",
+            if existed { "Wrote" } else { "Created" },
+            self.display(&path),
+            content.len()
+        ))
+    }
+
+    pub fn allowed_path(&self, path: &str) -> Result<PathBuf, String> {
+        let path = self.resolve(path)?;
+        if !self.is_allowed(&path) {
+            return Err(format!(
+                "{} is not in the allowed paths",
+                self.display(&path)
+            ));
+        }
+        Ok(path)
+    }
+
+    fn is_allowed(&self, path: &Path) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|allowed| path.starts_with(allowed))
+    }
+
+    fn leads_to_allowed(&self, dir: &Path) -> bool {
+        self.allowed.iter().any(|allowed| allowed.starts_with(dir))
+    }
+
+    // Absolute path for a relative (to the root) or absolute one. `..` is applied
+    // before symlinks are followed, and the part that exists is canonicalized, so
+    // neither can lead outside the root. A broken symlink can't be canonicalized and
+    // would be followed by a write, so it is refused rather than taken as missing.
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let path = path.trim();
+        let joined = self.root.join(if path.is_empty() { "." } else { path });
+        let mut normalized = PathBuf::new();
+        for component in joined.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                other => normalized.push(other),
+            }
+        }
+
+        let mut existing = normalized.as_path();
+        let mut missing = Vec::new();
+        let mut resolved = loop {
+            match existing.canonicalize() {
+                Ok(canonical) => break canonical,
+                Err(_) if existing.symlink_metadata().is_ok_and(|m| m.is_symlink()) => {
+                    return Err(format!("{} goes through a broken symlink", path));
+                }
+                Err(_) => match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        missing.push(name.to_os_string());
+                        existing = parent;
+                    }
+                    _ => return Err(format!("Cannot resolve {}", path)),
+                },
+            }
+        };
+        resolved.extend(missing.iter().rev());
+
+        if !resolved.starts_with(&self.root) {
+            return Err(format!("{} is outside the workspace", path));
+        }
+        Ok(resolved)
+    }
+}
+
+pub fn unified_diff(name: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return "(no changes)".to_string();
+    }
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&format!("a/{}", name), &format!("b/{}", name))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_inside_the_allowed_ones() {
+        let root = std::env::temp_dir().join(format!("agnt-workspace-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("secret.txt"), "hunter2").unwrap();
+        let workspace = Workspace::new(root.canonicalize().unwrap(), &["src".to_string()]).unwrap();
+
+        assert_eq!(workspace.read("src/main.rs").unwrap(), "fn main() {}\n");
+        assert!(workspace.read("secret.txt").is_err());
+        assert!(workspace.read("src/../secret.txt").is_err());
+        assert!(workspace.read("../../etc/passwd").is_err());
+        assert_eq!(workspace.list(".").unwrap(), "src/");
+
+        let diff = workspace.diff("src/new/lib.rs", "pub fn f() {}\n").unwrap();
+        assert!(diff.contains("+++ b/src/new/lib.rs") && diff.contains("+pub fn f() {}"));
+        assert!(workspace.write("src/new/lib.rs", "pub fn f() {}\n").is_ok());
+        assert_eq!(workspace.list("src").unwrap(), "new/\nmain.rs");
+
+        // A dangling link must not let a write create its target outside the root
+        #[cfg(unix)]
+        {
+            let outside = root.with_extension("outside");
+            std::os::unix::fs::symlink(&outside, root.join("src/link")).unwrap();
+            assert!(workspace.write("src/link", "x").is_err());
+            assert!(workspace.write("src/link/inner.rs", "x").is_err());
+            assert!(!outside.exists());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}